@@ -11,19 +11,24 @@ use anyhow::{anyhow, Context};
 use slog::crit;
 use slog::info;
 
+use crate::constants as afconst;
+use crate::errors::AlevinFryError;
+use crate::io_utils::{self, SpillingReader};
+use crate::knee::{self, KneeThresholds};
 use crate::prog_opts::GenPermitListOpts;
 use crate::utils as afutils;
 #[allow(unused_imports)]
 use ahash::{AHasher, RandomState};
 use bio_types::strand::Strand;
 use bstr::io::BufReadExt;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use itertools::Itertools;
-use libradicl::exit_codes;
 use libradicl::rad_types;
 use libradicl::BarcodeLookupMap;
 use needletail::bitkmer::*;
 use num_format::{Locale, ToFormattedString};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::cmp;
 use std::collections::HashMap;
@@ -33,7 +38,7 @@ use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::time::Instant;
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum CellFilterMethod {
     // cut off at this cell in
     // the frequency sorted list
@@ -53,161 +58,73 @@ pub enum CellFilterMethod {
     // automatically find the knee
     // in the curve
     KneeFinding,
+    // use the inflection point of the
+    // log-log barcode rank curve
+    KneeInflection,
+    // use the point of minimum curvature
+    // of the smoothed log-log barcode
+    // rank curve
+    KneeCurvature,
+    // use the crossover point of a
+    // two-component mixture fit to the
+    // log barcode counts
+    KneeMixture,
 }
 
-struct Point {
-    x: f64,
-    y: f64,
+/// Summary of a run of [`generate_permit_list`].
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PermitListStats {
+    /// the directory to which the permit list was written
+    pub output_dir: PathBuf,
+    /// the number of read records processed
+    pub num_reads: usize,
+    /// the largest number of references to which a single read mapped
+    pub max_ambiguity_read: usize,
+    /// the expected orientation of alignments that was used
+    pub expected_ori: String,
+    /// the number of barcodes in the permit list
+    pub num_permitted: usize,
+    /// the number of distinct observed barcodes that were corrected
+    pub num_corrected: u64,
+    /// the read count threshold used to select permitted barcodes (if any)
+    pub permit_threshold: Option<u64>,
 }
 
-/// compute the distance between the query point `Q`
-/// and the line defined by points `P1` and `P2`.  The
-/// formula used here is taken from :
-/// https://en.wikipedia.org/wiki/Distance_from_a_point_to_a_line
-fn distance_to_line(p1: &Point, p2: &Point, q: &Point) -> f64 {
-    let x_0 = q.x;
-    let y_0 = q.y;
-
-    let x_1 = p1.x;
-    let y_1 = p1.y;
-
-    let x_2 = p2.x;
-    let y_2 = p2.y;
-
-    let numer = ((y_2 - y_1) * x_0 - (x_2 - x_1) * y_0 + x_2 * y_1 - y_2 * x_1).abs();
-    let denom = ((y_2 - y_1).powi(2) + (x_2 - x_1).powi(2)).sqrt();
-    assert!(denom > 0.0f64);
-    numer / denom
-}
-
-/// This method is a implementation of the distance method
-/// used in umi_tools :
-///     Smith, Tom, Andreas Heger, and Ian Sudbery.
-///     "UMI-tools: modeling sequencing errors in Unique Molecular Identifiers to improve quantification accuracy."
-///     Genome research 27.3 (2017): 491-499.
-///
-/// though this is a re-implementation and uses the same basic algorithm
-/// the result may not be identical.
-///
-/// Given a list of cumulative frequencies, where the index of a
-/// point is interpreted as its x-coordinate and its frequency
-/// is interpreted as its y-coordinate, define the line L based on
-/// `sorted_frequences.first()` and `sorted_frequencies.last()`.  Compute
-/// the distance of each point from L, and return the index of the point
-/// having the maximum distance.
-fn get_max_distance_index(sorted_frequencies: &[u64], is_cumulative: bool) -> usize {
-    assert!(sorted_frequencies.len() >= 2,
-        "ERROR: when attempting to find a knee-distance threshold, the list of putative cells is only of length {}. Cannot proceed. Please check the mapping rate.",
-        sorted_frequencies.len());
-    let first = sorted_frequencies
-        .first()
-        .expect("cannot process empty frequency list.");
-    let last = sorted_frequencies
-        .last()
-        .expect("cannot process empty frequency list.");
-
-    // length as a float
-    let max_x = sorted_frequencies.len() as f64;
-
-    // if the distribution is cumulative, then the smallest y coordinate is
-    // f, otherewise it is l
-    let max_y = if is_cumulative {
-        *last as f64
-    } else {
-        *first as f64
-    };
-
-    let p1 = Point {
-        x: 0.0f64,
-        y: (*first as f64) / max_y,
-    };
-    let p2 = Point {
-        x: 1.0f64,
-        y: (*last as f64) / max_y,
-    };
-
-    let mut max_d: f64 = -1.0;
-    let mut max_ind: usize = 0;
-
-    for (ind, freq) in sorted_frequencies.iter().enumerate() {
-        let x = ind as f64 / max_x;
-        let y = *freq as f64 / max_y;
-        let q = Point { x, y };
-        let d = distance_to_line(&p1, &p2, &q);
-        if d >= max_d {
-            max_d = d;
-            max_ind = ind;
-        }
-    }
-    max_ind
-}
-
-/// Get the knee of the cure using the `distance` method as described
-/// in the [UMI-tools documentation](https://github.com/CGATOxford/UMI-tools).
-/// This method takes a reverse-sorted (sorted in descending order) llist of
-/// frequencies, and a maximum number of iterations to run the algorithm.  It
-/// returns the point on the CDF of the reverse-sorted frequency vector that is
-/// farthest from the line defined by the end-points.  The algorithm is taken from
-/// [here](https://github.com/CGATOxford/UMI-tools/blob/master/umi_tools/whitelist_methods.py#L248).
-fn get_knee(freq: &[u64], max_iterations: usize, log: &slog::Logger) -> usize {
-    // get the cumulative frequency from the frequency
-    let cfreq: Vec<u64> = freq
-        .iter()
-        .scan(0u64, |acc, &num| {
-            *acc += num;
-            Some(*acc)
-        })
-        .collect();
-    // get the guess about the max distance point
-    let mut prev_max = 0;
-    let mut max_idx = get_max_distance_index(&cfreq[..], true);
-
-    // if we think we should include no cells, something is probably wrong.
-    assert_ne!(max_idx, 0,
-	       "get_knee determined a knee index of 0. This probably should not happen with valid input data.");
-
-    let mut iterations = 0;
-    let iter_slack = 5;
-    // while our algorithm hasn't converged
-    while max_idx - prev_max != 0 {
-        info!(log, "max_idx = {}", max_idx);
-        prev_max = max_idx;
-        iterations += 1;
-        if iterations % 10 == 0 {
-            info!(log, "knee-finding iter = {}", iterations);
-        }
-        if iterations > max_iterations {
-            break;
-        }
-        let last_idx = std::cmp::min(cfreq.len() - 1, max_idx * iter_slack);
-        max_idx = get_max_distance_index(&cfreq[0..last_idx], true);
-        assert_ne!(max_idx, 0,
-	       "get_knee determined a knee index of 0. This probably should not happen with valid input data.");
-    }
-    max_idx
+/// The in-memory state produced by [`generate_permit_list_with_maps`],
+/// holding the same information as the `permit_freq.bin` and
+/// `permit_map.bin` files, so that it can be passed directly to
+/// [`crate::collate::collate_with_permit_maps`].
+#[derive(Clone, Debug, Default)]
+pub struct PermitMaps {
+    /// the number of reads assigned to each permitted barcode
+    pub permit_freq: HashMap<u64, u64>,
+    /// the permitted barcode to which each correctable barcode maps
+    pub correct_map: HashMap<u64, u64>,
 }
 
 fn populate_unfiltered_barcode_map<T: Read>(
     br: BufReader<T>,
     first_bclen: &mut usize,
-) -> HashMap<u64, u64, ahash::RandomState> {
+) -> Result<HashMap<u64, u64, ahash::RandomState>, AlevinFryError> {
     let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
     let mut hm = HashMap::with_hasher(s);
 
     // read through the external unfiltered barcode list
     // and generate a vector of encoded barcodes
     // let mut kv = Vec::<u64>::new();
-    for l in br.byte_lines().flatten() {
+    for l in br.byte_lines() {
+        let l = l?;
+        if l.is_empty() {
+            continue;
+        }
         if *first_bclen == 0 {
             *first_bclen = l.len();
-        } else {
-            assert_eq!(
-                *first_bclen,
-                l.len(),
-                "found barcodes of different lengths {} and {}",
+        } else if *first_bclen != l.len() {
+            return Err(AlevinFryError::InvalidInput(format!(
+                "found barcodes of different lengths {} and {} in the unfiltered permit list",
                 *first_bclen,
                 l.len()
-            );
+            )));
         }
         if let Some((_, km, _)) =
             needletail::bitkmer::BitNuclKmer::new(&l[..], l.len() as u8, false).next()
@@ -215,7 +132,101 @@ fn populate_unfiltered_barcode_map<T: Read>(
             hm.insert(km.0, 0);
         }
     }
-    hm
+    Ok(hm)
+}
+
+/// The fraction of the informative sampled reads that must support
+/// a single strand for `--expected-ori auto` to select it.
+const AUTO_ORI_MIN_FRACTION: f64 = 0.75;
+
+/// The result of inferring the expected orientation of alignments
+/// from a sample of the input records.
+#[derive(Clone, Debug, Serialize)]
+pub struct OrientationDetection {
+    /// number of chunks that were sampled
+    pub sampled_chunks: usize,
+    /// number of sampled reads having only forward alignments
+    pub fw_reads: u64,
+    /// number of sampled reads having only reverse-complement alignments
+    pub rc_reads: u64,
+    /// number of sampled reads having alignments in both orientations
+    pub both_reads: u64,
+    /// the fraction of the single-orientation reads supporting the chosen strand
+    pub confidence: f64,
+    /// the strand that was chosen
+    pub chosen: String,
+}
+
+/// Tally the orientation of the alignments of each read in `chunks`, and
+/// determine the dominant strand.  Returns an error if too few reads are
+/// informative, or if neither strand is supported by at least
+/// `AUTO_ORI_MIN_FRACTION` of the single-orientation reads.
+fn detect_orientation(
+    chunks: &[rad_types::Chunk],
+    log: &slog::Logger,
+) -> anyhow::Result<(Strand, OrientationDetection)> {
+    let (mut fw_reads, mut rc_reads, mut both_reads) = (0u64, 0u64, 0u64);
+    for r in chunks.iter().flat_map(|c| c.reads.iter()) {
+        let has_fw = r.dirs.iter().any(|&x| x);
+        let has_rc = r.dirs.iter().any(|&x| !x);
+        match (has_fw, has_rc) {
+            (true, true) => both_reads += 1,
+            (true, false) => fw_reads += 1,
+            (false, true) => rc_reads += 1,
+            (false, false) => {}
+        }
+    }
+
+    let informative = fw_reads + rc_reads;
+    info!(
+        log,
+        "orientation detection sampled {} chunks : {} forward, {} reverse-complement and {} mixed-orientation reads",
+        chunks.len().to_formatted_string(&Locale::en),
+        fw_reads.to_formatted_string(&Locale::en),
+        rc_reads.to_formatted_string(&Locale::en),
+        both_reads.to_formatted_string(&Locale::en)
+    );
+    if informative == 0 {
+        return Err(anyhow!(
+            "could not determine the expected orientation of alignments; none of the sampled \
+            reads aligned in a single orientation. Please provide --expected-ori explicitly."
+        ));
+    }
+
+    let fw_frac = fw_reads as f64 / informative as f64;
+    let (strand, confidence) = if fw_frac >= AUTO_ORI_MIN_FRACTION {
+        (Strand::Forward, fw_frac)
+    } else if 1.0 - fw_frac >= AUTO_ORI_MIN_FRACTION {
+        (Strand::Reverse, 1.0 - fw_frac)
+    } else {
+        return Err(anyhow!(
+            "could not determine the expected orientation of alignments; {:.1}% of the \
+            single-orientation sampled reads were forward and {:.1}% were reverse-complement \
+            (at least {:.0}% are required to select a strand). Please provide --expected-ori \
+            explicitly (e.g. \"both\" if the protocol is not strand-specific).",
+            100.0 * fw_frac,
+            100.0 * (1.0 - fw_frac),
+            100.0 * AUTO_ORI_MIN_FRACTION
+        ));
+    };
+    info!(
+        log,
+        "automatically selected expected orientation {} ({:.2}% of single-orientation reads agree)",
+        strand.strand_symbol(),
+        100.0 * confidence
+    );
+
+    Ok((
+        strand,
+        OrientationDetection {
+            sampled_chunks: chunks.len(),
+            fw_reads,
+            rc_reads,
+            both_reads,
+            confidence,
+            chosen: strand.strand_symbol().to_string(),
+        },
+    ))
 }
 
 #[allow(clippy::unnecessary_unwrap, clippy::too_many_arguments)]
@@ -231,8 +242,11 @@ fn process_unfiltered(
     velo_mode: bool,
     cmdline: &str,
     log: &slog::Logger,
+    ori_detection: Option<&OrientationDetection>,
+    spilled_rad_file: Option<&str>,
+    num_chunks: u64,
     gpl_opts: &GenPermitListOpts,
-) -> anyhow::Result<u64> {
+) -> anyhow::Result<(PermitListStats, PermitMaps)> {
     let parent = std::path::Path::new(output_dir);
     std::fs::create_dir_all(parent)
         .with_context(|| format!("couldn't create directory path {}", parent.display()))?;
@@ -382,12 +396,13 @@ fn process_unfiltered(
     })?;
     let o_path = parent.join("permit_freq.bin");
 
-    match afutils::write_permit_list_freq(&o_path, ft_vals.bclen, &hm) {
-        Ok(_) => {}
-        Err(error) => {
-            panic!("Error: {}", error);
-        }
-    };
+    afutils::write_permit_list_freq(&o_path, ft_vals.bclen, &hm).map_err(|error| {
+        anyhow!(
+            "could not write the permit list frequencies to {} : {}",
+            o_path.display(),
+            error
+        )
+    })?;
 
     /*
     // don't need this right now
@@ -397,6 +412,8 @@ fn process_unfiltered(
     bincode::serialize_into(&mut s_writer, &bcmap2).expect("couldn't serialize barcode list.");
     */
 
+    let permit_freq: HashMap<u64, u64> = hm.iter().map(|(k, v)| (*k, *v)).collect();
+
     // now that we are done with using hm to count, we can repurpose it as
     // the correction map.
     for (k, v) in hm.iter_mut() {
@@ -416,6 +433,9 @@ fn process_unfiltered(
     let meta_info = json!({
     "velo_mode" : velo_mode,
     "expected_ori" : *expected_ori.strand_symbol(),
+    "orientation_detection" : ori_detection,
+    "spilled_rad_file" : spilled_rad_file,
+    "num_chunks" : num_chunks,
     "version_str" : version,
     "max-ambig-record" : max_ambiguity_read,
     "cmd" : cmdline,
@@ -438,7 +458,91 @@ fn process_unfiltered(
         num_corrected.to_formatted_string(&Locale::en)
     );
 
-    Ok(num_corrected)
+    Ok((
+        PermitListStats {
+            output_dir: output_dir.clone(),
+            max_ambiguity_read,
+            expected_ori: expected_ori.strand_symbol().to_string(),
+            num_permitted: num_passing,
+            num_corrected,
+            permit_threshold: Some(min_freq),
+            ..Default::default()
+        },
+        PermitMaps {
+            permit_freq,
+            correct_map: hm.into_iter().collect(),
+        },
+    ))
+}
+
+/// Write the barcode rank table `barcode_ranks.tsv.gz` to `parent`.  Each
+/// observed barcode is listed, in descending order of read count, along
+/// with its rank, its read count, whether it passed the filter, and the
+/// permitted barcode to which it is corrected (if any).  The threshold
+/// that each automatic method would choose is written alongside it in
+/// `barcode_rank_thresholds.tsv`.
+fn write_barcode_ranks(
+    parent: &Path,
+    hm: &HashMap<u64, u64, ahash::RandomState>,
+    full_permit_list: &HashMap<u64, u64>,
+    bclen: u16,
+    knee_thresholds: &KneeThresholds,
+    permit_threshold: Option<u64>,
+) -> anyhow::Result<()> {
+    let mut ranked: Vec<(u64, u64)> = hm.iter().map(|(k, v)| (*k, *v)).collect();
+    // break ties by barcode so that the output is deterministic
+    ranked.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let bc_str = |bc: u64| -> String {
+        String::from_utf8(bitmer_to_bytes((bc, bclen as u8))).unwrap_or_default()
+    };
+
+    let r_path = parent.join("barcode_ranks.tsv.gz");
+    let r_file =
+        File::create(&r_path).with_context(|| format!("could not create {}", r_path.display()))?;
+    let mut writer = BufWriter::new(GzEncoder::new(r_file, Compression::default()));
+    writeln!(
+        writer,
+        "rank\tbarcode\tread_count\tpasses_filter\tcorrected_to"
+    )?;
+    for (rank, (bc, count)) in ranked.iter().enumerate() {
+        let corrected_to = full_permit_list.get(bc);
+        let passes_filter = corrected_to == Some(bc);
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            rank + 1,
+            bc_str(*bc),
+            count,
+            passes_filter,
+            corrected_to.map(|c| bc_str(*c)).unwrap_or_default()
+        )?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| anyhow!("could not flush barcode_ranks.tsv.gz : {}", e))?
+        .finish()
+        .context("could not finish writing barcode_ranks.tsv.gz")?;
+
+    let t_path = parent.join("barcode_rank_thresholds.tsv");
+    let mut t_writer = BufWriter::new(
+        File::create(&t_path).with_context(|| format!("could not create {}", t_path.display()))?,
+    );
+    writeln!(t_writer, "method\tmin_freq\tnum_barcodes")?;
+    for (name, kt) in knee_thresholds.iter() {
+        match kt {
+            Some(kt) => writeln!(t_writer, "{}\t{}\t{}", name, kt.min_freq, kt.num_barcodes)?,
+            None => writeln!(t_writer, "{}\tNA\tNA", name)?,
+        }
+    }
+    if let Some(min_freq) = permit_threshold {
+        let num_barcodes = ranked.partition_point(|&(_, c)| c >= min_freq);
+        writeln!(t_writer, "selected\t{}\t{}", min_freq, num_barcodes)?;
+    }
+    t_writer
+        .flush()
+        .with_context(|| format!("could not write {}", t_path.display()))?;
+    Ok(())
 }
 
 #[allow(clippy::unnecessary_unwrap, clippy::too_many_arguments)]
@@ -453,25 +557,65 @@ fn process_filtered(
     velo_mode: bool,
     cmdline: &str,
     log: &slog::Logger,
+    ori_detection: Option<&OrientationDetection>,
+    spilled_rad_file: Option<&str>,
+    num_chunks: u64,
     gpl_opts: &GenPermitListOpts,
-) -> anyhow::Result<u64> {
+) -> anyhow::Result<(PermitListStats, PermitMaps)> {
     let valid_bc: Vec<u64>;
+    if hm.is_empty() {
+        return Err(AlevinFryError::EmptyHistogram(
+            "no barcodes were observed in orientation-consistent reads; cannot determine a \
+            permit list. Please check the mapping rate and the expected orientation."
+                .to_string(),
+        )
+        .into());
+    }
+
     let mut freq: Vec<u64> = hm.values().cloned().collect();
     freq.sort_unstable();
     freq.reverse();
 
+    // evaluate all of the automatic knee-finding methods
+    // so that their thresholds can be compared.
+    let (knee_thresholds, knee_distance_error) = KneeThresholds::compute(&freq[..], log);
+    let mut permit_threshold: Option<u64> = None;
+
     // select from among supported filter methods
     match filter_meth {
-        CellFilterMethod::KneeFinding => {
-            let num_bc = get_knee(&freq[..], 100, log);
-            let min_freq = freq[num_bc];
+        CellFilterMethod::KneeFinding
+        | CellFilterMethod::KneeInflection
+        | CellFilterMethod::KneeCurvature
+        | CellFilterMethod::KneeMixture => {
+            let (method_name, knee_threshold) = match filter_meth {
+                CellFilterMethod::KneeFinding => ("knee distance", knee_thresholds.distance),
+                CellFilterMethod::KneeInflection => ("knee inflection", knee_thresholds.inflection),
+                CellFilterMethod::KneeCurvature => ("knee curvature", knee_thresholds.curvature),
+                _ => ("knee mixture", knee_thresholds.mixture),
+            };
+            let min_freq = match (knee_threshold, knee_distance_error) {
+                (Some(kt), _) => kt.min_freq,
+                // report the underlying reason for the failure
+                (None, Some(e)) if matches!(filter_meth, CellFilterMethod::KneeFinding) => {
+                    return Err(e);
+                }
+                (None, _) => {
+                    return Err(anyhow!(
+                        "the {} method could not determine a threshold on this data; \
+                        consider using a different filtering method.",
+                        method_name
+                    ));
+                }
+            };
+            permit_threshold = Some(min_freq);
 
             // collect all of the barcodes that have a frequency
             // >= to min_thresh.
             valid_bc = permit_list_from_threshold(hm, min_freq);
             info!(
                 log,
-                "knee distance method resulted in the selection of {} permitted barcodes.",
+                "{} method resulted in the selection of {} permitted barcodes.",
+                method_name,
                 valid_bc.len()
             );
         }
@@ -483,13 +627,14 @@ fn process_filtered(
             };
 
             let min_freq = freq[num_bc];
+            permit_threshold = Some(min_freq);
 
             // collect all of the barcodes that have a frequency
             // >= to min_thresh.
             valid_bc = permit_list_from_threshold(hm, min_freq);
         }
         CellFilterMethod::ExplicitList(valid_bc_file) => {
-            valid_bc = permit_list_from_file(valid_bc_file, ft_vals.bclen);
+            valid_bc = permit_list_from_file(valid_bc_file, ft_vals.bclen)?;
         }
         CellFilterMethod::ExpectCells(expected_num_cells) => {
             let robust_quantile = 0.99f64;
@@ -499,6 +644,7 @@ fn process_filtered(
             let ind = cmp::min(freq.len() - 1, robust_ind as usize);
             let robust_freq = freq[ind];
             let min_freq = std::cmp::max(1u64, (robust_freq as f64 / robust_div).round() as u64);
+            permit_threshold = Some(min_freq);
             valid_bc = permit_list_from_threshold(hm, min_freq);
         }
         CellFilterMethod::UnfilteredExternalList(_, _min_reads) => {
@@ -508,8 +654,10 @@ fn process_filtered(
 
     // generate the map from each permitted barcode to all barcodes within
     // edit distance 1 of it.
-    let full_permit_list =
-        afutils::generate_permitlist_map(&valid_bc, ft_vals.bclen as usize).unwrap();
+    let full_permit_list = afutils::generate_permitlist_map(&valid_bc, ft_vals.bclen as usize)
+        .map_err(|e| {
+            AlevinFryError::InvalidInput(format!("could not generate the permit list map: {}", e))
+        })?;
 
     let s2 = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
     let mut permitted_map = HashMap::with_capacity_and_hasher(valid_bc.len(), s2);
@@ -532,21 +680,23 @@ fn process_filtered(
     })?;
     let o_path = parent.join("permit_freq.bin");
 
-    match afutils::write_permit_list_freq(&o_path, ft_vals.bclen, &permitted_map) {
-        Ok(_) => {}
-        Err(error) => {
-            panic!("Error: {}", error);
-        }
-    };
+    afutils::write_permit_list_freq(&o_path, ft_vals.bclen, &permitted_map).map_err(|error| {
+        anyhow!(
+            "could not write the permit list frequencies to {} : {}",
+            o_path.display(),
+            error
+        )
+    })?;
 
     let o_path = parent.join("all_freq.bin");
 
-    match afutils::write_permit_list_freq(&o_path, ft_vals.bclen, hm) {
-        Ok(_) => {}
-        Err(error) => {
-            panic!("Error: {}", error);
-        }
-    };
+    afutils::write_permit_list_freq(&o_path, ft_vals.bclen, hm).map_err(|error| {
+        anyhow!(
+            "could not write the permit list frequencies to {} : {}",
+            o_path.display(),
+            error
+        )
+    })?;
 
     let s_path = parent.join("permit_map.bin");
     let s_file = std::fs::File::create(s_path).context("could not create serialization file.")?;
@@ -554,13 +704,33 @@ fn process_filtered(
     bincode::serialize_into(&mut s_writer, &full_permit_list)
         .context("couldn't serialize permit list.")?;
 
+    write_barcode_ranks(
+        parent,
+        hm,
+        &full_permit_list,
+        ft_vals.bclen,
+        &knee_thresholds,
+        permit_threshold,
+    )?;
+
+    if gpl_opts.knee_plot {
+        let p_path = parent.join("knee_plot.svg");
+        knee::write_knee_plot_svg(&p_path, &freq, &knee_thresholds, permit_threshold)?;
+        info!(log, "wrote knee plot to {}", p_path.display());
+    }
+
     let meta_info = json!({
     "velo_mode" : velo_mode,
     "expected_ori" : *expected_ori.strand_symbol(),
+    "orientation_detection" : ori_detection,
+    "spilled_rad_file" : spilled_rad_file,
+    "num_chunks" : num_chunks,
     "version_str" : version,
     "max-ambig-record" : max_ambiguity_read,
     "cmd" : cmdline,
     "permit-list-type" : "filtered",
+    "permit_threshold" : permit_threshold,
+    "knee_thresholds" : &knee_thresholds,
     "gpl_options" : &gpl_opts
     });
 
@@ -579,19 +749,58 @@ fn process_filtered(
         num_corrected.to_formatted_string(&Locale::en)
     );
 
-    Ok(num_corrected)
+    Ok((
+        PermitListStats {
+            output_dir: output_dir.clone(),
+            max_ambiguity_read,
+            expected_ori: expected_ori.strand_symbol().to_string(),
+            num_permitted: valid_bc.len(),
+            num_corrected,
+            permit_threshold,
+            ..Default::default()
+        },
+        PermitMaps {
+            permit_freq: permitted_map.into_iter().collect(),
+            correct_map: full_permit_list,
+        },
+    ))
+}
+
+/// Finish reading the input RAD stream `br`, returning the name of the
+/// spill file (relative to the output directory) to which it was copied,
+/// if any.
+fn finish_input_stream<R: Read>(
+    br: BufReader<SpillingReader<R>>,
+) -> Result<Option<String>, AlevinFryError> {
+    let spill_path = br
+        .into_inner()
+        .finish()
+        .context("could not write the spill file")?;
+    Ok(spill_path.and_then(|p| p.file_name().map(|f| f.to_string_lossy().into_owned())))
 }
 
 /// Given the input RAD file `input_file`, compute
 /// and output (in `output_dir`) the list of valid
 /// (i.e. "permitted") barcode values, as well as
 /// a map from each correctable barcode to the
-/// permitted barcode to which it maps.
-pub fn generate_permit_list(gpl_opts: GenPermitListOpts) -> anyhow::Result<u64> {
+/// permitted barcode to which it maps.  Returns
+/// a summary of the resulting permit list.
+pub fn generate_permit_list(
+    gpl_opts: GenPermitListOpts,
+) -> Result<PermitListStats, AlevinFryError> {
+    generate_permit_list_with_maps(gpl_opts).map(|(stats, _)| stats)
+}
+
+/// Like [`generate_permit_list`], but also returns the permit list
+/// frequencies and correction map that were written to `output_dir`,
+/// so that they need not be read back from disk by a subsequent stage.
+pub fn generate_permit_list_with_maps(
+    gpl_opts: GenPermitListOpts,
+) -> Result<(PermitListStats, PermitMaps), AlevinFryError> {
     let rad_dir = gpl_opts.input_dir;
     let output_dir = gpl_opts.output_dir;
     let filter_meth = gpl_opts.fmeth.clone();
-    let expected_ori = gpl_opts.expected_ori;
+    let mut expected_ori = gpl_opts.expected_ori;
     let version = gpl_opts.version;
     let velo_mode = gpl_opts.velo_mode;
     let cmdline = gpl_opts.cmdline;
@@ -607,8 +816,7 @@ pub fn generate_permit_list(gpl_opts: GenPermitListOpts) -> anyhow::Result<u64>
             "the input RAD path {} does not exist",
             rad_dir.display()
         );
-        // std::process::exit(1);
-        return Err(anyhow!("execution terminated unexpectedly"));
+        return Err(AlevinFryError::missing_file(rad_dir));
     }
 
     let mut first_bclen = 0usize;
@@ -616,7 +824,7 @@ pub fn generate_permit_list(gpl_opts: GenPermitListOpts) -> anyhow::Result<u64>
     if let CellFilterMethod::UnfilteredExternalList(fname, _) = &filter_meth {
         let i_file = File::open(fname).context("could not open input file")?;
         let br = BufReader::new(i_file);
-        unfiltered_bc_counts = Some(populate_unfiltered_barcode_map(br, &mut first_bclen));
+        unfiltered_bc_counts = Some(populate_unfiltered_barcode_map(br, &mut first_bclen)?);
         info!(
             log,
             "number of unfiltered bcs read = {}",
@@ -628,8 +836,49 @@ pub fn generate_permit_list(gpl_opts: GenPermitListOpts) -> anyhow::Result<u64>
         );
     }
 
-    let i_file = File::open(i_dir.join("map.rad")).context("could not open input rad file")?;
-    let mut br = BufReader::new(i_file);
+    // the input RAD file may be compressed
+    let rad_path = match io_utils::find_input_rad(i_dir) {
+        Some(p) => p,
+        None => {
+            let rad_path = i_dir.join("map.rad");
+            crit!(
+                log,
+                "the input RAD file {} (or a compressed map.rad.zst, map.rad.gz or map.rad.sz) does not exist",
+                rad_path.display()
+            );
+            return Err(AlevinFryError::missing_file(rad_path));
+        }
+    };
+    // if the input can only be read once (i.e. it is a named pipe), then
+    // we spill a (compressed) copy of it to the output directory as we
+    // read it, and collate will read the records from this copy.
+    let spill = gpl_opts.spill || io_utils::is_fifo(&rad_path);
+    let (i_file, codec) =
+        io_utils::open_rad_input(&rad_path).context("could not open input rad file")?;
+    if codec != io_utils::RadCodec::Raw {
+        info!(
+            log,
+            "reading {:?}-compressed input RAD file {}",
+            codec,
+            rad_path.display()
+        );
+    }
+    let reader = if spill {
+        std::fs::create_dir_all(output_dir).with_context(|| {
+            format!("couldn't create output directory {}", output_dir.display())
+        })?;
+        let spill_path = output_dir.join(afconst::SPILLED_RAD_FILE);
+        info!(
+            log,
+            "spilling a compressed copy of the input RAD stream to {}",
+            spill_path.display()
+        );
+        SpillingReader::with_spill(i_file, &spill_path)
+            .with_context(|| format!("could not create spill file {}", spill_path.display()))?
+    } else {
+        SpillingReader::new(i_file)
+    };
+    let mut br = BufReader::new(reader);
     let hdr = rad_types::RadHeader::from_bytes(&mut br);
     info!(
         log,
@@ -645,32 +894,15 @@ pub fn generate_permit_list(gpl_opts: GenPermitListOpts) -> anyhow::Result<u64>
     let rl_tags = rad_types::TagSection::from_bytes(&mut br);
     info!(log, "read {:?} read-level tags", rl_tags.tags.len());
 
-    // right now, we only handle BC and UMI types of U8—U64, so validate that
-    const BNAME: &str = "b";
-    const UNAME: &str = "u";
-
-    let mut bct: Option<u8> = None;
-    let mut umit: Option<u8> = None;
-
-    for rt in &rl_tags.tags {
-        // if this is one of our tags
-        if rt.name == BNAME || rt.name == UNAME {
-            if rad_types::decode_int_type_tag(rt.typeid).is_none() {
-                crit!(
-                    log,
-                    "currently only RAD types 1--4 are supported for 'b' and 'u' tags."
-                );
-                std::process::exit(exit_codes::EXIT_UNSUPPORTED_TAG_TYPE);
-            }
-
-            if rt.name == BNAME {
-                bct = Some(rt.typeid);
-            }
-            if rt.name == UNAME {
-                umit = Some(rt.typeid);
-            }
-        }
-    }
+    // right now, we only handle BC and UMI types of U8—U64; the UMI tag
+    // is optional, as protocols without UMIs have none.
+    let types = io_utils::RecordTypes::from_tags(&rl_tags).map_err(|e| {
+        crit!(
+            log,
+            "currently only RAD types 1--4 are supported for 'b' and 'u' tags."
+        );
+        e
+    })?;
 
     // alignment-level
     let al_tags = rad_types::TagSection::from_bytes(&mut br);
@@ -680,11 +912,33 @@ pub fn generate_permit_list(gpl_opts: GenPermitListOpts) -> anyhow::Result<u64>
     info!(log, "File-level tag values {:?}", ft_vals);
 
     let mut num_reads: usize = 0;
+    let mut num_chunks = 0u64;
 
-    let bc_type = rad_types::decode_int_type_tag(bct.expect("no barcode tag description present."))
-        .context("unknown barcode type id.")?;
-    let umi_type = rad_types::decode_int_type_tag(umit.expect("no umi tag description present"))
-        .context("unknown barcode type id.")?;
+    // a RAD stream that the mapper wrote to a named pipe has no chunk
+    // count in its header, and is read until the end of the input.
+    let known_chunks = io_utils::known_num_chunks(&rad_path, hdr.num_chunks);
+    if known_chunks.is_none() {
+        info!(
+            log,
+            "the number of chunks is not recorded in the input; reading until the end of the input."
+        );
+    }
+    let mut chunks = io_utils::ChunkReader::new(&mut br, types, known_chunks);
+
+    // if we are asked to infer the expected orientation, read the first
+    // chunks of the file and determine the dominant strand.  The sampled
+    // chunks are retained so that they can be processed below without
+    // having to seek back in the input.
+    let mut sampled_chunks = Vec::new();
+    let mut ori_detection = None;
+    if gpl_opts.auto_ori {
+        for c in chunks.by_ref().take(gpl_opts.auto_ori_chunks) {
+            sampled_chunks.push(c?);
+        }
+        let (strand, detection) = detect_orientation(&sampled_chunks, log)?;
+        expected_ori = strand;
+        ori_detection = Some(detection);
+    }
 
     // if dealing with filtered type
     let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
@@ -701,8 +955,9 @@ pub fn generate_permit_list(gpl_opts: GenPermitListOpts) -> anyhow::Result<u64>
             unmatched_bc = Vec::with_capacity(10000000);
             // the unfiltered_bc_count map must be valid in this branch
             if let Some(mut hmu) = unfiltered_bc_counts {
-                for _ in 0..(hdr.num_chunks as usize) {
-                    let c = rad_types::Chunk::from_bytes(&mut br, &bc_type, &umi_type);
+                for c in sampled_chunks.into_iter().map(Ok).chain(chunks) {
+                    let c = c?;
+                    num_chunks += 1;
                     num_orientation_compat_reads += update_barcode_hist_unfiltered(
                         &mut hmu,
                         &mut unmatched_bc,
@@ -717,9 +972,10 @@ pub fn generate_permit_list(gpl_opts: GenPermitListOpts) -> anyhow::Result<u64>
                     "observed {} reads ({} orientation consistent) in {} chunks --- max ambiguity read occurs in {} refs",
                     num_reads.to_formatted_string(&Locale::en),
                     num_orientation_compat_reads.to_formatted_string(&Locale::en),
-                    hdr.num_chunks.to_formatted_string(&Locale::en),
+                    num_chunks.to_formatted_string(&Locale::en),
                     max_ambiguity_read.to_formatted_string(&Locale::en)
                 );
+                let spilled = finish_input_stream(br)?;
                 process_unfiltered(
                     hmu,
                     unmatched_bc,
@@ -732,15 +988,21 @@ pub fn generate_permit_list(gpl_opts: GenPermitListOpts) -> anyhow::Result<u64>
                     velo_mode,
                     cmdline,
                     log,
+                    ori_detection.as_ref(),
+                    spilled.as_deref(),
+                    num_chunks,
                     &gpl_opts,
                 )
+                .map(|(stats, maps)| (PermitListStats { num_reads, ..stats }, maps))
+                .map_err(AlevinFryError::from)
             } else {
-                Ok(0)
+                Ok((PermitListStats::default(), PermitMaps::default()))
             }
         }
         _ => {
-            for _ in 0..(hdr.num_chunks as usize) {
-                let c = rad_types::Chunk::from_bytes(&mut br, &bc_type, &umi_type);
+            for c in sampled_chunks.into_iter().map(Ok).chain(chunks) {
+                let c = c?;
+                num_chunks += 1;
                 update_barcode_hist(&mut hm, &mut max_ambiguity_read, &c, &expected_ori);
                 num_reads += c.reads.len();
             }
@@ -748,9 +1010,10 @@ pub fn generate_permit_list(gpl_opts: GenPermitListOpts) -> anyhow::Result<u64>
                 log,
                 "observed {} reads in {} chunks --- max ambiguity read occurs in {} refs",
                 num_reads.to_formatted_string(&Locale::en),
-                hdr.num_chunks.to_formatted_string(&Locale::en),
+                num_chunks.to_formatted_string(&Locale::en),
                 max_ambiguity_read.to_formatted_string(&Locale::en)
             );
+            let spilled = finish_input_stream(br)?;
             process_filtered(
                 &hm,
                 &ft_vals,
@@ -762,8 +1025,13 @@ pub fn generate_permit_list(gpl_opts: GenPermitListOpts) -> anyhow::Result<u64>
                 velo_mode,
                 cmdline,
                 log,
+                ori_detection.as_ref(),
+                spilled.as_deref(),
+                num_chunks,
                 &gpl_opts,
             )
+            .map(|(stats, maps)| (PermitListStats { num_reads, ..stats }, maps))
+            .map_err(AlevinFryError::from)
         }
     }
 
@@ -1004,19 +1272,217 @@ pub fn permit_list_from_threshold(
     valid_bc
 }
 
-pub fn permit_list_from_file<P>(ifile: P, bclen: u16) -> Vec<u64>
+pub fn permit_list_from_file<P>(ifile: P, bclen: u16) -> Result<Vec<u64>, AlevinFryError>
 where
     P: AsRef<Path>,
 {
-    let f = File::open(ifile).expect("couldn't open input barcode file.");
-    let br = BufReader::new(f);
+    let ifile = ifile.as_ref();
+    if !ifile.exists() {
+        return Err(AlevinFryError::missing_file(ifile));
+    }
+    let br = BufReader::new(File::open(ifile)?);
     let mut bc = Vec::<u64>::with_capacity(10_000);
 
-    for l in br.lines() {
-        let line = l.expect("couldn't read line from barcode file.");
+    for (lnum, l) in br.lines().enumerate() {
+        let line = l?;
+        if line.trim().is_empty() {
+            continue;
+        }
         let mut bnk = BitNuclKmer::new(line.as_bytes(), bclen as u8, false);
-        let (_, k, _) = bnk.next().expect("can't extract kmer");
+        let (_, k, _) = bnk.next().ok_or_else(|| {
+            AlevinFryError::InvalidInput(format!(
+                "line {} of the barcode file {} is not a barcode of length {}",
+                lnum + 1,
+                ifile.display(),
+                bclen
+            ))
+        })?;
         bc.push(k.0);
     }
-    bc
+    Ok(bc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::knee::KneeThreshold;
+    use std::io::Cursor;
+
+    /// a barcode-only chunk holding one record per entry of `dirs`, with
+    /// one alignment in each given orientation (true for forward).
+    fn oriented_chunk(dirs: &[&[bool]]) -> rad_types::Chunk {
+        let mut recs = Vec::<u8>::new();
+        for (i, d) in dirs.iter().enumerate() {
+            recs.extend_from_slice(&(d.len() as u32).to_le_bytes());
+            recs.extend_from_slice(&(i as u32).to_le_bytes());
+            for &fw in d.iter() {
+                let r = if fw { 0x8000_0001u32 } else { 1u32 };
+                recs.extend_from_slice(&r.to_le_bytes());
+            }
+        }
+        let mut chunk = ((recs.len() + 8) as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(&(dirs.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(&recs);
+        let types = io_utils::RecordTypes {
+            bc_type: rad_types::RadIntId::U32,
+            umi_type: None,
+        };
+        io_utils::read_chunk(&mut Cursor::new(chunk), &types).unwrap()
+    }
+
+    #[test]
+    fn test_detect_orientation() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+
+        // mixed-orientation reads don't count towards either strand
+        let chunks = vec![
+            oriented_chunk(&[&[true], &[true, true], &[false, true]]),
+            oriented_chunk(&[&[true], &[false]]),
+        ];
+        let (strand, det) = detect_orientation(&chunks, &log).unwrap();
+        assert_eq!(strand, Strand::Forward);
+        assert_eq!(det.sampled_chunks, 2);
+        assert_eq!((det.fw_reads, det.rc_reads, det.both_reads), (3, 1, 1));
+        assert!((det.confidence - 0.75).abs() < 1e-12);
+
+        let chunks = vec![oriented_chunk(&[&[false], &[false, false], &[false]])];
+        let (strand, det) = detect_orientation(&chunks, &log).unwrap();
+        assert_eq!(strand, Strand::Reverse);
+        assert!((det.confidence - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_detect_orientation_ambiguous() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+
+        // neither strand is dominant
+        let chunks = vec![oriented_chunk(&[&[true], &[false], &[true], &[false]])];
+        assert!(detect_orientation(&chunks, &log).is_err());
+
+        // no single-orientation reads
+        let chunks = vec![oriented_chunk(&[&[true, false]]), oriented_chunk(&[])];
+        assert!(detect_orientation(&chunks, &log).is_err());
+        assert!(detect_orientation(&[], &log).is_err());
+    }
+
+    #[test]
+    fn test_unfiltered_barcode_map() {
+        let mut bclen = 0usize;
+        let hm =
+            populate_unfiltered_barcode_map(BufReader::new(&b"AAAC\nAAAG\n\n"[..]), &mut bclen)
+                .unwrap();
+        assert_eq!(bclen, 4);
+        assert_eq!(hm.len(), 2);
+        assert!(hm.contains_key(&1) && hm.contains_key(&2));
+
+        let mut bclen = 0usize;
+        assert!(matches!(
+            populate_unfiltered_barcode_map(BufReader::new(&b"AAAC\nAAAGT\n"[..]), &mut bclen),
+            Err(AlevinFryError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_write_barcode_ranks() {
+        let dir =
+            std::env::temp_dir().join(format!("alevin_fry_barcode_ranks_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // AAAC and AAAG are permitted, AAAT is corrected to AAAC and
+        // AAAA is not permitted at all
+        let mut hm = HashMap::with_hasher(ahash::RandomState::new());
+        hm.extend([(1u64, 50u64), (2, 50), (3, 5), (0, 10)]);
+        let full_permit_list = HashMap::from([(1u64, 1u64), (2, 2), (3, 1)]);
+        let knee_thresholds = KneeThresholds {
+            distance: Some(KneeThreshold {
+                min_freq: 50,
+                num_barcodes: 2,
+            }),
+            inflection: None,
+            curvature: None,
+            mixture: None,
+        };
+        let res = write_barcode_ranks(&dir, &hm, &full_permit_list, 4, &knee_thresholds, Some(10));
+
+        let mut ranks = String::new();
+        let rank_file = File::open(dir.join("barcode_ranks.tsv.gz"));
+        let thresholds = std::fs::read_to_string(dir.join("barcode_rank_thresholds.tsv"));
+        if let Ok(f) = rank_file {
+            flate2::read::GzDecoder::new(f)
+                .read_to_string(&mut ranks)
+                .unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        res.unwrap();
+
+        assert_eq!(
+            ranks,
+            "rank\tbarcode\tread_count\tpasses_filter\tcorrected_to\n\
+             1\tAAAC\t50\ttrue\tAAAC\n\
+             2\tAAAG\t50\ttrue\tAAAG\n\
+             3\tAAAA\t10\tfalse\t\n\
+             4\tAAAT\t5\tfalse\tAAAC\n"
+        );
+        assert_eq!(
+            thresholds.unwrap(),
+            "method\tmin_freq\tnum_barcodes\n\
+             knee-distance\t50\t2\n\
+             knee-inflection\tNA\tNA\n\
+             knee-curvature\tNA\tNA\n\
+             knee-mixture\tNA\tNA\n\
+             selected\t10\t3\n"
+        );
+    }
+
+    #[test]
+    fn test_barcode_rank_thresholds_of_all_methods() {
+        let dir =
+            std::env::temp_dir().join(format!("alevin_fry_rank_thresholds_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // 1,000 cells with ~10,000 reads and 20,000 background barcodes
+        // with ~200 reads
+        let mut hm = HashMap::with_hasher(ahash::RandomState::new());
+        hm.extend((0..1_000u64).map(|i| (i, 8_000 + (i * 37) % 4_000)));
+        hm.extend((1_000..21_000u64).map(|i| (i, 120 + (i * 13) % 160)));
+        let mut freq: Vec<u64> = hm.values().copied().collect();
+        freq.sort_unstable_by(|a, b| b.cmp(a));
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let (knee_thresholds, _) = KneeThresholds::compute(&freq, &log);
+        // select the inflection threshold; the thresholds of the other
+        // methods (including knee-distance) are still reported
+        let selected = knee_thresholds.inflection.unwrap().min_freq;
+        let res = write_barcode_ranks(
+            &dir,
+            &hm,
+            &HashMap::new(),
+            16,
+            &knee_thresholds,
+            Some(selected),
+        );
+        let thresholds = std::fs::read_to_string(dir.join("barcode_rank_thresholds.tsv"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        res.unwrap();
+
+        let thresholds = thresholds.unwrap();
+        let rows: Vec<Vec<&str>> = thresholds
+            .lines()
+            .skip(1)
+            .map(|l| l.split('\t').collect())
+            .collect();
+        let methods: Vec<&str> = rows.iter().map(|r| r[0]).collect();
+        assert_eq!(
+            methods,
+            vec![
+                "knee-distance",
+                "knee-inflection",
+                "knee-curvature",
+                "knee-mixture",
+                "selected"
+            ]
+        );
+        assert_ne!(rows[0][1], "NA");
+        assert_eq!(rows[4][1], selected.to_string());
+    }
 }
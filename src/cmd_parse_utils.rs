@@ -1,5 +1,13 @@
+use crate::collate::{CollateCodec, CollateOrder};
+use crate::em::EmAlgorithm;
 use crate::quant::{ResolutionStrategy, SplicedAmbiguityModel};
+use anyhow::{anyhow, bail, Context};
 use clap;
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
 use std::path::{Path, PathBuf};
 
 impl clap::ValueEnum for ResolutionStrategy {
@@ -25,6 +33,9 @@ impl clap::ValueEnum for ResolutionStrategy {
             Self::ParsimonyGeneEm => Some(clap::builder::PossibleValue::new("parsimony-gene-em")),
             Self::ParsimonyGene => Some(clap::builder::PossibleValue::new("parsimony-gene")),
         }
+        // also accept the serialized name of the variant (as written
+        // to quant.json) so that recorded options can be fed back in.
+        .map(|pv| pv.alias(self.to_string()))
     }
 }
 
@@ -35,12 +46,46 @@ impl clap::ValueEnum for SplicedAmbiguityModel {
 
     fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
         match self {
-            Self::PreferAmbiguity => Some(clap::builder::PossibleValue::new("prefer-ambig")),
-            Self::WinnerTakeAll => Some(clap::builder::PossibleValue::new("winner-take-all")),
+            Self::PreferAmbiguity => {
+                Some(clap::builder::PossibleValue::new("prefer-ambig").alias("PreferAmbiguity"))
+            }
+            Self::WinnerTakeAll => {
+                Some(clap::builder::PossibleValue::new("winner-take-all").alias("WinnerTakeAll"))
+            }
         }
     }
 }
 
+impl clap::ValueEnum for CollateCodec {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::None, Self::Snappy, Self::Zstd]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(self.as_str()))
+    }
+}
+
+impl clap::ValueEnum for CollateOrder {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Frequency, Self::Barcode]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(self.as_str()))
+    }
+}
+
+impl clap::ValueEnum for EmAlgorithm {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Em, Self::Vbem]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(self.as_str()))
+    }
+}
+
 /// Checks if the path pointed to by v exists.  It can be
 /// any valid entity (e.g. disk file, FIFO, directory, etc.).
 /// If there is any issue with permissions or failure to properly
@@ -69,3 +114,527 @@ pub fn pathbuf_directory_exists_validator(v: &str) -> Result<PathBuf, String> {
         Ok(PathBuf::from(v))
     }
 }
+
+/// Parses a memory size given either as a number of bytes or with one
+/// of the (binary) suffixes K, M, G or T, optionally followed by "B" or
+/// "iB" (e.g. "512M", "1.5GiB" or "32g").
+pub fn memory_size_parser(v: &str) -> Result<u64, String> {
+    let v = v.trim();
+    let split = v
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(v.len());
+    let (num, suffix) = v.split_at(split);
+    let num: f64 = num
+        .parse()
+        .map_err(|_| format!("could not parse the memory size '{}'", v))?;
+    let suffix = suffix.trim().to_ascii_lowercase();
+    let unit = suffix
+        .strip_suffix("ib")
+        .or_else(|| suffix.strip_suffix('b'))
+        .unwrap_or(&suffix);
+    let shift = match unit {
+        "" => 0,
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        "t" => 40,
+        _ => return Err(format!("unknown unit in the memory size '{}'", v)),
+    };
+    Ok((num * (1u64 << shift) as f64) as u64)
+}
+
+/// Keys that may appear in a configuration file (in particular, in the
+/// `quant_options` and `gpl_options` recorded in `quant.json` and
+/// `generate_permit_list.json`) but that do not correspond to any
+/// command line argument, and are therefore ignored.
+const IGNORED_CONFIG_KEYS: &[&str] = &["cmdline", "version", "velo-mode", "log"];
+
+/// Maps the names of the fields of the options structures, as they are
+/// serialized in the output JSON files, to the ids of the corresponding
+/// command line arguments (when these differ).
+const CONFIG_KEY_ALIASES: &[(&str, &str)] = &[
+    ("input-dir", "input"),
+    ("num-threads", "threads"),
+    ("dump-eq", "dump-eqclasses"),
+    ("dump-tx-eq", "dump-tx-eqclasses"),
+    ("filter-list", "quant-subset"),
+];
+
+/// A single setting read from a configuration file, in the form in which
+/// it will be passed on to the command line parser.
+#[derive(Debug, Clone, PartialEq)]
+enum ConfigSetting {
+    Flag,
+    Values(Vec<String>),
+}
+
+/// Reads the configuration file at `path`.  Files with a `.toml` extension
+/// are parsed as TOML, files with a `.json` extension are parsed as JSON,
+/// and any other file is parsed as JSON if possible, and otherwise as TOML.
+fn read_config_file(path: &Path) -> anyhow::Result<Value> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("could not read configuration file {}", path.display()))?;
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let parse_toml = |s: &str| -> anyhow::Result<Value> {
+        toml::from_str::<Value>(s)
+            .with_context(|| format!("could not parse {} as TOML", path.display()))
+    };
+    let parse_json = |s: &str| -> anyhow::Result<Value> {
+        serde_json::from_str::<Value>(s)
+            .with_context(|| format!("could not parse {} as JSON", path.display()))
+    };
+    match ext.as_str() {
+        "toml" => parse_toml(&contents),
+        "json" => parse_json(&contents),
+        _ => parse_json(&contents).or_else(|_| parse_toml(&contents)),
+    }
+}
+
+/// Extracts the settings relevant to the subcommand `subcmd` from the
+/// parsed configuration file `cfg`.  If the file has a section (table)
+/// named after the subcommand, or a `quant_options` / `gpl_options` object
+/// (as written by `quant` and `generate-permit-list` respectively), only
+/// those sections are used.  Otherwise, all top-level keys (other than
+/// tables) are used.
+fn config_section(cfg: Value, subcmd: &str) -> anyhow::Result<Map<String, Value>> {
+    let top = match cfg {
+        Value::Object(m) => m,
+        _ => bail!("the configuration file must contain a table of settings"),
+    };
+
+    let section_names = [subcmd, "quant_options", "gpl_options"];
+    let mut sections = Map::new();
+    let mut found_section = false;
+    for name in section_names {
+        if let Some(v) = top.get(name) {
+            match v {
+                Value::Object(m) => {
+                    found_section = true;
+                    sections.extend(m.clone());
+                }
+                _ => bail!(
+                    "the '{}' section of the configuration file must be a table",
+                    name
+                ),
+            }
+        }
+    }
+    if found_section {
+        Ok(sections)
+    } else {
+        // tables at the top level hold the options of other subcommands
+        Ok(top.into_iter().filter(|(_, v)| !v.is_object()).collect())
+    }
+}
+
+/// Convert a scalar configuration value into the string that would be
+/// passed for it on the command line.
+fn config_value_to_string(key: &str, v: &Value) -> anyhow::Result<String> {
+    match v {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => bail!("unsupported value {} for configuration key '{}'", v, key),
+    }
+}
+
+/// Translate the serialized representation of a [`crate::cellfilter::CellFilterMethod`]
+/// into the corresponding command line settings.
+fn filter_method_settings(v: &Value) -> anyhow::Result<Vec<(String, Value)>> {
+    let flag = |id: &str| -> anyhow::Result<Vec<(String, Value)>> {
+        Ok(vec![(id.to_string(), Value::Bool(true))])
+    };
+    match v {
+        Value::String(s) => match s.as_str() {
+            "KneeFinding" => flag("knee-distance"),
+            "KneeInflection" => flag("knee-inflection"),
+            "KneeCurvature" => flag("knee-curvature"),
+            "KneeMixture" => flag("knee-mixture"),
+            _ => bail!("unknown filter method '{}' in configuration file", s),
+        },
+        Value::Object(m) if m.len() == 1 => {
+            let (name, payload) = m.iter().next().unwrap();
+            match (name.as_str(), payload) {
+                ("ExpectCells", p) => Ok(vec![("expect-cells".to_string(), p.clone())]),
+                ("ForceCells", p) => Ok(vec![("force-cells".to_string(), p.clone())]),
+                ("ExplicitList", p) => Ok(vec![("valid-bc".to_string(), p.clone())]),
+                ("UnfilteredExternalList", Value::Array(a)) if a.len() == 2 => Ok(vec![
+                    ("unfiltered-pl".to_string(), a[0].clone()),
+                    ("min-reads".to_string(), a[1].clone()),
+                ]),
+                _ => bail!("unknown filter method {} in configuration file", v),
+            }
+        }
+        _ => bail!("unknown filter method {} in configuration file", v),
+    }
+}
+
+/// Translate a single `key = value` entry of a configuration file into the
+/// (argument id, value) pairs it implies for the subcommand `cmd`.
+fn translate_config_entry(
+    cmd: &Command,
+    key: &str,
+    v: &Value,
+) -> anyhow::Result<Vec<(String, Value)>> {
+    let key = key.replace('_', "-");
+    let has_arg = |id: &str| cmd.get_arguments().any(|a| a.get_id() == id);
+
+    if IGNORED_CONFIG_KEYS.contains(&key.as_str()) || v.is_null() {
+        return Ok(vec![]);
+    }
+
+    // fields of the options structures that don't map 1-1 onto an argument
+    match (key.as_str(), v) {
+        ("fmeth", _) => return filter_method_settings(v),
+        ("expected-ori", Value::String(s)) => {
+            let ori = match s.as_str() {
+                "Forward" => "fw",
+                "Reverse" => "rc",
+                "Unknown" => "both",
+                o => o,
+            };
+            return Ok(vec![(
+                "expected-ori".to_string(),
+                Value::String(ori.to_string()),
+            )]);
+        }
+        ("auto-ori", Value::Bool(b)) => {
+            return Ok(if *b {
+                vec![(
+                    "expected-ori".to_string(),
+                    Value::String("auto".to_string()),
+                )]
+            } else {
+                vec![]
+            });
+        }
+        ("use-mtx", Value::Bool(false)) if has_arg("use-eds") => {
+            return Ok(vec![("use-eds".to_string(), Value::Bool(true))]);
+        }
+        ("pug-exact-umi", Value::Bool(b)) => {
+            return Ok(if *b {
+                vec![("umi-edit-dist".to_string(), Value::from(0))]
+            } else {
+                vec![]
+            });
+        }
+        _ => {}
+    }
+
+    if has_arg(&key) {
+        return Ok(vec![(key, v.clone())]);
+    }
+    if let Some((_, id)) = CONFIG_KEY_ALIASES
+        .iter()
+        .find(|(k, id)| *k == key && has_arg(id))
+    {
+        return Ok(vec![(id.to_string(), v.clone())]);
+    }
+    bail!(
+        "unknown key '{}' in configuration file for the {} command",
+        key,
+        cmd.get_name()
+    )
+}
+
+/// Returns true if the argument `arg` appears in the command line
+/// arguments `cli_args` (the arguments following the subcommand name).
+fn arg_on_cli(arg: &Arg, cli_args: &[String]) -> bool {
+    cli_args.iter().take_while(|a| *a != "--").any(|a| {
+        if let Some(l) = arg.get_long() {
+            if a.strip_prefix("--")
+                .map_or(false, |r| r == l || r.starts_with(&format!("{}=", l)))
+            {
+                return true;
+            }
+        }
+        if let Some(s) = arg.get_short() {
+            if !a.starts_with("--") && a.starts_with('-') && a.chars().nth(1) == Some(s) {
+                return true;
+            }
+        }
+        false
+    })
+}
+
+/// Returns the value passed to `--config` in `cli_args`, if any.
+fn config_path_from_cli(cli_args: &[String]) -> Option<PathBuf> {
+    let mut it = cli_args.iter().take_while(|a| *a != "--");
+    while let Some(a) = it.next() {
+        if a == "--config" {
+            return it.next().map(PathBuf::from);
+        }
+        if let Some(p) = a.strip_prefix("--config=") {
+            return Some(PathBuf::from(p));
+        }
+    }
+    None
+}
+
+/// If the subcommand invoked in `args` was passed a `--config <FILE>`
+/// argument, the settings in this file are merged into the command line
+/// and the resulting list of arguments is returned.  Otherwise, `args`
+/// is returned unmodified.
+///
+/// The keys of the configuration file mirror the long names of the
+/// arguments of the subcommand (`_` may be used in place of `-`); flags
+/// take boolean values.  Any argument given explicitly on the command line
+/// takes precedence over the configuration file, as does any argument
+/// given on the command line that conflicts with (e.g. belongs to the same
+/// group as) a setting in the file.
+pub fn apply_config_file(app: &Command, args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let str_args: Vec<String> = args
+        .iter()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect();
+
+    // find the subcommand being invoked
+    let (pos, cmd) = match str_args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find_map(|(i, a)| app.find_subcommand(a).map(|c| (i, c)))
+    {
+        Some(x) => x,
+        None => return Ok(args),
+    };
+
+    let cli_args = &str_args[pos + 1..];
+    let cfg_path = match config_path_from_cli(cli_args) {
+        Some(p) => p,
+        None => return Ok(args),
+    };
+
+    let cfg = read_config_file(&cfg_path)?;
+    let section = config_section(cfg, cmd.get_name())?;
+
+    // translate the configuration entries into argument settings, the
+    // ordered map ensures that the resulting command line is deterministic.
+    let mut settings: BTreeMap<String, ConfigSetting> = BTreeMap::new();
+    for (key, v) in section.iter() {
+        for (id, v) in translate_config_entry(cmd, key, v)? {
+            if id == "config" || id == "dump-config" {
+                bail!("the '{}' option can not be set in a configuration file", id);
+            }
+            let arg = cmd
+                .get_arguments()
+                .find(|a| a.get_id() == id.as_str())
+                .ok_or_else(|| anyhow!("unknown configuration key '{}'", id))?;
+            if arg.get_action().takes_values() {
+                let vals = match &v {
+                    Value::Array(a) => a
+                        .iter()
+                        .map(|x| config_value_to_string(&id, x))
+                        .collect::<anyhow::Result<Vec<String>>>()?,
+                    x => vec![config_value_to_string(&id, x)?],
+                };
+                settings.insert(id, ConfigSetting::Values(vals));
+            } else {
+                match v {
+                    Value::Bool(true) => {
+                        settings.insert(id, ConfigSetting::Flag);
+                    }
+                    Value::Bool(false) => {}
+                    x => bail!(
+                        "the configuration key '{}' expects a boolean, found {}",
+                        id,
+                        x
+                    ),
+                }
+            }
+        }
+    }
+
+    // the arguments explicitly given on the command line
+    let on_cli: Vec<&Arg> = cmd
+        .get_arguments()
+        .filter(|a| arg_on_cli(a, cli_args))
+        .collect();
+
+    let overridden_by_cli = |arg: &Arg| -> bool {
+        on_cli.iter().any(|c| {
+            c.get_id() == arg.get_id()
+                || cmd
+                    .get_arg_conflicts_with(c)
+                    .iter()
+                    .any(|x| x.get_id() == arg.get_id())
+                || cmd
+                    .get_arg_conflicts_with(arg)
+                    .iter()
+                    .any(|x| x.get_id() == c.get_id())
+                || cmd.get_groups().any(|g| {
+                    let members: Vec<&clap::Id> = g.get_args().collect();
+                    members.contains(&c.get_id()) && members.contains(&arg.get_id())
+                })
+        })
+    };
+
+    let mut cfg_args = Vec::new();
+    for (id, setting) in settings {
+        let arg = cmd
+            .get_arguments()
+            .find(|a| a.get_id() == id.as_str())
+            .unwrap();
+        if overridden_by_cli(arg) {
+            continue;
+        }
+        let name = match (arg.get_long(), arg.get_short()) {
+            (Some(l), _) => format!("--{}", l),
+            (None, Some(s)) => format!("-{}", s),
+            (None, None) => bail!("the configuration key '{}' can not be set", id),
+        };
+        match setting {
+            ConfigSetting::Flag => cfg_args.push(OsString::from(name)),
+            ConfigSetting::Values(vals) => {
+                for v in vals {
+                    cfg_args.push(OsString::from(format!("{}={}", name, v)));
+                }
+            }
+        }
+    }
+
+    let mut args = args;
+    args.splice(pos + 1..pos + 1, cfg_args);
+    Ok(args)
+}
+
+/// Writes the fully-resolved settings of the subcommand `cmd`, as parsed
+/// into `matches` (i.e. including default values and settings read from
+/// a configuration file), to `path`.  The output is written as TOML if
+/// `path` has a `.toml` extension and as JSON otherwise, and can be
+/// passed back to the subcommand using `--config`.
+pub fn write_resolved_config(
+    cmd: &Command,
+    matches: &ArgMatches,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let mut settings = Map::new();
+    for arg in cmd.get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches!(id, "config" | "dump-config" | "help" | "version") {
+            continue;
+        }
+        if arg.get_action().takes_values() {
+            if let Some(raw) = matches.get_raw(id) {
+                let mut vals: Vec<Value> = raw
+                    .map(|v| {
+                        let s = v.to_string_lossy();
+                        s.parse::<i64>()
+                            .map(Value::from)
+                            .unwrap_or_else(|_| Value::from(s.into_owned()))
+                    })
+                    .collect();
+                let v = if vals.len() == 1 {
+                    vals.pop().unwrap()
+                } else {
+                    Value::Array(vals)
+                };
+                settings.insert(id.to_string(), v);
+            }
+        } else {
+            settings.insert(id.to_string(), Value::Bool(matches.get_flag(id)));
+        }
+    }
+
+    let mut top = Map::new();
+    top.insert(cmd.get_name().to_string(), Value::Object(settings));
+    let top = Value::Object(top);
+
+    let is_toml = path
+        .extension()
+        .map_or(false, |e| e.to_string_lossy().eq_ignore_ascii_case("toml"));
+    let out = if is_toml {
+        toml::to_string_pretty(&top)?
+    } else {
+        serde_json::to_string_pretty(&top)?
+    };
+    fs::write(path, out)
+        .with_context(|| format!("could not write configuration file {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_size_parser() {
+        assert_eq!(memory_size_parser("1024"), Ok(1024));
+        assert_eq!(memory_size_parser(" 2k "), Ok(2048));
+        assert_eq!(memory_size_parser("512M"), Ok(512 << 20));
+        assert_eq!(memory_size_parser("512MB"), Ok(512 << 20));
+        assert_eq!(memory_size_parser("1.5GiB"), Ok(3 << 29));
+        assert_eq!(memory_size_parser("32g"), Ok(32 << 30));
+        assert_eq!(memory_size_parser("1 T"), Ok(1 << 40));
+
+        assert!(memory_size_parser("").is_err());
+        assert!(memory_size_parser("G").is_err());
+        assert!(memory_size_parser("1..5G").is_err());
+        assert!(memory_size_parser("12X").is_err());
+        assert!(memory_size_parser("12 GiBs").is_err());
+    }
+
+    #[test]
+    fn test_translate_config_entry() {
+        let cmd = Command::new("quant")
+            .arg(Arg::new("threads").long("threads"))
+            .arg(Arg::new("expected-ori").long("expected-ori"))
+            .arg(Arg::new("force-cells").long("force-cells"))
+            .arg(Arg::new("umi-edit-dist").long("umi-edit-dist"))
+            .arg(
+                Arg::new("use-eds")
+                    .long("use-eds")
+                    .action(clap::ArgAction::SetTrue),
+            );
+        let tr = |k: &str, v: Value| translate_config_entry(&cmd, k, &v).unwrap();
+        let setting = |id: &str, v: Value| vec![(id.to_string(), v)];
+
+        // keys are matched with '_' in place of '-', and through the
+        // names of the fields of the options structures
+        assert_eq!(
+            tr("threads", Value::from(4)),
+            setting("threads", Value::from(4))
+        );
+        assert_eq!(
+            tr("num_threads", Value::from(4)),
+            setting("threads", Value::from(4))
+        );
+
+        // ignored keys and unset values
+        assert!(tr("cmdline", Value::from("alevin-fry quant")).is_empty());
+        assert!(tr("threads", Value::Null).is_empty());
+
+        // fields that don't map 1-1 onto an argument
+        assert_eq!(
+            tr("expected_ori", Value::from("Reverse")),
+            setting("expected-ori", Value::from("rc"))
+        );
+        assert_eq!(
+            tr("auto_ori", Value::Bool(true)),
+            setting("expected-ori", Value::from("auto"))
+        );
+        assert!(tr("auto_ori", Value::Bool(false)).is_empty());
+        assert_eq!(
+            tr("use_mtx", Value::Bool(false)),
+            setting("use-eds", Value::Bool(true))
+        );
+        assert_eq!(
+            tr("pug_exact_umi", Value::Bool(true)),
+            setting("umi-edit-dist", Value::from(0))
+        );
+        assert_eq!(
+            tr("fmeth", serde_json::json!({ "ForceCells": 100 })),
+            setting("force-cells", Value::from(100))
+        );
+        assert_eq!(
+            tr("fmeth", Value::from("KneeFinding")),
+            setting("knee-distance", Value::Bool(true))
+        );
+
+        assert!(translate_config_entry(&cmd, "no_such_key", &Value::from(1)).is_err());
+        assert!(translate_config_entry(&cmd, "fmeth", &Value::from("NoSuchMethod")).is_err());
+    }
+}
@@ -9,10 +9,13 @@
 
 use anyhow::{anyhow, Context};
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
-use slog::{crit, info};
+use slog::{crit, info, warn};
 //use anyhow::{anyhow, Result};
+use crate::cellfilter::PermitMaps;
 use crate::constants as afconst;
-use crate::utils::InternalVersionInfo;
+use crate::errors::AlevinFryError;
+use crate::io_utils::{self, int_type_bytes, RecordingReader};
+use crate::utils::{self as afutils, InternalVersionInfo};
 use bio_types::strand::{Strand, StrandError};
 use crossbeam_queue::ArrayQueue;
 // use dashmap::DashMap;
@@ -20,30 +23,821 @@ use libradicl::rad_types;
 use libradicl::schema::TempCellInfo;
 use num_format::{Locale, ToFormattedString};
 use scroll::{Pread, Pwrite};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::BufReader;
-use std::io::{BufWriter, Cursor, Read, Seek, Write};
+use std::io::{BufRead, BufWriter, Cursor, Read, Write};
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 
+/// Summary of a run of [`collate`].
+#[derive(Clone, Debug, Serialize)]
+pub struct CollateStats {
+    /// the collated RAD file that was written
+    pub output_file: PathBuf,
+    /// the number of cells (corrected barcodes) in the collated file
+    pub num_cells: u64,
+    /// the number of read records that were collated
+    pub num_records: u64,
+    /// whether the collated file is compressed
+    pub compressed: bool,
+    /// the codec with which the collated file is compressed
+    pub codec: CollateCodec,
+    /// the order of the cells in the collated file
+    pub order: CollateOrder,
+    /// how the memory of the run was planned
+    pub memory_plan: CollateMemoryPlan,
+    /// the peak memory (resident set size) of the process, if known
+    pub peak_memory_bytes: Option<u64>,
+}
+
+/// The codec with which the collated RAD file is compressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CollateCodec {
+    /// the collated file is not compressed
+    #[default]
+    None,
+    /// each collated chunk is compressed as a separate snappy frame
+    Snappy,
+    /// the collated file is compressed as a single zstd stream
+    Zstd,
+}
+
+impl CollateCodec {
+    /// The name of this codec as recorded in `collate.json`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CollateCodec::None => "none",
+            CollateCodec::Snappy => "snappy",
+            CollateCodec::Zstd => "zstd",
+        }
+    }
+
+    /// The name of the collated RAD file written with this codec.
+    pub fn collated_file_name(&self) -> &'static str {
+        match self {
+            CollateCodec::None => "map.collated.rad",
+            CollateCodec::Snappy => "map.collated.rad.sz",
+            CollateCodec::Zstd => "map.collated.rad.zst",
+        }
+    }
+}
+
+impl std::fmt::Display for CollateCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for CollateCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(CollateCodec::None),
+            "snappy" => Ok(CollateCodec::Snappy),
+            "zstd" => Ok(CollateCodec::Zstd),
+            _ => Err(format!("unknown collated RAD codec '{}'", s)),
+        }
+    }
+}
+
+/// How the collated RAD file should be compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CollateCompression {
+    /// the codec to use
+    pub codec: CollateCodec,
+    /// the compression level; only used by zstd
+    pub zstd_level: i32,
+    /// the number of background threads used to compress the zstd
+    /// stream; if 0, compression happens in the writing threads
+    pub zstd_threads: u32,
+}
+
+impl CollateCompression {
+    /// The default zstd compression level.
+    pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+    /// No compression.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Snappy compression (what `collate --compress` has always done).
+    pub fn snappy() -> Self {
+        CollateCompression {
+            codec: CollateCodec::Snappy,
+            ..Self::default()
+        }
+    }
+
+    /// zstd compression at `level`, using `threads` background
+    /// compression threads.
+    pub fn zstd(level: i32, threads: u32) -> Self {
+        CollateCompression {
+            codec: CollateCodec::Zstd,
+            zstd_level: level,
+            zstd_threads: threads,
+        }
+    }
+
+    /// Snappy compression if `compress` is true and none otherwise.
+    pub fn from_flag(compress: bool) -> Self {
+        if compress {
+            Self::snappy()
+        } else {
+            Self::none()
+        }
+    }
+
+    /// Is the collated output compressed at all?
+    pub fn is_compressed(&self) -> bool {
+        self.codec != CollateCodec::None
+    }
+
+    /// The codec with which the temporary bucket files are compressed;
+    /// the same as that of the collated output.
+    pub fn bucket_codec(&self) -> io_utils::RadCodec {
+        match self.codec {
+            CollateCodec::None => io_utils::RadCodec::Raw,
+            CollateCodec::Snappy => io_utils::RadCodec::Snappy,
+            CollateCodec::Zstd => io_utils::RadCodec::Zstd,
+        }
+    }
+}
+
+impl Default for CollateCompression {
+    fn default() -> Self {
+        CollateCompression {
+            codec: CollateCodec::None,
+            zstd_level: Self::DEFAULT_ZSTD_LEVEL,
+            zstd_threads: 0,
+        }
+    }
+}
+
+/// The order in which the cells are written to the collated RAD file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CollateOrder {
+    /// approximately in decreasing order of the number of reads of each
+    /// cell; the exact order depends on the permit list and on the
+    /// order in which the threads finish their work
+    #[default]
+    Frequency,
+    /// in increasing order of the (2-bit encoded) cell barcode, which is
+    /// also the lexicographic order of the barcode sequences
+    Barcode,
+}
+
+impl CollateOrder {
+    /// The name of this order as recorded in `collate.json`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CollateOrder::Frequency => "frequency",
+            CollateOrder::Barcode => "barcode",
+        }
+    }
+}
+
+impl std::fmt::Display for CollateOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The sink for the collated RAD file.  Snappy compression is applied
+/// per chunk by the gather threads, so that the snappy output goes
+/// through the `Plain` variant; zstd compresses the whole stream.
+enum CollatedWriter {
+    Plain(BufWriter<File>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<File>>),
+}
+
+impl CollatedWriter {
+    fn new(ofile: File, compression: &CollateCompression) -> std::io::Result<Self> {
+        let bw = BufWriter::with_capacity(1048576, ofile);
+        match compression.codec {
+            CollateCodec::None | CollateCodec::Snappy => Ok(CollatedWriter::Plain(bw)),
+            CollateCodec::Zstd => {
+                let mut enc = zstd::stream::write::Encoder::new(bw, compression.zstd_level)?;
+                if compression.zstd_threads > 0 {
+                    enc.multithread(compression.zstd_threads)?;
+                }
+                Ok(CollatedWriter::Zstd(enc))
+            }
+        }
+    }
+
+    /// Flush all output, writing the end of the zstd stream if needed.
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            CollatedWriter::Plain(mut w) => w.flush(),
+            CollatedWriter::Zstd(enc) => enc.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for CollatedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CollatedWriter::Plain(w) => w.write(buf),
+            CollatedWriter::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        match self {
+            CollatedWriter::Plain(w) => w.write_all(buf),
+            CollatedWriter::Zstd(w) => w.write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CollatedWriter::Plain(w) => w.flush(),
+            CollatedWriter::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// Approximate in-memory size of a hash map entry (key, value and table
+/// overhead), used to account for the maps collate holds.
+const HASH_ENTRY_BYTES: u64 = 48;
+/// The gather phase holds (roughly) two copies of the records of each
+/// bucket being collated: the records as read from the bucket, and the
+/// collated output buffer.
+const GATHER_BYTES_PER_BUCKET_BYTE: u64 = 2;
+/// When the output is sorted, up to this many collated buckets per
+/// gather thread may be in memory at once (being collated, or waiting
+/// for the buckets before them to be written).
+const PENDING_BUCKETS_PER_WORKER: usize = 2;
+/// The record size assumed if it can't be estimated from the input.
+const DEFAULT_EST_RECORD_BYTES: u64 = 40;
+/// The chunk size assumed if it can't be estimated from the input.
+const DEFAULT_EST_CHUNK_BYTES: u64 = 65536;
+/// The smallest amount of memory that will be used for the per-thread
+/// buffers and buckets, even if the budget is already exhausted by the
+/// correction map and per-cell bookkeeping.
+const MIN_WORKING_BYTES: u64 = 16 * 1024 * 1024;
+/// bounds on the size of the buffer each scatter thread keeps for each
+/// temporary bucket.
+const MIN_LOC_BUFFER_BYTES: usize = 1000;
+const MAX_LOC_BUFFER_BYTES: usize = 262_144;
+
+/// How collate divides its memory between the temporary buckets and the
+/// per-thread buffers, along with the memory it is predicted to use.
+#[derive(Clone, Debug, Serialize)]
+pub struct CollateMemoryPlan {
+    /// the memory budget (in bytes), if one was given
+    pub budget_bytes: Option<u64>,
+    /// the estimated size (in bytes) of a read record
+    pub est_record_bytes: u64,
+    /// memory for the correction map and the per-cell bookkeeping
+    pub fixed_bytes: u64,
+    /// the maximum number of records assigned to each temporary bucket
+    pub max_records_per_bucket: u64,
+    /// the number of temporary buckets
+    pub num_buckets: usize,
+    /// the size of the buffer each scatter thread keeps for each bucket
+    pub local_buffer_bytes: usize,
+    /// buckets that are larger than this (in bytes) are partitioned
+    /// again before they are collated
+    pub max_bucket_bytes: Option<u64>,
+    /// the predicted memory use of the scatter phase
+    pub predicted_scatter_bytes: u64,
+    /// the number of collated buckets that may be held in memory until
+    /// they can be written in order (0 unless the output is sorted)
+    pub max_pending_buckets: usize,
+    /// the predicted memory use of the collated buckets waiting to be
+    /// written in order
+    pub predicted_ordered_bytes: u64,
+    /// the predicted memory use of the gather phase
+    pub predicted_gather_bytes: u64,
+    /// the predicted peak memory use
+    pub predicted_peak_bytes: u64,
+}
+
+impl CollateMemoryPlan {
+    /// Determine the bucket size for collating with `n_workers` threads
+    /// under the memory `budget` (if any) and the `max_records` limit.
+    /// `num_cells` and `correct_map_len` are the sizes of the maps that
+    /// collate holds throughout, and `est_chunk_bytes` the size of the
+    /// chunks queued for the scatter threads.  If `sorted_output` is true,
+    /// collated buckets may also wait in memory to be written in order.
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        budget: Option<u64>,
+        n_workers: usize,
+        max_records: u32,
+        est_record_bytes: u64,
+        est_chunk_bytes: u64,
+        num_cells: usize,
+        correct_map_len: usize,
+        sorted_output: bool,
+    ) -> Self {
+        let max_pending_buckets = if sorted_output {
+            PENDING_BUCKETS_PER_WORKER * n_workers
+        } else {
+            0
+        };
+        let n_workers = n_workers as u64;
+        // beyond the buckets being collated, each waiting bucket holds
+        // (about) one copy of its records.
+        let pending_per_worker = if sorted_output {
+            PENDING_BUCKETS_PER_WORKER as u64 - 1
+        } else {
+            0
+        };
+        // the correction map, the bucket of each cell (and the
+        // frequencies of the cells) are held for the whole run, as is
+        // the queue of chunks waiting for the scatter threads.
+        let fixed_bytes = (correct_map_len as u64 + 2 * num_cells as u64) * HASH_ENTRY_BYTES
+            + 4 * n_workers * est_chunk_bytes;
+        let records_limit = (max_records as u64 / n_workers) + 1;
+        let (max_records_per_bucket, max_bucket_bytes) = match budget {
+            Some(b) => {
+                let avail = b.saturating_sub(fixed_bytes).max(MIN_WORKING_BYTES);
+                // each gather thread collates one bucket at a time
+                let bucket_bytes = (avail
+                    / (n_workers * (GATHER_BYTES_PER_BUCKET_BYTE + pending_per_worker)))
+                    .max(1);
+                (
+                    records_limit.min((bucket_bytes / est_record_bytes).max(1)),
+                    Some(bucket_bytes),
+                )
+            }
+            None => (records_limit, None),
+        };
+        CollateMemoryPlan {
+            budget_bytes: budget,
+            est_record_bytes,
+            fixed_bytes,
+            max_records_per_bucket,
+            num_buckets: 0,
+            local_buffer_bytes: 0,
+            max_bucket_bytes,
+            max_pending_buckets,
+            predicted_scatter_bytes: 0,
+            predicted_ordered_bytes: 0,
+            predicted_gather_bytes: 0,
+            predicted_peak_bytes: 0,
+        }
+    }
+
+    /// Size the per-thread buffers for `num_buckets` buckets, where each
+    /// buffer must be able to hold at least `min_buffer_bytes`, and
+    /// predict the memory use of each phase.
+    fn size_buffers(
+        &mut self,
+        num_buckets: usize,
+        n_workers: usize,
+        max_records: u32,
+        min_buffer_bytes: usize,
+    ) {
+        // smallest size an individual record can be loaded in memory
+        let min_rec_len = 24usize;
+        let nbuf = num_buckets * n_workers;
+        let per_buffer = match self.budget_bytes {
+            Some(b) => {
+                let avail = b.saturating_sub(self.fixed_bytes).max(MIN_WORKING_BYTES);
+                (avail as usize / nbuf).clamp(MIN_LOC_BUFFER_BYTES, MAX_LOC_BUFFER_BYTES)
+            }
+            None => ((min_rec_len * max_records as usize) / nbuf)
+                .clamp(MIN_LOC_BUFFER_BYTES, MAX_LOC_BUFFER_BYTES),
+        };
+        self.num_buckets = num_buckets;
+        self.local_buffer_bytes = min_buffer_bytes.max(per_buffer);
+
+        let n_workers = n_workers as u64;
+        let bucket_bytes = self
+            .max_bucket_bytes
+            .unwrap_or(self.max_records_per_bucket * self.est_record_bytes);
+        self.predicted_scatter_bytes = nbuf as u64 * self.local_buffer_bytes as u64;
+        // the buckets waiting to be written beyond those being collated
+        self.predicted_ordered_bytes =
+            (self.max_pending_buckets as u64).saturating_sub(n_workers) * bucket_bytes;
+        self.predicted_gather_bytes =
+            n_workers * GATHER_BYTES_PER_BUCKET_BYTE * bucket_bytes + self.predicted_ordered_bytes;
+        self.predicted_peak_bytes = self.fixed_bytes
+            + self
+                .predicted_scatter_bytes
+                .max(self.predicted_gather_bytes);
+    }
+}
+
+/// Estimate the size of a read record, and of a chunk, from the header
+/// of the first chunk of the RAD file, if it is already buffered in `br`.
+fn estimate_record_and_chunk_bytes<R: BufRead>(br: &mut R) -> (u64, u64) {
+    let buf = match br.fill_buf() {
+        Ok(b) => b,
+        Err(_) => return (DEFAULT_EST_RECORD_BYTES, DEFAULT_EST_CHUNK_BYTES),
+    };
+    if buf.len() < 8 {
+        return (DEFAULT_EST_RECORD_BYTES, DEFAULT_EST_CHUNK_BYTES);
+    }
+    let nbytes = buf.pread::<u32>(0).unwrap() as u64;
+    let nrec = buf.pread::<u32>(4).unwrap() as u64;
+    if nrec == 0 || nbytes <= 8 {
+        return (DEFAULT_EST_RECORD_BYTES, DEFAULT_EST_CHUNK_BYTES);
+    }
+    (((nbytes - 8) / nrec).max(1), nbytes)
+}
+
+/// Collate the temporary bucket `fname`, holding the records of the
+/// `cells` (with their record counts) and compressed with `bucket_codec`,
+/// which is too large to collate in one piece.  The bucket is first
+/// partitioned (by cell) into `num_parts` smaller (uncompressed) buckets
+/// of about the same number of records, each of which is then collated
+/// in turn.  Returns the number of cells written.
+#[allow(clippy::too_many_arguments)]
+fn collate_bucket_in_parts<W: Write>(
+    fname: &Path,
+    cells: &[(u64, u64)],
+    num_parts: usize,
+    bc_type: &rad_types::RadIntId,
+    umi_type: &rad_types::RadIntId,
+    bucket_codec: io_utils::RadCodec,
+    owriter: &Arc<Mutex<W>>,
+    snappy_out: bool,
+    cmap: &mut HashMap<u64, TempCellInfo, ahash::RandomState>,
+) -> std::io::Result<u64> {
+    // assign the cells to parts, in order, so that each part
+    // has about the same number of records.
+    let total: u64 = cells.iter().map(|c| c.1).sum();
+    let per_part = (total / num_parts as u64).max(1);
+    let mut part_of = HashMap::<u64, usize>::with_capacity(cells.len());
+    let mut part = 0usize;
+    let mut in_part = 0u64;
+    for &(bc, count) in cells {
+        if in_part >= per_part && part + 1 < num_parts {
+            part += 1;
+            in_part = 0;
+        }
+        part_of.insert(bc, part);
+        in_part += count;
+    }
+    let num_parts = part + 1;
+
+    let part_names: Vec<PathBuf> = (0..num_parts)
+        .map(|i| fname.with_extension(format!("{}.tmp", i)))
+        .collect();
+    let mut part_writers = Vec::with_capacity(num_parts);
+    for pn in &part_names {
+        part_writers.push(BufWriter::new(File::create(pn)?));
+    }
+    let mut part_records = vec![0u32; num_parts];
+
+    // each record is the number of alignments (u32), the barcode,
+    // the UMI, and then the alignments themselves (u32 each).
+    let bc_bytes = int_type_bytes(bc_type);
+    let header_bytes = std::mem::size_of::<u32>() + bc_bytes + int_type_bytes(umi_type);
+    let mut rec_header = vec![0u8; header_bytes];
+    let mut alns = Vec::<u8>::new();
+    let mut reader = BufReader::new(io_utils::open_with_codec(fname, bucket_codec)?);
+    loop {
+        match reader.read_exact(&mut rec_header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let na = rec_header.pread::<u32>(0).unwrap() as usize;
+        let mut bc_buf = [0u8; 8];
+        bc_buf[..bc_bytes].copy_from_slice(&rec_header[4..4 + bc_bytes]);
+        let bc = u64::from_le_bytes(bc_buf);
+        alns.resize(na * std::mem::size_of::<u32>(), 0);
+        reader.read_exact(&mut alns)?;
+
+        let p = *part_of.get(&bc).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unexpected barcode {} in {}", bc, fname.display()),
+            )
+        })?;
+        part_writers[p].write_all(&rec_header)?;
+        part_writers[p].write_all(&alns)?;
+        part_records[p] += 1;
+    }
+    drop(reader);
+    for w in part_writers.iter_mut() {
+        w.flush()?;
+    }
+    drop(part_writers);
+
+    let mut num_chunks = 0u64;
+    for (pn, nrec) in part_names.iter().zip(part_records) {
+        cmap.clear();
+        let mut treader = BufReader::new(File::open(pn)?);
+        num_chunks += libradicl::collate_temporary_bucket_twopass(
+            &mut treader,
+            bc_type,
+            umi_type,
+            nrec,
+            owriter,
+            snappy_out,
+            cmap,
+        ) as u64;
+        drop(treader);
+        std::fs::remove_file(pn)?;
+    }
+    Ok(num_chunks)
+}
+
+/// Write the records of the chunk `buf` (including its header), whose
+/// barcodes and UMIs are of type `bc_type` and `umi_type`, to the
+/// temporary buckets of their corrected barcodes.  Only the alignments
+/// compatible with `expected_ori` are kept; records with none, or whose
+/// barcode has no correction in `correct_map`, are dropped.  Each record
+/// is added to this thread's buffer for its bucket in `local_buffers`,
+/// and a full buffer is compressed (as an independent block) and
+/// appended to the bucket file.
+#[allow(clippy::too_many_arguments)]
+fn scatter_chunk(
+    buf: &[u8],
+    bc_type: &rad_types::RadIntId,
+    umi_type: &rad_types::RadIntId,
+    correct_map: &HashMap<u64, u64>,
+    expected_ori: &Strand,
+    output_cache: &HashMap<u64, Arc<libradicl::TempBucket>>,
+    local_buffers: &mut [Cursor<&mut [u8]>],
+    compression: &CollateCompression,
+) -> std::io::Result<()> {
+    let malformed = || std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed RAD chunk");
+    let bucket_codec = compression.bucket_codec();
+    // each record is the number of alignments (u32), the barcode,
+    // the UMI, and then the alignments themselves (u32 each).
+    let bc_bytes = int_type_bytes(bc_type);
+    let header_bytes = std::mem::size_of::<u32>() + bc_bytes + int_type_bytes(umi_type);
+    let nrec = buf.pread::<u32>(4).map_err(|_| malformed())?;
+    let mut offset = 2 * std::mem::size_of::<u32>();
+    let mut alns = Vec::<u32>::new();
+    for _ in 0..nrec {
+        let na = buf.pread::<u32>(offset).map_err(|_| malformed())? as usize;
+        let rec_end = offset + header_bytes + na * std::mem::size_of::<u32>();
+        if rec_end > buf.len() {
+            return Err(malformed());
+        }
+        let mut bc_buf = [0u8; 8];
+        bc_buf[..bc_bytes].copy_from_slice(&buf[(offset + 4)..(offset + 4 + bc_bytes)]);
+        let bc = u64::from_le_bytes(bc_buf);
+        let umi = &buf[(offset + 4 + bc_bytes)..(offset + header_bytes)];
+        alns.clear();
+        for a in buf[(offset + header_bytes)..rec_end].chunks_exact(4) {
+            let v = u32::from_le_bytes([a[0], a[1], a[2], a[3]]);
+            // the orientation is held in the top bit of the reference id
+            let fw = v & 0x8000_0000 != 0;
+            let compatible = match expected_ori {
+                Strand::Forward => fw,
+                Strand::Reverse => !fw,
+                Strand::Unknown => true,
+            };
+            if compatible {
+                alns.push(v);
+            }
+        }
+        offset = rec_end;
+        if alns.is_empty() {
+            continue;
+        }
+
+        let (corrected_bc, bucket) = match correct_map
+            .get(&bc)
+            .and_then(|cbc| Some((*cbc, output_cache.get(cbc)?)))
+        {
+            Some(x) => x,
+            None => continue,
+        };
+        let nb = header_bytes + alns.len() * std::mem::size_of::<u32>();
+        let cursor = &mut local_buffers[bucket.bucket_id as usize];
+        let len = cursor.position() as usize;
+        if len + nb >= cursor.get_ref().len() {
+            let mut filebuf = bucket.bucket_writer.lock().unwrap();
+            io_utils::write_block(
+                &mut *filebuf,
+                &cursor.get_ref()[0..len],
+                bucket_codec,
+                compression.zstd_level,
+            )?;
+            cursor.set_position(0);
+        }
+        cursor.write_all(&(alns.len() as u32).to_le_bytes())?;
+        cursor.write_all(&corrected_bc.to_le_bytes()[..bc_bytes])?;
+        cursor.write_all(umi)?;
+        for a in &alns {
+            cursor.write_all(&a.to_le_bytes())?;
+        }
+        bucket.num_records_written.fetch_add(1, Ordering::SeqCst);
+        bucket
+            .num_bytes_written
+            .fetch_add(nb as u64, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+/// Collate the temporary bucket `fname`, holding `nrec` records of the
+/// `cells` and compressed with `bucket_codec`, in `num_parts` parts (see
+/// [`collate_bucket_in_parts`]) or, if `num_parts` is 1, in one piece,
+/// writing the collated cells to `owriter`.  The bucket file is removed
+/// afterwards.  Returns the number of cells written.
+#[allow(clippy::too_many_arguments)]
+fn gather_bucket<W: Write>(
+    fname: &Path,
+    cells: &[(u64, u64)],
+    num_parts: usize,
+    nrec: u32,
+    bc_type: &rad_types::RadIntId,
+    umi_type: &rad_types::RadIntId,
+    bucket_codec: io_utils::RadCodec,
+    owriter: &Arc<Mutex<W>>,
+    snappy_out: bool,
+    cmap: &mut HashMap<u64, TempCellInfo, ahash::RandomState>,
+) -> std::io::Result<u64> {
+    cmap.clear();
+    let num_chunks = if num_parts > 1 {
+        collate_bucket_in_parts(
+            fname,
+            cells,
+            num_parts,
+            bc_type,
+            umi_type,
+            bucket_codec,
+            owriter,
+            snappy_out,
+            cmap,
+        )?
+    } else {
+        // create a new handle for reading
+        let mut treader = BufReader::new(io_utils::open_with_codec(fname, bucket_codec)?);
+        libradicl::collate_temporary_bucket_twopass(
+            &mut treader,
+            bc_type,
+            umi_type,
+            nrec,
+            owriter,
+            snappy_out,
+            cmap,
+        ) as u64
+    };
+    // we don't need the file anymore
+    std::fs::remove_file(fname)?;
+    Ok(num_chunks)
+}
+
+/// Reorder the (uncompressed) collated chunks in `buf` so that they
+/// appear in increasing order of their cell barcode, the type of which
+/// is `bc_type`.
+fn sort_collated_chunks(buf: &[u8], bc_type: &rad_types::RadIntId) -> std::io::Result<Vec<u8>> {
+    // each chunk is its size in bytes (u32) and number of records (u32),
+    // followed by the records, each of which starts with its number of
+    // alignments (u32) and then the barcode.
+    let bc_bytes = int_type_bytes(bc_type);
+    let bc_offset = 2 * std::mem::size_of::<u32>() + std::mem::size_of::<u32>();
+    let mut chunks = Vec::<(u64, std::ops::Range<usize>)>::new();
+    let mut offset = 0usize;
+    while offset < buf.len() {
+        let nbytes = buf.pread::<u32>(offset).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "truncated collated chunk header",
+            )
+        })? as usize;
+        let bc_start = offset + bc_offset;
+        if nbytes < bc_offset + bc_bytes || offset + nbytes > buf.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "malformed collated chunk",
+            ));
+        }
+        let mut bc_buf = [0u8; 8];
+        bc_buf[..bc_bytes].copy_from_slice(&buf[bc_start..bc_start + bc_bytes]);
+        chunks.push((u64::from_le_bytes(bc_buf), offset..offset + nbytes));
+        offset += nbytes;
+    }
+    chunks.sort_unstable_by_key(|c| c.0);
+
+    let mut sorted = Vec::with_capacity(buf.len());
+    for (_bc, r) in chunks {
+        sorted.extend_from_slice(&buf[r]);
+    }
+    Ok(sorted)
+}
+
+/// The state of an [`OrderedBucketWriter`].
+struct OrderedState {
+    /// the id of the next bucket to write
+    next: u32,
+    /// the finished buckets that are waiting for earlier buckets
+    /// to be written
+    pending: BTreeMap<u32, Vec<u8>>,
+    /// set if a bucket could not be collated or written, in which
+    /// case the buckets after it will never be written
+    failed: bool,
+}
+
+/// Writes the collated buckets to the output in the order of their ids,
+/// although they may be finished (by different threads) in any order.
+/// Buckets must be collated in (about) the order of their ids; at most
+/// `max_pending` buckets, starting from the next one to be written, may be
+/// collated (and held in memory) at once.
+struct OrderedBucketWriter {
+    state: Mutex<OrderedState>,
+    ready: Condvar,
+    max_pending: u32,
+}
+
+impl OrderedBucketWriter {
+    fn new(max_pending: usize) -> Self {
+        OrderedBucketWriter {
+            state: Mutex::new(OrderedState {
+                next: 0,
+                pending: BTreeMap::new(),
+                failed: false,
+            }),
+            ready: Condvar::new(),
+            max_pending: max_pending.max(1) as u32,
+        }
+    }
+
+    /// Wait until the bucket `bucket_id` may be collated, i.e. until
+    /// fewer than `max_pending` buckets before it remain to be written.
+    fn wait_turn(&self, bucket_id: u32) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while !state.failed && bucket_id >= state.next.saturating_add(self.max_pending) {
+            state = self.ready.wait(state).unwrap();
+        }
+        if state.failed {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "an earlier bucket could not be collated",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Record that a bucket could not be collated or written, waking
+    /// the threads waiting for their turn.
+    fn fail(&self) {
+        self.state.lock().unwrap().failed = true;
+        self.ready.notify_all();
+    }
+
+    /// Submit the collated contents of the bucket `bucket_id`, and write
+    /// (and compress with snappy, if `snappy_out` is true) all buckets
+    /// that are now ready to `owriter`.
+    fn submit<W: Write>(
+        &self,
+        bucket_id: u32,
+        bytes: Vec<u8>,
+        owriter: &Mutex<W>,
+        snappy_out: bool,
+    ) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.pending.insert(bucket_id, bytes);
+        loop {
+            let next = state.next;
+            let bytes = match state.pending.remove(&next) {
+                Some(b) => b,
+                None => break,
+            };
+            if !bytes.is_empty() {
+                let mut oput = owriter.lock().unwrap();
+                if snappy_out {
+                    let mut enc = snap::write::FrameEncoder::new(&mut *oput);
+                    enc.write_all(&bytes)?;
+                    enc.flush()?;
+                } else {
+                    oput.write_all(&bytes)?;
+                }
+            }
+            state.next += 1;
+        }
+        drop(state);
+        self.ready.notify_all();
+        Ok(())
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn collate<P1, P2>(
     input_dir: P1,
     rad_dir: P2,
     num_threads: u32,
     max_records: u32,
-    compress_out: bool,
+    max_memory: Option<u64>,
+    compression: CollateCompression,
+    order: CollateOrder,
     cmdline: &str,
     version_str: &str,
     //expected_ori: Strand,
     log: &slog::Logger,
-) -> anyhow::Result<()>
+) -> Result<CollateStats, AlevinFryError>
 where
     P1: Into<PathBuf>,
     P2: AsRef<Path>,
@@ -53,6 +847,14 @@ where
 
     // open the metadata file and read the json
     let gpl_path = parent.join("generate_permit_list.json");
+    if !gpl_path.exists() {
+        crit!(
+            log,
+            "the file {} does not exist; please run generate-permit-list first.",
+            gpl_path.display()
+        );
+        return Err(AlevinFryError::missing_file(gpl_path));
+    }
     let meta_data_file = File::open(&gpl_path)
         .with_context(|| format!("Could not open the file {:?}.", gpl_path.display()))?;
     let mdata: serde_json::Value = serde_json::from_reader(meta_data_file)?;
@@ -65,23 +867,27 @@ where
                 vd = InternalVersionInfo::from_str(s)?;
             }
             None => {
-                return Err(anyhow!("The version_str field must be a string"));
+                return Err(AlevinFryError::VersionMismatch(
+                    "The version_str field must be a string".to_string(),
+                ));
             }
         },
         None => {
-            return Err(anyhow!("The generate_permit_list.json file does not contain a version_str field. Please re-run the generate-permit-list step with a newer version of alevin-fry"));
+            return Err(AlevinFryError::VersionMismatch("The generate_permit_list.json file does not contain a version_str field. Please re-run the generate-permit-list step with a newer version of alevin-fry".to_string()));
         }
     };
 
     if let Err(es) = calling_version.is_compatible_with(&vd) {
-        return Err(anyhow!(es));
+        return Err(AlevinFryError::VersionMismatch(es));
     }
 
     // if only an *old* version of the permit_freq is present, then complain and exit
     if parent.join("permit_freq.tsv").exists() && !parent.join("permit_freq.bin").exists() {
         crit!(log, "The file permit_freq.bin doesn't exist, please rerun alevin-fry generate-permit-list command.");
-        // std::process::exit(1);
-        return Err(anyhow!("execution terminated unexpectedly"));
+        return Err(AlevinFryError::MissingFile {
+            path: parent.join("permit_freq.bin"),
+            hint: Some("please rerun alevin-fry generate-permit-list command".to_string()),
+        });
     }
 
     // open file
@@ -104,7 +910,11 @@ where
               "The permit_freq.bin file had version {}, but this version of alevin-fry requires version {}",
               freq_file_version, afconst::PERMIT_FILE_VER
         );
-        return Err(anyhow!("execution terminated unexpectedly"));
+        return Err(AlevinFryError::VersionMismatch(format!(
+            "The permit_freq.bin file had version {}, but this version of alevin-fry requires version {}",
+            freq_file_version,
+            afconst::PERMIT_FILE_VER
+        )));
     }
 
     // read the barcode length
@@ -117,12 +927,7 @@ where
     // read the barcode -> frequency hashmap
     let freq_hm: HashMap<u64, u64> =
         bincode::deserialize_from(rdr).context("couldn't deserialize barcode to frequency map.")?;
-    let total_to_collate = freq_hm.values().sum();
-    let mut tsv_map = Vec::from_iter(freq_hm.into_iter());
-
-    // sort this so that we deal with largest cells (by # of reads) first
-    // sort in _descending_ order by count.
-    tsv_map.sort_unstable_by_key(|&a: &(u64, u64)| std::cmp::Reverse(a.1));
+    let (tsv_map, total_to_collate) = sorted_permit_freq(freq_hm);
 
     /*
     let est_num_rounds = (total_to_collate as f64 / max_records as f64).ceil() as u64;
@@ -139,9 +944,11 @@ where
         rad_dir,
         num_threads,
         max_records,
+        max_memory,
         tsv_map,
         total_to_collate,
-        compress_out,
+        compression,
+        order,
         cmdline,
         version_str,
         log,
@@ -161,17 +968,75 @@ where
     }*/
 }
 
-fn get_orientation(mdata: &serde_json::Value) -> Result<Strand, StrandError> {
-    // next line is ugly — should be a better way.  We need a char to
-    // get the strand, so we get the correct field as a `str` then
-    // use the chars iterator and get the first char.
-    let ori_str: char = mdata["expected_ori"]
-        .as_str()
-        .unwrap()
-        .chars()
-        .next()
-        .unwrap();
-    Strand::from_char(&ori_str)
+/// Collate the RAD file in `rad_dir` using the permit list frequencies and
+/// correction map in `maps`, as returned by
+/// [`crate::cellfilter::generate_permit_list_with_maps`], rather than
+/// reading them back from the `permit_freq.bin` and `permit_map.bin` files
+/// in `input_dir`.  The remaining metadata is still read from `input_dir`.
+#[allow(clippy::too_many_arguments)]
+pub fn collate_with_permit_maps<P1, P2>(
+    input_dir: P1,
+    rad_dir: P2,
+    num_threads: u32,
+    max_records: u32,
+    max_memory: Option<u64>,
+    compression: CollateCompression,
+    order: CollateOrder,
+    maps: PermitMaps,
+    cmdline: &str,
+    version_str: &str,
+    log: &slog::Logger,
+) -> Result<CollateStats, AlevinFryError>
+where
+    P1: Into<PathBuf>,
+    P2: AsRef<Path>,
+{
+    let (tsv_map, total_to_collate) = sorted_permit_freq(maps.permit_freq);
+    collate_with_temp_impl(
+        input_dir,
+        rad_dir,
+        num_threads,
+        max_records,
+        max_memory,
+        tsv_map,
+        total_to_collate,
+        Some(maps.correct_map),
+        compression,
+        order,
+        cmdline,
+        version_str,
+        log,
+    )
+}
+
+/// Returns the (barcode, frequency) pairs of `freq_hm` sorted in
+/// descending order of frequency, so that we deal with the largest
+/// cells (by # of reads) first, along with the total frequency.
+fn sorted_permit_freq(freq_hm: HashMap<u64, u64>) -> (Vec<(u64, u64)>, u64) {
+    let total_to_collate = freq_hm.values().sum();
+    let mut tsv_map = Vec::from_iter(freq_hm.into_iter());
+    tsv_map.sort_unstable_by_key(|&a: &(u64, u64)| std::cmp::Reverse(a.1));
+    (tsv_map, total_to_collate)
+}
+
+fn get_orientation(mdata: &serde_json::Value) -> Result<Strand, AlevinFryError> {
+    // We need a char to get the strand, so we get the correct field
+    // as a `str` then take its first char.
+    let ori_char = mdata
+        .get("expected_ori")
+        .and_then(|v| v.as_str())
+        .and_then(|s| s.chars().next())
+        .ok_or_else(|| {
+            AlevinFryError::InvalidInput(
+                "generate_permit_list.json has no expected_ori entry".to_string(),
+            )
+        })?;
+    Strand::from_char(&ori_char).map_err(|e: StrandError| {
+        AlevinFryError::InvalidInput(format!(
+            "invalid expected_ori in generate_permit_list.json : {}",
+            e
+        ))
+    })
 }
 
 #[derive(Debug)]
@@ -216,8 +1081,11 @@ fn correct_unmapped_counts(
     correct_map: &Arc<HashMap<u64, u64>>,
     unmapped_file: &std::path::Path,
     parent: &std::path::Path,
-) {
-    let i_file = File::open(unmapped_file).unwrap();
+) -> Result<(), AlevinFryError> {
+    let i_file = File::open(unmapped_file).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => AlevinFryError::missing_file(unmapped_file),
+        _ => AlevinFryError::from(e),
+    })?;
     let mut br = BufReader::new(i_file);
 
     // enough to hold a key value pair (a u64 key and u32 value)
@@ -244,25 +1112,72 @@ fn correct_unmapped_counts(
     }
 
     let s_path = parent.join("unmapped_bc_count_collated.bin");
-    let s_file = std::fs::File::create(s_path).expect("could not create serialization file.");
+    let s_file = std::fs::File::create(&s_path)
+        .with_context(|| format!("could not create {}", s_path.display()))?;
     let mut s_writer = BufWriter::new(&s_file);
     bincode::serialize_into(&mut s_writer, &unmapped_count)
-        .expect("couldn't serialize corrected unmapped bc count.");
+        .context("couldn't serialize corrected unmapped bc count.")?;
+    s_writer
+        .flush()
+        .with_context(|| format!("could not write {}", s_path.display()))?;
+    Ok(())
 }
 
-#[allow(clippy::too_many_arguments, clippy::manual_clamp)]
+#[allow(clippy::too_many_arguments)]
 pub fn collate_with_temp<P1, P2>(
     input_dir: P1,
     rad_dir: P2,
     num_threads: u32,
     max_records: u32,
+    max_memory: Option<u64>,
     tsv_map: Vec<(u64, u64)>,
     total_to_collate: u64,
-    compress_out: bool,
+    compression: CollateCompression,
+    order: CollateOrder,
     cmdline: &str,
     version: &str,
     log: &slog::Logger,
-) -> anyhow::Result<()>
+) -> Result<CollateStats, AlevinFryError>
+where
+    P1: Into<PathBuf>,
+    P2: AsRef<Path>,
+{
+    collate_with_temp_impl(
+        input_dir,
+        rad_dir,
+        num_threads,
+        max_records,
+        max_memory,
+        tsv_map,
+        total_to_collate,
+        None,
+        compression,
+        order,
+        cmdline,
+        version,
+        log,
+    )
+}
+
+/// The implementation of [`collate_with_temp`].  If `correct_map` is
+/// `None`, the correction map is read from the `permit_map.bin` file
+/// in `input_dir`.
+#[allow(clippy::too_many_arguments)]
+fn collate_with_temp_impl<P1, P2>(
+    input_dir: P1,
+    rad_dir: P2,
+    num_threads: u32,
+    max_records: u32,
+    max_memory: Option<u64>,
+    mut tsv_map: Vec<(u64, u64)>,
+    total_to_collate: u64,
+    correct_map: Option<HashMap<u64, u64>>,
+    compression: CollateCompression,
+    order: CollateOrder,
+    cmdline: &str,
+    version: &str,
+    log: &slog::Logger,
+) -> Result<CollateStats, AlevinFryError>
 where
     P1: Into<PathBuf>,
     P2: AsRef<Path>,
@@ -297,7 +1212,7 @@ where
                 &meta_data_file,
                 e
             );
-            return Err(anyhow!(e));
+            return Err(e);
         }
     };
 
@@ -306,40 +1221,35 @@ where
 
     // log the filter type
     info!(log, "filter_type = {:?}", filter_type);
-    info!(
-        log,
-        "collated rad file {} be compressed",
-        if compress_out { "will" } else { "will not" }
-    );
+    info!(log, "collated cells will be written in {} order", order);
+    // assigning the cells to buckets in barcode order makes each bucket
+    // hold a contiguous range of barcodes, so that writing the buckets
+    // in order, each sorted by barcode, sorts the whole output.
+    if order == CollateOrder::Barcode {
+        tsv_map.sort_unstable_by_key(|&(bc, _)| bc);
+    }
+    match compression.codec {
+        CollateCodec::None => info!(log, "collated rad file will not be compressed"),
+        CollateCodec::Snappy => info!(log, "collated rad file will be compressed with snappy"),
+        CollateCodec::Zstd => info!(
+            log,
+            "collated rad file will be compressed with zstd (level {}, {} compression threads)",
+            compression.zstd_level,
+            compression.zstd_threads
+        ),
+    }
+    // only snappy compression is done by the gather threads themselves
+    let snappy_out = compression.codec == CollateCodec::Snappy;
+    // the temporary buckets are compressed with the same codec
+    let bucket_codec = compression.bucket_codec();
     // because :
     // https://superuser.com/questions/865710/write-to-newfile-vs-overwriting-performance-issue
     let cfname = if velo_mode {
         "velo.map.collated.rad"
-    } else if compress_out {
-        "map.collated.rad.sz"
     } else {
-        "map.collated.rad"
+        compression.codec.collated_file_name()
     };
 
-    // writing the collate metadata
-    {
-        let collate_meta = json!({
-            "cmd" : cmdline,
-            "version_str" : version,
-            "compressed_output" : compress_out,
-        });
-
-        let cm_path = parent.join("collate.json");
-        let mut cm_file =
-            std::fs::File::create(cm_path).context("could not create metadata file.")?;
-
-        let cm_info_string =
-            serde_json::to_string_pretty(&collate_meta).context("could not format json.")?;
-        cm_file
-            .write_all(cm_info_string.as_bytes())
-            .context("cannot write to collate.json file")?;
-    }
-
     let oname = parent.join(cfname);
     if oname.exists() {
         std::fs::remove_file(&oname)
@@ -348,7 +1258,10 @@ where
 
     let ofile = File::create(parent.join(cfname))
         .with_context(|| format!("couldn't create directory {}", cfname))?;
-    let owriter = Arc::new(Mutex::new(BufWriter::with_capacity(1048576, ofile)));
+    let owriter = Arc::new(Mutex::new(
+        CollatedWriter::new(ofile, &compression)
+            .context("couldn't create the zstd encoder for the collated output")?,
+    ));
 
     let i_dir = std::path::Path::new(rad_dir.as_ref());
 
@@ -358,18 +1271,53 @@ where
             "the input RAD path {:?} does not exist",
             rad_dir.as_ref()
         );
-        return Err(anyhow!("invalid input"));
+        return Err(AlevinFryError::missing_file(i_dir));
     }
 
-    let input_rad_path = i_dir.join("map.rad");
-    let i_file = File::open(&input_rad_path).context("couldn't open input RAD file")?;
-    let mut br = BufReader::new(i_file);
+    // if generate-permit-list spilled a copy of the input RAD stream
+    // (because the input could only be read once), read the records
+    // from the spill file.
+    let spilled_rad_path = mdata
+        .get("spilled_rad_file")
+        .and_then(|v| v.as_str())
+        .map(|f| parent.join(f));
+    // otherwise, the input RAD file may be compressed
+    let input_rad_path = match &spilled_rad_path {
+        Some(p) => p.clone(),
+        None => io_utils::find_input_rad(i_dir).unwrap_or_else(|| i_dir.join("map.rad")),
+    };
+    if !input_rad_path.exists() {
+        crit!(
+            log,
+            "the input RAD file {} does not exist",
+            input_rad_path.display()
+        );
+        return Err(AlevinFryError::missing_file(input_rad_path));
+    }
+    if spilled_rad_path.is_some() {
+        info!(
+            log,
+            "reading the input RAD records from the spill file {}",
+            input_rad_path.display()
+        );
+    }
+    // the codec (if any) is detected from the start of the file, so that
+    // we need not rely on the extension.
+    let (inner, codec) =
+        io_utils::open_rad_input(&input_rad_path).context("couldn't open input RAD file")?;
+    if codec != io_utils::RadCodec::Raw {
+        info!(log, "input RAD file is {:?}-compressed", codec);
+    }
+    let mut br = BufReader::new(inner);
 
-    let hdr = rad_types::RadHeader::from_bytes(&mut br);
+    // the header is recorded as it is parsed so that it can be copied
+    // to the output without having to re-read (or seek in) the input.
+    let mut hr = RecordingReader::new(&mut br);
+    let hdr = rad_types::RadHeader::from_bytes(&mut hr);
 
     // the exact position at the end of the header,
     // precisely sizeof(u64) bytes beyond the num_chunks field.
-    let end_header_pos = br.get_ref().stream_position().unwrap() - (br.buffer().len() as u64);
+    let end_header_pos = hr.num_read() as u64;
 
     info!(
         log,
@@ -381,23 +1329,60 @@ where
     );
 
     // file-level
-    let fl_tags = rad_types::TagSection::from_bytes(&mut br);
+    let fl_tags = rad_types::TagSection::from_bytes(&mut hr);
     info!(log, "read {:?} file-level tags", fl_tags.tags.len());
     // read-level
-    let rl_tags = rad_types::TagSection::from_bytes(&mut br);
+    let rl_start = hr.num_read();
+    let rl_tags = rad_types::TagSection::from_bytes(&mut hr);
+    let rl_end = hr.num_read();
     info!(log, "read {:?} read-level tags", rl_tags.tags.len());
     // alignment-level
-    let al_tags = rad_types::TagSection::from_bytes(&mut br);
+    let al_tags = rad_types::TagSection::from_bytes(&mut hr);
     info!(log, "read {:?} alignemnt-level tags", al_tags.tags.len());
 
-    let ft_vals = rad_types::FileTags::from_bytes(&mut br);
+    let ft_vals = rad_types::FileTags::from_bytes(&mut hr);
     info!(log, "File-level tag values {:?}", ft_vals);
 
-    let bct = rl_tags.tags[0].typeid;
-    let umit = rl_tags.tags[1].typeid;
+    // a RAD stream that the mapper wrote to a named pipe has no chunk count
+    // in its header (nor does the copy spilled by generate-permit-list),
+    // so use the number of chunks that generate-permit-list observed.  If
+    // that isn't recorded either, chunks are read until the end of the input.
+    let num_input_chunks = io_utils::known_num_chunks(&input_rad_path, hdr.num_chunks)
+        .or_else(|| mdata.get("num_chunks").and_then(|v| v.as_u64()));
+    if hdr.num_chunks == 0 {
+        match num_input_chunks {
+            Some(n) => info!(
+                log,
+                "the input header records no chunks; using the {} chunks observed by generate-permit-list.",
+                n.to_formatted_string(&Locale::en)
+            ),
+            None => info!(
+                log,
+                "the number of chunks is not recorded in the input; reading until the end of the input."
+            ),
+        }
+    }
 
-    // the exact position at the end of the header + file tags
-    let pos = br.get_ref().stream_position().unwrap() - (br.buffer().len() as u64);
+    let types = io_utils::RecordTypes::from_tags(&rl_tags)?;
+    if !types.has_umi() {
+        info!(
+            log,
+            "the input has no UMI tag; a UMI of 0 will be written for every record."
+        );
+    }
+    // the types of the barcode and UMI of the records as collated; records
+    // without a UMI are given a UMI (of type u8) of 0.
+    let bc_type = types.bc_type;
+    let umi_type = types.umi_type.unwrap_or(rad_types::RadIntId::U8);
+
+    // the bytes of the header + file tags
+    let hdr_bytes = if types.has_umi() {
+        hr.into_recorded()
+    } else {
+        io_utils::add_umi_tag(&hr.into_recorded(), rl_start..rl_end)
+    };
+    let pos = hdr_bytes.len() as u64;
+    let (est_record_bytes, est_chunk_bytes) = estimate_record_and_chunk_bytes(&mut br);
 
     // copy the header
     {
@@ -407,14 +1392,8 @@ where
         let chunk_bytes = std::mem::size_of::<u64>() as u64;
         let take_pos = end_header_pos - chunk_bytes;
 
-        // This temporary file pointer and buffer will be dropped
-        // at the end of this block (scope).
-        let mut rfile = File::open(&input_rad_path).context("Couldn't open input RAD file")?;
-        let mut hdr_buf = Cursor::new(vec![0u8; pos as usize]);
-
-        rfile
-            .read_exact(hdr_buf.get_mut())
-            .context("couldn't read input file header")?;
+        // This buffer will be dropped at the end of this block (scope).
+        let mut hdr_buf = Cursor::new(hdr_bytes);
         hdr_buf.set_position(take_pos);
         hdr_buf
             .write_all(&expected_output_chunks.to_le_bytes())
@@ -422,7 +1401,8 @@ where
         hdr_buf.set_position(0);
 
         // compress the header buffer to a compressed buffer
-        if compress_out {
+        // (with zstd, the writer compresses the header itself)
+        if snappy_out {
             let mut compressed_buf =
                 snap::write::FrameEncoder::new(Cursor::new(Vec::<u8>::with_capacity(pos as usize)));
             compressed_buf
@@ -441,14 +1421,21 @@ where
     }
 
     // get the correction map
-    let cmfile = std::fs::File::open(parent.join("permit_map.bin"))
-        .context("couldn't open output permit_map.bin file")?;
-    let correct_map: Arc<HashMap<u64, u64>> = Arc::new(bincode::deserialize_from(&cmfile).unwrap());
+    let correct_map: Arc<HashMap<u64, u64>> = match correct_map {
+        Some(cm) => Arc::new(cm),
+        None => {
+            let cmfile = std::fs::File::open(parent.join("permit_map.bin"))
+                .context("couldn't open output permit_map.bin file")?;
+            Arc::new(bincode::deserialize_from(&cmfile).map_err(|e| {
+                AlevinFryError::InvalidInput(format!("could not read permit_map.bin : {}", e))
+            })?)
+        }
+    };
 
     // NOTE: the assumption of where the unmapped file will be
     // should be robustified
     let unmapped_file = i_dir.join("unmapped_bc_count.bin");
-    correct_unmapped_counts(&correct_map, &unmapped_file, parent);
+    correct_unmapped_counts(&correct_map, &unmapped_file, parent)?;
 
     info!(
         log,
@@ -456,11 +1443,16 @@ where
         correct_map.len().to_formatted_string(&Locale::en)
     );
 
-    let cc = rad_types::ChunkConfig {
-        num_chunks: hdr.num_chunks,
-        bc_type: bct,
-        umi_type: umit,
-    };
+    let mut mem_plan = CollateMemoryPlan::new(
+        max_memory,
+        n_workers,
+        max_records,
+        est_record_bytes,
+        est_chunk_bytes,
+        tsv_map.len(),
+        correct_map.len(),
+        order == CollateOrder::Barcode,
+    );
 
     // TODO: see if we can do this without the Arc
     let mut output_cache = Arc::new(HashMap::<u64, Arc<libradicl::TempBucket>>::new());
@@ -474,20 +1466,22 @@ where
         Arc::new(libradicl::TempBucket::from_id_and_parent(0, parent)),
     )];
 
-    let max_records_per_thread = (max_records / n_workers as u32) + 1;
+    // the range of (the cells of) tsv_map assigned to each bucket
+    let mut bucket_ranges = vec![0..0];
     // The tsv_map tells us, for each "true" barcode
     // how many records belong to it.  We can scan this information
     // to determine what true barcodes we will keep in memory.
     let mut num_bucket_chunks = 0u32;
     {
         let moutput_cache = Arc::make_mut(&mut output_cache);
-        for rec in tsv_map.iter() {
+        for (i, rec) in tsv_map.iter().enumerate() {
             // corrected barcode points to the bucket
             // file.
             moutput_cache.insert(rec.0, temp_buckets.last().unwrap().2.clone());
             allocated_records += rec.1;
             num_bucket_chunks += 1;
-            if allocated_records >= (max_records_per_thread as u64) {
+            bucket_ranges.last_mut().unwrap().end = i + 1;
+            if allocated_records >= mem_plan.max_records_per_bucket {
                 temp_buckets.last_mut().unwrap().0 = num_bucket_chunks;
                 temp_buckets.last_mut().unwrap().1 = allocated_records as u32;
                 let tn = temp_buckets.len() as u32;
@@ -496,6 +1490,7 @@ where
                     0,
                     Arc::new(libradicl::TempBucket::from_id_and_parent(tn, parent)),
                 ));
+                bucket_ranges.push((i + 1)..(i + 1));
                 total_allocated_records += allocated_records;
                 allocated_records = 0;
                 num_bucket_chunks = 0;
@@ -517,7 +1512,7 @@ where
         .progress_chars("╢▌▌░╟");
 
     let pbar_inner = ProgressBar::with_draw_target(
-        Some(cc.num_chunks),
+        num_input_chunks,
         ProgressDrawTarget::stderr_with_hz(5u8), // update at most 5 times/sec.
     );
 
@@ -527,18 +1522,42 @@ where
     // create a thread-safe queue based on the number of worker threads
     let q = Arc::new(ArrayQueue::<(usize, Vec<u8>)>::new(4 * n_workers));
 
-    // the number of cells left to process
-    let chunks_to_process = Arc::new(AtomicUsize::new(cc.num_chunks as usize));
+    // set once all of the input chunks have been queued
+    let input_done = Arc::new(AtomicBool::new(false));
 
-    let mut thread_handles: Vec<thread::JoinHandle<u64>> = Vec::with_capacity(n_workers);
+    let mut thread_handles: Vec<thread::JoinHandle<std::io::Result<()>>> =
+        Vec::with_capacity(n_workers);
+    // set if a scatter thread fails, so that the others stop
+    let scatter_failed = Arc::new(AtomicBool::new(false));
 
-    let min_rec_len = 24usize; // smallest size an individual record can be loaded in memory
-    let max_rec = max_records as usize;
-    let num_buckets = temp_buckets.len();
-    let num_threads = n_workers;
-    let loc_buffer_size = (min_rec_len + (most_ambig_record * 4_usize) - 4_usize).max(
-        (1000_usize.max((min_rec_len * max_rec) / (num_buckets * num_threads))).min(262_144_usize),
-    ); //131072_usize);
+    // smallest size an individual record can be loaded in memory; each
+    // local buffer must be able to hold the most ambiguous record
+    let min_rec_len = 24usize;
+    mem_plan.size_buffers(
+        temp_buckets.len(),
+        n_workers,
+        max_records,
+        min_rec_len + (most_ambig_record * 4_usize) - 4_usize,
+    );
+    let loc_buffer_size = mem_plan.local_buffer_bytes;
+    info!(
+        log,
+        "estimated record size {} bytes; up to {} records per bucket; local buffers of {} bytes; predicted peak memory {} bytes.",
+        mem_plan.est_record_bytes,
+        mem_plan.max_records_per_bucket.to_formatted_string(&Locale::en),
+        loc_buffer_size.to_formatted_string(&Locale::en),
+        mem_plan.predicted_peak_bytes.to_formatted_string(&Locale::en)
+    );
+    if let Some(b) = mem_plan.budget_bytes {
+        if mem_plan.predicted_peak_bytes > b {
+            warn!(
+                log,
+                "the predicted peak memory use ({} bytes) exceeds the memory budget ({} bytes); the budget is too small for the number of cells, barcodes and threads.",
+                mem_plan.predicted_peak_bytes.to_formatted_string(&Locale::en),
+                b.to_formatted_string(&Locale::en)
+            );
+        }
+    }
 
     // for each worker, spawn off a thread
     for _worker in 0..n_workers {
@@ -547,17 +1566,14 @@ where
         // the output cache and correction map
         let oc = output_cache.clone();
         let correct_map = correct_map.clone();
-        // the number of chunks remaining to be processed
-        let chunks_remaining = chunks_to_process.clone();
-        // and knowledge of the UMI and BC types
-        let bc_type = rad_types::decode_int_type_tag(cc.bc_type).expect("unknown barcode type id.");
-        let umi_type =
-            rad_types::decode_int_type_tag(cc.umi_type).expect("unknown barcode type id.");
+        // whether any more chunks will be queued
+        let input_done = input_done.clone();
         let nbuckets = temp_buckets.len();
         let loc_temp_buckets = temp_buckets.clone();
+        let scatter_failed = scatter_failed.clone();
         //let owrite = owriter.clone();
         // now, make the worker thread
-        let handle = std::thread::spawn(move || {
+        let handle = std::thread::spawn(move || -> std::io::Result<()> {
             // old code
             //let mut local_buffers = vec![Cursor::new(vec![0u8; loc_buffer_size]); nbuckets];
 
@@ -586,21 +1602,25 @@ where
             }
 
             // pop from the work queue until everything is
-            // processed
-            while chunks_remaining.load(Ordering::SeqCst) > 0 {
+            // processed (or another thread has failed)
+            while !scatter_failed.load(Ordering::SeqCst) {
                 if let Some((_chunk_num, buf)) = in_q.pop() {
-                    chunks_remaining.fetch_sub(1, Ordering::SeqCst);
-                    let mut nbr = BufReader::new(&buf[..]);
-                    libradicl::dump_corrected_cb_chunk_to_temp_file(
-                        &mut nbr,
+                    if let Err(e) = scatter_chunk(
+                        &buf,
                         &bc_type,
                         &umi_type,
                         &correct_map,
                         &expected_ori,
                         &oc,
                         &mut local_buffers,
-                        loc_buffer_size,
-                    );
+                        &compression,
+                    ) {
+                        scatter_failed.store(true, Ordering::SeqCst);
+                        return Err(e);
+                    }
+                } else if input_done.load(Ordering::SeqCst) && in_q.is_empty() {
+                    // all chunks were queued before `input_done` was set
+                    break;
                 }
             }
 
@@ -609,11 +1629,15 @@ where
                 let len = lb.position() as usize;
                 if len > 0 {
                     let mut filebuf = loc_temp_buckets[bucket_id].2.bucket_writer.lock().unwrap();
-                    filebuf.write_all(&lb.get_ref()[0..len]).unwrap();
+                    io_utils::write_block(
+                        &mut *filebuf,
+                        &lb.get_ref()[0..len],
+                        bucket_codec,
+                        compression.zstd_level,
+                    )?;
                 }
             }
-            // return something more meaningful
-            0
+            Ok(())
         });
 
         thread_handles.push(handle);
@@ -630,33 +1654,63 @@ where
     // read chunks from the input file and pass them to the
     // worker threads.
     let mut buf = vec![0u8; 65536];
-    for cell_num in 0..(cc.num_chunks as usize) {
+    let mut cell_num = 0usize;
+    'chunks: loop {
+        let more_chunks = match num_input_chunks {
+            Some(n) => (cell_num as u64) < n,
+            None => !io_utils::at_eof(&mut br).context("couldn't read the input RAD file")?,
+        };
+        if !more_chunks {
+            break;
+        }
         let (nbytes_chunk, nrec_chunk) = rad_types::Chunk::read_header(&mut br);
         buf.resize(nbytes_chunk as usize, 0);
-        buf.pwrite::<u32>(nbytes_chunk, 0)?;
-        buf.pwrite::<u32>(nrec_chunk, 4)?;
-        br.read_exact(&mut buf[8..]).unwrap();
+        buf.pwrite::<u32>(nbytes_chunk, 0)
+            .context("couldn't write chunk header")?;
+        buf.pwrite::<u32>(nrec_chunk, 4)
+            .context("couldn't write chunk header")?;
+        br.read_exact(&mut buf[8..])
+            .context("couldn't read the input RAD chunk")?;
+        if !types.has_umi() {
+            buf = io_utils::add_zero_umis(&buf, &bc_type)?;
+        }
 
         let mut bclone = (cell_num, buf.clone());
         // keep trying until we can push this payload
         while let Err(t) = q.push(bclone) {
             bclone = t;
-            // no point trying to push if the queue is full
-            while q.is_full() {}
+            // no point trying to push if the queue is full, or
+            // if the worker threads have stopped
+            while q.is_full() {
+                if scatter_failed.load(Ordering::SeqCst) {
+                    break 'chunks;
+                }
+            }
         }
         pbar_inner.inc(1);
+        cell_num += 1;
     }
+    input_done.store(true, Ordering::SeqCst);
     pbar_inner.finish();
 
     // wait for the worker threads to finish
+    let mut scatter_error = None;
     for h in thread_handles.drain(0..) {
         match h.join() {
-            Ok(_) => {}
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                scatter_error.get_or_insert(e);
+            }
             Err(_e) => {
                 info!(log, "thread panicked");
             }
         }
     }
+    if let Some(e) = scatter_error {
+        return Err(anyhow::Error::new(e)
+            .context("could not write temporary bucket.")
+            .into());
+    }
     pbar_inner.finish_with_message("partitioned records into temporary files.");
     drop(q);
 
@@ -666,6 +1720,7 @@ where
     // phase of collating the temporary files and merging
     // them into the final output file.
 
+    let mut largest_bucket_bytes = 0u64;
     for (i, temp_bucket) in temp_buckets.iter().enumerate() {
         // make sure we flush each temp bucket
         temp_bucket
@@ -679,16 +1734,44 @@ where
         // and the expected number of bytes in each file
         let expected = temp_bucket.1;
         let observed = temp_bucket.2.num_records_written.load(Ordering::SeqCst);
-        assert_eq!(expected, observed);
+        if expected != observed {
+            return Err(AlevinFryError::InvalidInput(format!(
+                "expected {} records in temporary bucket {}, but {} were written",
+                expected, i, observed
+            )));
+        }
 
-        let md = std::fs::metadata(parent.join(format!("bucket_{}.tmp", i)))?;
+        // (the bytes counted are those before compression)
         let expected_bytes = temp_bucket.2.num_bytes_written.load(Ordering::SeqCst);
-        let observed_bytes = md.len();
-        assert_eq!(expected_bytes, observed_bytes);
+        if bucket_codec == io_utils::RadCodec::Raw {
+            let md = std::fs::metadata(parent.join(format!("bucket_{}.tmp", i)))?;
+            if expected_bytes != md.len() {
+                return Err(AlevinFryError::InvalidInput(format!(
+                    "expected {} bytes in temporary bucket {}, but it holds {}",
+                    expected_bytes,
+                    i,
+                    md.len()
+                )));
+            }
+        }
+        largest_bucket_bytes = largest_bucket_bytes.max(expected_bytes);
     }
 
     //std::process::exit(1);
 
+    // buckets that turned out to be too large for the memory budget
+    // (because the record size was underestimated, or because of the
+    // orientation filter) are partitioned again before being collated.
+    let max_bucket_bytes = mem_plan.max_bucket_bytes;
+    let num_split_buckets = Arc::new(AtomicUsize::new(0));
+    let tsv_map = Arc::new(tsv_map);
+    let bucket_ranges = Arc::new(bucket_ranges);
+    // when the output is sorted, the buckets must be written in order
+    let ordered_writer = (order == CollateOrder::Barcode)
+        .then(|| Arc::new(OrderedBucketWriter::new(mem_plan.max_pending_buckets)));
+    // set if a gather thread fails, so that the others stop
+    let gather_failed = Arc::new(AtomicBool::new(false));
+
     // to hold the temp buckets threads will process
     let slack = (n_workers / 2).max(1_usize);
     let temp_bucket_queue_size = slack + n_workers;
@@ -700,6 +1783,8 @@ where
     // the number of cells left to process
     let buckets_to_process = Arc::new(AtomicUsize::new(temp_buckets.len()));
 
+    let mut gather_handles: Vec<thread::JoinHandle<std::io::Result<u64>>> =
+        Vec::with_capacity(n_workers);
     let pbar_gather = ProgressBar::new(temp_buckets.len() as u64);
     pbar_gather.set_style(sty);
     pbar_gather.tick();
@@ -716,88 +1801,169 @@ where
 
         // the number of chunks remaining to be processed
         let buckets_remaining = buckets_to_process.clone();
-        // and knowledge of the UMI and BC types
-        let bc_type =
-            rad_types::decode_int_type_tag(cc.bc_type).context("unknown barcode type id.")?;
-        let umi_type =
-            rad_types::decode_int_type_tag(cc.umi_type).context("unknown barcode type id.")?;
         // have access to the input directory
         let input_dir: PathBuf = input_dir.clone();
         // the output file
         let owriter = owriter.clone();
         // and the progress bar
         let pbar_gather = pbar_gather.clone();
+        // the cells of each bucket, to partition those that are too large
+        let tsv_map = tsv_map.clone();
+        let bucket_ranges = bucket_ranges.clone();
+        let num_split_buckets = num_split_buckets.clone();
+        let ordered_writer = ordered_writer.clone();
+        let gather_failed = gather_failed.clone();
 
         // now, make the worker threads
-        let handle = std::thread::spawn(move || {
+        let handle = std::thread::spawn(move || -> std::io::Result<u64> {
             let mut local_chunks = 0u64;
             let parent = std::path::Path::new(&input_dir);
             // pop from the work queue until everything is
-            // processed
-            while buckets_remaining.load(Ordering::SeqCst) > 0 {
+            // processed (or another thread has failed)
+            while buckets_remaining.load(Ordering::SeqCst) > 0
+                && !gather_failed.load(Ordering::SeqCst)
+            {
                 if let Some(temp_bucket) = in_q.pop() {
                     buckets_remaining.fetch_sub(1, Ordering::SeqCst);
                     cmap.clear();
 
                     let fname = parent.join(format!("bucket_{}.tmp", temp_bucket.2.bucket_id));
-                    // create a new handle for reading
-                    let tfile = std::fs::File::open(&fname).expect("couldn't open temporary file.");
-                    let mut treader = BufReader::new(tfile);
-
-                    local_chunks += libradicl::collate_temporary_bucket_twopass(
-                        &mut treader,
-                        &bc_type,
-                        &umi_type,
-                        temp_bucket.1,
-                        &owriter,
-                        compress_out,
-                        &mut cmap,
-                    ) as u64;
-
-                    // we don't need the file or reader anymore
-                    drop(treader);
-                    std::fs::remove_file(fname).expect("could not delete temporary file.");
+                    let cells = &tsv_map[bucket_ranges[temp_bucket.2.bucket_id as usize].clone()];
+                    let bucket_bytes = temp_bucket.2.num_bytes_written.load(Ordering::SeqCst);
+                    let num_parts = match max_bucket_bytes {
+                        Some(m) if bucket_bytes > m && cells.len() > 1 => {
+                            (((bucket_bytes + m - 1) / m) as usize).min(cells.len())
+                        }
+                        _ => 1,
+                    };
+                    if num_parts > 1 {
+                        num_split_buckets.fetch_add(1, Ordering::SeqCst);
+                    }
+
+                    let gathered = match &ordered_writer {
+                        Some(ordered_writer) => ordered_writer
+                            .wait_turn(temp_bucket.2.bucket_id)
+                            .and_then(|()| {
+                                // collate the bucket into memory, so that its cells
+                                // can be sorted and written out in bucket order.
+                                let local_out = Arc::new(Mutex::new(Vec::<u8>::new()));
+                                let nchunks = gather_bucket(
+                                    &fname,
+                                    cells,
+                                    num_parts,
+                                    temp_bucket.1,
+                                    &bc_type,
+                                    &umi_type,
+                                    bucket_codec,
+                                    &local_out,
+                                    false,
+                                    &mut cmap,
+                                )?;
+                                let local_out = Arc::try_unwrap(local_out)
+                                    .map_err(|_| {
+                                        std::io::Error::new(
+                                            std::io::ErrorKind::Other,
+                                            "the collated bucket is still in use",
+                                        )
+                                    })?
+                                    .into_inner()
+                                    .map_err(|_| {
+                                        std::io::Error::new(
+                                            std::io::ErrorKind::Other,
+                                            "the collated bucket was poisoned",
+                                        )
+                                    })?;
+                                let sorted_out = sort_collated_chunks(&local_out, &bc_type)?;
+                                drop(local_out);
+                                ordered_writer.submit(
+                                    temp_bucket.2.bucket_id,
+                                    sorted_out,
+                                    &owriter,
+                                    snappy_out,
+                                )?;
+                                Ok(nchunks)
+                            }),
+                        None => gather_bucket(
+                            &fname,
+                            cells,
+                            num_parts,
+                            temp_bucket.1,
+                            &bc_type,
+                            &umi_type,
+                            bucket_codec,
+                            &owriter,
+                            snappy_out,
+                            &mut cmap,
+                        ),
+                    };
+                    match gathered {
+                        Ok(nchunks) => local_chunks += nchunks,
+                        Err(e) => {
+                            gather_failed.store(true, Ordering::SeqCst);
+                            if let Some(ordered_writer) = &ordered_writer {
+                                ordered_writer.fail();
+                            }
+                            return Err(e);
+                        }
+                    }
 
                     pbar_gather.inc(1);
                 }
             }
-            local_chunks
+            Ok(local_chunks)
         });
-        thread_handles.push(handle);
+        gather_handles.push(handle);
     } // for each worker
 
     // push the temporary buckets onto the work queue to be dispatched
     // by the worker threads.
-    for temp_bucket in temp_buckets {
+    'dispatch: for temp_bucket in temp_buckets {
         let mut bclone = temp_bucket.clone();
         // keep trying until we can push this payload
         while let Err(t) = fq.push(bclone) {
             bclone = t;
-            // no point trying to push if the queue is full
-            while fq.is_full() {}
+            // no point trying to push if the queue is full, or
+            // if the worker threads have stopped
+            while fq.is_full() {
+                if gather_failed.load(Ordering::SeqCst) {
+                    break 'dispatch;
+                }
+            }
         }
-        let expected = temp_bucket.1;
-        let observed = temp_bucket.2.num_records_written.load(Ordering::SeqCst);
-        assert_eq!(expected, observed);
+        // (the number of records in each bucket was checked above)
     }
 
     // wait for all of the workers to finish
     let mut num_output_chunks = 0u64;
-    for h in thread_handles.drain(0..) {
+    let mut gather_error = None;
+    for h in gather_handles.drain(0..) {
         match h.join() {
-            Ok(c) => {
+            Ok(Ok(c)) => {
                 num_output_chunks += c;
             }
+            Ok(Err(e)) => {
+                gather_error.get_or_insert(e);
+            }
             Err(_e) => {
                 info!(log, "thread panicked");
             }
         }
     }
+    if let Some(e) = gather_error {
+        return Err(anyhow::Error::new(e)
+            .context("could not collate temporary file.")
+            .into());
+    }
     pbar_gather.finish_with_message("gathered all temp files.");
 
     // make sure we wrote the same number of records that our
     // file suggested we should.
-    assert_eq!(total_allocated_records, total_to_collate);
+    if total_allocated_records != total_to_collate {
+        return Err(AlevinFryError::InvalidInput(format!(
+            "expected to collate {} records, but {} were allocated",
+            total_to_collate, total_allocated_records
+        )));
+    }
 
     info!(
         log,
@@ -811,19 +1977,303 @@ where
         expected_output_chunks.to_formatted_string(&Locale::en)
     );
 
-    assert_eq!(
-        expected_output_chunks,
-        num_output_chunks,
-        "expected to write {} chunks but wrote {}",
-        expected_output_chunks.to_formatted_string(&Locale::en),
-        num_output_chunks.to_formatted_string(&Locale::en),
-    );
+    if expected_output_chunks != num_output_chunks {
+        return Err(AlevinFryError::InvalidInput(format!(
+            "expected to write {} chunks but wrote {}",
+            expected_output_chunks.to_formatted_string(&Locale::en),
+            num_output_chunks.to_formatted_string(&Locale::en),
+        )));
+    }
+
+    // all of the gather threads have been joined, so this is the
+    // only remaining handle on the output.
+    Arc::try_unwrap(owriter)
+        .map_err(|_| anyhow!("the collated output writer is still in use"))?
+        .into_inner()
+        .map_err(|_| anyhow!("a thread panicked while writing the collated output"))?
+        .finish()
+        .context("could not finish writing the collated output")?;
+
+    let num_split_buckets = num_split_buckets.load(Ordering::SeqCst);
+    if num_split_buckets > 0 {
+        info!(
+            log,
+            "partitioned {} temporary buckets that exceeded the memory budget.", num_split_buckets
+        );
+    }
+    let peak_memory_bytes = afutils::peak_rss_bytes();
+
+    // writing the collate metadata
+    {
+        let collate_meta = json!({
+            "cmd" : cmdline,
+            "version_str" : version,
+            "compressed_output" : compression.is_compressed(),
+            "codec" : compression.codec,
+            "zstd_level" : compression.zstd_level,
+            "cell_order" : order,
+            "has_umis" : types.has_umi(),
+            "memory" : {
+                "plan" : &mem_plan,
+                "largest_bucket_bytes" : largest_bucket_bytes,
+                "num_split_buckets" : num_split_buckets,
+                "peak_bytes" : peak_memory_bytes,
+            },
+        });
+
+        let cm_path = parent.join("collate.json");
+        let mut cm_file =
+            std::fs::File::create(cm_path).context("could not create metadata file.")?;
+
+        let cm_info_string =
+            serde_json::to_string_pretty(&collate_meta).context("could not format json.")?;
+        cm_file
+            .write_all(cm_info_string.as_bytes())
+            .context("cannot write to collate.json file")?;
+    }
 
-    owriter.lock().unwrap().flush()?;
     info!(
         log,
-        "finished collating input rad file {:?}.",
-        i_dir.join("map.rad")
+        "finished collating input rad file {:?}.", input_rad_path
     );
-    Ok(())
+    Ok(CollateStats {
+        output_file: parent.join(cfname),
+        num_cells: expected_output_chunks,
+        num_records: total_to_collate,
+        compressed: compression.is_compressed(),
+        codec: compression.codec,
+        order,
+        memory_plan: mem_plan,
+        peak_memory_bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a collated chunk for the cell `bc` (u32 barcodes and u8 UMIs),
+    /// holding one record per entry of `umis`, each with one alignment.
+    fn collated_chunk(bc: u32, umis: &[u8]) -> Vec<u8> {
+        let mut recs = Vec::<u8>::new();
+        for (i, umi) in umis.iter().enumerate() {
+            recs.extend_from_slice(&1u32.to_le_bytes());
+            recs.extend_from_slice(&bc.to_le_bytes());
+            recs.push(*umi);
+            recs.extend_from_slice(&(i as u32).to_le_bytes());
+        }
+        let mut chunk = ((recs.len() + 8) as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(&(umis.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(&recs);
+        chunk
+    }
+
+    #[test]
+    fn test_sort_collated_chunks() {
+        let c30 = collated_chunk(30, &[1, 2]);
+        let c10 = collated_chunk(10, &[3]);
+        let c20 = collated_chunk(20, &[4, 5, 6]);
+        let buf = [c30.clone(), c10.clone(), c20.clone()].concat();
+
+        let sorted = sort_collated_chunks(&buf, &rad_types::RadIntId::U32).unwrap();
+        assert_eq!(sorted, [c10, c20, c30].concat());
+        assert!(sort_collated_chunks(&[], &rad_types::RadIntId::U32)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_sort_collated_chunks_malformed() {
+        let mut buf = collated_chunk(30, &[1, 2]);
+        buf.extend_from_slice(&collated_chunk(10, &[3]));
+        // a truncated final chunk
+        buf.truncate(buf.len() - 3);
+        assert!(sort_collated_chunks(&buf, &rad_types::RadIntId::U32).is_err());
+        // a chunk header that is cut short
+        assert!(sort_collated_chunks(&[1, 0], &rad_types::RadIntId::U32).is_err());
+    }
+
+    #[test]
+    fn test_ordered_bucket_writer() {
+        let out = Mutex::new(Vec::<u8>::new());
+        let w = OrderedBucketWriter::new(2);
+        w.wait_turn(1).unwrap();
+        w.submit(1, vec![1, 1], &out, false).unwrap();
+        assert!(out.lock().unwrap().is_empty());
+        w.submit(0, vec![0], &out, false).unwrap();
+        w.submit(2, vec![], &out, false).unwrap();
+        w.wait_turn(4).unwrap();
+        assert_eq!(*out.lock().unwrap(), vec![0, 1, 1]);
+
+        // after a failure, no thread waits for its turn
+        w.fail();
+        assert!(w.wait_turn(10).is_err());
+    }
+
+    /// the bytes of a RAD file (with 4bp barcodes, u32 barcodes and UMIs)
+    /// whose header records `num_chunks` chunks, followed by a chunk for
+    /// each entry of `chunks` holding its (barcode, UMI) records, each
+    /// with one forward alignment.
+    fn rad_file(num_chunks: u64, chunks: &[Vec<(u32, u32)>]) -> Vec<u8> {
+        let tag_section = |tags: &[(&str, u8)]| {
+            let mut b = (tags.len() as u16).to_le_bytes().to_vec();
+            for (name, typeid) in tags {
+                b.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                b.extend_from_slice(name.as_bytes());
+                b.push(*typeid);
+            }
+            b
+        };
+        // is_paired, then the reference names
+        let mut b = vec![0u8];
+        b.extend_from_slice(&2u64.to_le_bytes());
+        for name in ["t0", "t1"] {
+            b.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            b.extend_from_slice(name.as_bytes());
+        }
+        b.extend_from_slice(&num_chunks.to_le_bytes());
+        b.extend_from_slice(&tag_section(&[("cblen", 2), ("ulen", 2)]));
+        b.extend_from_slice(&tag_section(&[("b", 3), ("u", 3)]));
+        b.extend_from_slice(&tag_section(&[("compressed_ori_refid", 3)]));
+        // the barcode and UMI lengths
+        b.extend_from_slice(&4u16.to_le_bytes());
+        b.extend_from_slice(&4u16.to_le_bytes());
+        for recs in chunks {
+            b.extend_from_slice(&((8 + 16 * recs.len()) as u32).to_le_bytes());
+            b.extend_from_slice(&(recs.len() as u32).to_le_bytes());
+            for (bc, umi) in recs {
+                b.extend_from_slice(&1u32.to_le_bytes());
+                b.extend_from_slice(&bc.to_le_bytes());
+                b.extend_from_slice(&umi.to_le_bytes());
+                b.extend_from_slice(&0x8000_0001u32.to_le_bytes());
+            }
+        }
+        b
+    }
+
+    #[test]
+    fn test_streamed_input_without_chunk_count() {
+        use crate::cellfilter::{generate_permit_list, CellFilterMethod, PermitListStats};
+        use crate::prog_opts::GenPermitListOpts;
+
+        let dir = std::env::temp_dir().join(format!("alevin_fry_streamed_{}", std::process::id()));
+        let rad_dir = dir.join("map");
+        let out_dir = dir.join("quant");
+        std::fs::create_dir_all(&rad_dir).unwrap();
+        // the header of a RAD stream written to a named pipe records no
+        // chunks; the barcodes are AACC (5), TTTT (255) and TAGA (200).
+        let chunks = vec![
+            vec![(5, 1), (5, 2), (255, 1)],
+            vec![(5, 3), (200, 1), (255, 2)],
+            vec![(5, 4)],
+        ];
+        std::fs::write(rad_dir.join("map.rad"), rad_file(0, &chunks)).unwrap();
+        std::fs::write(rad_dir.join("unmapped_bc_count.bin"), []).unwrap();
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let run = || -> anyhow::Result<(PermitListStats, serde_json::Value, CollateStats)> {
+            // spilling the input reads it through a `SpillingReader`, as
+            // is done for a named pipe.
+            let gpl_opts = GenPermitListOpts::builder()
+                .input_dir(&rad_dir)
+                .output_dir(&out_dir)
+                .fmeth(CellFilterMethod::ForceCells(2))
+                .expected_ori(Strand::Unknown)
+                .velo_mode(false)
+                .spill(true)
+                .cmdline("")
+                .version(afconst::VERSION)
+                .log(&log)
+                .build();
+            let gpl_stats = generate_permit_list(gpl_opts)?;
+            let mdata: serde_json::Value =
+                serde_json::from_reader(File::open(out_dir.join("generate_permit_list.json"))?)?;
+            let collate_stats = collate(
+                &out_dir,
+                &rad_dir,
+                2,
+                1000,
+                None,
+                CollateCompression::none(),
+                CollateOrder::Frequency,
+                "",
+                afconst::VERSION,
+                &log,
+            )?;
+            Ok((gpl_stats, mdata, collate_stats))
+        };
+        let res = run();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let (gpl_stats, mdata, collate_stats) = res.unwrap();
+
+        assert_eq!(gpl_stats.num_reads, 7);
+        assert_eq!(gpl_stats.num_permitted, 2);
+        assert_eq!(mdata["num_chunks"].as_u64(), Some(3));
+        assert!(mdata["spilled_rad_file"].is_string());
+        // the reads of the two permitted barcodes are collated from the
+        // spill file, whose header also records no chunks
+        assert_eq!(collate_stats.num_cells, 2);
+        assert_eq!(collate_stats.num_records, 6);
+    }
+
+    #[test]
+    fn test_memory_plan_tiny_budget() {
+        // a budget smaller than the fixed memory falls back to the
+        // minimum working memory
+        let plan = CollateMemoryPlan::new(Some(1), 2, u32::MAX, 40, 0, 0, 0, false);
+        let bucket_bytes = MIN_WORKING_BYTES / (2 * GATHER_BYTES_PER_BUCKET_BYTE);
+        assert_eq!(plan.max_bucket_bytes, Some(bucket_bytes));
+        assert_eq!(plan.max_records_per_bucket, bucket_bytes / 40);
+        assert_eq!(plan.max_pending_buckets, 0);
+
+        // with sorted output, room is left for the buckets waiting to be
+        // written in order
+        let sorted = CollateMemoryPlan::new(Some(1), 2, u32::MAX, 40, 0, 0, 0, true);
+        let sorted_bucket_bytes = MIN_WORKING_BYTES / (2 * (GATHER_BYTES_PER_BUCKET_BYTE + 1));
+        assert_eq!(sorted.max_bucket_bytes, Some(sorted_bucket_bytes));
+        assert_eq!(sorted.max_pending_buckets, 2 * PENDING_BUCKETS_PER_WORKER);
+
+        // the number of records in a bucket is at least 1, and at most
+        // the max_records limit
+        let huge_records = CollateMemoryPlan::new(Some(1), 1, u32::MAX, u64::MAX, 0, 0, 0, false);
+        assert_eq!(huge_records.max_records_per_bucket, 1);
+        let few_records = CollateMemoryPlan::new(Some(1), 2, 100, 40, 0, 0, 0, false);
+        assert_eq!(few_records.max_records_per_bucket, 51);
+    }
+
+    #[test]
+    fn test_memory_plan_size_buffers() {
+        let mut plan = CollateMemoryPlan::new(Some(1), 2, u32::MAX, 40, 0, 0, 0, true);
+        let bucket_bytes = plan.max_bucket_bytes.unwrap();
+        plan.size_buffers(1000, 2, u32::MAX, 100);
+        let per_buffer = MIN_WORKING_BYTES as usize / 2000;
+        assert_eq!(plan.num_buckets, 1000);
+        assert_eq!(plan.local_buffer_bytes, per_buffer);
+        assert_eq!(plan.predicted_scatter_bytes, 2000 * per_buffer as u64);
+        assert_eq!(
+            plan.predicted_ordered_bytes,
+            (2 * PENDING_BUCKETS_PER_WORKER as u64 - 2) * bucket_bytes
+        );
+        assert_eq!(
+            plan.predicted_gather_bytes,
+            2 * GATHER_BYTES_PER_BUCKET_BYTE * bucket_bytes + plan.predicted_ordered_bytes
+        );
+        assert_eq!(
+            plan.predicted_peak_bytes,
+            plan.predicted_scatter_bytes
+                .max(plan.predicted_gather_bytes)
+        );
+
+        // each buffer holds at least the most ambiguous record
+        plan.size_buffers(1000, 2, u32::MAX, 100_000);
+        assert_eq!(plan.local_buffer_bytes, 100_000);
+
+        // without a budget, the buffers are sized from max_records and
+        // clamped to the allowed range
+        let mut unbounded = CollateMemoryPlan::new(None, 2, 100, 40, 0, 0, 0, false);
+        assert_eq!(unbounded.max_bucket_bytes, None);
+        unbounded.size_buffers(10, 2, 100, 0);
+        assert_eq!(unbounded.local_buffer_bytes, MIN_LOC_BUFFER_BYTES);
+        assert_eq!(unbounded.predicted_ordered_bytes, 0);
+    }
 }
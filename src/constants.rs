@@ -8,3 +8,5 @@
  */
 
 pub(crate) const PERMIT_FILE_VER: u64 = 1;
+pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub(crate) const SPILLED_RAD_FILE: &str = "map.rad.sz";
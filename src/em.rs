@@ -12,9 +12,11 @@ use crate::eq_class::IndexedEqList;
 #[allow(unused_imports)]
 use ahash::{AHasher, RandomState};
 use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 #[allow(unused_imports)]
 use slog::info;
 use statrs::distribution::Multinomial;
+use statrs::function::gamma::digamma;
 use std::collections::HashMap;
 use std::f32;
 
@@ -31,6 +33,13 @@ const ALPHA_CHECK_CUTOFF: f32 = 1e-2;
 const MIN_ITER: u32 = 2;
 const MAX_ITER: u32 = 100;
 const REL_DIFF_TOLERANCE: f32 = 1e-2;
+/// The smallest change in an abundance, relative to the total count of
+/// the equivalence classes, that the EM checks for convergence; smaller
+/// changes can't be resolved in the `f32` abundances of large totals.
+const MIN_TOTAL_REL_TOLERANCE: f64 = 1e-6;
+
+// the (per-gene) Dirichlet prior used by the Gibbs sampler
+const GIBBS_PRIOR: f64 = 1e-3;
 
 #[derive(Copy, Clone)]
 pub enum EmInitType {
@@ -39,6 +48,124 @@ pub enum EmInitType {
     Random,
 }
 
+/// The algorithm used to resolve gene-level multimapping.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmAlgorithm {
+    /// the maximum likelihood EM algorithm
+    #[default]
+    Em,
+    /// the variational Bayesian EM algorithm, under a per-gene
+    /// Dirichlet prior (as in salmon's `--useVBOpt`); small priors
+    /// favor sparse solutions, rather than spreading the molecules of
+    /// an equivalence class across all of its genes.
+    Vbem,
+}
+
+impl EmAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmAlgorithm::Em => "em",
+            EmAlgorithm::Vbem => "vbem",
+        }
+    }
+}
+
+impl std::fmt::Display for EmAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The settings of the (per-cell) EM algorithm.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct EmOpts {
+    /// the algorithm to run
+    pub algorithm: EmAlgorithm,
+    /// the per-gene Dirichlet prior of the VBEM algorithm
+    pub vb_prior: f32,
+    /// the maximum number of iterations
+    pub max_iter: u32,
+    /// the EM is considered converged once no abundance above
+    /// `ALPHA_CHECK_CUTOFF` changes by more than this amount
+    pub rel_diff_tolerance: f32,
+    /// the number of pseudo-molecules that a cross-cell prior (if one is
+    /// given) contributes to each gene-ambiguous equivalence class
+    pub prior_weight: f32,
+}
+
+impl EmOpts {
+    pub const DEFAULT_VB_PRIOR: f32 = 0.01;
+    pub const DEFAULT_PRIOR_WEIGHT: f32 = 1.0;
+
+    /// These options, with the convergence tolerance raised, if needed,
+    /// so that it scales with `total_count`, the total count of the
+    /// equivalence classes to be resolved.  This only changes the
+    /// tolerance for large totals, such as the pooled counts of many cells.
+    pub fn for_total_count(&self, total_count: u64) -> EmOpts {
+        let min_tolerance = (total_count as f64 * MIN_TOTAL_REL_TOLERANCE) as f32;
+        EmOpts {
+            rel_diff_tolerance: self.rel_diff_tolerance.max(min_tolerance),
+            ..*self
+        }
+    }
+}
+
+impl Default for EmOpts {
+    fn default() -> Self {
+        EmOpts {
+            algorithm: EmAlgorithm::Em,
+            vb_prior: Self::DEFAULT_VB_PRIOR,
+            max_iter: MAX_ITER,
+            rel_diff_tolerance: REL_DIFF_TOLERANCE,
+            prior_weight: Self::DEFAULT_PRIOR_WEIGHT,
+        }
+    }
+}
+
+/// Information about the run of the EM algorithm for a cell.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct EmStats {
+    /// the number of iterations performed; 0 if the cell did not
+    /// require the EM (e.g. because it had no gene-ambiguous molecules)
+    pub num_iterations: u32,
+    /// whether the EM reached the convergence tolerance (rather than
+    /// stopping after the maximum number of iterations)
+    pub converged: bool,
+    /// the largest change of an abundance (above `ALPHA_CHECK_CUTOFF`)
+    /// in the final iteration
+    pub final_rel_diff: f32,
+    /// the log-likelihood of the cell's equivalence class counts under
+    /// the final abundances
+    pub log_likelihood: f64,
+    /// the fraction of the cell's UMIs in gene-ambiguous equivalence classes
+    pub multi_gene_frac: f32,
+}
+
+impl Default for EmStats {
+    fn default() -> Self {
+        EmStats {
+            num_iterations: 0,
+            converged: true,
+            final_rel_diff: 0.0,
+            log_likelihood: 0.0,
+            multi_gene_frac: 0.0,
+        }
+    }
+}
+
+/// The parameters of the Gibbs sampler used to draw posterior
+/// samples of the gene abundances of a cell.
+#[derive(Copy, Clone, Debug)]
+pub struct GibbsOpts {
+    /// the number of posterior samples to record
+    pub num_samples: u32,
+    /// record a sample only every `thinning` rounds of the sampler
+    pub thinning: u32,
+    /// the number of initial rounds of the sampler to discard
+    pub burn_in: u32,
+}
+
 #[allow(dead_code)]
 fn mean(data: &[f64]) -> Option<f64> {
     let sum = data.iter().sum::<f64>();
@@ -173,24 +300,198 @@ pub(crate) fn em_update_subset_usa(
     }
 }
 
+/// Perform one round of the VBEM update over the equivalence classes
+/// `classes`.  This is the EM update, except that the molecules of each
+/// equivalence class are apportioned among its labels in proportion to
+/// exp(E[log theta]) under the Dirichlet posterior, rather than in
+/// proportion to the current abundances.
+fn vbem_update<'a, I>(
+    alphas_in: &[f32],
+    alphas_out: &mut [f32],
+    classes: I,
+    vb_prior: f32,
+    usa_offsets: Option<(usize, usize)>,
+) where
+    I: Iterator<Item = (&'a [u32], u32)>,
+{
+    // the normalizing digamma term is shared by all labels, so it
+    // cancels within each equivalence class.
+    let weight = |label: u32| -> f32 {
+        let a = match usa_offsets {
+            Some(otup) => get_abundance_for(label, alphas_in, otup),
+            None => alphas_in[label as usize],
+        };
+        (digamma((a + vb_prior) as f64).exp() as f32).max(MIN_ALPHA)
+    };
+
+    for (labels, count) in classes {
+        if labels.len() > 1 {
+            let denominator: f32 = labels.iter().map(|l| weight(*l)).sum();
+            let inv_denominator = count as f32 / denominator;
+            for label in labels {
+                alphas_out[*label as usize] += weight(*label) * inv_denominator;
+            }
+        } else {
+            let tidx = labels.first().expect("can't extract labels");
+            alphas_out[*tidx as usize] += count as f32;
+        }
+    }
+}
+
+/// The log-likelihood of the equivalence class counts `classes` given the
+/// abundances `alphas`, where the probability of a UMI arising from a class
+/// is the fraction of the total abundance held by the labels (in USA-mode,
+/// the labels and the splicing statuses compatible with them) of that class.
+/// If `ref_lengths` is given, the abundance of each label is first divided
+/// by its length.  Classes none of whose labels hold any abundance are
+/// skipped.
+fn log_likelihood<'a, I>(
+    alphas: &[f32],
+    classes: I,
+    ref_lengths: Option<&[f32]>,
+    usa_offsets: Option<(usize, usize)>,
+) -> f64
+where
+    I: Iterator<Item = (&'a [u32], u32)>,
+{
+    let weight = |row: usize| -> f64 {
+        match ref_lengths {
+            Some(lens) => alphas[row] as f64 / lens[row] as f64,
+            None => alphas[row] as f64,
+        }
+    };
+    let total: f64 = (0..alphas.len()).map(weight).sum();
+    if total <= 0.0 {
+        return 0.0;
+    }
+
+    let mut rows = Vec::<usize>::new();
+    let mut ll = 0.0_f64;
+    for (labels, count) in classes {
+        rows.clear();
+        for l in labels {
+            let index = *l as usize;
+            rows.push(index);
+            if let Some((unspliced_offset, ambig_offset)) = usa_offsets {
+                // as in `get_abundance_for`, the ambiguous row is
+                // compatible with both splicing statuses.
+                if index >= ambig_offset {
+                    rows.push(index - unspliced_offset);
+                    rows.push(index - ambig_offset);
+                } else if index >= unspliced_offset {
+                    rows.push(index + unspliced_offset);
+                } else {
+                    rows.push(index + ambig_offset);
+                }
+            }
+        }
+        rows.sort_unstable();
+        rows.dedup();
+
+        let mass: f64 = rows.iter().map(|r| weight(*r)).sum();
+        if mass > 0.0 {
+            ll += count as f64 * (mass / total).ln();
+        }
+    }
+    ll
+}
+
+/// Perform one round of the EM (or, if `vb_prior` is given, VBEM) update
+/// over the equivalence classes `classes`, informed by the abundances
+/// `prior` observed across cells and / or the lengths `ref_lengths` of the
+/// labels.  With a `prior`, in addition to the molecules currently assigned
+/// to each label, the molecules of each equivalence class are apportioned
+/// according to `prior_weight` pseudo-molecules, which are distributed among
+/// its labels in proportion to their `prior` abundance.  The pseudo-molecules
+/// only steer the assignment; they are not added to the output abundances.
+/// With `ref_lengths`, the weight of each label is divided by its length, as
+/// the chance of observing a read from a molecule grows with the length of
+/// the molecule when reads are drawn from its full length.  These are the
+/// reference lengths of the labels, not effective lengths corrected for
+/// the fragment length distribution, which the RAD file does not record.
+#[allow(clippy::too_many_arguments)]
+fn informed_update<'a, I>(
+    alphas_in: &[f32],
+    alphas_out: &mut [f32],
+    classes: I,
+    prior: Option<&[f32]>,
+    prior_weight: f32,
+    ref_lengths: Option<&[f32]>,
+    vb_prior: Option<f32>,
+    usa_offsets: Option<(usize, usize)>,
+) where
+    I: Iterator<Item = (&'a [u32], u32)>,
+{
+    let abundance = |label: u32, v: &[f32]| -> f32 {
+        match usa_offsets {
+            Some(otup) => get_abundance_for(label, v, otup),
+            None => v[label as usize],
+        }
+    };
+
+    let mut weights = Vec::<f32>::new();
+    for (labels, count) in classes {
+        if labels.len() > 1 {
+            let prior_sum: f32 = match prior {
+                Some(p) => labels.iter().map(|l| abundance(*l, p)).sum(),
+                None => 0.0,
+            };
+            weights.clear();
+            weights.extend(labels.iter().map(|l| {
+                let pseudo = match prior {
+                    Some(p) if prior_sum > 0.0 => prior_weight * abundance(*l, p) / prior_sum,
+                    _ => 0.0,
+                };
+                let a = abundance(*l, alphas_in) + pseudo;
+                let w = match vb_prior {
+                    Some(vp) => (digamma((a + vp) as f64).exp() as f32).max(MIN_ALPHA),
+                    None => a,
+                };
+                match ref_lengths {
+                    Some(lens) => w / lens[*l as usize],
+                    None => w,
+                }
+            }));
+
+            let denominator: f32 = weights.iter().sum();
+            if denominator > 0.0 {
+                let inv_denominator = count as f32 / denominator;
+                for (label, w) in labels.iter().zip(weights.iter()) {
+                    alphas_out[*label as usize] += w * inv_denominator;
+                }
+            }
+        } else {
+            let tidx = labels.first().expect("can't extract labels");
+            alphas_out[*tidx as usize] += count as f32;
+        }
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
-pub fn em_optimize_subset(
+pub fn em_optimize_subset<R: Rng + ?Sized>(
     eqclasses: &IndexedEqList,
     cell_data: &[(u32, u32)], // indices into eqclasses relevant for this cell
     unique_evidence: &mut [bool],
     no_ambiguity: &mut [bool],
     init_type: EmInitType,
+    rng: &mut R, // only used for the random initialization
+    em_opts: &EmOpts,
+    prior: Option<&[f32]>,       // abundances observed across cells, if any
+    ref_lengths: Option<&[f32]>, // the length of each label, for length-aware EM
     num_alphas: usize,
     only_unique: bool,
     usa_offsets: Option<(usize, usize)>,
     _log: &slog::Logger,
-) -> Vec<f32> {
+) -> (Vec<f32>, EmStats) {
     let mut alphas_in: Vec<f32> = vec![0.0; num_alphas];
     let mut alphas_out: Vec<f32> = vec![0.0; num_alphas];
 
     let mut needs_em = false;
+    let mut num_umis = 0u64;
+    let mut num_multi_gene_umis = 0u64;
     for (i, count) in cell_data {
         let labels = eqclasses.refs_for_eqc(*i);
+        num_umis += *count as u64;
         if labels.len() == 1 {
             let idx = labels.first().expect("can't extract labels");
             alphas_in[*idx as usize] += *count as f32;
@@ -199,19 +500,37 @@ pub fn em_optimize_subset(
             for idx in labels {
                 no_ambiguity[*idx as usize] = false;
             }
+            num_multi_gene_umis += *count as u64;
             needs_em = true;
         }
     }
+    let multi_gene_frac = if num_umis > 0 {
+        (num_multi_gene_umis as f64 / num_umis as f64) as f32
+    } else {
+        0.0
+    };
+    let cell_classes = || {
+        cell_data
+            .iter()
+            .map(|(i, count)| (eqclasses.refs_for_eqc(*i), *count))
+    };
 
     // if we are just pulling out unique counts
     // or there were no multi-mapping reads, then
     // we're done
     if only_unique || !needs_em {
-        return alphas_in;
+        let log_likelihood = log_likelihood(&alphas_in, cell_classes(), ref_lengths, usa_offsets);
+        return (
+            alphas_in,
+            EmStats {
+                log_likelihood,
+                multi_gene_frac,
+                ..EmStats::default()
+            },
+        );
     }
 
     // fill in the alphas based on the initialization strategy
-    let mut rng = rand::thread_rng();
     let uni_prior = 1.0 / (num_alphas as f32);
     for item in alphas_in.iter_mut().take(num_alphas) {
         match init_type {
@@ -232,14 +551,48 @@ pub fn em_optimize_subset(
     // allow one last round of the EM after thresholding
     // very small counts to 0.
     let mut last_round: bool = false;
+    // set once the convergence tolerance has been reached
+    let mut reached_tolerance = false;
+    // the largest change in the most recent iteration
+    let mut final_rel_diff = 0.0_f32;
 
-    while it_num < MIN_ITER || (it_num < MAX_ITER && !converged) || last_round {
+    while it_num < MIN_ITER || (it_num < em_opts.max_iter && !converged) || last_round {
         // perform one round of em update
-        match usa_offsets {
-            Some(otup) => {
+        let informed = prior.is_some() || ref_lengths.is_some();
+        match (informed, em_opts.algorithm, usa_offsets) {
+            (true, alg, _) => {
+                informed_update(
+                    &alphas_in,
+                    &mut alphas_out,
+                    cell_data
+                        .iter()
+                        .map(|(i, count)| (eqclasses.refs_for_eqc(*i), *count)),
+                    prior,
+                    em_opts.prior_weight,
+                    ref_lengths,
+                    if alg == EmAlgorithm::Vbem {
+                        Some(em_opts.vb_prior)
+                    } else {
+                        None
+                    },
+                    usa_offsets,
+                );
+            }
+            (false, EmAlgorithm::Vbem, _) => {
+                vbem_update(
+                    &alphas_in,
+                    &mut alphas_out,
+                    cell_data
+                        .iter()
+                        .map(|(i, count)| (eqclasses.refs_for_eqc(*i), *count)),
+                    em_opts.vb_prior,
+                    usa_offsets,
+                );
+            }
+            (false, EmAlgorithm::Em, Some(otup)) => {
                 em_update_subset_usa(&alphas_in, &mut alphas_out, eqclasses, cell_data, otup);
             }
-            None => {
+            (false, EmAlgorithm::Em, None) => {
                 em_update_subset(&alphas_in, &mut alphas_out, eqclasses, cell_data);
             }
         }
@@ -254,7 +607,7 @@ pub fn em_optimize_subset(
 
                 max_rel_diff = max_rel_diff.max(rel_diff as f64);
 
-                if rel_diff > REL_DIFF_TOLERANCE {
+                if rel_diff > em_opts.rel_diff_tolerance {
                     converged = false;
                 }
             } // end- in>out if
@@ -263,6 +616,7 @@ pub fn em_optimize_subset(
             alphas_out[index] = 0.0_f32;
         } //end-for
 
+        final_rel_diff = max_rel_diff.max(0.0) as f32;
         it_num += 1;
 
         // if this was the last round
@@ -282,6 +636,7 @@ pub fn em_optimize_subset(
                 }
             });
             last_round = true;
+            reached_tolerance = true;
         }
     }
 
@@ -294,7 +649,17 @@ pub fn em_optimize_subset(
 
     //let alphas_sum: f32 = alphas_in.iter().sum();
     //assert!(alphas_sum > 0.0, "Alpha Sum too small");
-    alphas_in
+    let log_likelihood = log_likelihood(&alphas_in, cell_classes(), ref_lengths, usa_offsets);
+    (
+        alphas_in,
+        EmStats {
+            num_iterations: it_num,
+            converged: reached_tolerance,
+            final_rel_diff,
+            log_likelihood,
+            multi_gene_frac,
+        },
+    )
 }
 
 pub fn em_update(
@@ -325,19 +690,27 @@ pub fn em_update(
     }
 }
 
-pub fn em_optimize(
+#[allow(clippy::too_many_arguments)]
+pub fn em_optimize<R: Rng + ?Sized>(
     eqclasses: &HashMap<Vec<u32>, u32, ahash::RandomState>,
     unique_evidence: &mut [bool],
     no_ambiguity: &mut [bool],
     init_type: EmInitType,
+    rng: &mut R, // only used for the random initialization
+    em_opts: &EmOpts,
+    prior: Option<&[f32]>,       // abundances observed across cells, if any
+    ref_lengths: Option<&[f32]>, // the length of each label, for length-aware EM
     num_alphas: usize,
     only_unique: bool,
     _log: &slog::Logger,
-) -> Vec<f32> {
+) -> (Vec<f32>, EmStats) {
     let mut alphas_in: Vec<f32> = vec![0.0; num_alphas];
     let mut alphas_out: Vec<f32> = vec![0.0; num_alphas];
 
+    let mut num_umis = 0u64;
+    let mut num_multi_gene_umis = 0u64;
     for (labels, count) in eqclasses {
+        num_umis += *count as u64;
         if labels.len() == 1 {
             let idx = labels.first().expect("can't extract labels");
             alphas_in[*idx as usize] += *count as f32;
@@ -346,15 +719,33 @@ pub fn em_optimize(
             for idx in labels {
                 no_ambiguity[*idx as usize] = false;
             }
+            num_multi_gene_umis += *count as u64;
         }
     }
+    let multi_gene_frac = if num_umis > 0 {
+        (num_multi_gene_umis as f64 / num_umis as f64) as f32
+    } else {
+        0.0
+    };
+    let cell_classes = || {
+        eqclasses
+            .iter()
+            .map(|(labels, count)| (&labels[..], *count))
+    };
 
     if only_unique {
-        return alphas_in;
+        let log_likelihood = log_likelihood(&alphas_in, cell_classes(), ref_lengths, None);
+        return (
+            alphas_in,
+            EmStats {
+                log_likelihood,
+                multi_gene_frac,
+                ..EmStats::default()
+            },
+        );
     }
 
     // fill in the alphas based on the initialization strategy
-    let mut rng = rand::thread_rng();
     let uni_prior = 1.0 / (num_alphas as f32);
     for item in alphas_in.iter_mut().take(num_alphas) {
         match init_type {
@@ -375,9 +766,39 @@ pub fn em_optimize(
 
     let mut it_num: u32 = 0;
     let mut converged: bool = true;
-    while it_num < MIN_ITER || (it_num < MAX_ITER && !converged) {
+    // the largest change in the most recent iteration
+    let mut final_rel_diff = 0.0_f32;
+    while it_num < MIN_ITER || (it_num < em_opts.max_iter && !converged) {
         // perform one round of em update
-        em_update(&alphas_in, &mut alphas_out, eqclasses);
+        let informed = prior.is_some() || ref_lengths.is_some();
+        match (informed, em_opts.algorithm) {
+            (true, alg) => informed_update(
+                &alphas_in,
+                &mut alphas_out,
+                eqclasses
+                    .iter()
+                    .map(|(labels, count)| (&labels[..], *count)),
+                prior,
+                em_opts.prior_weight,
+                ref_lengths,
+                if alg == EmAlgorithm::Vbem {
+                    Some(em_opts.vb_prior)
+                } else {
+                    None
+                },
+                None,
+            ),
+            (false, EmAlgorithm::Em) => em_update(&alphas_in, &mut alphas_out, eqclasses),
+            (false, EmAlgorithm::Vbem) => vbem_update(
+                &alphas_in,
+                &mut alphas_out,
+                eqclasses
+                    .iter()
+                    .map(|(labels, count)| (&labels[..], *count)),
+                em_opts.vb_prior,
+                None,
+            ),
+        }
 
         converged = true;
         let mut max_rel_diff = -f64::INFINITY;
@@ -392,7 +813,7 @@ pub fn em_optimize(
                     false => max_rel_diff,
                 };
 
-                if rel_diff > REL_DIFF_TOLERANCE {
+                if rel_diff > em_opts.rel_diff_tolerance {
                     converged = false;
                 }
             } // end- in>out if
@@ -401,6 +822,7 @@ pub fn em_optimize(
             alphas_out[index] = 0.0_f32;
         } //end-for
 
+        final_rel_diff = max_rel_diff.max(0.0) as f32;
         it_num += 1;
     }
 
@@ -418,16 +840,31 @@ pub fn em_optimize(
     alphas_sum
     );
     */
-    alphas_in
+    let log_likelihood = log_likelihood(&alphas_in, cell_classes(), ref_lengths, None);
+    (
+        alphas_in,
+        EmStats {
+            num_iterations: it_num,
+            converged,
+            final_rel_diff,
+            log_likelihood,
+            multi_gene_frac,
+        },
+    )
 }
 
-pub(crate) fn run_bootstrap_subset(
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run_bootstrap_subset<R: Rng + ?Sized>(
     eqclasses: &IndexedEqList,
     cell_data: &[(u32, u32)], // (eq_id, count) vec for classes relevant for this cell
     num_alphas: u32,          // number of genes
     num_bootstraps: u32,      // number of bootstraps to draw
     _init_uniform: bool,
     summary_stat: bool, // if true, the output will simply be a vector of means and variances
+    rng: &mut R,        // the source of randomness for the resampling and EM initialization
+    em_opts: &EmOpts,   // the settings of the EM run on each bootstrap sample
+    ref_lengths: Option<&[f32]>, // the length of each label, for length-aware EM
+    usa_offsets: Option<(usize, usize)>, // if in USA-mode, the unspliced and ambiguous offsets
     _log: &slog::Logger,
 ) -> Vec<Vec<f32>> {
     // the population sample size
@@ -469,20 +906,24 @@ pub(crate) fn run_bootstrap_subset(
     // let mut old_resampled_counts = Vec::new();
     for _bs_num in 0..num_bootstraps {
         // resample from multinomial
-        let resampled_counts = thread_rng().sample(dist.clone());
+        let resampled_counts = rng.sample(dist.clone());
         for (idx, (eq_id, _orig_count)) in cell_data.iter().enumerate() {
             bootstrap_counts.push((*eq_id, resampled_counts[idx].round() as u32));
         }
 
-        let alphas = em_optimize_subset(
+        let (alphas, _) = em_optimize_subset(
             eqclasses,
             &bootstrap_counts[..], // indices into eqclasses relevant for this cell
             &mut unique_evidence,
             &mut no_ambiguity,
             EmInitType::Random,
+            rng,
+            em_opts,
+            None,
+            ref_lengths,
             num_alphas_us,
             false, // only unique
-            None,
+            usa_offsets,
             _log,
         );
 
@@ -520,7 +961,8 @@ pub(crate) fn run_bootstrap_subset(
     bootstraps
 }
 
-pub fn run_bootstrap(
+#[allow(clippy::too_many_arguments)]
+pub fn run_bootstrap<R: Rng + ?Sized>(
     eqclasses: &HashMap<Vec<u32>, u32, ahash::RandomState>,
     num_bootstraps: u32,
     gene_alpha: &[f32],
@@ -530,6 +972,9 @@ pub fn run_bootstrap(
     // only_unique: bool,
     _init_uniform: bool,
     summary_stat: bool,
+    rng: &mut R,
+    em_opts: &EmOpts,
+    ref_lengths: Option<&[f32]>,
     _log: &slog::Logger,
 ) -> Vec<Vec<f32>> {
     // This function is just a thin wrapper around run_bootstrap_subset.
@@ -560,6 +1005,189 @@ pub fn run_bootstrap(
         num_bootstraps,
         _init_uniform,
         summary_stat,
+        rng,
+        em_opts,
+        ref_lengths,
+        None,
+        _log,
+    )
+}
+
+/// Draw the number of the `count` molecules of an equivalence class that
+/// are assigned to each of its labels, where each molecule is assigned to
+/// label `i` with probability proportional to `weights[i]`.
+fn sample_assignment<R: Rng + ?Sized>(weights: &[f64], count: u32, rng: &mut R) -> Vec<u32> {
+    if weights.len() == 1 {
+        return vec![count];
+    }
+    let total: f64 = weights.iter().sum();
+    if count == 0 || total <= 0.0 {
+        // nothing to assign, or nothing to prefer one label over another
+        let mut assigned = vec![0u32; weights.len()];
+        for _ in 0..count {
+            assigned[rng.gen_range(0..weights.len())] += 1;
+        }
+        return assigned;
+    }
+    let probs: Vec<f64> = weights.iter().map(|w| w / total).collect();
+    let dist = Multinomial::new(&probs, count as u64).unwrap();
+    let sampled: Vec<f64> = rng.sample(dist);
+    sampled.iter().map(|x| x.round() as u32).collect()
+}
+
+/// Draw samples from the posterior distribution of the abundances of the
+/// `num_alphas` genes of a cell using a collapsed Gibbs sampler.  The
+/// molecules of each equivalence class are assigned to its labels, and, in
+/// each round, the assignment of each multi-gene equivalence class is
+/// redrawn given the current assignment of all of the others (with the
+/// abundances integrated out under a Dirichlet prior).  The sampler starts
+/// from an assignment drawn according to `init_alphas` (e.g. the EM
+/// estimate), discards the first `burn_in` rounds and then records the
+/// gene counts every `thinning` rounds.  If `summary_stat` is true, the
+/// output is the mean and variance of the recorded samples, otherwise it
+/// is the samples themselves, exactly as for `run_bootstrap_subset`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run_gibbs_subset<R: Rng + ?Sized>(
+    eqclasses: &IndexedEqList,
+    cell_data: &[(u32, u32)], // (eq_id, count) vec for classes relevant for this cell
+    init_alphas: &[f32],      // the initial abundance estimates
+    gibbs_opts: GibbsOpts,
+    summary_stat: bool, // if true, the output will simply be a vector of means and variances
+    rng: &mut R,
+    ref_lengths: Option<&[f32]>, // the length of each label, for length-aware sampling
+    usa_offsets: Option<(usize, usize)>, // if in USA-mode, the unspliced and ambiguous offsets
+    _log: &slog::Logger,
+) -> Vec<Vec<f32>> {
+    let num_alphas = init_alphas.len();
+    let num_samples = gibbs_opts.num_samples as usize;
+    let thinning = gibbs_opts.thinning.max(1);
+
+    // the abundance used to weight a label, which, in USA-mode, accounts
+    // for the ambiguous (and spliced or unspliced) abundance of the gene
+    // exactly as the USA-mode EM update does.
+    let abundance = |label: u32, alphas: &[f32]| -> f64 {
+        match usa_offsets {
+            Some(otup) => get_abundance_for(label, alphas, otup) as f64,
+            None => alphas[label as usize] as f64,
+        }
+    };
+    // the length of a label, if the sampling is length-aware
+    let length = |label: u32| -> f64 {
+        match ref_lengths {
+            Some(lens) => lens[label as usize] as f64,
+            None => 1.0,
+        }
+    };
+
+    // the number of molecules currently assigned to each gene, and
+    // the current assignment of each equivalence class
+    let mut gene_counts: Vec<f32> = vec![0.0; num_alphas];
+    let mut assignments: Vec<Vec<u32>> = Vec::with_capacity(cell_data.len());
+    let mut weights: Vec<f64> = Vec::new();
+
+    for (eq_id, count) in cell_data {
+        let labels = eqclasses.refs_for_eqc(*eq_id);
+        weights.clear();
+        weights.extend(
+            labels
+                .iter()
+                .map(|l| (abundance(*l, init_alphas) + GIBBS_PRIOR) / length(*l)),
+        );
+        let assigned = sample_assignment(&weights, *count, rng);
+        for (l, n) in labels.iter().zip(assigned.iter()) {
+            gene_counts[*l as usize] += *n as f32;
+        }
+        assignments.push(assigned);
+    }
+
+    let mut counts_sum: Vec<f32> = vec![0.0; num_alphas];
+    let mut counts_square_sum: Vec<f32> = vec![0.0; num_alphas];
+    let num_output = if summary_stat { 2usize } else { num_samples };
+    let mut samples = Vec::with_capacity(num_output);
+
+    let num_rounds = gibbs_opts.burn_in + gibbs_opts.num_samples * thinning;
+    for round in 1..=num_rounds {
+        for ((eq_id, count), assigned) in cell_data.iter().zip(assignments.iter_mut()) {
+            let labels = eqclasses.refs_for_eqc(*eq_id);
+            // the assignment of unique equivalence classes never changes
+            if labels.len() == 1 {
+                continue;
+            }
+            // remove this class from the counts, and redraw its
+            // assignment given those of all of the other classes
+            for (l, n) in labels.iter().zip(assigned.iter()) {
+                gene_counts[*l as usize] -= *n as f32;
+            }
+            weights.clear();
+            weights.extend(
+                labels
+                    .iter()
+                    .map(|l| (abundance(*l, &gene_counts) + GIBBS_PRIOR) / length(*l)),
+            );
+            *assigned = sample_assignment(&weights, *count, rng);
+            for (l, n) in labels.iter().zip(assigned.iter()) {
+                gene_counts[*l as usize] += *n as f32;
+            }
+        }
+
+        // record a sample every `thinning` rounds after the burn-in
+        if round > gibbs_opts.burn_in && (round - gibbs_opts.burn_in) % thinning == 0 {
+            if summary_stat {
+                for i in 0..num_alphas {
+                    counts_sum[i] += gene_counts[i];
+                    counts_square_sum[i] += gene_counts[i] * gene_counts[i];
+                }
+            } else {
+                samples.push(gene_counts.clone());
+            }
+        }
+    }
+
+    // if we are only providing summary stats, then
+    // do that computation here.
+    if summary_stat {
+        let n = num_samples.max(1) as f32;
+        let mut sample_mean: Vec<f32> = vec![0.0; num_alphas];
+        let mut sample_var: Vec<f32> = vec![0.0; num_alphas];
+        for i in 0..num_alphas {
+            let mean_count = counts_sum[i] / n;
+            sample_mean[i] = mean_count;
+            sample_var[i] = (counts_square_sum[i] / n) - (mean_count * mean_count);
+        }
+        samples.push(sample_mean);
+        samples.push(sample_var);
+    }
+
+    samples
+}
+
+/// Like `run_bootstrap`, this is a thin wrapper around `run_gibbs_subset`
+/// for a cell whose equivalence classes are held in a hash map.
+pub fn run_gibbs<R: Rng + ?Sized>(
+    eqclasses: &HashMap<Vec<u32>, u32, ahash::RandomState>,
+    gene_alpha: &[f32],
+    gibbs_opts: GibbsOpts,
+    summary_stat: bool,
+    rng: &mut R,
+    ref_lengths: Option<&[f32]>,
+    _log: &slog::Logger,
+) -> Vec<Vec<f32>> {
+    let eql = IndexedEqList::init_from_hash(eqclasses, gene_alpha.len());
+    let cell_data: Vec<(u32, u32)> = eqclasses
+        .iter()
+        .enumerate()
+        .map(|(idx, (_labels, count))| (idx as u32, *count))
+        .collect();
+
+    run_gibbs_subset(
+        &eql,
+        &cell_data[..],
+        gene_alpha,
+        gibbs_opts,
+        summary_stat,
+        rng,
+        ref_lengths,
+        None,
         _log,
     )
 }
@@ -705,3 +1333,266 @@ pub fn run_bootstrap_old(
 
     bootstraps
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn test_logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    /// gene 0 has 10 unique UMIs, gene 1 has 4, and 6 UMIs are shared.
+    fn shared_eqclasses() -> (IndexedEqList, Vec<(u32, u32)>) {
+        let mut eql = IndexedEqList::new();
+        eql.num_genes = 2;
+        eql.eq_label_starts.push(0);
+        eql.add_label_vec(&[0]);
+        eql.add_label_vec(&[0, 1]);
+        eql.add_label_vec(&[1]);
+        (eql, vec![(0, 10), (1, 6), (2, 4)])
+    }
+
+    #[test]
+    fn test_em_converges_on_pooled_counts() {
+        let (eql, cell_data) = shared_eqclasses();
+        // the counts of a large pool of cells
+        let pooled: Vec<(u32, u32)> = cell_data.iter().map(|(e, c)| (*e, c * 1_000_000)).collect();
+        let base = EmOpts::default();
+        // small totals keep the tolerance
+        assert_eq!(
+            base.for_total_count(20).rel_diff_tolerance,
+            base.rel_diff_tolerance
+        );
+        let em_opts = base.for_total_count(20_000_000);
+        assert!((em_opts.rel_diff_tolerance - 20.0).abs() < 1e-3);
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let (alphas, stats) = em_optimize_subset(
+            &eql,
+            &pooled,
+            &mut [false; 2],
+            &mut [false; 2],
+            EmInitType::Informative,
+            &mut rng,
+            &em_opts,
+            None,
+            None,
+            2,
+            false,
+            None,
+            &test_logger(),
+        );
+        assert!(stats.converged);
+        assert!(stats.num_iterations < em_opts.max_iter);
+        // the EM gives gene 0 (10 + 6 p) / 20 = p of the UMIs, i.e. p = 5 / 7
+        let p = alphas[0] / (alphas[0] + alphas[1]);
+        assert!((p - 5.0 / 7.0).abs() < 1e-4, "gene 0 has fraction {}", p);
+    }
+
+    #[test]
+    fn test_vbem_update_conserves_and_favors_abundant_label() {
+        let labels: Vec<Vec<u32>> = vec![vec![0], vec![0, 1], vec![1]];
+        let counts = [10u32, 6, 4];
+        let classes = || {
+            labels
+                .iter()
+                .map(|l| l.as_slice())
+                .zip(counts.iter().copied())
+        };
+        let alphas_in = [10.0_f32, 4.0];
+        let mut alphas_out = [0.0_f32; 2];
+        vbem_update(&alphas_in, &mut alphas_out, classes(), 0.01, None);
+        // every UMI is assigned, the unique UMIs to their gene
+        assert!((alphas_out.iter().sum::<f32>() - 20.0).abs() < 1e-4);
+        assert!(alphas_out[0] >= 10.0 && alphas_out[1] >= 4.0);
+        // the shared UMIs go mostly to the more abundant gene, and more
+        // so than under the EM, which would give it 6 * 10 / 14 of them
+        let shared_0 = alphas_out[0] - 10.0;
+        assert!(shared_0 > 6.0 * 10.0 / 14.0, "gene 0 got {}", shared_0);
+    }
+
+    #[test]
+    fn test_informed_update_prior_steers_assignment() {
+        let labels: Vec<u32> = vec![0, 1];
+        let alphas_in = [1.0_f32, 1.0];
+
+        // without a prior, the class is split evenly
+        let mut alphas_out = [0.0_f32; 2];
+        let classes = std::iter::once((labels.as_slice(), 8u32));
+        informed_update(
+            &alphas_in,
+            &mut alphas_out,
+            classes,
+            None,
+            4.0,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(alphas_out, [4.0, 4.0]);
+
+        // with a prior, 4 pseudo-molecules are split 1:3, so the weights
+        // are 1 + 1 and 1 + 3, but only the 8 real molecules are assigned
+        let mut alphas_out = [0.0_f32; 2];
+        let prior = [1.0_f32, 3.0];
+        let classes = std::iter::once((labels.as_slice(), 8u32));
+        informed_update(
+            &alphas_in,
+            &mut alphas_out,
+            classes,
+            Some(&prior),
+            4.0,
+            None,
+            None,
+            None,
+        );
+        assert!((alphas_out[0] - 8.0 * 2.0 / 6.0).abs() < 1e-5);
+        assert!((alphas_out[1] - 8.0 * 4.0 / 6.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_log_likelihood() {
+        let labels: Vec<Vec<u32>> = vec![vec![0], vec![0, 1], vec![2]];
+        let counts = [2u32, 3, 1];
+        let classes = || {
+            labels
+                .iter()
+                .map(|l| l.as_slice())
+                .zip(counts.iter().copied())
+        };
+        let alphas = [2.0_f32, 1.0, 1.0];
+        // P(class 0) = 2/4, P(class 1) = 3/4, P(class 2) = 1/4
+        let expected = 2.0 * 0.5_f64.ln() + 3.0 * 0.75_f64.ln() + 0.25_f64.ln();
+        assert!((log_likelihood(&alphas, classes(), None, None) - expected).abs() < 1e-9);
+
+        // with lengths, the abundances are first divided by them
+        let lens = [2.0_f32, 1.0, 1.0];
+        let expected =
+            2.0 * (1.0_f64 / 3.0).ln() + 3.0 * (2.0_f64 / 3.0).ln() + (1.0_f64 / 3.0).ln();
+        assert!((log_likelihood(&alphas, classes(), Some(&lens), None) - expected).abs() < 1e-9);
+
+        // with no abundance at all, there is nothing to score
+        assert_eq!(log_likelihood(&[0.0; 3], classes(), None, None), 0.0);
+    }
+
+    #[test]
+    fn test_log_likelihood_usa() {
+        // 1 gene, with spliced (0), unspliced (1) and ambiguous (2) rows;
+        // a spliced UMI is also compatible with the ambiguous row.
+        let labels: Vec<u32> = vec![0];
+        let alphas = [1.0_f32, 2.0, 1.0];
+        let classes = std::iter::once((labels.as_slice(), 2u32));
+        let expected = 2.0 * 0.5_f64.ln();
+        assert!((log_likelihood(&alphas, classes, None, Some((1, 2))) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sample_assignment_single_label() {
+        let mut rng = StdRng::seed_from_u64(7);
+        assert_eq!(sample_assignment(&[0.3], 9, &mut rng), vec![9]);
+    }
+
+    #[test]
+    fn test_sample_assignment_conserves_count() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..20 {
+            let assigned = sample_assignment(&[1.0, 0.0, 3.0], 25, &mut rng);
+            assert_eq!(assigned.len(), 3);
+            assert_eq!(assigned.iter().sum::<u32>(), 25);
+            // a label with no weight never receives a molecule
+            assert_eq!(assigned[1], 0);
+        }
+        // with no weight on any label, the molecules are still all assigned
+        let assigned = sample_assignment(&[0.0, 0.0], 5, &mut rng);
+        assert_eq!(assigned.iter().sum::<u32>(), 5);
+        assert_eq!(sample_assignment(&[1.0, 2.0], 0, &mut rng), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_run_gibbs_subset_samples() {
+        let (eql, cell_data) = shared_eqclasses();
+        let gibbs_opts = GibbsOpts {
+            num_samples: 5,
+            thinning: 2,
+            burn_in: 3,
+        };
+        let mut rng = StdRng::seed_from_u64(11);
+        let samples = run_gibbs_subset(
+            &eql,
+            &cell_data,
+            &[13.0, 7.0],
+            gibbs_opts,
+            false,
+            &mut rng,
+            None,
+            None,
+            &test_logger(),
+        );
+        assert_eq!(samples.len(), 5);
+        for s in &samples {
+            // every sample assigns all of the UMIs, and the unique
+            // UMIs always stay with their gene
+            assert_eq!(s.iter().sum::<f32>(), 20.0);
+            assert!(s[0] >= 10.0 && s[1] >= 4.0);
+        }
+    }
+
+    #[test]
+    fn test_run_gibbs_subset_summary_stat() {
+        let (eql, cell_data) = shared_eqclasses();
+        let gibbs_opts = GibbsOpts {
+            num_samples: 10,
+            thinning: 1,
+            burn_in: 0,
+        };
+        let mut rng = StdRng::seed_from_u64(11);
+        let summary = run_gibbs_subset(
+            &eql,
+            &cell_data,
+            &[13.0, 7.0],
+            gibbs_opts,
+            true,
+            &mut rng,
+            None,
+            None,
+            &test_logger(),
+        );
+        // the mean and the variance
+        assert_eq!(summary.len(), 2);
+        let (mean, var) = (&summary[0], &summary[1]);
+        assert!((mean.iter().sum::<f32>() - 20.0).abs() < 1e-3);
+        assert!(mean[0] >= 10.0 && mean[0] <= 16.0);
+        assert!(var.iter().all(|v| *v >= -1e-3));
+    }
+
+    #[test]
+    fn test_run_bootstrap_subset_is_reproducible() {
+        let (eql, cell_data) = shared_eqclasses();
+        let bootstrap = |seed: u64| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            run_bootstrap_subset(
+                &eql,
+                &cell_data,
+                2,
+                4,
+                false,
+                false,
+                &mut rng,
+                &EmOpts::default(),
+                None,
+                None,
+                &test_logger(),
+            )
+        };
+        let bs = bootstrap(5);
+        assert_eq!(bs.len(), 4);
+        for b in &bs {
+            // each replicate resamples, and assigns, all 20 UMIs
+            assert!((b.iter().sum::<f32>() - 20.0).abs() < 1e-2);
+        }
+        // the same seed draws the same replicates
+        assert_eq!(bs, bootstrap(5));
+    }
+}
@@ -1,6 +1,8 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::hash::{BuildHasher, Hasher};
-use std::io::BufRead;
+use std::io::{BufRead, BufReader, BufWriter};
 
 use libradicl::rad_types;
 
@@ -190,6 +192,62 @@ impl Default for IndexedEqList {
     }
 }
 
+/// The transcript-level equivalence classes of the quantified cells, and
+/// the number of distinct UMIs in each class in each cell.  This is
+/// written by `quant --dump-tx-eqclasses` (as bincode) and can be read
+/// back by `infer`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TxEqcDump {
+    /// the names of the transcripts, in the order of the RAD header, so
+    /// that transcript `i` in a label is `ref_names[i]`
+    pub ref_names: Vec<String>,
+    /// the barcode of each cell, in row order
+    pub barcodes: Vec<String>,
+    /// the (sorted) transcript ids labeling each equivalence class,
+    /// indexed by class id
+    pub eq_labels: Vec<Vec<u32>>,
+    /// for each cell, the (class id, UMI count) pairs of the classes
+    /// that occur in it
+    pub cell_counts: Vec<Vec<(u32, u32)>>,
+}
+
+impl TxEqcDump {
+    /// Writes this dump to `path`.
+    pub fn write<P: AsRef<std::path::Path>>(&self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("could not create {}", path.display()))?;
+        bincode::serialize_into(BufWriter::new(file), self)
+            .with_context(|| format!("could not write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Reads a dump previously written by [`TxEqcDump::write`] from `path`.
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<TxEqcDump> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        bincode::deserialize_from(BufReader::new(file)).with_context(|| {
+            format!(
+                "could not read transcript-level equivalence classes from {}",
+                path.display()
+            )
+        })
+    }
+
+    /// Returns the labels of the equivalence classes as an `IndexedEqList`
+    /// whose targets are the transcripts.
+    pub fn to_indexed_eq_list(&self) -> IndexedEqList {
+        let mut eql = IndexedEqList::new();
+        eql.num_genes = self.ref_names.len();
+        eql.eq_label_starts.push(0);
+        for labels in self.eq_labels.iter() {
+            eql.add_label_vec(labels);
+        }
+        eql
+    }
+}
+
 pub struct EqMap {
     // for each equivalence class, holds the (umi, freq) pairs
     // and the id of that class
@@ -542,3 +600,47 @@ impl EqMap {
             ..(self.eq_label_starts[(idx + 1) as usize] as usize)]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tx_eqc_dump_round_trip() {
+        let dump = TxEqcDump {
+            ref_names: vec!["tA".to_string(), "tB".to_string(), "tC".to_string()],
+            barcodes: vec!["AAAC".to_string(), "GGTT".to_string()],
+            eq_labels: vec![vec![0], vec![0, 2], vec![1, 2]],
+            cell_counts: vec![vec![(0, 3), (1, 1)], vec![(2, 5)]],
+        };
+
+        let path =
+            std::env::temp_dir().join(format!("alevin_fry_tx_eqc_dump_{}.bin", std::process::id()));
+        dump.write(&path).unwrap();
+        let read_back = TxEqcDump::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        let read_back = read_back.unwrap();
+
+        assert_eq!(read_back.ref_names, dump.ref_names);
+        assert_eq!(read_back.barcodes, dump.barcodes);
+        assert_eq!(read_back.eq_labels, dump.eq_labels);
+        assert_eq!(read_back.cell_counts, dump.cell_counts);
+
+        let eql = read_back.to_indexed_eq_list();
+        assert_eq!(eql.num_genes, 3);
+        assert_eq!(eql.num_eq_classes(), 3);
+        assert_eq!(eql.label_list_size, 5);
+        assert_eq!(eql.refs_for_eqc(0), &[0]);
+        assert_eq!(eql.refs_for_eqc(1), &[0, 2]);
+        assert_eq!(eql.refs_for_eqc(2), &[1, 2]);
+    }
+
+    #[test]
+    fn test_tx_eqc_dump_from_missing_file() {
+        let path = std::env::temp_dir().join(format!(
+            "alevin_fry_missing_tx_eqc_dump_{}.bin",
+            std::process::id()
+        ));
+        assert!(TxEqcDump::from_file(path).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+/*
+ * Copyright (c) 2020-2022 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+use crate::utils::VersionParseError;
+use libradicl::exit_codes;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// exit code used when a required input file or directory is missing
+pub const EXIT_MISSING_INPUT: i32 = 66;
+/// exit code used when the input data are malformed or unusable
+pub const EXIT_INVALID_INPUT: i32 = 65;
+/// exit code used when the input was produced by an incompatible version
+pub const EXIT_VERSION_MISMATCH: i32 = 78;
+/// exit code used when the requested options are invalid
+pub const EXIT_INVALID_CONFIG: i32 = 64;
+/// exit code used when an I/O operation fails
+pub const EXIT_IO_ERROR: i32 = 74;
+/// exit code used for all other errors
+pub const EXIT_OTHER: i32 = 1;
+
+/// The errors that may be returned by the public functions of the
+/// `cellfilter`, `collate` and `quant` modules.
+#[derive(Error, Debug)]
+pub enum AlevinFryError {
+    #[error("the RAD type id {typeid} of the '{tag}' tag is not supported; currently only RAD types 1--4 are supported for 'b' and 'u' tags.")]
+    UnsupportedTagType { tag: String, typeid: u8 },
+    #[error("the input RAD file does not contain a description of the required '{0}' tag.")]
+    MissingTag(String),
+    #[error("the required input {} does not exist{}", .path.display(), .hint.as_ref().map(|h| format!("; {}", h)).unwrap_or_default())]
+    MissingFile { path: PathBuf, hint: Option<String> },
+    #[error("{0}")]
+    EmptyHistogram(String),
+    #[error("invalid input : {0}")]
+    InvalidInput(String),
+    #[error("{0}")]
+    VersionMismatch(String),
+    #[error("invalid configuration : {0}")]
+    InvalidConfig(String),
+    #[error(transparent)]
+    VersionParse(#[from] VersionParseError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl AlevinFryError {
+    /// Convenience constructor for a [`AlevinFryError::MissingFile`]
+    /// error without a hint.
+    pub fn missing_file<P: Into<PathBuf>>(path: P) -> Self {
+        AlevinFryError::MissingFile {
+            path: path.into(),
+            hint: None,
+        }
+    }
+
+    /// The code with which the process should exit when
+    /// terminated by this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AlevinFryError::UnsupportedTagType { .. } => exit_codes::EXIT_UNSUPPORTED_TAG_TYPE,
+            AlevinFryError::MissingTag(_)
+            | AlevinFryError::EmptyHistogram(_)
+            | AlevinFryError::InvalidInput(_) => EXIT_INVALID_INPUT,
+            AlevinFryError::MissingFile { .. } => EXIT_MISSING_INPUT,
+            AlevinFryError::VersionMismatch(_) | AlevinFryError::VersionParse(_) => {
+                EXIT_VERSION_MISMATCH
+            }
+            AlevinFryError::InvalidConfig(_) => EXIT_INVALID_CONFIG,
+            AlevinFryError::Json(_) => EXIT_INVALID_INPUT,
+            AlevinFryError::Io(_) => EXIT_IO_ERROR,
+            AlevinFryError::Other(_) => EXIT_OTHER,
+        }
+    }
+}
+
+/// Errors raised in code that uses `anyhow` internally are converted
+/// back to the typed variant if they originated as an `AlevinFryError`
+/// (even if context was subsequently attached to them).
+impl From<anyhow::Error> for AlevinFryError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<AlevinFryError>() {
+            Ok(afe) => afe,
+            Err(e) => AlevinFryError::Other(e),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, AlevinFryError>;
@@ -7,75 +7,443 @@
  * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
  */
 
-use crate::cellfilter::permit_list_from_file;
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use crossbeam_queue::ArrayQueue;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 #[allow(unused_imports)]
 use slog::{crit, info, warn};
 
-use needletail::bitkmer::*;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use sprs::TriMatI;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use crate::em::{em_optimize_subset, EmInitType};
-use crate::utils::read_filter_list;
+use crate::em::{em_optimize_subset, run_bootstrap_subset, EmInitType, EmOpts};
+use crate::eq_class::{IndexedEqList, TxEqcDump};
+use crate::quant::{BootstrapHelper, BufferedGzFile, CellCounts};
+use crate::utils as afutils;
+
+/// The equivalence class counts from which `infer` estimates abundances.
+pub enum InferInput<'a> {
+    /// a cell-by-equivalence-class count matrix and the gene labels of
+    /// its classes, as written by `quant --dump-eqclasses`
+    GeneLevel {
+        count_mat_file: &'a PathBuf,
+        eq_label_file: &'a PathBuf,
+    },
+    /// the transcript-level equivalence classes written by
+    /// `quant --dump-tx-eqclasses`
+    TranscriptLevel(&'a PathBuf),
+    /// the output directory of a `quant` run with `--dump-eqclasses` or
+    /// `--dump-tx-eqclasses`
+    QuantDir(&'a PathBuf),
+}
 
-#[allow(clippy::too_many_arguments)]
-pub fn infer(
-    //num_bootstraps,
-    //init_uniform,
-    //summary_stat,
-    count_mat_file: &PathBuf,
-    eq_label_file: &PathBuf,
-    usa_mode: bool,
-    _use_mtx: bool,
-    num_threads: u32,
-    filter_list: Option<&PathBuf>,
-    output_dir: &PathBuf,
+/// The equivalence class counts of the input cells.
+struct EqcCounts {
+    eq_classes: IndexedEqList,
+    // the barcode of each cell, in row order
+    barcodes: Vec<String>,
+    // the (equivalence class id, count) pairs of each cell, in row order
+    cell_counts: Vec<Vec<(u32, u32)>>,
+    // the names of the targets (the columns of the output)
+    col_names: Vec<String>,
+}
+
+/// Read the cell-by-equivalence-class count matrix at `count_mat_path`,
+/// of dimension `num_cells` x `num_eqcs`, into the (eq_id, count) pairs
+/// of each row.  The matrix is read as (gzip compressed) EDS if its file
+/// name ends in `.eds.gz` (`.eds`), and otherwise in matrix market
+/// format, gzip compressed if its file name ends in `.gz`.
+fn read_count_mat(
+    count_mat_path: &Path,
+    num_cells: usize,
+    num_eqcs: usize,
     log: &slog::Logger,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Vec<Vec<(u32, u32)>>> {
+    let fname = count_mat_path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap_or("");
+    let file = fs::File::open(count_mat_path)
+        .with_context(|| format!("could not open {:?}", count_mat_path))?;
+
+    if fname.ends_with(".eds.gz") || fname.ends_with(".eds") {
+        let cell_counts = if fname.ends_with(".gz") {
+            read_eds_rows(
+                BufReader::new(flate2::read::MultiGzDecoder::new(file)),
+                num_cells,
+                num_eqcs,
+            )?
+        } else {
+            read_eds_rows(BufReader::new(file), num_cells, num_eqcs)?
+        };
+        info!(
+            log,
+            "read {} x {} equivalence class count matrix.", num_cells, num_eqcs
+        );
+        return Ok(cell_counts);
+    }
+
+    // read the file and convert it to csr (rows are *cells*)
+    let trimat = if fname.ends_with(".gz") {
+        let mut rdr = BufReader::new(flate2::read::MultiGzDecoder::new(file));
+        sprs::io::read_matrix_market_from_bufread::<i32, u32, _>(&mut rdr)
+    } else {
+        sprs::io::read_matrix_market_from_bufread::<i32, u32, _>(&mut BufReader::new(file))
+    };
+    let count_mat: sprs::CsMatBase<i32, u32, Vec<u32>, Vec<u32>, Vec<i32>, _> = match trimat {
+        Ok(t) => t.to_csr(),
+        Err(e) => {
+            warn!(log, "error reading mtx file{:?}", e);
+            return Err(anyhow!("error reading mtx format matrix : {}", e));
+        }
+    };
+
     info!(
         log,
-        "inferring abundances from equivalence class count input."
+        "read {} x {} equivalence class count matrix.",
+        count_mat.rows(),
+        count_mat.cols()
     );
 
-    // get the path for the equivalence class count matrix
-    let count_mat_path = std::path::Path::new(count_mat_file);
+    // gather the data from the rows of the matrix into vectors of
+    // (eq_id, count) tuples.
+    // NOTE: because of the matrix market format requirements (and the compatible
+    // implmentation in sprs), we have read the matrix in with i32 instead of u32 entries.
+    // Here we convert the i32 counts (the second element of the tuple here) to u32.
+    Ok(count_mat
+        .outer_iterator()
+        .map(|row_vec| row_vec.iter().map(|e| (e.0 as u32, *e.1 as u32)).collect())
+        .collect())
+}
+
+/// Read the first `num_rows` rows, of `num_cols` columns, of the EDS
+/// encoded matrix in `reader`.  Each row consists of a bit vector, with
+/// one bit per column (the most significant bit of the first byte being
+/// that of the first column), flagging the non-zero columns, followed by
+/// the values of these columns as little-endian `f32`s.  The values are
+/// rounded to the nearest integer.
+fn read_eds_rows<R: Read>(
+    mut reader: R,
+    num_rows: usize,
+    num_cols: usize,
+) -> anyhow::Result<Vec<Vec<(u32, u32)>>> {
+    let mut flags = vec![0u8; num_cols.div_ceil(8)];
+    let mut value = [0u8; 4];
+    let mut rows = Vec::with_capacity(num_rows);
+    for row_index in 0..num_rows {
+        reader
+            .read_exact(&mut flags)
+            .with_context(|| format!("could not read row {} of EDS matrix", row_index))?;
+        let mut row = Vec::new();
+        for col in 0..num_cols {
+            if flags[col / 8] & (128u8 >> (col % 8)) != 0 {
+                reader
+                    .read_exact(&mut value)
+                    .with_context(|| format!("could not read row {} of EDS matrix", row_index))?;
+                let count = f32::from_le_bytes(value).round();
+                if count > 0.0 {
+                    row.push((col as u32, count as u32));
+                }
+            }
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Read the gene-level equivalence class count matrix `count_mat_path`
+/// and the labels of its classes from `eq_label_path`.  The row and
+/// column names are read from the directory containing the matrix.
+fn read_gene_level_eqcs(
+    count_mat_path: &Path,
+    eq_label_path: &Path,
+    log: &slog::Logger,
+) -> anyhow::Result<EqcCounts> {
     let count_mat_parent = count_mat_path
         .parent()
         .unwrap_or_else(|| panic!("cannot get parent path of {:?}", count_mat_path));
 
-    // read the file and convert it to csr (rows are *cells*)
-    let count_mat: sprs::CsMatBase<i32, u32, Vec<u32>, Vec<u32>, Vec<i32>, _> =
-        match sprs::io::read_matrix_market::<i32, u32, &std::path::Path>(count_mat_path) {
-            Ok(t) => t.to_csr(),
-            Err(e) => {
-                warn!(log, "error reading mtx file{:?}", e);
-                return Err(anyhow!("error reading mtx format matrix : {}", e));
+    // read in the global equivalence class representation
+    let eq_classes = IndexedEqList::init_from_eqc_file(eq_label_path);
+
+    let barcodes = read_names(&count_mat_parent.join("quants_mat_rows.txt"))?;
+
+    let cell_counts = read_count_mat(
+        count_mat_path,
+        barcodes.len(),
+        eq_classes.num_eq_classes(),
+        log,
+    )?;
+
+    let col_names = read_names(&count_mat_parent.join("quants_mat_cols.txt"))?;
+
+    Ok(EqcCounts {
+        eq_classes,
+        barcodes,
+        cell_counts,
+        col_names,
+    })
+}
+
+/// Read the transcript-level equivalence classes written by
+/// `quant --dump-tx-eqclasses` to `tx_eqc_path`.
+fn read_transcript_level_eqcs(tx_eqc_path: &Path) -> anyhow::Result<EqcCounts> {
+    let dump = TxEqcDump::from_file(tx_eqc_path)?;
+    let eq_classes = dump.to_indexed_eq_list();
+
+    Ok(EqcCounts {
+        eq_classes,
+        barcodes: dump.barcodes,
+        cell_counts: dump.cell_counts,
+        col_names: dump.ref_names,
+    })
+}
+
+/// Read the equivalence classes that `quant` wrote to its output directory
+/// `quant_dir`, using its `quant.json` to find them and to check their
+/// dimensions.  The gene-level classes are used if they were written, and
+/// the transcript-level classes otherwise.  Also returns whether the
+/// classes were computed in USA mode.
+fn read_quant_dir_eqcs(quant_dir: &Path, log: &slog::Logger) -> anyhow::Result<(EqcCounts, bool)> {
+    let json_path = quant_dir.join("quant.json");
+    let json_file =
+        fs::File::open(&json_path).with_context(|| format!("could not open {:?}", json_path))?;
+    let meta: serde_json::Value = serde_json::from_reader(BufReader::new(json_file))
+        .with_context(|| format!("could not parse {:?}", json_path))?;
+    let get_flag = |key: &str| meta.get(key).and_then(|v| v.as_bool()).unwrap_or(false);
+    let get_count = |key: &str| {
+        meta.get(key)
+            .and_then(|v| v.as_u64())
+            .map(|v| v as usize)
+            .ok_or_else(|| anyhow!("{:?} does not record {}", json_path, key))
+    };
+    let num_cells = get_count("num_quantified_cells")?;
+    let num_genes = get_count("num_genes")?;
+
+    let alevin_path = quant_dir.join("alevin");
+    let (eqc_counts, usa_mode) = if get_flag("dump_eq") {
+        let count_mat_path = ["geqc_counts.mtx", "geqc_counts.mtx.gz"]
+            .iter()
+            .map(|f| alevin_path.join(f))
+            .find(|p| p.exists())
+            .ok_or_else(|| anyhow!("could not find geqc_counts.mtx in {:?}", alevin_path))?;
+        let eqc_counts = read_gene_level_eqcs(
+            &count_mat_path,
+            &alevin_path.join("gene_eqclass.txt.gz"),
+            log,
+        )?;
+        if eqc_counts.eq_classes.num_genes != num_genes || eqc_counts.col_names.len() != num_genes {
+            return Err(anyhow!(
+                "quant.json records {} genes, but the equivalence classes are over {} genes and quants_mat_cols.txt lists {}",
+                num_genes,
+                eqc_counts.eq_classes.num_genes,
+                eqc_counts.col_names.len()
+            ));
+        }
+        (eqc_counts, get_flag("usa_mode"))
+    } else if get_flag("dump_tx_eq") {
+        // transcript-level classes are never resolved in USA mode
+        (
+            read_transcript_level_eqcs(&alevin_path.join("tx_eqc.bin"))?,
+            false,
+        )
+    } else {
+        return Err(anyhow!(
+            "{:?} contains no equivalence classes; run quant with --dump-eqclasses or --dump-tx-eqclasses",
+            quant_dir
+        ));
+    };
+
+    // NOTE: with a filter list, quant records the size of the list as
+    // the number of quantified cells, even if some of its barcodes were
+    // not present.
+    if eqc_counts.barcodes.len() != num_cells {
+        warn!(
+            log,
+            "quant.json records {} quantified cells, but {} barcodes were found.",
+            num_cells,
+            eqc_counts.barcodes.len()
+        );
+    }
+    Ok((eqc_counts, usa_mode))
+}
+
+/// Pool the equivalence class counts of the cells of each group listed in
+/// `groups`, a two-column (headerless) tab-separated file of barcodes and
+/// group names, in which each barcode may appear only once.  Cells that
+/// are not listed (or, if `retained_bc` is given, that are not retained)
+/// are ignored.  Returns the group names, in the order in which they first
+/// appear, and the pooled counts of each.
+fn pool_group_counts<R: BufRead>(
+    groups: R,
+    barcodes: &[String],
+    cell_counts: Vec<Vec<(u32, u32)>>,
+    retained_bc: Option<&HashSet<String, ahash::RandomState>>,
+    log: &slog::Logger,
+) -> anyhow::Result<(Vec<String>, Vec<Vec<(u32, u32)>>)> {
+    let mut group_names = Vec::<String>::new();
+    let mut group_ids = HashMap::<String, usize>::new();
+    let mut cell_group = HashMap::<String, usize>::new();
+    for (lnum, line) in groups.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (bc, group) = line.split_once('\t').with_context(|| {
+            format!(
+                "line {} of the groups file doesn't have 2 tab-separated columns",
+                lnum + 1
+            )
+        })?;
+        let group = group.trim();
+        let gid = match group_ids.get(group) {
+            Some(gid) => *gid,
+            None => {
+                let gid = group_names.len();
+                group_names.push(group.to_string());
+                group_ids.insert(group.to_string(), gid);
+                gid
             }
         };
+        if cell_group.insert(bc.trim().to_string(), gid).is_some() {
+            return Err(anyhow!(
+                "barcode {} is listed more than once in the groups file (line {})",
+                bc.trim(),
+                lnum + 1
+            ));
+        }
+    }
 
+    let mut pooled = vec![BTreeMap::<u32, u32>::new(); group_names.len()];
+    let mut num_pooled_cells = 0usize;
+    for (bc, counts) in barcodes.iter().zip(cell_counts) {
+        if retained_bc.is_some_and(|r| !r.contains(bc)) {
+            continue;
+        }
+        if let Some(gid) = cell_group.get(bc) {
+            num_pooled_cells += 1;
+            for (eqid, count) in counts {
+                let pooled_count = pooled[*gid].entry(eqid).or_insert(0);
+                *pooled_count = pooled_count.checked_add(count).with_context(|| {
+                    format!(
+                        "the pooled count of equivalence class {} in group {} is too large",
+                        eqid, group_names[*gid]
+                    )
+                })?;
+            }
+        }
+    }
     info!(
         log,
-        "read {} x {} equivalence class count matrix.",
-        count_mat.rows(),
-        count_mat.cols()
+        "pooled the equivalence class counts of {} cells into {} groups.",
+        num_pooled_cells,
+        group_names.len()
     );
 
-    let mut num_cells = count_mat.rows();
+    let pooled_counts = pooled
+        .into_iter()
+        .map(|p| p.into_iter().collect())
+        .collect();
+    Ok((group_names, pooled_counts))
+}
 
-    // read in the global equivalence class representation
-    let eq_label_path = std::path::Path::new(eq_label_file);
-    let global_eq_classes = Arc::new(crate::eq_class::IndexedEqList::init_from_eqc_file(
-        eq_label_path,
-    ));
+/// A key for the output row (cell or group) named `name`, from which its
+/// random number generator is seeded in deterministic mode.  This is the
+/// (64-bit FNV-1a) hash of the name, which, unlike the hashers of the
+/// standard library, is the same on every platform and in every version.
+fn row_key(name: &str) -> u64 {
+    name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Read the (non-empty, whitespace trimmed) lines of `path`, such as the
+/// barcodes or gene names written by `quant`.
+fn read_names(path: &Path) -> anyhow::Result<Vec<String>> {
+    let file = fs::File::open(path).with_context(|| format!("could not open {:?}", path))?;
+    let mut names = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("could not read {:?}", path))?;
+        let name = line.trim();
+        if !name.is_empty() {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn infer(
+    input: InferInput,
+    usa_mode: bool,
+    use_mtx: bool,
+    num_bootstraps: u32,
+    summary_stat: bool,
+    em_max_iter: u32,
+    em_rel_tol: f32,
+    rng_seed: Option<u64>,
+    num_threads: u32,
+    filter_list: Option<&PathBuf>,
+    groups: Option<&PathBuf>,
+    output_dir: &PathBuf,
+    log: &slog::Logger,
+) -> anyhow::Result<()> {
+    info!(
+        log,
+        "inferring abundances from equivalence class count input."
+    );
+    if em_max_iter < 1 {
+        return Err(anyhow!("--em-max-iter must be at least 1"));
+    }
+    if em_rel_tol.is_nan() || em_rel_tol <= 0.0 {
+        return Err(anyhow!(
+            "--em-rel-tol must be positive, but was set to {}",
+            em_rel_tol
+        ));
+    }
+    let base_em_opts = EmOpts {
+        max_iter: em_max_iter,
+        rel_diff_tolerance: em_rel_tol,
+        ..EmOpts::default()
+    };
+
+    let (eqc_counts, usa_mode) = match input {
+        InferInput::GeneLevel {
+            count_mat_file,
+            eq_label_file,
+        } => (
+            read_gene_level_eqcs(count_mat_file, eq_label_file, log)?,
+            usa_mode,
+        ),
+        InferInput::TranscriptLevel(tx_eqc_file) => {
+            if usa_mode {
+                return Err(anyhow!(
+                    "USA mode does not apply to transcript-level equivalence classes"
+                ));
+            }
+            (read_transcript_level_eqcs(tx_eqc_file)?, false)
+        }
+        // the USA mode is that recorded in quant.json
+        InferInput::QuantDir(quant_dir) => read_quant_dir_eqcs(quant_dir, log)?,
+    };
+    let EqcCounts {
+        eq_classes,
+        barcodes,
+        cell_counts,
+        col_names,
+    } = eqc_counts;
+
+    let mut num_cells = cell_counts.len();
+
+    let global_eq_classes = Arc::new(eq_classes);
 
     info!(
         log,
@@ -92,46 +460,49 @@ pub fn infer(
         None
     };
 
-    // if we have a filter list, extract it here
+    // if we have a filter list, extract it here; the barcodes are
+    // matched by name.
     let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
-    let mut retained_bc: HashSet<u64, ahash::RandomState> = HashSet::with_hasher(s);
+    let mut retained_bc: HashSet<String, ahash::RandomState> = HashSet::with_hasher(s);
     let mut filter_bc = false;
-    // read the first barcode in the file to get the barcode
-    // length.
-    let bc_path = count_mat_parent.join("quants_mat_rows.txt");
-    let bc_len;
-    {
-        let mut first_line = String::new();
-        let file = match fs::File::open(&bc_path) {
-            Ok(file) => file,
-            Err(_) => panic!("Unable to read first barcode from {:?}", &bc_path),
-        };
-        let mut rdr = BufReader::new(file);
-        rdr.read_line(&mut first_line).expect("Unable to read line");
-        if first_line.ends_with('\n') {
-            first_line.pop();
-        }
-        bc_len = first_line.len() as u16;
-    }
-    let bc_fname = bc_path.to_str().expect("couldn't unwrap barcode file path");
-    let bcvec = permit_list_from_file(bc_fname, bc_len);
 
     if let Some(fname) = filter_list {
         // read in the fitler list
-        match read_filter_list(fname, bc_len) {
-            Ok(fset) => {
-                // the number of cells we expect to
-                // actually process
-                num_cells = fset.len();
-                retained_bc = fset;
-                filter_bc = true;
-            }
-            Err(e) => {
-                return Err(e);
-            }
-        }
+        retained_bc.extend(read_names(fname)?);
+        filter_bc = true;
+        // the number of cells we expect to
+        // actually process
+        num_cells = barcodes.iter().filter(|b| retained_bc.contains(*b)).count();
+        info!(
+            log,
+            "{} of the {} barcodes in the filter list are present in the input.",
+            num_cells,
+            retained_bc.len()
+        );
     }
 
+    // in pseudobulk mode, the rows of the output are groups of cells,
+    // whose equivalence class counts are pooled before running the EM
+    // (once per group).
+    let (bcvec, cell_counts) = match groups {
+        Some(group_file) => {
+            let gfile = fs::File::open(group_file)
+                .with_context(|| format!("could not open the groups file {:?}", group_file))?;
+            let (group_names, group_counts) = pool_group_counts(
+                BufReader::new(gfile),
+                &barcodes,
+                cell_counts,
+                filter_bc.then_some(&retained_bc),
+                log,
+            )?;
+            num_cells = group_names.len();
+            filter_bc = false;
+            (group_names, group_counts)
+        }
+        None => (barcodes, cell_counts),
+    };
+    let nnz = cell_counts.iter().map(|c| c.len()).sum::<usize>();
+
     // the progress bar we'll use to monitor progress of the EM
     let pbar = ProgressBar::with_draw_target(
         Some(num_cells as u64),
@@ -147,6 +518,10 @@ pub fn infer(
             .progress_chars("╢▌▌░╟"),
     );
 
+    // create our output directory
+    let output_path = std::path::Path::new(output_dir);
+    fs::create_dir_all(output_path)?;
+
     // create a thread-safe queue based on the number of worker threads
     let n_workers = if num_threads > 1 {
         (num_threads - 1) as usize
@@ -154,19 +529,38 @@ pub fn infer(
         1
     };
     // the queue will hold tuples of the
-    // cell id (so that the output matrix is in the same order as input)
+    // cell id (so that the output matrix is in the same order as input),
+    // the key from which its random draws are seeded, and the
     // vector of eq_id and count for each cell
-    let q = Arc::new(ArrayQueue::<(usize, Vec<(u32, u32)>)>::new(4 * n_workers));
+    let q = Arc::new(ArrayQueue::<(usize, u64, Vec<(u32, u32)>)>::new(
+        4 * n_workers,
+    ));
 
     let mut thread_handles: Vec<thread::JoinHandle<_>> = Vec::with_capacity(n_workers);
 
     // the number of cells left to process
     let cells_to_process = Arc::new(AtomicUsize::new(num_cells));
-    // the output cell-by-gene matrix
-    let trimat = Arc::new(Mutex::new(TriMatI::<f32, u32>::with_capacity(
-        (num_cells, num_genes),
-        count_mat.nnz(),
-    )));
+    // the number of cells (or groups) whose EM did not converge
+    let num_unconverged = Arc::new(AtomicUsize::new(0));
+    // the output cell-by-gene matrix (and bootstraps)
+    let eds_file = if use_mtx {
+        None
+    } else {
+        Some(BufWriter::new(GzEncoder::new(
+            fs::File::create(output_path.join("quants_mat.gz"))?,
+            Compression::default(),
+        )))
+    };
+    let out_writer = Arc::new(Mutex::new(InferOutputInfo {
+        trimat: TriMatI::<f32, u32>::with_capacity(
+            (num_cells, num_genes),
+            if use_mtx { nnz } else { 0 },
+        ),
+        eds_file,
+        bootstrap_helper: BootstrapHelper::new(output_path, num_bootstraps, summary_stat),
+        next_cell: 0usize,
+        pending: BTreeMap::new(),
+    }));
 
     // for each worker, spawn off a thread
     for _worker in 0..n_workers {
@@ -176,13 +570,11 @@ pub fn infer(
         let log = log.clone();
         // and the atomic counter of remaining work
         let cells_remaining = cells_to_process.clone();
-        // and the output matrix
-        let matout = trimat.clone();
+        // and the output writer
+        let matout = out_writer.clone();
         // and the global set of eq class labels
         let global_eq_classes = global_eq_classes.clone();
-
-        //let unmapped_count = bc_unmapped_map.clone();
-        //let mmrate = mmrate.clone();
+        let num_unconverged = num_unconverged.clone();
 
         // now, make the worker thread
         let handle = std::thread::spawn(move || {
@@ -191,34 +583,42 @@ pub fn infer(
             let mut unique_evidence = vec![false; num_genes];
             let mut no_ambiguity = vec![false; num_genes];
 
-            let mut expressed_vec = Vec::<f32>::with_capacity(num_genes);
-            let mut expressed_ind = Vec::<usize>::with_capacity(num_genes);
-            //let mut eds_bytes = Vec::<u8>::new();
-            //let mut bt_eds_bytes: Vec<u8> = Vec::new();
-            //let mut eds_mean_bytes: Vec<u8> = Vec::new();
-            //let mut eds_var_bytes: Vec<u8> = Vec::new();
-
             // pop from the work queue until everything is
             // processed
             while cells_remaining.load(Ordering::SeqCst) > 0 {
-                if let Some((cell_num, cell_data)) = in_q.pop() {
+                if let Some((cell_num, cell_key, cell_data)) = in_q.pop() {
                     cells_remaining.fetch_sub(1, Ordering::SeqCst);
+                    // with a seed, the draws for this cell depend only on
+                    // the seed and the cell (or group) name.
+                    let mut rng = afutils::cell_rng(rng_seed, cell_key);
+                    // the pooled counts of a group can be large enough
+                    // that the tolerance must scale with them.
+                    let total_count = cell_data.iter().map(|(_, c)| *c as u64).sum::<u64>();
+                    let em_opts = base_em_opts.for_total_count(total_count);
 
                     // given the set of equivalence classes and counts for
                     // this cell (coming from the input matrix), perform
                     // inference to obtain gene-level counts.
-                    let counts = em_optimize_subset(
+                    let (counts, em_stats) = em_optimize_subset(
                         &global_eq_classes,
                         &cell_data,
                         &mut unique_evidence,
                         &mut no_ambiguity,
                         EmInitType::Informative,
+                        &mut rng,
+                        &em_opts,
+                        None,
+                        None,
                         num_genes,
                         false,
                         usa_offsets,
                         &log,
                     );
 
+                    if !em_stats.converged {
+                        num_unconverged.fetch_add(1, Ordering::SeqCst);
+                    }
+
                     // Note: there is a fill method, but it is only on
                     // the nightly branch.  Use this for now:
                     unique_evidence.clear();
@@ -227,107 +627,79 @@ pub fn infer(
                     no_ambiguity.resize(num_genes, false);
                     // done clearing
 
-                    let mut max_umi = 0.0f32;
-                    let mut _sum_umi = 0.0f32;
-                    let mut _num_expr: u32 = 0;
-                    expressed_vec.clear();
-                    expressed_ind.clear();
-                    // go over the output counts and fill in the vector of
-                    // expressed gene ids and their corresponding counts
-                    for (gn, c) in counts.iter().enumerate() {
-                        max_umi = if *c > max_umi { *c } else { max_umi };
-                        _sum_umi += *c;
-                        if *c > 0.0 {
-                            _num_expr += 1;
-                            expressed_vec.push(*c);
-                            expressed_ind.push(gn);
+                    // if the user requested bootstraps, resample this cell's
+                    // equivalence class counts as `quant` does.
+                    let bootstraps = if num_bootstraps == 0 {
+                        Vec::new()
+                    } else if cell_data.is_empty() {
+                        // there is nothing to resample, so there is no
+                        // variation.
+                        if summary_stat {
+                            vec![counts.clone(), vec![0f32; num_genes]]
+                        } else {
+                            vec![counts.clone(); num_bootstraps as usize]
                         }
-                    }
-
-                    /*
-                    let num_mapped = nrec;
-                    let dedup_rate = sum_umi / num_mapped as f32;
-
-                    let num_unmapped = match unmapped_count.get(&bc) {
-                    Some(nu) => *nu,
-                    None => 0u32,
+                    } else {
+                        run_bootstrap_subset(
+                            &global_eq_classes,
+                            &cell_data,
+                            num_genes as u32,
+                            num_bootstraps,
+                            false,
+                            summary_stat,
+                            &mut rng,
+                            &em_opts,
+                            None,
+                            usa_offsets,
+                            &log,
+                        )
                     };
 
-                    let mapping_rate = num_mapped as f32 / (num_mapped + num_unmapped) as f32;
-
-                    // mean of the "expressed" genes
-                    let mean_expr = sum_umi / num_expr as f32;
-                    // number of genes with expression > expressed mean
-                    let num_genes_over_mean = expressed_vec.iter().fold(0u32, |acc, x| {
-                    if x > &mean_expr {
-                        acc + 1u32
+                    let cell_counts = if use_mtx {
+                        // the (gene index, count) pairs of the expressed genes
+                        CellCounts::Sparse(
+                            counts
+                                .iter()
+                                .enumerate()
+                                .filter(|(_, c)| **c > 0.0)
+                                .map(|(gn, c)| (gn, *c))
+                                .collect(),
+                        )
                     } else {
-                        acc
-                    }
-                    });
-                    // expressed mean / max expression
-                    let mean_by_max = mean_expr / max_umi;
-                    */
-
-                    let row_index: usize; // the index for this row (cell)
-                    {
-                        row_index = cell_num;
-                        let writer_deref = matout.lock();
-                        let writer = &mut *writer_deref.unwrap();
-
-                        // fill out the triplet matrix in memory
-                        for (ind, val) in expressed_ind.iter().zip(expressed_vec.iter()) {
-                            writer.add_triplet(row_index, *ind, *val);
-                        }
-                        /*
-                        writeln!(
-                            &mut writer.feature_file,
-                            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-                            unsafe { std::str::from_utf8_unchecked(&bc_bytes) },
-                            (num_mapped + num_unmapped),
-                            num_mapped,
-                            sum_umi,
-                            mapping_rate,
-                            dedup_rate,
-                            mean_by_max,
-                            num_expr,
-                            num_genes_over_mean
+                        CellCounts::Eds(
+                            sce::eds::as_bytes(&counts, num_genes)
+                                .expect("can't convert vector to eds"),
                         )
-                        .expect("can't write to feature file");
-                            */
-                        /*
-                        if num_bootstraps > 0 {
-                            if summary_stat {
-                            if let Some((meanf, varf)) =
-                                &mut writer.bootstrap_helper.mean_var_files
-                            {
-                                meanf
-                                .write_all(&eds_mean_bytes)
-                                .expect("can't write to bootstrap mean file.");
-                                varf.write_all(&eds_var_bytes)
-                                .expect("can't write to bootstrap var file.");
-                            }
-                            } else if let Some(bsfile) = &mut writer.bootstrap_helper.bsfile {
-                            bsfile
-                                .write_all(&bt_eds_bytes)
-                                .expect("can't write to bootstrap file");
-                            }
-                        } // done bootstrap writing
-                        */
-                    }
+                    };
+                    let bootstrap_eds = bootstraps
+                        .iter()
+                        .map(|b| {
+                            sce::eds::as_bytes(b, num_genes).expect("can't convert vector to eds")
+                        })
+                        .collect::<Vec<Vec<u8>>>();
+
+                    let writer_deref = matout.lock();
+                    let writer = &mut *writer_deref.unwrap();
+                    writer.submit_cell(
+                        cell_num,
+                        InferCellOutput {
+                            counts: cell_counts,
+                            bootstrap_eds,
+                        },
+                    );
                 } // while we can get work
             } // while cells remain
         });
         thread_handles.push(handle);
     }
 
-    // create our output directory
-    let output_path = std::path::Path::new(output_dir);
-    fs::create_dir_all(output_path)?;
-
-    let in_col_path = count_mat_parent.join("quants_mat_cols.txt");
     let out_col_path = output_path.join("quants_mat_cols.txt");
-    fs::copy(in_col_path, out_col_path).expect("could not copy column (gene) names to output");
+    let mut col_writer = BufWriter::new(
+        fs::File::create(out_col_path).expect("couldn't create output column (gene) name file"),
+    );
+    for name in col_names.iter() {
+        writeln!(&mut col_writer, "{}", name).expect("can't write to column (gene) name file.");
+    }
 
     let out_barcode_path = output_path.join("quants_mat_rows.txt");
     let out_bc_file =
@@ -335,13 +707,10 @@ pub fn infer(
     let mut bc_writer = BufWriter::new(out_bc_file);
 
     let mut processed_ind = 0_usize;
-    let short_bc_len = bc_len as u8;
-    // iterate over the rows (cells) of the equivalence class count
-    // matrix.  The iterator will yield a pair of the row index and
-    // an iterator over the row data.
+    // iterate over the rows (cells) of the equivalence class counts.
     // we zip the bcvec iterator (vector of barcodes in row order)
-    // with the actual rows of the matrix.
-    for (barcode, row_vec) in bcvec.iter().zip(count_mat.outer_iterator()) {
+    // with the (eq_id, count) tuples of each row.
+    for (barcode, cell_data) in bcvec.iter().zip(cell_counts) {
         let process_cell = if filter_bc {
             // if the reatined_bc list contains this cell id
             // then process it
@@ -351,22 +720,11 @@ pub fn infer(
         };
         if process_cell {
             // write to barcode file
-            let bc_bytes = &bitmer_to_bytes((*barcode, short_bc_len))[..];
-            writeln!(&mut bc_writer, "{}", unsafe {
-                std::str::from_utf8_unchecked(bc_bytes)
-            })
-            .expect("can't write to barcode file.");
-
-            // gather the data from the row's iterator into a vector of
-            // (eq_id, count) tuples.
-            // NOTE: because of the matrix market format requirements (and the compatible
-            // implmentation in sprs), we have read the matrix in with i32 instead of u32 entries.
-            // Here we convert the i32 counts (the second element of the tuple here) to u32.
-            let cell_data: Vec<(u32, u32)> =
-                row_vec.iter().map(|e| (e.0 as u32, *e.1 as u32)).collect();
+            writeln!(&mut bc_writer, "{}", barcode).expect("can't write to barcode file.");
+
             // keep pushing this data onto our work queue while we can.
             // launch off these cells on the queue
-            let mut cd_clone = (processed_ind, cell_data.clone());
+            let mut cd_clone = (processed_ind, row_key(barcode), cell_data);
             // keep trying until we can push this payload
             while let Err(t) = q.push(cd_clone) {
                 cd_clone = t;
@@ -389,13 +747,236 @@ pub fn infer(
 
     let pb_msg = format!("finished quantifying {} cells.", num_cells);
     pbar.finish_with_message(pb_msg);
+    let num_unconverged = num_unconverged.load(Ordering::SeqCst);
+    if num_unconverged > 0 {
+        warn!(
+            log,
+            "the EM of {} of {} rows did not converge within {} iterations.",
+            num_unconverged,
+            num_cells,
+            em_max_iter
+        );
+    }
 
+    let writer_deref = out_writer.lock();
+    let writer = &mut *writer_deref.unwrap();
+    writer.finish()?;
     // finally, we write our output matrix of gene
     // counts.
-    let output_matrix_path = output_path.join("quants_mat.mtx");
-    let writer_deref = trimat.lock();
-    let writer = &*writer_deref.unwrap();
-    sprs::io::write_matrix_market(output_matrix_path, writer)?;
+    if use_mtx {
+        let output_matrix_path = output_path.join("quants_mat.mtx");
+        sprs::io::write_matrix_market(output_matrix_path, &writer.trimat)?;
+    }
 
     Ok(())
 }
+
+/// The output computed by `infer` for a single cell.
+struct InferCellOutput {
+    counts: CellCounts,
+    // the EDS encoded bootstraps (or their mean and variance)
+    bootstrap_eds: Vec<Vec<u8>>,
+}
+
+/// The output of `infer`.  The cells are written in the order in which
+/// they appear in the input, as the barcodes of `quants_mat_rows.txt`.
+struct InferOutputInfo {
+    trimat: TriMatI<f32, u32>,
+    // the EDS encoded count matrix, if not writing matrix market output
+    eds_file: Option<BufferedGzFile>,
+    bootstrap_helper: BootstrapHelper,
+    // the next cell (in input order) to be written
+    next_cell: usize,
+    // finished cells waiting for the cells preceding them
+    pending: BTreeMap<usize, InferCellOutput>,
+}
+
+impl InferOutputInfo {
+    /// Submit the output of the cell `cell_num`, which is held until all
+    /// of the cells preceding it have been written.
+    fn submit_cell(&mut self, cell_num: usize, cell: InferCellOutput) {
+        self.pending.insert(cell_num, cell);
+        while let Some(cell) = self.pending.remove(&self.next_cell) {
+            self.write_cell(cell);
+            self.next_cell += 1;
+        }
+    }
+
+    /// Write the output of `cell` as the next row of the output.
+    fn write_cell(&mut self, cell: InferCellOutput) {
+        let row_index = self.next_cell;
+        match cell.counts {
+            CellCounts::Eds(bytes) => {
+                if let Some(eds_file) = &mut self.eds_file {
+                    eds_file
+                        .write_all(&bytes)
+                        .expect("can't write to matrix file.");
+                }
+            }
+            CellCounts::Sparse(entries) => {
+                // fill out the triplet matrix in memory
+                for (ind, val) in entries {
+                    self.trimat.add_triplet(row_index, ind, val);
+                }
+            }
+        }
+
+        if let Some((meanf, varf)) = &mut self.bootstrap_helper.mean_var_files {
+            if let [mean_bytes, var_bytes] = &cell.bootstrap_eds[..] {
+                meanf
+                    .write_all(mean_bytes)
+                    .expect("can't write to bootstrap mean file.");
+                varf.write_all(var_bytes)
+                    .expect("can't write to bootstrap var file.");
+            }
+        } else if let Some(bsfile) = &mut self.bootstrap_helper.bsfile {
+            for bt_eds_bytes in &cell.bootstrap_eds {
+                bsfile
+                    .write_all(bt_eds_bytes)
+                    .expect("can't write to bootstrap file");
+            }
+        }
+    }
+
+    /// Flush the EDS encoded outputs.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some(eds_file) = &mut self.eds_file {
+            eds_file.flush()?;
+        }
+        if let Some((meanf, varf)) = &mut self.bootstrap_helper.mean_var_files {
+            meanf.flush()?;
+            varf.flush()?;
+        }
+        if let Some(bsfile) = &mut self.bootstrap_helper.bsfile {
+            bsfile.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn test_logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn names(n: &[&str]) -> Vec<String> {
+        n.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_read_eds_rows_round_trip() {
+        // 11 columns, so that the flags of a row don't fill whole bytes
+        let num_cols = 11;
+        let rows: Vec<Vec<f32>> = vec![
+            vec![0.0, 3.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 7.0],
+            vec![0.0; 11],
+            vec![2.6, 0.0, 0.4, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 5.0, 0.0],
+        ];
+        let mut bytes = Vec::new();
+        for r in &rows {
+            bytes.extend(sce::eds::as_bytes(r, num_cols).unwrap());
+        }
+
+        let read = read_eds_rows(Cursor::new(bytes.clone()), rows.len(), num_cols).unwrap();
+        assert_eq!(
+            read,
+            vec![
+                vec![(1, 3), (8, 1), (10, 7)],
+                vec![],
+                // the values are rounded, and those rounding to 0 dropped
+                vec![(0, 3), (9, 5)],
+            ]
+        );
+
+        // a truncated matrix is an error
+        bytes.pop();
+        assert!(read_eds_rows(Cursor::new(bytes), rows.len(), num_cols).is_err());
+    }
+
+    #[test]
+    fn test_row_key_is_stable() {
+        // the 64-bit FNV-1a hashes of these names
+        assert_eq!(row_key(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(row_key("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_ne!(row_key("ACGT"), row_key("ACGA"));
+    }
+
+    #[test]
+    fn test_pool_group_counts() {
+        let groups = "AAAA\tT cells\nCCCC\tB cells\n\nGGGG\tT cells\nNOTINPUT\tB cells\n";
+        let barcodes = names(&["AAAA", "CCCC", "GGGG", "TTTT"]);
+        let cell_counts = vec![
+            vec![(0, 2), (3, 1)],
+            vec![(1, 5)],
+            vec![(3, 4), (7, 1)],
+            vec![(0, 100)],
+        ];
+        let (group_names, pooled) = pool_group_counts(
+            Cursor::new(groups),
+            &barcodes,
+            cell_counts.clone(),
+            None,
+            &test_logger(),
+        )
+        .unwrap();
+        assert_eq!(group_names, names(&["T cells", "B cells"]));
+        assert_eq!(pooled[0], vec![(0, 2), (3, 5), (7, 1)]);
+        assert_eq!(pooled[1], vec![(1, 5)]);
+
+        // only the retained cells are pooled
+        let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+        let mut retained: HashSet<String, ahash::RandomState> = HashSet::with_hasher(s);
+        retained.insert("GGGG".to_string());
+        let (_, pooled) = pool_group_counts(
+            Cursor::new(groups),
+            &barcodes,
+            cell_counts,
+            Some(&retained),
+            &test_logger(),
+        )
+        .unwrap();
+        assert_eq!(pooled[0], vec![(3, 4), (7, 1)]);
+        assert!(pooled[1].is_empty());
+    }
+
+    #[test]
+    fn test_pool_group_counts_rejects_bad_input() {
+        let barcodes = names(&["AAAA", "CCCC"]);
+        let cell_counts = vec![vec![(0, u32::MAX)], vec![(0, 1)]];
+
+        // a barcode listed twice
+        let dup = "AAAA\tx\nCCCC\ty\nAAAA\ty\n";
+        assert!(pool_group_counts(
+            Cursor::new(dup),
+            &barcodes,
+            cell_counts.clone(),
+            None,
+            &test_logger()
+        )
+        .is_err());
+
+        // a line without a group
+        assert!(pool_group_counts(
+            Cursor::new("AAAA\n"),
+            &barcodes,
+            cell_counts.clone(),
+            None,
+            &test_logger()
+        )
+        .is_err());
+
+        // a pooled count that overflows
+        assert!(pool_group_counts(
+            Cursor::new("AAAA\tx\nCCCC\tx\n"),
+            &barcodes,
+            cell_counts,
+            None,
+            &test_logger()
+        )
+        .is_err());
+    }
+}
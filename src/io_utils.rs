@@ -2,20 +2,250 @@
 use anyhow::Context;
 use crossbeam_queue::ArrayQueue;
 use indicatif::ProgressBar;
-use scroll::Pwrite;
+use scroll::{Pread, Pwrite};
 
+use crate::errors::AlevinFryError;
 use libradicl::rad_types;
 use std::collections::HashSet;
-use std::io::Read;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 pub(crate) type MetaChunk = (usize, usize, u32, u32, Vec<u8>);
 
+/// The name of the read-level tag holding the cell barcode of each record.
+pub(crate) const BARCODE_TAG: &str = "b";
+/// The name of the read-level tag holding the UMI of each record.
+pub(crate) const UMI_TAG: &str = "u";
+/// The RAD type id of a `u8`.
+const RAD_U8_TYPEID: u8 = 1;
+
+/// The number of bytes taken by an integer of RAD type `t`.
+pub(crate) fn int_type_bytes(t: &rad_types::RadIntId) -> usize {
+    match t {
+        rad_types::RadIntId::U8 => 1,
+        rad_types::RadIntId::U16 => 2,
+        rad_types::RadIntId::U32 => 4,
+        rad_types::RadIntId::U64 => 8,
+    }
+}
+
+/// The types of the barcode and UMI of each read record of a RAD file,
+/// found by looking up the read-level tags by name.  Files from protocols
+/// without UMIs (e.g. plate-based Smart-seq2) have no UMI tag, and their
+/// records hold no UMI.
+#[derive(Clone, Copy)]
+pub(crate) struct RecordTypes {
+    pub bc_type: rad_types::RadIntId,
+    pub umi_type: Option<rad_types::RadIntId>,
+}
+
+impl RecordTypes {
+    /// Look up the barcode and (optional) UMI tags in the read-level
+    /// tags `rl_tags`.
+    pub fn from_tags(rl_tags: &rad_types::TagSection) -> Result<RecordTypes, AlevinFryError> {
+        let decode = |name: &str| -> Result<Option<rad_types::RadIntId>, AlevinFryError> {
+            match rl_tags.tags.iter().find(|t| t.name == name) {
+                Some(t) => rad_types::decode_int_type_tag(t.typeid)
+                    .map(Some)
+                    .ok_or_else(|| AlevinFryError::UnsupportedTagType {
+                        tag: t.name.clone(),
+                        typeid: t.typeid,
+                    }),
+                None => Ok(None),
+            }
+        };
+        let bc_type = decode(BARCODE_TAG)?
+            .ok_or_else(|| AlevinFryError::MissingTag(BARCODE_TAG.to_string()))?;
+        let umi_type = decode(UMI_TAG)?;
+        Ok(RecordTypes { bc_type, umi_type })
+    }
+
+    /// Returns true if the records hold a UMI.
+    pub fn has_umi(&self) -> bool {
+        self.umi_type.is_some()
+    }
+}
+
+/// Read an integer of RAD type `t` from `reader`.
+fn read_int<R: Read>(reader: &mut R, t: &rad_types::RadIntId) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf[..int_type_bytes(t)])?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Read the next chunk (the records of one cell, in a collated file) from
+/// `reader`.  If the records hold no UMI, the `umi` of every record is 0.
+pub(crate) fn read_chunk<R: Read>(
+    reader: &mut R,
+    types: &RecordTypes,
+) -> std::io::Result<rad_types::Chunk> {
+    if let Some(umi_type) = &types.umi_type {
+        return Ok(rad_types::Chunk::from_bytes(
+            reader,
+            &types.bc_type,
+            umi_type,
+        ));
+    }
+
+    let mut hbuf = [0u8; 8];
+    reader.read_exact(&mut hbuf)?;
+    let nbytes = u32::from_le_bytes([hbuf[0], hbuf[1], hbuf[2], hbuf[3]]);
+    let nrec = u32::from_le_bytes([hbuf[4], hbuf[5], hbuf[6], hbuf[7]]);
+    let mut reads = Vec::with_capacity(nrec as usize);
+    let mut abuf = [0u8; 4];
+    for _ in 0..nrec {
+        reader.read_exact(&mut abuf)?;
+        let na = u32::from_le_bytes(abuf);
+        let bc = read_int(reader, &types.bc_type)?;
+        let mut dirs = Vec::with_capacity(na as usize);
+        let mut refs = Vec::with_capacity(na as usize);
+        for _ in 0..na {
+            reader.read_exact(&mut abuf)?;
+            // the orientation is held in the top bit of the reference id
+            let v = u32::from_le_bytes(abuf);
+            dirs.push(v & 0x8000_0000 != 0);
+            refs.push(v & 0x7FFF_FFFF);
+        }
+        reads.push(rad_types::ReadRecord {
+            bc,
+            umi: 0,
+            dirs,
+            refs,
+        });
+    }
+    Ok(rad_types::Chunk {
+        nbytes,
+        nrec,
+        reads,
+    })
+}
+
+/// The barcode of the first record of the chunk whose records begin at
+/// the start of `buf` (i.e. just after the chunk header).
+pub(crate) fn peek_barcode(buf: &[u8], types: &RecordTypes) -> u64 {
+    match &types.umi_type {
+        Some(umi_type) => rad_types::Chunk::peek_record(buf, &types.bc_type, umi_type).0,
+        None => {
+            // the record begins with the number of alignments (u32)
+            let mut bcbuf = [0u8; 8];
+            let nb = int_type_bytes(&types.bc_type);
+            bcbuf[..nb].copy_from_slice(&buf[4..4 + nb]);
+            u64::from_le_bytes(bcbuf)
+        }
+    }
+}
+
+/// The number of chunks in the RAD file at `path`, whose header records
+/// `hdr_num_chunks`.  A mapper writing to a named pipe can't seek back to
+/// fill in the chunk count of the header, which is then left as 0; in
+/// this case (and whenever the input is a named pipe), the count is
+/// unknown and `None` is returned.
+pub(crate) fn known_num_chunks<P: AsRef<Path>>(path: P, hdr_num_chunks: u64) -> Option<u64> {
+    if hdr_num_chunks == 0 || is_fifo(path) {
+        None
+    } else {
+        Some(hdr_num_chunks)
+    }
+}
+
+/// Returns true if there is no more input to read from `reader`.
+pub(crate) fn at_eof<R: BufRead>(reader: &mut R) -> std::io::Result<bool> {
+    Ok(reader.fill_buf()?.is_empty())
+}
+
+/// An iterator over the chunks of a RAD file, read from `reader` (which
+/// is positioned just after the header).  If the number of chunks is not
+/// known (see [`known_num_chunks`]), chunks are read until the end of
+/// the input.
+pub(crate) struct ChunkReader<'a, R: BufRead> {
+    reader: &'a mut R,
+    types: RecordTypes,
+    remaining: Option<u64>,
+}
+
+impl<'a, R: BufRead> ChunkReader<'a, R> {
+    pub fn new(reader: &'a mut R, types: RecordTypes, num_chunks: Option<u64>) -> Self {
+        ChunkReader {
+            reader,
+            types,
+            remaining: num_chunks,
+        }
+    }
+}
+
+impl<'a, R: BufRead> Iterator for ChunkReader<'a, R> {
+    type Item = std::io::Result<rad_types::Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.remaining {
+            Some(0) => return None,
+            Some(n) => self.remaining = Some(n - 1),
+            None => match at_eof(self.reader) {
+                Ok(true) => return None,
+                Ok(false) => {}
+                Err(e) => return Some(Err(e)),
+            },
+        }
+        Some(read_chunk(self.reader, &self.types))
+    }
+}
+
+/// Given a chunk `chunk` (with its header) of records that hold no UMI,
+/// return the same chunk with a UMI of type `u8`, equal to 0, inserted
+/// after the barcode of each record.  This lets collate write the records
+/// of files without UMIs through the same (UMI-aware) path as all others.
+pub(crate) fn add_zero_umis(
+    chunk: &[u8],
+    bc_type: &rad_types::RadIntId,
+) -> anyhow::Result<Vec<u8>> {
+    let nbytes = chunk.pread::<u32>(0)?;
+    let nrec = chunk.pread::<u32>(4)?;
+    let bc_bytes = int_type_bytes(bc_type);
+    let mut out = Vec::with_capacity(nbytes as usize + nrec as usize);
+    out.extend_from_slice(&(nbytes + nrec).to_le_bytes());
+    out.extend_from_slice(&nrec.to_le_bytes());
+    let mut offset = 8usize;
+    for _ in 0..nrec {
+        let na = chunk.pread::<u32>(offset)? as usize;
+        let head_end = offset + 4 + bc_bytes;
+        let rec_end = head_end + 4 * na;
+        if rec_end > chunk.len() {
+            anyhow::bail!("truncated RAD record in chunk of {} bytes", nbytes);
+        }
+        out.extend_from_slice(&chunk[offset..head_end]);
+        out.push(0u8);
+        out.extend_from_slice(&chunk[head_end..rec_end]);
+        offset = rec_end;
+    }
+    Ok(out)
+}
+
+/// Given the bytes `header` of a RAD header whose read-level tag section
+/// spans `rl_tags` and has no UMI tag, return the header with a UMI tag of
+/// type `u8` appended to that section (see [`add_zero_umis`]).
+pub(crate) fn add_umi_tag(header: &[u8], rl_tags: std::ops::Range<usize>) -> Vec<u8> {
+    let num_tags = u16::from_le_bytes([header[rl_tags.start], header[rl_tags.start + 1]]);
+    let mut out = Vec::with_capacity(header.len() + 4 + UMI_TAG.len());
+    out.extend_from_slice(&header[..rl_tags.start]);
+    out.extend_from_slice(&(num_tags + 1).to_le_bytes());
+    out.extend_from_slice(&header[(rl_tags.start + 2)..rl_tags.end]);
+    // a tag is its name (prefixed by its u16 length) and its type id
+    out.extend_from_slice(&(UMI_TAG.len() as u16).to_le_bytes());
+    out.extend_from_slice(UMI_TAG.as_bytes());
+    out.push(RAD_U8_TYPEID);
+    out.extend_from_slice(&header[rl_tags.end..]);
+    out
+}
+
 pub(crate) fn fill_work_queue<T: Read>(
     q: Arc<ArrayQueue<MetaChunk>>,
     mut br: T,
     num_chunks: usize,
     pbar: &ProgressBar,
+    failed: &AtomicBool,
 ) -> anyhow::Result<()> {
     const BUFSIZE: usize = 524208;
     // the buffer that will hold our records
@@ -86,8 +316,13 @@ pub(crate) fn fill_work_queue<T: Read>(
             // keep trying until we can push this payload
             while let Err(t) = q.push(bclone) {
                 bclone = t;
-                // no point trying to push if the queue is full
-                while q.is_full() {}
+                // no point trying to push if the queue is full, or
+                // if the worker threads have stopped
+                while q.is_full() {
+                    if failed.load(Ordering::SeqCst) {
+                        return Ok(());
+                    }
+                }
             }
             pbar.inc(cells_in_chunk as u64);
 
@@ -109,17 +344,13 @@ pub(crate) fn fill_work_queue<T: Read>(
 /// any cell whose barcode is not in `keep_set`.
 pub(crate) fn fill_work_queue_filtered<T: Read>(
     keep_set: HashSet<u64, ahash::RandomState>,
-    rl_tags: &rad_types::TagSection,
+    types: &RecordTypes,
     q: Arc<ArrayQueue<MetaChunk>>,
     mut br: T,
     num_chunks: usize,
     pbar: &ProgressBar,
+    failed: &AtomicBool,
 ) -> anyhow::Result<()> {
-    let bct = rl_tags.tags[0].typeid;
-    let umit = rl_tags.tags[1].typeid;
-    let bc_type = rad_types::decode_int_type_tag(bct).context("unsupported barcode type id.")?;
-    let umi_type = rad_types::decode_int_type_tag(umit).context("unsupported umi type id.")?;
-
     const BUFSIZE: usize = 524208;
     // the buffer that will hold our records
     let mut buf = vec![0u8; BUFSIZE];
@@ -164,8 +395,7 @@ pub(crate) fn fill_work_queue_filtered<T: Read>(
             br.read_exact(&mut buf[(boffset + 8)..(boffset + nbytes_chunk as usize)])
                 .context("failed to read from queue.")?;
             // get the barcode for this chunk
-            let (bc, _umi) =
-                rad_types::Chunk::peek_record(&buf[boffset + 8..], &bc_type, &umi_type);
+            let bc = peek_barcode(&buf[boffset + 8..], types);
             if keep_set.contains(&bc) {
                 cells_in_chunk += 1;
                 cbytes += nbytes_chunk;
@@ -198,8 +428,13 @@ pub(crate) fn fill_work_queue_filtered<T: Read>(
             // keep trying until we can push this payload
             while let Err(t) = q.push(bclone) {
                 bclone = t;
-                // no point trying to push if the queue is full
-                while q.is_full() {}
+                // no point trying to push if the queue is full, or
+                // if the worker threads have stopped
+                while q.is_full() {
+                    if failed.load(Ordering::SeqCst) {
+                        return Ok(());
+                    }
+                }
             }
             pbar.inc(cells_in_chunk as u64);
 
@@ -215,3 +450,428 @@ pub(crate) fn fill_work_queue_filtered<T: Read>(
     }
     Ok(())
 }
+
+/// The compression codecs with which an input RAD file may be compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RadCodec {
+    Raw,
+    Gzip,
+    Zstd,
+    Snappy,
+}
+
+/// The names under which the (uncollated) input RAD file is searched for
+/// in the mapping directory, in order of preference.
+pub const INPUT_RAD_NAMES: &[&str] = &["map.rad", "map.rad.zst", "map.rad.gz", "map.rad.sz"];
+
+impl RadCodec {
+    /// Determine the codec from the leading (magic) bytes of a file.
+    /// Returns `None` if the bytes don't match any compressed format.  Since
+    /// a raw RAD file begins with the `is_paired` byte (0 or 1), it can't be
+    /// mistaken for any of the compressed formats.
+    pub fn from_magic(bytes: &[u8]) -> Option<RadCodec> {
+        const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+        const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+        // the stream identifier chunk of the snappy framing format
+        const SNAPPY_MAGIC: &[u8] = &[0xff, 0x06, 0x00, 0x00, b's', b'N', b'a', b'P', b'p', b'Y'];
+        if bytes.starts_with(ZSTD_MAGIC) {
+            Some(RadCodec::Zstd)
+        } else if bytes.starts_with(SNAPPY_MAGIC) {
+            Some(RadCodec::Snappy)
+        } else if bytes.starts_with(GZIP_MAGIC) {
+            Some(RadCodec::Gzip)
+        } else {
+            None
+        }
+    }
+
+    /// Determine the codec from the extension of `path`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> RadCodec {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("gz") => RadCodec::Gzip,
+            Some("zst") => RadCodec::Zstd,
+            Some("sz") => RadCodec::Snappy,
+            _ => RadCodec::Raw,
+        }
+    }
+}
+
+/// Returns the path of the input RAD file in the mapping directory `dir`;
+/// the first of `map.rad`, `map.rad.zst`, `map.rad.gz` and `map.rad.sz`
+/// that exists.
+pub fn find_input_rad<P: AsRef<Path>>(dir: P) -> Option<PathBuf> {
+    INPUT_RAD_NAMES
+        .iter()
+        .map(|n| dir.as_ref().join(n))
+        .find(|p| p.exists())
+}
+
+/// Open the (possibly compressed) RAD file at `path` for reading, returning
+/// a reader over the decompressed contents and the codec that was used.
+/// The codec is determined from the magic bytes at the start of the file,
+/// or, if there are too few bytes available to do so (e.g. because the
+/// file is a named pipe that has not yet been written), from its extension.
+pub fn open_rad_input<P: AsRef<Path>>(path: P) -> std::io::Result<(Box<dyn Read>, RadCodec)> {
+    let f = File::open(path.as_ref())?;
+    let mut br = BufReader::with_capacity(1 << 16, f);
+    let magic = br.fill_buf()?;
+    let codec = match RadCodec::from_magic(magic) {
+        Some(c) => c,
+        None if magic.len() >= 10 => RadCodec::Raw,
+        None => RadCodec::from_path(path.as_ref()),
+    };
+    Ok((decode_with(codec, br)?, codec))
+}
+
+/// A reader over the contents of `br`, decompressed with `codec`.
+fn decode_with<R: BufRead + 'static>(codec: RadCodec, br: R) -> std::io::Result<Box<dyn Read>> {
+    let reader: Box<dyn Read> = match codec {
+        RadCodec::Raw => Box::new(br),
+        RadCodec::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(br)),
+        RadCodec::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(br)?),
+        RadCodec::Snappy => Box::new(snap::read::FrameDecoder::new(br)),
+    };
+    Ok(reader)
+}
+
+/// Open the file at `path`, which is compressed with `codec`, for reading.
+pub fn open_with_codec<P: AsRef<Path>>(path: P, codec: RadCodec) -> std::io::Result<Box<dyn Read>> {
+    let f = File::open(path.as_ref())?;
+    decode_with(codec, BufReader::with_capacity(1 << 16, f))
+}
+
+/// Append `bytes` to `w` as an independently compressed block (a gzip
+/// member, a zstd frame compressed at `zstd_level`, or a snappy stream),
+/// so that a file written block by block decompresses as one stream.
+pub fn write_block<W: Write>(
+    w: &mut W,
+    bytes: &[u8],
+    codec: RadCodec,
+    zstd_level: i32,
+) -> std::io::Result<()> {
+    match codec {
+        RadCodec::Raw => w.write_all(bytes),
+        RadCodec::Gzip => {
+            let mut enc = flate2::write::GzEncoder::new(&mut *w, flate2::Compression::default());
+            enc.write_all(bytes)?;
+            enc.finish()?;
+            Ok(())
+        }
+        RadCodec::Zstd => zstd::stream::copy_encode(bytes, &mut *w, zstd_level),
+        RadCodec::Snappy => {
+            let mut enc = snap::write::FrameEncoder::new(&mut *w);
+            enc.write_all(bytes)?;
+            enc.flush()
+        }
+    }
+}
+
+/// Returns true if `path` refers to a named pipe (FIFO), which can
+/// only be read once.
+pub fn is_fifo<P: AsRef<Path>>(path: P) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        std::fs::metadata(path)
+            .map(|md| md.file_type().is_fifo())
+            .unwrap_or(false)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// A reader that (optionally) copies every byte read from the underlying
+/// reader into a snappy-compressed spill file.  This allows a RAD stream
+/// that can only be read once (e.g. from a named pipe) to be processed as
+/// it is read, while retaining a copy from which it can be read again.
+pub struct SpillingReader<R: Read> {
+    inner: R,
+    spill: Option<(PathBuf, snap::write::FrameEncoder<BufWriter<File>>)>,
+}
+
+impl<R: Read> SpillingReader<R> {
+    /// A reader that reads from `inner` without spilling.
+    pub fn new(inner: R) -> Self {
+        SpillingReader { inner, spill: None }
+    }
+
+    /// A reader that reads from `inner`, copying everything that is read
+    /// to the spill file `spill_path`.
+    pub fn with_spill(inner: R, spill_path: &Path) -> std::io::Result<Self> {
+        let f = File::create(spill_path)?;
+        Ok(SpillingReader {
+            inner,
+            spill: Some((
+                spill_path.to_path_buf(),
+                snap::write::FrameEncoder::new(BufWriter::new(f)),
+            )),
+        })
+    }
+
+    /// Read any remaining input (so that the spill file contains a complete
+    /// copy of it), and flush the spill file.  Returns the path of the spill
+    /// file, if there is one.
+    pub fn finish(mut self) -> std::io::Result<Option<PathBuf>> {
+        if self.spill.is_none() {
+            return Ok(None);
+        }
+        std::io::copy(&mut self, &mut std::io::sink())?;
+        let (path, mut enc) = self.spill.take().unwrap();
+        enc.flush()?;
+        Ok(Some(path))
+    }
+}
+
+impl<R: Read> Read for SpillingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some((_, enc)) = self.spill.as_mut() {
+            enc.write_all(&buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+/// A reader that keeps a copy of every byte read through it.  This is used
+/// to capture the header of a RAD file (which must be copied to the output
+/// of collate) when the input can not be re-read or seeked, e.g. because
+/// it is compressed or is not a regular file.
+pub struct RecordingReader<R: Read> {
+    inner: R,
+    recorded: Vec<u8>,
+}
+
+impl<R: Read> RecordingReader<R> {
+    pub fn new(inner: R) -> Self {
+        RecordingReader {
+            inner,
+            recorded: Vec::new(),
+        }
+    }
+
+    /// The number of bytes read so far.
+    pub fn num_read(&self) -> usize {
+        self.recorded.len()
+    }
+
+    /// The bytes read so far.
+    pub fn into_recorded(self) -> Vec<u8> {
+        self.recorded
+    }
+}
+
+impl<R: Read> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// the bytes of a tag section holding the tags `tags` (name, type id).
+    fn tag_section_bytes(tags: &[(&str, u8)]) -> Vec<u8> {
+        let mut b = (tags.len() as u16).to_le_bytes().to_vec();
+        for (name, typeid) in tags {
+            b.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            b.extend_from_slice(name.as_bytes());
+            b.push(*typeid);
+        }
+        b
+    }
+
+    /// a barcode-only (u32 barcodes) chunk holding, for each barcode,
+    /// one record with the given references.
+    fn barcode_only_chunk(records: &[(u32, Vec<u32>)]) -> Vec<u8> {
+        let mut recs = Vec::<u8>::new();
+        for (bc, refs) in records {
+            recs.extend_from_slice(&(refs.len() as u32).to_le_bytes());
+            recs.extend_from_slice(&bc.to_le_bytes());
+            for r in refs {
+                recs.extend_from_slice(&r.to_le_bytes());
+            }
+        }
+        let mut chunk = ((recs.len() + 8) as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(&(records.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(&recs);
+        chunk
+    }
+
+    #[test]
+    fn test_record_types_without_umi_tag() {
+        let tb = tag_section_bytes(&[("b", 3)]);
+        let tags = rad_types::TagSection::from_bytes(&mut Cursor::new(tb));
+        let types = RecordTypes::from_tags(&tags).unwrap();
+        assert!(matches!(types.bc_type, rad_types::RadIntId::U32));
+        assert!(!types.has_umi());
+
+        // the tags are found by name, not position
+        let tb = tag_section_bytes(&[("u", 2), ("b", 4)]);
+        let tags = rad_types::TagSection::from_bytes(&mut Cursor::new(tb));
+        let types = RecordTypes::from_tags(&tags).unwrap();
+        assert!(matches!(types.bc_type, rad_types::RadIntId::U64));
+        assert!(matches!(types.umi_type, Some(rad_types::RadIntId::U16)));
+
+        let tb = tag_section_bytes(&[("u", 2)]);
+        let tags = rad_types::TagSection::from_bytes(&mut Cursor::new(tb));
+        assert!(matches!(
+            RecordTypes::from_tags(&tags),
+            Err(AlevinFryError::MissingTag(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_barcode_only_chunk() {
+        let types = RecordTypes {
+            bc_type: rad_types::RadIntId::U32,
+            umi_type: None,
+        };
+        let chunk = barcode_only_chunk(&[(7, vec![1, 0x8000_0002]), (7, vec![3])]);
+        assert_eq!(peek_barcode(&chunk[8..], &types), 7);
+
+        let c = read_chunk(&mut Cursor::new(&chunk), &types).unwrap();
+        assert_eq!(c.nrec, 2);
+        assert_eq!(c.reads[0].bc, 7);
+        assert_eq!(c.reads[0].umi, 0);
+        assert_eq!(c.reads[0].refs, vec![1, 2]);
+        assert_eq!(c.reads[0].dirs, vec![false, true]);
+        assert_eq!(c.reads[1].refs, vec![3]);
+    }
+
+    #[test]
+    fn test_add_zero_umis() {
+        let chunk = barcode_only_chunk(&[(5, vec![1, 2]), (9, vec![0x8000_0004])]);
+        let padded = add_zero_umis(&chunk, &rad_types::RadIntId::U32).unwrap();
+        assert_eq!(padded.len(), chunk.len() + 2);
+        assert_eq!(padded.pread::<u32>(0).unwrap() as usize, padded.len());
+
+        let c = rad_types::Chunk::from_bytes(
+            &mut Cursor::new(&padded),
+            &rad_types::RadIntId::U32,
+            &rad_types::RadIntId::U8,
+        );
+        let bcs: Vec<u64> = c.reads.iter().map(|r| r.bc).collect();
+        assert_eq!(bcs, vec![5, 9]);
+        assert!(c.reads.iter().all(|r| r.umi == 0));
+        assert_eq!(c.reads[0].refs, vec![1, 2]);
+        assert_eq!(c.reads[1].refs, vec![4]);
+        assert_eq!(c.reads[1].dirs, vec![true]);
+    }
+
+    #[test]
+    fn test_write_block_round_trip() {
+        for codec in [
+            RadCodec::Raw,
+            RadCodec::Gzip,
+            RadCodec::Zstd,
+            RadCodec::Snappy,
+        ] {
+            let mut out = Vec::<u8>::new();
+            write_block(&mut out, b"first block;", codec, 3).unwrap();
+            write_block(&mut out, b"", codec, 3).unwrap();
+            write_block(&mut out, b" second block", codec, 3).unwrap();
+
+            let mut decoded = String::new();
+            decode_with(codec, Cursor::new(out))
+                .unwrap()
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, "first block; second block", "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn test_codec_from_magic() {
+        // the magic bytes written by each of the encoders are recognized
+        for codec in [RadCodec::Gzip, RadCodec::Zstd, RadCodec::Snappy] {
+            let mut out = Vec::<u8>::new();
+            write_block(&mut out, b"\x01some RAD bytes", codec, 3).unwrap();
+            assert_eq!(RadCodec::from_magic(&out), Some(codec), "{:?}", codec);
+        }
+
+        // a raw RAD file begins with the is_paired byte
+        assert_eq!(
+            RadCodec::from_magic(&[0u8, 2, 0, 0, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+        assert_eq!(
+            RadCodec::from_magic(&[1u8, 2, 0, 0, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+        // too few bytes to identify a format
+        assert_eq!(RadCodec::from_magic(&[]), None);
+        assert_eq!(RadCodec::from_magic(&[0x28, 0xb5]), None);
+        assert_eq!(RadCodec::from_magic(&[0xff, 0x06, 0x00]), None);
+
+        assert_eq!(RadCodec::from_path("map.rad"), RadCodec::Raw);
+        assert_eq!(RadCodec::from_path("map.rad.gz"), RadCodec::Gzip);
+        assert_eq!(RadCodec::from_path("map.rad.zst"), RadCodec::Zstd);
+        assert_eq!(RadCodec::from_path("map.rad.sz"), RadCodec::Snappy);
+    }
+
+    #[test]
+    fn test_spilling_reader() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+
+        // without a spill file, the reader just passes the data through
+        let mut plain = SpillingReader::new(Cursor::new(data.clone()));
+        let mut read_back = Vec::new();
+        plain.read_to_end(&mut read_back).unwrap();
+        assert_eq!(read_back, data);
+        assert_eq!(plain.finish().unwrap(), None);
+
+        // the spill file holds all of the input, including the part that
+        // was never read before finishing
+        let spill_path =
+            std::env::temp_dir().join(format!("alevin_fry_spill_{}.sz", std::process::id()));
+        let mut spilling =
+            SpillingReader::with_spill(Cursor::new(data.clone()), &spill_path).unwrap();
+        let mut head = vec![0u8; 100];
+        spilling.read_exact(&mut head).unwrap();
+        assert_eq!(head, data[..100]);
+        assert_eq!(spilling.finish().unwrap(), Some(spill_path.clone()));
+
+        let mut spilled = Vec::new();
+        let read_res = open_with_codec(&spill_path, RadCodec::Snappy)
+            .and_then(|mut r| r.read_to_end(&mut spilled));
+        std::fs::remove_file(&spill_path).unwrap();
+        read_res.unwrap();
+        assert_eq!(spilled, data);
+    }
+
+    #[test]
+    fn test_recording_reader() {
+        let mut rr = RecordingReader::new(Cursor::new(b"RAD header and chunks".to_vec()));
+        let mut head = [0u8; 10];
+        rr.read_exact(&mut head).unwrap();
+        assert_eq!(&head, b"RAD header");
+        assert_eq!(rr.num_read(), 10);
+        assert_eq!(rr.into_recorded(), b"RAD header".to_vec());
+    }
+
+    #[test]
+    fn test_add_umi_tag() {
+        let fl = tag_section_bytes(&[]);
+        let rl = tag_section_bytes(&[("b", 3)]);
+        let mut header = b"HDR".to_vec();
+        header.extend_from_slice(&fl);
+        let start = header.len();
+        header.extend_from_slice(&rl);
+        let end = header.len();
+        header.extend_from_slice(b"TAIL");
+
+        let with_umi = add_umi_tag(&header, start..end);
+        let mut expected = b"HDR".to_vec();
+        expected.extend_from_slice(&fl);
+        expected.extend_from_slice(&tag_section_bytes(&[("b", 3), ("u", 1)]));
+        expected.extend_from_slice(b"TAIL");
+        assert_eq!(with_umi, expected);
+    }
+}
@@ -0,0 +1,783 @@
+/*
+ * Copyright (c) 2020-2022 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+//! Methods for determining the read-count threshold that separates
+//! barcodes belonging to "true" cells from background barcodes, given
+//! the (reverse sorted) barcode frequency distribution.
+
+use crate::errors::AlevinFryError;
+use anyhow::{bail, Context};
+use serde::Serialize;
+use slog::info;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// Barcodes with this many reads or fewer are ignored when building the
+/// log-log barcode rank curve (this mirrors the `lower` parameter of
+/// `DropletUtils::barcodeRanks`).
+const RANK_CURVE_LOWER: u64 = 100;
+/// The first barcodes (by rank) are excluded from the search for the
+/// inflection point, since the head of the curve is very noisy.
+const RANK_CURVE_EXCLUDE_FROM: f64 = 50.0;
+/// The minimum number of distinct points the rank curve must have for
+/// the smoothing-based methods to be attempted.
+const MIN_RANK_CURVE_POINTS: usize = 10;
+
+struct Point {
+    x: f64,
+    y: f64,
+}
+
+/// compute the distance between the query point `Q`
+/// and the line defined by points `P1` and `P2`.  The
+/// formula used here is taken from :
+/// https://en.wikipedia.org/wiki/Distance_from_a_point_to_a_line
+fn distance_to_line(p1: &Point, p2: &Point, q: &Point) -> f64 {
+    let x_0 = q.x;
+    let y_0 = q.y;
+
+    let x_1 = p1.x;
+    let y_1 = p1.y;
+
+    let x_2 = p2.x;
+    let y_2 = p2.y;
+
+    let numer = ((y_2 - y_1) * x_0 - (x_2 - x_1) * y_0 + x_2 * y_1 - y_2 * x_1).abs();
+    let denom = ((y_2 - y_1).powi(2) + (x_2 - x_1).powi(2)).sqrt();
+    assert!(denom > 0.0f64);
+    numer / denom
+}
+
+/// This method is a implementation of the distance method
+/// used in umi_tools :
+///     Smith, Tom, Andreas Heger, and Ian Sudbery.
+///     "UMI-tools: modeling sequencing errors in Unique Molecular Identifiers to improve quantification accuracy."
+///     Genome research 27.3 (2017): 491-499.
+///
+/// though this is a re-implementation and uses the same basic algorithm
+/// the result may not be identical.
+///
+/// Given a list of cumulative frequencies, where the index of a
+/// point is interpreted as its x-coordinate and its frequency
+/// is interpreted as its y-coordinate, define the line L based on
+/// `sorted_frequences.first()` and `sorted_frequencies.last()`.  Compute
+/// the distance of each point from L, and return the index of the point
+/// having the maximum distance.
+fn get_max_distance_index(sorted_frequencies: &[u64], is_cumulative: bool) -> usize {
+    assert!(sorted_frequencies.len() >= 2,
+        "ERROR: when attempting to find a knee-distance threshold, the list of putative cells is only of length {}. Cannot proceed. Please check the mapping rate.",
+        sorted_frequencies.len());
+    let first = sorted_frequencies
+        .first()
+        .expect("cannot process empty frequency list.");
+    let last = sorted_frequencies
+        .last()
+        .expect("cannot process empty frequency list.");
+
+    // length as a float
+    let max_x = sorted_frequencies.len() as f64;
+
+    // if the distribution is cumulative, then the smallest y coordinate is
+    // f, otherewise it is l
+    let max_y = if is_cumulative {
+        *last as f64
+    } else {
+        *first as f64
+    };
+
+    let p1 = Point {
+        x: 0.0f64,
+        y: (*first as f64) / max_y,
+    };
+    let p2 = Point {
+        x: 1.0f64,
+        y: (*last as f64) / max_y,
+    };
+
+    let mut max_d: f64 = -1.0;
+    let mut max_ind: usize = 0;
+
+    for (ind, freq) in sorted_frequencies.iter().enumerate() {
+        let x = ind as f64 / max_x;
+        let y = *freq as f64 / max_y;
+        let q = Point { x, y };
+        let d = distance_to_line(&p1, &p2, &q);
+        if d >= max_d {
+            max_d = d;
+            max_ind = ind;
+        }
+    }
+    max_ind
+}
+
+/// Get the knee of the cure using the `distance` method as described
+/// in the [UMI-tools documentation](https://github.com/CGATOxford/UMI-tools).
+/// This method takes a reverse-sorted (sorted in descending order) llist of
+/// frequencies, and a maximum number of iterations to run the algorithm.  It
+/// returns the point on the CDF of the reverse-sorted frequency vector that is
+/// farthest from the line defined by the end-points.  The algorithm is taken from
+/// [here](https://github.com/CGATOxford/UMI-tools/blob/master/umi_tools/whitelist_methods.py#L248).
+pub(crate) fn get_knee(
+    freq: &[u64],
+    max_iterations: usize,
+    log: &slog::Logger,
+) -> anyhow::Result<usize> {
+    if freq.len() < 2 {
+        return Err(AlevinFryError::EmptyHistogram(format!(
+            "when attempting to find a knee-distance threshold, the list of putative cells is only of length {}. Cannot proceed. Please check the mapping rate.",
+            freq.len()
+        ))
+        .into());
+    }
+    // get the cumulative frequency from the frequency
+    let cfreq: Vec<u64> = freq
+        .iter()
+        .scan(0u64, |acc, &num| {
+            *acc += num;
+            Some(*acc)
+        })
+        .collect();
+    // get the guess about the max distance point
+    let mut prev_max = 0;
+    let mut max_idx = get_max_distance_index(&cfreq[..], true);
+
+    // if we think we should include no cells, something is probably wrong.
+    if max_idx == 0 {
+        bail!("get_knee determined a knee index of 0. This probably should not happen with valid input data.");
+    }
+
+    let mut iterations = 0;
+    let iter_slack = 5;
+    // while our algorithm hasn't converged
+    while max_idx != prev_max {
+        info!(log, "max_idx = {}", max_idx);
+        prev_max = max_idx;
+        iterations += 1;
+        if iterations % 10 == 0 {
+            info!(log, "knee-finding iter = {}", iterations);
+        }
+        if iterations > max_iterations {
+            break;
+        }
+        // we always need at least 2 points to define the line
+        let last_idx = std::cmp::min(cfreq.len() - 1, max_idx * iter_slack).max(2);
+        max_idx = get_max_distance_index(&cfreq[0..last_idx], true);
+        if max_idx == 0 {
+            bail!("get_knee determined a knee index of 0. This probably should not happen with valid input data.");
+        }
+    }
+    Ok(max_idx)
+}
+
+/// The barcode rank curve in log-log space.  All barcodes sharing
+/// the same read count are collapsed into a single point placed
+/// at their mid-rank, as is done in `DropletUtils::barcodeRanks`.
+struct RankCurve {
+    // log10 of the (mid-)rank of each point
+    log_rank: Vec<f64>,
+    // log10 of the read count of each point
+    log_count: Vec<f64>,
+    // the read count of each point
+    count: Vec<u64>,
+}
+
+impl RankCurve {
+    /// Builds the rank curve from a list of frequencies sorted in
+    /// descending order, keeping only points with more than `lower`
+    /// reads.
+    fn from_sorted_freq(freq: &[u64], lower: u64) -> RankCurve {
+        let mut log_rank = Vec::new();
+        let mut log_count = Vec::new();
+        let mut count = Vec::new();
+
+        let mut start = 0usize;
+        while start < freq.len() {
+            let v = freq[start];
+            let mut end = start + 1;
+            while end < freq.len() && freq[end] == v {
+                end += 1;
+            }
+            if v > lower {
+                // ranks are 1-based; the run covers ranks [start + 1, end]
+                let mid_rank = (start + 1 + end) as f64 / 2.0;
+                log_rank.push(mid_rank.log10());
+                log_count.push((v as f64).log10());
+                count.push(v);
+            }
+            start = end;
+        }
+
+        RankCurve {
+            log_rank,
+            log_count,
+            count,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.count.len()
+    }
+
+    /// The index of the inflection point of the curve; the point
+    /// at which the (finite difference) first derivative is minimized.
+    /// Also returns the index of the point at which the derivative
+    /// is maximized to the left of the inflection point.
+    fn inflection_bounds(&self) -> Option<(usize, usize)> {
+        if self.len() < MIN_RANK_CURVE_POINTS {
+            return None;
+        }
+        let d1: Vec<f64> = (0..self.len() - 1)
+            .map(|i| {
+                (self.log_count[i + 1] - self.log_count[i])
+                    / (self.log_rank[i + 1] - self.log_rank[i])
+            })
+            .collect();
+
+        let exclude = RANK_CURVE_EXCLUDE_FROM.log10();
+        let skip = self
+            .log_rank
+            .iter()
+            .take_while(|&&x| x <= exclude)
+            .count()
+            .min(d1.len() - 1);
+
+        let right_edge = skip + argmin(&d1[skip..])?;
+        let left_edge = argmax(&d1[..=right_edge])?;
+        Some((left_edge, right_edge))
+    }
+
+    /// Returns the value and first and second derivatives of a local
+    /// quadratic (Gaussian kernel weighted) fit of the curve at the
+    /// point with index `idx`.
+    fn local_quadratic_fit(&self, idx: usize, bandwidth: f64) -> Option<(f64, f64, f64)> {
+        let x0 = self.log_rank[idx];
+        let max_dist = 3.0 * bandwidth;
+        // the weighted moments of the centered abscissa and the
+        // corresponding weighted cross-moments with the ordinate.
+        let mut s = [0f64; 5];
+        let mut t = [0f64; 3];
+        for (x, y) in self.log_rank.iter().zip(self.log_count.iter()) {
+            let d = x - x0;
+            if d.abs() > max_dist {
+                continue;
+            }
+            let w = (-0.5 * (d / bandwidth).powi(2)).exp();
+            let mut dk = w;
+            for (k, sk) in s.iter_mut().enumerate() {
+                *sk += dk;
+                if k < 3 {
+                    t[k] += dk * y;
+                }
+                dk *= d;
+            }
+        }
+
+        // solve the 3x3 normal equations for y ~ a + b d + c d^2
+        let m = [[s[0], s[1], s[2]], [s[1], s[2], s[3]], [s[2], s[3], s[4]]];
+        let det = det3(&m);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let mut coef = [0f64; 3];
+        for (j, c) in coef.iter_mut().enumerate() {
+            let mut mj = m;
+            for (r, row) in mj.iter_mut().enumerate() {
+                row[j] = t[r];
+            }
+            *c = det3(&mj) / det;
+        }
+        Some((coef[0], coef[1], 2.0 * coef[2]))
+    }
+}
+
+fn det3(m: &[[f64; 3]; 3]) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+fn argmin(v: &[f64]) -> Option<usize> {
+    v.iter()
+        .enumerate()
+        .filter(|(_, x)| x.is_finite())
+        .min_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(i, _)| i)
+}
+
+fn argmax(v: &[f64]) -> Option<usize> {
+    v.iter()
+        .enumerate()
+        .filter(|(_, x)| x.is_finite())
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(i, _)| i)
+}
+
+/// Find the read-count threshold at the inflection point of the log-log
+/// barcode rank curve (the point where the curve falls most steeply).
+/// This is the `inflection` value reported by `DropletUtils::barcodeRanks`.
+pub(crate) fn get_inflection_threshold(freq: &[u64]) -> Option<u64> {
+    let curve = RankCurve::from_sorted_freq(freq, RANK_CURVE_LOWER);
+    let (_left, right) = curve.inflection_bounds()?;
+    Some(curve.count[right])
+}
+
+/// Find the read-count threshold at the knee of the log-log barcode rank
+/// curve, defined as the point of minimum signed curvature of the smoothed
+/// curve between the point of maximum slope and the inflection point.  The
+/// curve is smoothed, and its derivatives obtained, by local quadratic
+/// regression.  This is analogous to the `knee` value reported by
+/// `DropletUtils::barcodeRanks`.
+pub(crate) fn get_curvature_threshold(freq: &[u64]) -> Option<u64> {
+    let curve = RankCurve::from_sorted_freq(freq, RANK_CURVE_LOWER);
+    let (left, right) = curve.inflection_bounds()?;
+
+    let span = curve.log_rank.last()? - curve.log_rank.first()?;
+    // roughly corresponds to the 20 degrees of freedom
+    // used for the smoothing spline in DropletUtils.
+    let bandwidth = (span / 20.0).max(1e-3);
+
+    let mut best: Option<(usize, f64)> = None;
+    for idx in left..=right {
+        if let Some((_, d1, d2)) = curve.local_quadratic_fit(idx, bandwidth) {
+            let curvature = d2 / (1.0 + d1 * d1).powf(1.5);
+            match best {
+                Some((_, bc)) if bc <= curvature => {}
+                _ => best = Some((idx, curvature)),
+            }
+        }
+    }
+    best.map(|(idx, _)| curve.count[idx])
+}
+
+/// Fit a two-component Gaussian mixture to the log10 read counts
+/// (of barcodes having more than `RANK_CURVE_LOWER` reads) and
+/// return the read count at which a barcode becomes more likely to
+/// belong to the high-count (cell) component than to the low-count
+/// (background) component.
+pub(crate) fn get_mixture_threshold(freq: &[u64]) -> Option<u64> {
+    let curve = RankCurve::from_sorted_freq(freq, RANK_CURVE_LOWER);
+    if curve.len() < MIN_RANK_CURVE_POINTS {
+        return None;
+    }
+
+    // each distinct count is a point weighted by the number
+    // of barcodes having that count.
+    let mut vals = Vec::with_capacity(curve.len());
+    let mut wts = Vec::with_capacity(curve.len());
+    for (i, c) in curve.count.iter().enumerate() {
+        let end = freq.partition_point(|&f| f >= *c);
+        let start = freq.partition_point(|&f| f > *c);
+        vals.push(curve.log_count[i]);
+        wts.push((end - start) as f64);
+    }
+
+    let fit = GaussianMixture2::fit(&vals, &wts, 500, 1e-8)?;
+    let t = fit.decision_boundary()?;
+    Some(10f64.powf(t).ceil() as u64)
+}
+
+/// A two-component, univariate Gaussian mixture model.  Component 0
+/// always has the smaller mean.
+struct GaussianMixture2 {
+    weight: [f64; 2],
+    mean: [f64; 2],
+    var: [f64; 2],
+}
+
+impl GaussianMixture2 {
+    fn log_density(&self, k: usize, x: f64) -> f64 {
+        let d = x - self.mean[k];
+        self.weight[k].ln()
+            - 0.5 * (2.0 * std::f64::consts::PI * self.var[k]).ln()
+            - 0.5 * d * d / self.var[k]
+    }
+
+    /// Fit the mixture to the weighted points `(x, w)` by EM.  The
+    /// components are initialized by splitting the points at their
+    /// weighted mean.
+    fn fit(x: &[f64], w: &[f64], max_iter: usize, tol: f64) -> Option<GaussianMixture2> {
+        let total: f64 = w.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let overall_mean = x.iter().zip(w).map(|(x, w)| x * w).sum::<f64>() / total;
+        let overall_var = x
+            .iter()
+            .zip(w)
+            .map(|(x, w)| w * (x - overall_mean).powi(2))
+            .sum::<f64>()
+            / total;
+        // the smallest variance a component may have
+        let min_var = (overall_var * 1e-4).max(1e-8);
+
+        // responsibilities of the high component for each point
+        let mut resp: Vec<f64> = x
+            .iter()
+            .map(|v| if *v >= overall_mean { 1.0 } else { 0.0 })
+            .collect();
+
+        let mut gmm = GaussianMixture2 {
+            weight: [0.5, 0.5],
+            mean: [overall_mean, overall_mean],
+            var: [overall_var, overall_var],
+        };
+
+        let mut prev_ll = f64::NEG_INFINITY;
+        for _ in 0..max_iter {
+            // M-step
+            for k in 0..2 {
+                let rk = |r: f64| if k == 1 { r } else { 1.0 - r };
+                let nk: f64 = resp.iter().zip(w).map(|(r, w)| rk(*r) * w).sum();
+                if nk <= 0.0 {
+                    return None;
+                }
+                let mk = resp
+                    .iter()
+                    .zip(w)
+                    .zip(x)
+                    .map(|((r, w), v)| rk(*r) * w * v)
+                    .sum::<f64>()
+                    / nk;
+                let vk = resp
+                    .iter()
+                    .zip(w)
+                    .zip(x)
+                    .map(|((r, w), v)| rk(*r) * w * (v - mk).powi(2))
+                    .sum::<f64>()
+                    / nk;
+                gmm.weight[k] = nk / total;
+                gmm.mean[k] = mk;
+                gmm.var[k] = vk.max(min_var);
+            }
+
+            // E-step
+            let mut ll = 0f64;
+            for (r, (v, wt)) in resp.iter_mut().zip(x.iter().zip(w)) {
+                let l0 = gmm.log_density(0, *v);
+                let l1 = gmm.log_density(1, *v);
+                let m = l0.max(l1);
+                let lse = m + ((l0 - m).exp() + (l1 - m).exp()).ln();
+                *r = (l1 - lse).exp();
+                ll += wt * lse;
+            }
+
+            if (ll - prev_ll).abs() <= tol * ll.abs().max(1.0) {
+                break;
+            }
+            prev_ll = ll;
+        }
+
+        if gmm.mean[0] > gmm.mean[1] {
+            gmm.weight.swap(0, 1);
+            gmm.mean.swap(0, 1);
+            gmm.var.swap(0, 1);
+        }
+        Some(gmm)
+    }
+
+    /// The point between the two component means at which the
+    /// (weighted) component densities are equal.
+    fn decision_boundary(&self) -> Option<f64> {
+        let f = |x: f64| self.log_density(1, x) - self.log_density(0, x);
+        let (mut lo, mut hi) = (self.mean[0], self.mean[1]);
+        if hi <= lo || f(lo) > 0.0 || f(hi) < 0.0 {
+            return None;
+        }
+        for _ in 0..100 {
+            let mid = 0.5 * (lo + hi);
+            if f(mid) < 0.0 {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        Some(0.5 * (lo + hi))
+    }
+}
+
+/// The threshold chosen by one knee-finding method.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct KneeThreshold {
+    /// barcodes with at least this many reads pass the filter
+    pub min_freq: u64,
+    /// the number of barcodes that pass the filter
+    pub num_barcodes: usize,
+}
+
+impl KneeThreshold {
+    fn from_min_freq(freq: &[u64], min_freq: u64) -> KneeThreshold {
+        KneeThreshold {
+            min_freq,
+            num_barcodes: freq.partition_point(|&f| f >= min_freq),
+        }
+    }
+}
+
+/// The thresholds that each of the automatic knee-finding methods
+/// would choose on a barcode frequency distribution.  A method that
+/// cannot determine a threshold on the given data is `None`.
+#[derive(Clone, Debug, Serialize)]
+pub struct KneeThresholds {
+    #[serde(rename = "knee-distance")]
+    pub distance: Option<KneeThreshold>,
+    #[serde(rename = "knee-inflection")]
+    pub inflection: Option<KneeThreshold>,
+    #[serde(rename = "knee-curvature")]
+    pub curvature: Option<KneeThreshold>,
+    #[serde(rename = "knee-mixture")]
+    pub mixture: Option<KneeThreshold>,
+}
+
+impl KneeThresholds {
+    /// Evaluate all knee-finding methods on `freq`, which must be
+    /// sorted in descending order.  A method that fails is recorded as
+    /// `None`; the reason the knee-distance method failed (if it did) is
+    /// also returned, so that it can be reported if that method was
+    /// selected.
+    pub fn compute(freq: &[u64], log: &slog::Logger) -> (KneeThresholds, Option<anyhow::Error>) {
+        let (distance, distance_error) = match get_knee(freq, 100, log) {
+            Ok(idx) => (Some(KneeThreshold::from_min_freq(freq, freq[idx])), None),
+            Err(e) => {
+                info!(log, "knee-distance method failed : {}", e);
+                (None, Some(e))
+            }
+        };
+
+        let report = |name: &str, t: Option<u64>| {
+            if t.is_none() {
+                info!(
+                    log,
+                    "{} method could not determine a threshold on this data.", name
+                );
+            }
+            t.map(|mf| KneeThreshold::from_min_freq(freq, mf))
+        };
+
+        let thresholds = KneeThresholds {
+            distance,
+            inflection: report("knee-inflection", get_inflection_threshold(freq)),
+            curvature: report("knee-curvature", get_curvature_threshold(freq)),
+            mixture: report("knee-mixture", get_mixture_threshold(freq)),
+        };
+        (thresholds, distance_error)
+    }
+
+    /// Iterate over the (name, threshold) pairs of all methods.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, Option<KneeThreshold>)> {
+        [
+            ("knee-distance", self.distance),
+            ("knee-inflection", self.inflection),
+            ("knee-curvature", self.curvature),
+            ("knee-mixture", self.mixture),
+        ]
+        .into_iter()
+    }
+}
+
+/// Render the barcode rank curve of `freq` (sorted in descending order)
+/// on log-log axes as an SVG image written to `path`.  The threshold
+/// chosen by each knee-finding method is drawn as a horizontal line, and
+/// the threshold that was actually used (if any) is drawn in black.
+pub fn write_knee_plot_svg(
+    path: &Path,
+    freq: &[u64],
+    knee_thresholds: &KneeThresholds,
+    permit_threshold: Option<u64>,
+) -> anyhow::Result<()> {
+    const WIDTH: f64 = 720.0;
+    const HEIGHT: f64 = 480.0;
+    const MARGIN_L: f64 = 70.0;
+    const MARGIN_R: f64 = 180.0;
+    const MARGIN_T: f64 = 30.0;
+    const MARGIN_B: f64 = 50.0;
+    const COLORS: [&str; 4] = ["#1b9e77", "#d95f02", "#7570b3", "#e7298a"];
+
+    let nz: Vec<u64> = freq.iter().copied().filter(|&f| f > 0).collect();
+    if nz.is_empty() {
+        bail!("cannot draw a knee plot of an empty barcode frequency distribution.");
+    }
+
+    let x_max = (nz.len() as f64).log10().ceil().max(1.0);
+    let y_min = (*nz.last().unwrap() as f64).log10().floor();
+    let y_max = (nz[0] as f64).log10().ceil().max(y_min + 1.0);
+    let plot_w = WIDTH - MARGIN_L - MARGIN_R;
+    let plot_h = HEIGHT - MARGIN_T - MARGIN_B;
+    let px = |lx: f64| MARGIN_L + plot_w * lx / x_max;
+    let py = |ly: f64| MARGIN_T + plot_h * (1.0 - (ly - y_min) / (y_max - y_min));
+
+    let mut svg = String::new();
+    svg.push_str(&format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+         viewBox=\"0 0 {w} {h}\" font-family=\"sans-serif\" font-size=\"12\">\n",
+        w = WIDTH,
+        h = HEIGHT
+    ));
+    svg.push_str("<rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n");
+
+    // axes, with a tick at each power of 10
+    svg.push_str(&format!(
+        "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"none\" stroke=\"black\"/>\n",
+        MARGIN_L, MARGIN_T, plot_w, plot_h
+    ));
+    for e in 0..=(x_max as i32) {
+        let x = px(e as f64);
+        svg.push_str(&format!(
+            "<line x1=\"{x:.1}\" y1=\"{y0:.1}\" x2=\"{x:.1}\" y2=\"{y1:.1}\" stroke=\"black\"/>\n\
+             <text x=\"{x:.1}\" y=\"{yt:.1}\" text-anchor=\"middle\">1e{e}</text>\n",
+            y0 = MARGIN_T + plot_h,
+            y1 = MARGIN_T + plot_h + 5.0,
+            yt = MARGIN_T + plot_h + 20.0,
+        ));
+    }
+    for e in (y_min as i32)..=(y_max as i32) {
+        let y = py(e as f64);
+        svg.push_str(&format!(
+            "<line x1=\"{x0:.1}\" y1=\"{y:.1}\" x2=\"{x1:.1}\" y2=\"{y:.1}\" stroke=\"black\"/>\n\
+             <text x=\"{xt:.1}\" y=\"{yt:.1}\" text-anchor=\"end\">1e{e}</text>\n",
+            x0 = MARGIN_L - 5.0,
+            x1 = MARGIN_L,
+            xt = MARGIN_L - 8.0,
+            yt = y + 4.0,
+        ));
+    }
+    svg.push_str(&format!(
+        "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">barcode rank</text>\n",
+        MARGIN_L + plot_w / 2.0,
+        HEIGHT - 10.0
+    ));
+    svg.push_str(&format!(
+        "<text transform=\"translate(18,{:.1}) rotate(-90)\" text-anchor=\"middle\">read count</text>\n",
+        MARGIN_T + plot_h / 2.0
+    ));
+
+    // the rank curve; each run of barcodes with the same count
+    // is drawn as a horizontal segment from its first to last rank.
+    let mut points = Vec::new();
+    let mut start = 0usize;
+    while start < nz.len() {
+        let v = nz[start];
+        let mut end = start + 1;
+        while end < nz.len() && nz[end] == v {
+            end += 1;
+        }
+        let y = py((v as f64).log10());
+        points.push(format!("{:.2},{:.2}", px(((start + 1) as f64).log10()), y));
+        if end > start + 1 {
+            points.push(format!("{:.2},{:.2}", px((end as f64).log10()), y));
+        }
+        start = end;
+    }
+    svg.push_str(&format!(
+        "<polyline fill=\"none\" stroke=\"#444444\" stroke-width=\"1.5\" points=\"{}\"/>\n",
+        points.join(" ")
+    ));
+
+    // threshold lines and legend
+    let mut legend_y = MARGIN_T + 10.0;
+    let mut add_threshold = |label: String, min_freq: u64, color: &str, dash: &str| {
+        if min_freq > 0 {
+            let y = py((min_freq as f64).log10());
+            if (MARGIN_T..=MARGIN_T + plot_h).contains(&y) {
+                svg.push_str(&format!(
+                    "<line x1=\"{:.1}\" y1=\"{y:.1}\" x2=\"{:.1}\" y2=\"{y:.1}\" stroke=\"{color}\" \
+                     stroke-dasharray=\"{dash}\"/>\n",
+                    MARGIN_L,
+                    MARGIN_L + plot_w,
+                ));
+            }
+        }
+        let lx = MARGIN_L + plot_w + 10.0;
+        svg.push_str(&format!(
+            "<line x1=\"{:.1}\" y1=\"{y:.1}\" x2=\"{:.1}\" y2=\"{y:.1}\" stroke=\"{color}\" \
+             stroke-dasharray=\"{dash}\"/>\n\
+             <text x=\"{:.1}\" y=\"{:.1}\">{label}</text>\n",
+            lx,
+            lx + 20.0,
+            lx + 25.0,
+            legend_y + 4.0,
+            y = legend_y,
+        ));
+        legend_y += 18.0;
+    };
+    for ((name, kt), color) in knee_thresholds.iter().zip(COLORS) {
+        if let Some(kt) = kt {
+            add_threshold(
+                format!("{} ({})", name, kt.num_barcodes),
+                kt.min_freq,
+                color,
+                "6,3",
+            );
+        }
+    }
+    if let Some(min_freq) = permit_threshold {
+        let num_barcodes = freq.partition_point(|&f| f >= min_freq);
+        add_threshold(
+            format!("selected ({})", num_barcodes),
+            min_freq,
+            "black",
+            "none",
+        );
+    }
+    svg.push_str("</svg>\n");
+
+    let mut f = File::create(path)
+        .with_context(|| format!("could not create knee plot file {}", path.display()))?;
+    f.write_all(svg.as_bytes())
+        .with_context(|| format!("could not write knee plot file {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a synthetic barcode distribution with `ncells` cells having
+    /// ~10,000 reads and many more background barcodes having ~200 reads.
+    fn synthetic_freq(ncells: u64, nbg: u64) -> Vec<u64> {
+        let mut freq: Vec<u64> = (0..ncells).map(|i| 8_000 + (i * 37) % 4_000).collect();
+        freq.extend((0..nbg).map(|i| 120 + (i * 13) % 160));
+        freq.sort_unstable();
+        freq.reverse();
+        freq
+    }
+
+    #[test]
+    fn test_rank_curve_mid_ranks() {
+        let freq = vec![500, 400, 400, 300, 50];
+        let curve = RankCurve::from_sorted_freq(&freq, 100);
+        assert_eq!(curve.count, vec![500, 400, 300]);
+        let ranks: Vec<f64> = curve.log_rank.iter().map(|x| 10f64.powf(*x)).collect();
+        assert!((ranks[0] - 1.0).abs() < 1e-9);
+        assert!((ranks[1] - 2.5).abs() < 1e-9);
+        assert!((ranks[2] - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_mixture_threshold_separates_modes() {
+        let freq = synthetic_freq(1_000, 20_000);
+        let t = get_mixture_threshold(&freq).unwrap();
+        assert!(t > 280 && t < 8_000, "threshold was {}", t);
+    }
+
+    #[test]
+    fn test_inflection_threshold_separates_modes() {
+        let freq = synthetic_freq(1_000, 20_000);
+        let t = get_inflection_threshold(&freq).unwrap();
+        assert!((280..=8_000).contains(&t), "threshold was {}", t);
+    }
+
+    #[test]
+    fn test_small_input_has_no_threshold() {
+        let freq = vec![1_000, 900, 5];
+        assert!(get_inflection_threshold(&freq).is_none());
+        assert!(get_curvature_threshold(&freq).is_none());
+        assert!(get_mixture_threshold(&freq).is_none());
+    }
+}
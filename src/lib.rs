@@ -14,9 +14,14 @@ pub mod constants;
 pub mod convert;
 pub mod em;
 pub mod eq_class;
+pub mod errors;
 pub mod infer;
 pub mod io_utils;
+pub mod knee;
+pub mod pipeline;
 pub mod prog_opts;
 pub mod pugutils;
 pub mod quant;
 pub mod utils;
+
+pub use pipeline::{Pipeline, PipelineResult, RunConfig};
@@ -9,18 +9,28 @@
 
 use anyhow::{anyhow, bail};
 use bio_types::strand::Strand;
-use clap::{arg, builder::ArgGroup, crate_authors, crate_version, value_parser, Command};
+use clap::{
+    arg, builder::ArgGroup, crate_authors, crate_version, value_parser, ArgMatches, Command,
+};
 use csv::Error as CSVError;
 use csv::ErrorKind;
 use itertools::Itertools;
 use mimalloc::MiMalloc;
+use num_format::{Locale, ToFormattedString};
 use rand::Rng;
-use slog::{crit, o, warn, Drain};
+use slog::{crit, info, o, warn, Drain};
 use std::path::PathBuf;
 
 use alevin_fry::cellfilter::{generate_permit_list, CellFilterMethod};
 use alevin_fry::cmd_parse_utils::{
-    pathbuf_directory_exists_validator, pathbuf_file_exists_validator,
+    apply_config_file, memory_size_parser, pathbuf_directory_exists_validator,
+    pathbuf_file_exists_validator, write_resolved_config,
+};
+use alevin_fry::collate::{CollateCodec, CollateOrder};
+use alevin_fry::em::EmAlgorithm;
+use alevin_fry::errors::AlevinFryError;
+use alevin_fry::pipeline::{
+    CollateConfig, ExpectedOri, PermitListConfig, Pipeline, QuantConfig, RunConfig,
 };
 use alevin_fry::prog_opts::{GenPermitListOpts, QuantOpts};
 use alevin_fry::quant::{ResolutionStrategy, SplicedAmbiguityModel};
@@ -31,6 +41,250 @@ static GLOBAL: MiMalloc = MiMalloc;
 // grab the version from the Cargo file.
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Report the error `e` and terminate the process with
+/// the exit code corresponding to its variant.
+fn exit_with_error(e: AlevinFryError) -> ! {
+    eprintln!("Error: {:#}", e);
+    std::process::exit(e.exit_code());
+}
+
+/// Add to the subcommand `cmd` the arguments used to read its
+/// options from, and to write them to, a configuration file.
+fn with_config_args(cmd: Command) -> Command {
+    cmd.arg(
+        arg!(--config <CONFIG> "TOML or JSON file from which to read options; options passed on the command line take precedence")
+            .value_parser(pathbuf_file_exists_validator),
+    )
+    .arg(
+        arg!(--"dump-config" <DUMPCONFIG> "write the fully resolved options to this TOML (.toml extension) or JSON file and exit")
+            .value_parser(value_parser!(PathBuf)),
+    )
+}
+
+/// Add to `cmd` the arguments of the `generate-permit-list` stage that
+/// are shared by the `generate-permit-list` and `run` subcommands.
+fn add_generate_args(cmd: Command) -> Command {
+    cmd.arg(arg!(-d --"expected-ori" <EXPECTEDORI> "the expected orientation of alignments")
+         .required(true)
+         .ignore_case(true)
+         .value_parser(["fw", "rc", "both", "either", "auto"]))
+    .arg(arg!(--"auto-ori-chunks" <AUTOORICHUNKS> "the number of chunks to sample when using --expected-ori auto")
+         .hide(true)
+         .default_value("100")
+         .value_parser(value_parser!(usize)))
+    .arg(arg!(-k --"knee-distance" "attempt to determine the number of barcodes to keep using the knee distance method."))
+    .arg(arg!(--"knee-inflection" "attempt to determine the number of barcodes to keep using the inflection point of the log-log barcode rank curve."))
+    .arg(arg!(--"knee-curvature" "attempt to determine the number of barcodes to keep using the point of minimum curvature of the smoothed log-log barcode rank curve."))
+    .arg(arg!(--"knee-mixture" "attempt to determine the number of barcodes to keep using a two-component mixture model on the log barcode counts."))
+    .arg(arg!(-e --"expect-cells" <EXPECTCELLS> "defines the expected number of cells to use in determining the (read, not UMI) based cutoff")
+         .value_parser(value_parser!(usize)))
+    .arg(arg!(-f --"force-cells" <FORCECELLS> "select the top-k most-frequent barcodes, based on read count, as valid (true)")
+         .value_parser(value_parser!(usize)))
+    .arg(arg!(-b --"valid-bc" <VALIDBC> "uses true barcode collected from a provided file")
+         .value_parser(pathbuf_file_exists_validator))
+    .arg(arg!(-u --"unfiltered-pl" <UNFILTEREDPL> "uses an unfiltered external permit list")
+         .value_parser(pathbuf_file_exists_validator))
+    .group(ArgGroup::new("filter-method")
+           .args(["knee-distance", "knee-inflection", "knee-curvature", "knee-mixture", "expect-cells", "force-cells", "valid-bc", "unfiltered-pl"])
+           .required(true))
+    .arg(arg!(--"knee-plot" "write an SVG plot of the barcode rank curve, and of the threshold chosen by each knee-finding method, to the output directory"))
+    .arg(arg!(--spill "write a compressed copy of the input RAD file to the output directory, from which collate will read it (always done if map.rad is a named pipe)"))
+    .arg(arg!(--"min-reads" <MINREADS> "minimum read count threshold; only used with --unfiltered-pl")
+         .value_parser(value_parser!(usize))
+         .default_value("10"))
+}
+
+/// Add to `cmd` the arguments of the `collate` stage that are shared by
+/// the `collate` and `run` subcommands.
+fn add_collate_args(cmd: Command) -> Command {
+    cmd.arg(arg!(-c --compress "compress the collated RAD file with snappy (equivalent to --codec snappy)"))
+    .arg(arg!(--codec <CODEC> "the codec with which to compress the collated RAD file")
+         .value_parser(value_parser!(CollateCodec))
+         .conflicts_with("compress"))
+    .arg(arg!(--"zstd-level" <LEVEL> "the compression level used with --codec zstd")
+         .value_parser(value_parser!(i32).range(1..=22))
+         .default_value("3"))
+    .arg(arg!(--"zstd-threads" <THREADS> "the number of background threads used to compress with --codec zstd (0 means compress in the collating threads)")
+         .value_parser(value_parser!(u32))
+         .default_value("0"))
+    .arg(arg!(--"max-records" <MAXRECORDS> "the maximum number of read records to keep in memory at once during collation")
+         .value_parser(value_parser!(u32))
+         .default_value("30000000"))
+    .arg(arg!(--"max-memory" <MAXMEMORY> "the (approximate) memory budget for collation, in bytes or with a K, M, G or T suffix (e.g. 16G)")
+         .value_parser(memory_size_parser))
+    .arg(arg!(--order <ORDER> "the order in which cells are written to the collated RAD file; 'barcode' sorts the cells by barcode, so that the output is reproducible and can be searched and merged")
+         .value_parser(value_parser!(CollateOrder))
+         .default_value("frequency"))
+}
+
+/// Add to `cmd` the arguments of the `quant` stage that are shared by
+/// the `quant` and `run` subcommands.  The consistency of these options
+/// is checked by [`QuantConfig::resolve`], rather than by clap, so that
+/// both subcommands (and configuration files) are held to the same rules.
+fn add_quant_args(cmd: Command) -> Command {
+    cmd.arg(arg!(-m --"tg-map" <TGMAP> "transcript to gene map").required(true).value_parser(pathbuf_file_exists_validator))
+    .arg(arg!(-r --resolution <RESOLUTION> "the resolution strategy by which molecules will be counted")
+        .required(true)
+        .ignore_case(true)
+        .value_parser(value_parser!(ResolutionStrategy)))
+    .arg(arg!(--"dump-eqclasses" "flag for dumping equivalence classes"))
+    .arg(arg!(--"dump-tx-eqclasses" "flag for dumping the transcript-level equivalence classes of each cell, in a binary format that can be read by infer"))
+    .arg(arg!(--"num-bootstraps" <NUMBOOTSTRAPS> "number of bootstraps to use").value_parser(value_parser!(u32)).default_value("0"))
+    .arg(arg!(--"num-gibbs-samples" <NUMGIBBS> "number of posterior samples to draw with a Gibbs sampler (an alternative to bootstrapping)").value_parser(value_parser!(u32)).default_value("0"))
+    .arg(arg!(--"thinning-factor" <THINNING> "number of rounds of the Gibbs sampler between recorded samples").value_parser(value_parser!(u32).range(1..)).default_value("16"))
+    .arg(arg!(--"gibbs-burn-in" <BURNIN> "number of initial rounds of the Gibbs sampler to discard").value_parser(value_parser!(u32)).default_value("100"))
+    .arg(arg!(--"em-algorithm" <EMALG> "the algorithm used to resolve gene-level multimapping with the -em resolution strategies")
+        .ignore_case(true)
+        .value_parser(value_parser!(EmAlgorithm))
+        .default_value("em"))
+    .arg(arg!(--"vb-prior" <PRIOR> "the per-gene Dirichlet prior used by the vbem algorithm").value_parser(value_parser!(f32)).default_value("0.01"))
+    .arg(arg!(--"em-max-iter" <MAXITER> "the maximum number of EM iterations per cell").value_parser(value_parser!(u32)).default_value("100"))
+    .arg(arg!(--"em-rel-tol" <TOL> "the EM stops once no gene abundance changes by more than this amount").value_parser(value_parser!(f32)).default_value("0.01"))
+    .arg(arg!(--"cross-cell-prior" "make a first pass over all cells to compute gene abundances across the dataset, and use them as a prior when resolving each cell's multi-gene equivalence classes"))
+    .arg(arg!(--"prior-weight" <WEIGHT> "the number of pseudo-molecules of cross-cell prior evidence added to each multi-gene equivalence class").value_parser(value_parser!(f32)).default_value("1.0"))
+    .arg(arg!(--"prior-clusters" <CFILE> "two-column, tab-separated file of barcodes and cluster names; a separate cross-cell prior is computed for each cluster").value_parser(pathbuf_file_exists_validator))
+    .arg(arg!(--"transcript-level" "quantify transcripts rather than genes, resolving the multimapping of each cell's UMIs among transcripts with the EM (requires the cr-like or cr-like-em resolution strategy)"))
+    .arg(arg!(--"gene-aggregate" "with --transcript-level, also write the gene-level sums of the transcript counts"))
+    .arg(arg!(--"ref-lengths" <LFILE> "FASTA file, or tab-separated file of names and lengths, giving the length of each reference sequence; if given, the EM accounts for the length of each transcript or gene (for full-length protocols)").value_parser(pathbuf_file_exists_validator))
+    .arg(arg!(--"count-reads" "count reads rather than UMIs, without UMI deduplication, for protocols without UMIs (e.g. plate-based Smart-seq2); requires the cr-like or cr-like-em resolution strategy"))
+    .arg(arg!(--"init-uniform" "flag for uniform sampling").requires("num-bootstraps"))
+    .arg(arg!(--"summary-stat" "flag for storing only summary statistics of the bootstraps or posterior samples"))
+    .arg(arg!(--"use-mtx" "flag for writing output matrix in matrix market format (default)"))
+    .arg(arg!(--"use-eds" "flag for writing output matrix in EDS format").conflicts_with("use-mtx"))
+    .arg(arg!(--"quant-subset" <SFILE> "file containing list of barcodes to quantify, those not in this list will be ignored").value_parser(pathbuf_file_exists_validator))
+    .arg(arg!(--deterministic "write cells in collated-file order and seed all random draws from the --seed and the cell barcode, making the output independent of the number of threads"))
+    .arg(arg!(--seed <SEED> "the seed used for random draws (e.g. bootstrap resampling) in deterministic mode").value_parser(value_parser!(u64)).default_value("0"))
+    .arg(arg!(--"sa-model" <SAMODEL> "preferred model of splicing ambiguity")
+        .ignore_case(true)
+        .value_parser(value_parser!(SplicedAmbiguityModel))
+        .default_value("winner-take-all")
+        .hide(true))
+    .arg(arg!(--"umi-edit-dist" <EDIST> "the Hamming distance within which potentially colliding UMIs will be considered for correction (by default, 1 for the parsimony strategies and 0 otherwise)")
+        .value_parser(value_parser!(u32))
+        .hide(true))
+    .arg(arg!(--"large-graph-thresh" <NVERT> "the order (number of nodes) of a PUG above which the alternative resolution strategy will be applied (by default, 1000 for the parsimony strategies and 0 otherwise)")
+        .value_parser(value_parser!(usize))
+        .hide(true))
+    .arg(arg!(--"small-thresh" <SMALLTHRESH> "cells with fewer than these many reads will be resolved using a custom approach")
+        .value_parser(value_parser!(usize))
+        .default_value("10")
+        .hide(true))
+}
+
+/// Build the collate options from the arguments of the `collate` and
+/// `run` subcommands.
+fn collate_config(t: &ArgMatches) -> CollateConfig {
+    CollateConfig::builder()
+        .max_records(*t.get_one::<u32>("max-records").unwrap())
+        .max_memory(t.get_one::<u64>("max-memory").copied())
+        .compress(t.get_flag("compress"))
+        .codec(t.get_one::<CollateCodec>("codec").copied())
+        .zstd_level(*t.get_one::<i32>("zstd-level").unwrap())
+        .zstd_threads(*t.get_one::<u32>("zstd-threads").unwrap())
+        .order(*t.get_one::<CollateOrder>("order").unwrap())
+        .build()
+}
+
+/// Build the quant options from the arguments of the `quant` and
+/// `run` subcommands.
+fn quant_config(t: &ArgMatches) -> QuantConfig {
+    QuantConfig::builder()
+        .tg_map(t.get_one::<PathBuf>("tg-map").unwrap().clone())
+        .resolution(*t.get_one::<ResolutionStrategy>("resolution").unwrap())
+        .sa_model(*t.get_one::<SplicedAmbiguityModel>("sa-model").unwrap())
+        .num_bootstraps(*t.get_one::<u32>("num-bootstraps").unwrap())
+        .num_gibbs_samples(*t.get_one::<u32>("num-gibbs-samples").unwrap())
+        .thinning_factor(*t.get_one::<u32>("thinning-factor").unwrap())
+        .gibbs_burn_in(*t.get_one::<u32>("gibbs-burn-in").unwrap())
+        .em_algorithm(*t.get_one::<EmAlgorithm>("em-algorithm").unwrap())
+        .vb_prior(*t.get_one::<f32>("vb-prior").unwrap())
+        .em_max_iter(*t.get_one::<u32>("em-max-iter").unwrap())
+        .em_rel_tol(*t.get_one::<f32>("em-rel-tol").unwrap())
+        .cross_cell_prior(t.get_flag("cross-cell-prior"))
+        .prior_weight(*t.get_one::<f32>("prior-weight").unwrap())
+        .prior_clusters(t.get_one::<PathBuf>("prior-clusters").cloned())
+        .transcript_level(t.get_flag("transcript-level"))
+        .gene_aggregate(t.get_flag("gene-aggregate"))
+        .ref_lengths(t.get_one::<PathBuf>("ref-lengths").cloned())
+        .count_reads(t.get_flag("count-reads"))
+        .init_uniform(t.get_flag("init-uniform"))
+        .summary_stat(t.get_flag("summary-stat"))
+        .dump_eq(t.get_flag("dump-eqclasses"))
+        .dump_tx_eq(t.get_flag("dump-tx-eqclasses"))
+        .use_mtx(!t.get_flag("use-eds"))
+        .umi_edit_dist(t.get_one::<u32>("umi-edit-dist").copied())
+        .large_graph_thresh(t.get_one::<usize>("large-graph-thresh").copied())
+        .small_thresh(*t.get_one::<usize>("small-thresh").unwrap())
+        .filter_list(t.get_one::<PathBuf>("quant-subset").cloned())
+        .deterministic(t.get_flag("deterministic"))
+        .seed(*t.get_one::<u64>("seed").unwrap())
+        .build()
+}
+
+/// Determine the cell filtering method from the (mutually exclusive)
+/// filter method arguments of the `generate-permit-list` and `run`
+/// subcommands.
+fn cell_filter_method(t: &ArgMatches, log: &slog::Logger) -> CellFilterMethod {
+    let mut fmeth = CellFilterMethod::KneeFinding;
+
+    let _expect_cells: Option<usize> = match t.get_one::<usize>("expect-cells") {
+        Some(v) => {
+            fmeth = CellFilterMethod::ExpectCells(*v);
+            Some(*v)
+        }
+        None => None,
+    };
+
+    if t.get_flag("knee-distance") {
+        fmeth = CellFilterMethod::KneeFinding;
+    }
+
+    if t.get_flag("knee-inflection") {
+        fmeth = CellFilterMethod::KneeInflection;
+    }
+
+    if t.get_flag("knee-curvature") {
+        fmeth = CellFilterMethod::KneeCurvature;
+    }
+
+    if t.get_flag("knee-mixture") {
+        fmeth = CellFilterMethod::KneeMixture;
+    }
+
+    let _force_cells = match t.get_one::<usize>("force-cells") {
+        Some(v) => {
+            fmeth = CellFilterMethod::ForceCells(*v);
+            Some(*v)
+        }
+        None => None,
+    };
+
+    let _valid_bc = match t.get_one::<PathBuf>("valid-bc") {
+        Some(v) => {
+            fmeth = CellFilterMethod::ExplicitList(v.clone());
+            Some(v)
+        }
+        None => None,
+    };
+
+    //let _unfiltered_pl = match t.get_one::<String>("unfiltered-pl") {
+    if let Some(v) = t.get_one::<PathBuf>("unfiltered-pl") {
+        let min_reads: usize = *t
+            .get_one("min-reads")
+            .expect("min-reads must be a valid integer");
+        if min_reads < 1 {
+            crit!(
+                log,
+                "min-reads < 1 is not supported, the value {} was provided",
+                min_reads
+            );
+            std::process::exit(1);
+        }
+        fmeth = CellFilterMethod::UnfilteredExternalList(v.clone(), min_reads);
+    };
+    fmeth
+}
+
 #[allow(dead_code)]
 fn gen_random_kmer(k: usize) -> String {
     const CHARSET: &[u8] = b"ACGT";
@@ -88,142 +342,152 @@ fn main() -> anyhow::Result<()> {
         )
         .arg(arg!(-H --header "flag for printing header"));
 
-    let gen_app = Command::new("generate-permit-list")
-        .about("Generate a permit list of barcodes from a RAD file")
-        .version(version)
-        .author(crate_authors)
-        .arg(arg!(-i --input <INPUT>  "input directory containing the map.rad RAD file")
-            .required(true)
-            .value_parser(pathbuf_directory_exists_validator))
-        .arg(arg!(-d --"expected-ori" <EXPECTEDORI> "the expected orientation of alignments")
-             .required(true)
-             .ignore_case(true)
-             .value_parser(["fw", "rc", "both", "either"]))
-        .arg(arg!(-o --"output-dir" <OUTPUTDIR>  "output directory").required(true).value_parser(value_parser!(PathBuf)))
-        .arg(arg!(
-            -k --"knee-distance"  "attempt to determine the number of barcodes to keep using the knee distance method."
+    let gen_app = add_generate_args(
+        Command::new("generate-permit-list")
+            .about("Generate a permit list of barcodes from a RAD file")
+            .version(version)
+            .author(crate_authors)
+            .arg(
+                arg!(-i --input <INPUT> "input directory containing the map.rad RAD file")
+                    .required(true)
+                    .value_parser(pathbuf_directory_exists_validator),
             )
-        )
-        .arg(arg!(-e --"expect-cells" <EXPECTCELLS> "defines the expected number of cells to use in determining the (read, not UMI) based cutoff")
-             .value_parser(value_parser!(usize)))
-        .arg(arg!(-f --"force-cells" <FORCECELLS>  "select the top-k most-frequent barcodes, based on read count, as valid (true)")
-             .value_parser(value_parser!(usize)))
-        .arg(
-            arg!(-b --"valid-bc" <VALIDBC> "uses true barcode collected from a provided file")
-            .value_parser(pathbuf_file_exists_validator)
-        )
-        .arg(
-            arg!(-u --"unfiltered-pl" <UNFILTEREDPL> "uses an unfiltered external permit list")
-            .value_parser(pathbuf_file_exists_validator)
-        )
-        .group(ArgGroup::new("filter-method")
-               .args(["knee-distance", "expect-cells", "force-cells", "valid-bc", "unfiltered-pl"])
-               .required(true)
-               )
-        .arg(
-            arg!(-m --"min-reads" <MINREADS> "minimum read count threshold; only used with --unfiltered-pl")
-                .value_parser(value_parser!(usize))
-                .default_value("10"));
-
-    let collate_app = Command::new("collate")
-    .about("Collate a RAD file by corrected cell barcode")
-    .version(version)
-    .author(crate_authors)
-    .arg(arg!(-i --"input-dir" <INPUTDIR> "input directory made by generate-permit-list")
-        .required(true)
-        .value_parser(pathbuf_directory_exists_validator))
-    .arg(arg!(-r --"rad-dir" <RADFILE> "the directory containing the RAD file to be collated")
-        .required(true)
-        .value_parser(pathbuf_directory_exists_validator))
-    .arg(arg!(-t --threads <THREADS> "number of threads to use for processing").value_parser(value_parser!(u32)).default_value(max_num_collate_threads))
-    .arg(arg!(-c --compress "compress the output collated RAD file"))
-    .arg(arg!(-m --"max-records" <MAXRECORDS> "the maximum number of read records to keep in memory at once")
-         .value_parser(value_parser!(u32))
-         .default_value("30000000"));
-
-    let quant_app = Command::new("quant")
-    .about("Quantify expression from a collated RAD file")
-    .version(version)
-    .author(crate_authors)
-    .arg(arg!(-i --"input-dir" <INPUTDIR>  "input directory containing collated RAD file")
-        .required(true)
-        .value_parser(pathbuf_directory_exists_validator))
-    .arg(arg!(-m --"tg-map" <TGMAP>  "transcript to gene map").required(true).value_parser(pathbuf_file_exists_validator))
-    .arg(arg!(-o --"output-dir" <OUTPUTDIR> "output directory where quantification results will be written").required(true).value_parser(value_parser!(PathBuf)))
-    .arg(arg!(-t --threads <THREADS> "number of threads to use for processing").value_parser(value_parser!(u32)).default_value(max_num_threads.clone()))
-    .arg(arg!(-d --"dump-eqclasses" "flag for dumping equivalence classes"))
-    .arg(arg!(-b --"num-bootstraps" <NUMBOOTSTRAPS> "number of bootstraps to use").value_parser(value_parser!(u32)).default_value("0"))
-    .arg(arg!(--"init-uniform" "flag for uniform sampling").requires("num-bootstraps"))
-    .arg(arg!(--"summary-stat" "flag for storing only summary statistics").requires("num-bootstraps"))
-    .arg(arg!(--"use-mtx" "flag for writing output matrix in matrix market format (default)"))
-    .arg(arg!(--"use-eds" "flag for writing output matrix in EDS format").conflicts_with("use-mtx"))
-    .arg(arg!(--"quant-subset" <SFILE> "file containing list of barcodes to quantify, those not in this list will be ignored").value_parser(pathbuf_file_exists_validator))
-    .arg(arg!(-r --resolution <RESOLUTION> "the resolution strategy by which molecules will be counted")
-        .required(true)
-        .ignore_case(true)
-        .value_parser(value_parser!(ResolutionStrategy)))
-    .arg(arg!(--"sa-model" <SAMODEL> "preferred model of splicing ambiguity")
-        .ignore_case(true)
-        .value_parser(value_parser!(SplicedAmbiguityModel))
-        .default_value("winner-take-all")
-        .hide(true))
-    .arg(arg!(--"umi-edit-dist" <EDIST> "the Hamming distance within which potentially colliding UMIs will be considered for correction")
-        .value_parser(value_parser!(u32))
-        .default_value_ifs([
-            ("resolution", "cr-like", Some("0")),
-            ("resolution", "cr-like-em", Some("0")),
-            ("resolution", "trivial", Some("0")),
-            ("resolution", "parsimony", Some("1")),
-            ("resolution", "parsimony-em", Some("1")),
-            ("resolution", "parsimony-gene", Some("1")),
-            ("resolution", "parsimony-gene-em", Some("1")),
-        ])
-        .hide(true))
-    .arg(arg!(--"large-graph-thresh" <NVERT> "the order (number of nodes) of a PUG above which the alternative resolution strategy will be applied")
-        .value_parser(value_parser!(usize))
-        .default_value_ifs([
-            ("resolution", "parsimony-gene-em", Some("1000")),
-            ("resolution", "parsimony", Some("1000")),
-            ("resolution", "parsimony-em", Some("1000")),
-            ("resolution", "parsimony-gene", Some("1000")),
-        ])
-        .default_value("0") // for any other mode
-        .hide(true))
-    .arg(arg!(--"small-thresh" <SMALLTHRESH> "cells with fewer than these many reads will be resolved using a custom approach")
-        .value_parser(value_parser!(usize))
-        .default_value("10")
-        .hide(true));
+            .arg(
+                arg!(-o --"output-dir" <OUTPUTDIR> "output directory")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            ),
+    )
+    .mut_arg("min-reads", |a| a.short('m'));
+
+    let collate_app = add_collate_args(
+        Command::new("collate")
+            .about("Collate a RAD file by corrected cell barcode")
+            .version(version)
+            .author(crate_authors)
+            .arg(
+                arg!(-i --"input-dir" <INPUTDIR> "input directory made by generate-permit-list")
+                    .required(true)
+                    .value_parser(pathbuf_directory_exists_validator),
+            )
+            .arg(
+                arg!(-r --"rad-dir" <RADFILE> "the directory containing the RAD file to be collated")
+                    .required(true)
+                    .value_parser(pathbuf_directory_exists_validator),
+            )
+            .arg(
+                arg!(-t --threads <THREADS> "number of threads to use for processing")
+                    .value_parser(value_parser!(u32))
+                    .default_value(max_num_collate_threads),
+            ),
+    )
+    .mut_arg("max-records", |a| a.short('m'));
+
+    let quant_app = add_quant_args(
+        Command::new("quant")
+            .about("Quantify expression from a collated RAD file")
+            .version(version)
+            .author(crate_authors)
+            .arg(
+                arg!(-i --"input-dir" <INPUTDIR> "input directory containing collated RAD file")
+                    .required(true)
+                    .value_parser(pathbuf_directory_exists_validator),
+            )
+            .arg(
+                arg!(-o --"output-dir" <OUTPUTDIR> "output directory where quantification results will be written")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(-t --threads <THREADS> "number of threads to use for processing")
+                    .value_parser(value_parser!(u32))
+                    .default_value(max_num_threads.clone()),
+            ),
+    )
+    .mut_arg("dump-eqclasses", |a| a.short('d'))
+    .mut_arg("num-bootstraps", |a| a.short('b'));
 
     let infer_app = Command::new("infer")
     .about("Perform inference on equivalence class count data")
     .version(version)
     .author(crate_authors)
     .arg(arg!(-c --"count-mat" <EQCMAT> "matrix of cells by equivalence class counts")
-        .required(true)
+        .required_unless_present_any(["tx-eqc", "quant-dir"])
         .value_parser(pathbuf_file_exists_validator))
     .arg(arg!(-e --"eq-labels" <EQLABELS> "file containing the gene labels of the equivalence classes")
-        .required(true)
+        .required_unless_present_any(["tx-eqc", "quant-dir"])
+        .value_parser(pathbuf_file_exists_validator))
+    .arg(arg!(--"tx-eqc" <TXEQC> "transcript-level equivalence class file (tx_eqc.bin) written by quant --dump-tx-eqclasses, to be used instead of --count-mat and --eq-labels")
+        .conflicts_with_all(["count-mat", "eq-labels", "usa"])
         .value_parser(pathbuf_file_exists_validator))
+    .arg(arg!(-q --"quant-dir" <QUANTDIR> "output directory of a quant run with --dump-eqclasses or --dump-tx-eqclasses, to be used instead of --count-mat and --eq-labels; the USA mode is read from its quant.json")
+        .conflicts_with_all(["count-mat", "eq-labels", "tx-eqc", "usa"])
+        .value_parser(pathbuf_directory_exists_validator))
     .arg(arg!(-o --"output-dir" <OUTPUTDIR> "output directory where quantification results will be written").required(true).value_parser(value_parser!(PathBuf)))
-    .arg(arg!(-t --threads <THREADS> "number of threads to use for processing").value_parser(value_parser!(u32)).default_value(max_num_threads))
+    .arg(arg!(-t --threads <THREADS> "number of threads to use for processing").value_parser(value_parser!(u32)).default_value(max_num_threads.clone()))
     .arg(arg!(--usa "flag specifying that input equivalence classes were computed in USA mode"))
     .arg(arg!(--"quant-subset" <SFILE> "file containing list of barcodes to quantify, those not in this list will be ignored").value_parser(pathbuf_file_exists_validator))
+    .arg(arg!(--groups <GFILE> "two-column, tab-separated file of barcodes and group names (e.g. clusters or samples); the equivalence class counts of the cells of each group are pooled, and a groups-by-genes (pseudobulk) matrix is written").value_parser(pathbuf_file_exists_validator))
+    .arg(arg!(-b --"num-bootstraps" <NUMBOOTSTRAPS> "number of bootstraps to use").value_parser(value_parser!(u32)).default_value("0"))
+    .arg(arg!(--"summary-stat" "flag for storing only summary statistics of the bootstraps").requires("num-bootstraps"))
+    .arg(arg!(--"em-max-iter" <MAXITER> "the maximum number of EM iterations per cell (or group)").value_parser(value_parser!(u32)).default_value("100"))
+    .arg(arg!(--"em-rel-tol" <TOL> "the EM stops once no gene abundance changes by more than this amount (raised, for groups with large pooled counts, to a millionth of their total count)").value_parser(value_parser!(f32)).default_value("0.01"))
+    .arg(arg!(--deterministic "seed the random draws (e.g. bootstrap resampling) for each cell or group from the --seed and its name, making the output reproducible"))
+    .arg(arg!(--seed <SEED> "the seed used for random draws in deterministic mode").value_parser(value_parser!(u64)).default_value("0"))
     .arg(arg!(--"use-mtx" "flag for writing output matrix in matrix market format (default)"))
     .arg(arg!(--"use-eds" "flag for writing output matrix in EDS format").conflicts_with("use-mtx"));
 
-    let opts = Command::new("alevin-fry")
+    let run_app = Command::new("run")
+        .about("Run generate-permit-list, collate and quant on a RAD file in a single process")
+        .version(version)
+        .author(crate_authors)
+        .arg(
+            arg!(-i --input <INPUT> "input directory containing the map.rad RAD file")
+                .required(true)
+                .value_parser(pathbuf_directory_exists_validator),
+        )
+        .arg(
+            arg!(-o --"output-dir" <OUTPUTDIR> "output directory where the permit list, collated RAD file and quantification results will be written")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-t --threads <THREADS> "number of threads to use for processing")
+                .value_parser(value_parser!(u32))
+                .default_value(max_num_threads),
+        );
+    let run_app = add_quant_args(add_collate_args(add_generate_args(run_app))).arg(arg!(
+        --cleanup "remove the intermediate files (the collated RAD file and the permit map) once the run completes successfully"
+    ));
+
+    let app = Command::new("alevin-fry")
         .subcommand_required(true)
         .arg_required_else_help(true)
         .version(version)
         .author(crate_authors)
         .about("Process RAD files from the command line")
-        .subcommand(gen_app)
-        .subcommand(collate_app)
-        .subcommand(quant_app)
-        .subcommand(infer_app)
-        .subcommand(convert_app)
-        .subcommand(view_app)
-        .get_matches();
+        .subcommand(with_config_args(gen_app))
+        .subcommand(with_config_args(collate_app))
+        .subcommand(with_config_args(quant_app))
+        .subcommand(with_config_args(infer_app))
+        .subcommand(with_config_args(convert_app))
+        .subcommand(with_config_args(view_app))
+        .subcommand(with_config_args(run_app));
+
+    // merge the options from any configuration file passed with --config
+    // into the command line before parsing it.
+    let args = apply_config_file(&app, std::env::args_os().collect())?;
+    let opts = app.clone().get_matches_from(args);
+
+    if let Some((name, t)) = opts.subcommand() {
+        if let Some(cfg_path) = t.get_one::<PathBuf>("dump-config") {
+            let cmd = app
+                .find_subcommand(name)
+                .expect("subcommand should be defined");
+            write_resolved_config(cmd, t, cfg_path)?;
+            return Ok(());
+        }
+    }
 
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::CompactFormat::new(decorator)
@@ -246,6 +510,7 @@ fn main() -> anyhow::Result<()> {
             .expect("no output directory specified");
 
         let valid_ori: bool;
+        let mut auto_ori = false;
         let expected_ori = match t
             .get_one::<String>("expected-ori")
             .unwrap()
@@ -268,6 +533,12 @@ fn main() -> anyhow::Result<()> {
                 valid_ori = true;
                 Strand::Unknown
             }
+            "AUTO" => {
+                // the orientation will be inferred from the data
+                valid_ori = true;
+                auto_ori = true;
+                Strand::Unknown
+            }
             _ => {
                 valid_ori = false;
                 Strand::Unknown
@@ -283,51 +554,7 @@ fn main() -> anyhow::Result<()> {
             std::process::exit(1);
         }
 
-        let mut fmeth = CellFilterMethod::KneeFinding;
-
-        let _expect_cells: Option<usize> = match t.get_one::<usize>("expect-cells") {
-            Some(v) => {
-                fmeth = CellFilterMethod::ExpectCells(*v);
-                Some(*v)
-            }
-            None => None,
-        };
-
-        if t.get_flag("knee-distance") {
-            fmeth = CellFilterMethod::KneeFinding;
-        }
-
-        let _force_cells = match t.get_one::<usize>("force-cells") {
-            Some(v) => {
-                fmeth = CellFilterMethod::ForceCells(*v);
-                Some(*v)
-            }
-            None => None,
-        };
-
-        let _valid_bc = match t.get_one::<PathBuf>("valid-bc") {
-            Some(v) => {
-                fmeth = CellFilterMethod::ExplicitList(v.clone());
-                Some(v)
-            }
-            None => None,
-        };
-
-        //let _unfiltered_pl = match t.get_one::<String>("unfiltered-pl") {
-        if let Some(v) = t.get_one::<PathBuf>("unfiltered-pl") {
-            let min_reads: usize = *t
-                .get_one("min-reads")
-                .expect("min-reads must be a valid integer");
-            if min_reads < 1 {
-                crit!(
-                    log,
-                    "min-reads < 1 is not supported, the value {} was provided",
-                    min_reads
-                );
-                std::process::exit(1);
-            }
-            fmeth = CellFilterMethod::UnfilteredExternalList(v.clone(), min_reads);
-        };
+        let fmeth = cell_filter_method(t, &log);
 
         // velo_mode --- currently, on this branch, it is always false
         let velo_mode = false; //t.get_flag("velocity-mode");
@@ -337,17 +564,21 @@ fn main() -> anyhow::Result<()> {
             .output_dir(output_dir)
             .fmeth(fmeth)
             .expected_ori(expected_ori)
+            .auto_ori(auto_ori)
+            .auto_ori_chunks(*t.get_one::<usize>("auto-ori-chunks").unwrap())
             .version(VERSION)
             .velo_mode(velo_mode)
+            .knee_plot(t.get_flag("knee-plot"))
+            .spill(t.get_flag("spill"))
             .cmdline(&cmdline)
             .log(&log)
             .build();
 
         match generate_permit_list(gpl_opts) {
-            Ok(nc) if nc == 0 => {
+            Ok(stats) if stats.num_corrected == 0 => {
                 warn!(log, "found 0 corrected barcodes; please check the input.");
             }
-            Err(e) => return Err(e),
+            Err(e) => exit_with_error(e),
             _ => (),
         };
     }
@@ -374,114 +605,30 @@ fn main() -> anyhow::Result<()> {
         let input_dir: &PathBuf = t.get_one("input-dir").unwrap();
         let rad_dir: &PathBuf = t.get_one("rad-dir").unwrap();
         let num_threads = *t.get_one("threads").unwrap();
-        let compress_out = t.get_flag("compress");
-        let max_records: u32 = *t.get_one("max-records").unwrap();
+        let collate_config = collate_config(t);
         alevin_fry::collate::collate(
             input_dir,
             rad_dir,
             num_threads,
-            max_records,
-            compress_out,
+            collate_config.max_records,
+            collate_config.max_memory,
+            collate_config.compression(),
+            collate_config.order,
             &cmdline,
             VERSION,
             &log,
         )
-        .expect("could not collate.");
+        .unwrap_or_else(|e| exit_with_error(e));
     }
 
     // perform quantification of a collated rad file.
     if let Some(t) = opts.subcommand_matches("quant") {
-        let num_threads = *t.get_one("threads").unwrap();
-        let num_bootstraps = *t.get_one("num-bootstraps").unwrap();
-        let init_uniform = t.get_flag("init-uniform");
-        let summary_stat = t.get_flag("summary-stat");
-        let dump_eq = t.get_flag("dump-eqclasses");
-        let use_mtx = !t.get_flag("use-eds");
         let input_dir: &PathBuf = t.get_one("input-dir").unwrap();
         let output_dir: &PathBuf = t.get_one("output-dir").unwrap();
-        let tg_map: &PathBuf = t.get_one("tg-map").unwrap();
-        let resolution = *t.get_one::<ResolutionStrategy>("resolution").unwrap();
-        let sa_model = *t.get_one::<SplicedAmbiguityModel>("sa-model").unwrap();
-        let small_thresh = *t.get_one("small-thresh").unwrap();
-        let filter_list: Option<&PathBuf> = t.get_one("quant-subset");
-        let large_graph_thresh: usize = *t.get_one("large-graph-thresh").unwrap();
-        let umi_edit_dist: u32 = *t.get_one("umi-edit-dist").unwrap();
-        let mut pug_exact_umi = false;
-
-        match umi_edit_dist {
-            0 => {
-                match resolution {
-                    ResolutionStrategy::Trivial
-                    | ResolutionStrategy::CellRangerLike
-                    | ResolutionStrategy::CellRangerLikeEm => {
-                        // already false, not pug_exact_umi because
-                        // these methods don't use PUG
-                    }
-                    ResolutionStrategy::Parsimony
-                    | ResolutionStrategy::ParsimonyEm
-                    | ResolutionStrategy::ParsimonyGene
-                    | ResolutionStrategy::ParsimonyGeneEm => {
-                        pug_exact_umi = true;
-                    }
-                }
-            }
-            1 => {
-                match resolution {
-                    ResolutionStrategy::Trivial
-                    | ResolutionStrategy::CellRangerLike
-                    | ResolutionStrategy::CellRangerLikeEm => {
-                        // these methods don't currently support 1 edit UMIs
-                        crit!(
-                            log,
-                            "\n\nResolution strategy {:?} doesn't currently support 1-edit UMI resolution",
-                            resolution
-                        );
-                        bail!("Invalid command line option");
-                    }
-                    ResolutionStrategy::Parsimony
-                    | ResolutionStrategy::ParsimonyEm
-                    | ResolutionStrategy::ParsimonyGene
-                    | ResolutionStrategy::ParsimonyGeneEm => {
-                        pug_exact_umi = false;
-                    }
-                }
-            }
-            j => {
-                // no method currently supported edit distance 2 or greater correction
-                crit!(
-                    log,
-                    "\n\nResolution strategy {:?} doesn't currently support {}-edit UMI resolution",
-                    resolution,
-                    j
-                );
-                bail!("Invalid command line option");
-            }
-        }
-
-        if dump_eq && (resolution == ResolutionStrategy::Trivial) {
-            crit!(
-                log,
-                "\n\nGene equivalence classes are not meaningful in case of Trivial resolution."
-            );
-            std::process::exit(1);
-        }
-
-        if num_bootstraps > 0 {
-            match resolution {
-                ResolutionStrategy::CellRangerLikeEm
-                | ResolutionStrategy::ParsimonyEm
-                | ResolutionStrategy::ParsimonyGeneEm => {
-                    // sounds good
-                }
-                _ => {
-                    eprintln!(
-                        "\n\nThe num_bootstraps argument was set to {}, but bootstrapping can only be used with the cr-like-em, parsimony-em, or parsimony-gene-em resolution strategies",
-                        num_bootstraps
-                    );
-                    std::process::exit(1);
-                }
-            }
-        }
+        let num_threads = *t.get_one("threads").unwrap();
+        let qc = quant_config(t);
+        let (pug_exact_umi, large_graph_thresh) =
+            qc.resolve().unwrap_or_else(|e| exit_with_error(e));
 
         // first make sure that the input direcory passed in has the
         // appropriate json file in it.
@@ -492,19 +639,36 @@ fn main() -> anyhow::Result<()> {
         // build the QuantOpts structure
         let quant_opts = QuantOpts::builder()
             .input_dir(input_dir)
-            .tg_map(tg_map)
+            .tg_map(&qc.tg_map)
             .output_dir(output_dir)
             .num_threads(num_threads)
-            .num_bootstraps(num_bootstraps)
-            .init_uniform(init_uniform)
-            .summary_stat(summary_stat)
-            .dump_eq(dump_eq)
-            .use_mtx(use_mtx)
-            .resolution(resolution)
-            .sa_model(sa_model)
-            .small_thresh(small_thresh)
+            .num_bootstraps(qc.num_bootstraps)
+            .num_gibbs_samples(qc.num_gibbs_samples)
+            .thinning_factor(qc.thinning_factor)
+            .gibbs_burn_in(qc.gibbs_burn_in)
+            .init_uniform(qc.init_uniform)
+            .em_algorithm(qc.em_algorithm)
+            .vb_prior(qc.vb_prior)
+            .em_max_iter(qc.em_max_iter)
+            .em_rel_tol(qc.em_rel_tol)
+            .cross_cell_prior(qc.cross_cell_prior)
+            .prior_weight(qc.prior_weight)
+            .prior_clusters(qc.prior_clusters.as_ref())
+            .transcript_level(qc.transcript_level)
+            .gene_aggregate(qc.gene_aggregate)
+            .ref_lengths(qc.ref_lengths.as_ref())
+            .count_reads(qc.count_reads)
+            .summary_stat(qc.summary_stat)
+            .dump_eq(qc.dump_eq)
+            .dump_tx_eq(qc.dump_tx_eq)
+            .use_mtx(qc.use_mtx)
+            .resolution(qc.resolution)
+            .sa_model(qc.sa_model)
+            .small_thresh(qc.small_thresh)
             .large_graph_thresh(large_graph_thresh)
-            .filter_list(filter_list)
+            .filter_list(qc.filter_list.as_ref())
+            .deterministic(qc.deterministic)
+            .seed(qc.seed)
             .pug_exact_umi(pug_exact_umi)
             .cmdline(&cmdline)
             .version(VERSION)
@@ -521,24 +685,17 @@ fn main() -> anyhow::Result<()> {
                     Ok(_) => {}
                     // if we have an error, see if it's an error parsing
                     // the CSV or something else.
-                    Err(e) => match e.downcast_ref::<CSVError>() {
-                        Some(error) => {
-                            match *error.kind() {
-                                // if a deserialize error, we already complained about it
-                                ErrorKind::Deserialize { .. } => {
+                    Err(e) => {
+                        // if a CSV deserialize error, we already complained about it
+                        if let AlevinFryError::Other(ae) = &e {
+                            if let Some(error) = ae.downcast_ref::<CSVError>() {
+                                if let ErrorKind::Deserialize { .. } = *error.kind() {
                                     return Err(anyhow!("execution terminated unexpectedly"));
                                 }
-                                // if another type of error, just panic for now
-                                _ => {
-                                    panic!("could not quantify rad file.");
-                                }
                             }
                         }
-                        // if something else, just panic
-                        None => {
-                            panic!("could not quantify rad file.");
-                        }
-                    },
+                        exit_with_error(e);
+                    }
                 }; // end match if
             } else {
                 match alevin_fry::quant::quantify(quant_opts) {
@@ -546,24 +703,17 @@ fn main() -> anyhow::Result<()> {
                     Ok(_) => {}
                     // if we have an error, see if it's an error parsing
                     // the CSV or something else.
-                    Err(e) => match e.downcast_ref::<CSVError>() {
-                        Some(error) => {
-                            match *error.kind() {
-                                // if a deserialize error, we already complained about it
-                                ErrorKind::Deserialize { .. } => {
+                    Err(e) => {
+                        // if a CSV deserialize error, we already complained about it
+                        if let AlevinFryError::Other(ae) = &e {
+                            if let Some(error) = ae.downcast_ref::<CSVError>() {
+                                if let ErrorKind::Deserialize { .. } = *error.kind() {
                                     return Err(anyhow!("execution terminated unexpectedly"));
                                 }
-                                // if another type of error, just panic for now
-                                _ => {
-                                    panic!("could not quantify rad file.");
-                                }
                             }
                         }
-                        // if something else, just panic
-                        None => {
-                            panic!("could not quantify rad file.");
-                        }
-                    },
+                        exit_with_error(e);
+                    }
                 }; //end quant if
             }; // end velo_mode if
         } else {
@@ -574,24 +724,102 @@ fn main() -> anyhow::Result<()> {
         }
     } // end quant if
 
+    // run generate-permit-list, collate and quant in a single process,
+    // passing the permit list between the first two stages in memory.
+    if let Some(t) = opts.subcommand_matches("run") {
+        let expected_ori = match t
+            .get_one::<String>("expected-ori")
+            .unwrap()
+            .to_lowercase()
+            .as_str()
+        {
+            "fw" => ExpectedOri::Forward,
+            "rc" => ExpectedOri::Reverse,
+            "auto" => ExpectedOri::Auto,
+            _ => ExpectedOri::Both,
+        };
+        let output_dir: &PathBuf = t.get_one("output-dir").unwrap();
+
+        let permit_list = PermitListConfig::builder()
+            .filter_method(cell_filter_method(t, &log))
+            .expected_ori(expected_ori)
+            .auto_ori_chunks(*t.get_one::<usize>("auto-ori-chunks").unwrap())
+            .knee_plot(t.get_flag("knee-plot"))
+            .spill(t.get_flag("spill"))
+            .build();
+        let collate = collate_config(t);
+        let quant = quant_config(t);
+        let config = RunConfig::builder()
+            .rad_dir(t.get_one::<PathBuf>("input").unwrap().clone())
+            .permit_dir(output_dir.clone())
+            .quant_dir(output_dir.clone())
+            .num_threads(*t.get_one::<u32>("threads").unwrap())
+            .permit_list(permit_list)
+            .collate(collate)
+            .quant(quant)
+            .cleanup(t.get_flag("cleanup"))
+            .build();
+
+        let result = Pipeline::new(config)
+            .with_logger(log.clone())
+            .with_cmdline(&cmdline)
+            .run()
+            .unwrap_or_else(|e| exit_with_error(e));
+        for stage in &result.stages {
+            info!(
+                log,
+                "{} took {:.2}s (peak memory {})",
+                stage.stage,
+                stage.elapsed_secs,
+                stage
+                    .peak_rss_bytes
+                    .map(|b| format!("{} bytes", b.to_formatted_string(&Locale::en)))
+                    .unwrap_or_else(|| "unknown".to_string())
+            );
+        }
+    }
+
     // Given an input of equivalence class counts, perform inference
     // and output a target-by-cell count matrix.
     if let Some(t) = opts.subcommand_matches("infer") {
         let num_threads = *t.get_one("threads").unwrap();
         let use_mtx = !t.get_flag("use-eds");
         let output_dir = t.get_one("output-dir").unwrap();
-        let count_mat = t.get_one("count-mat").unwrap();
-        let eq_label_file = t.get_one("eq-labels").unwrap();
+        let input = if let Some(quant_dir) = t.get_one::<PathBuf>("quant-dir") {
+            alevin_fry::infer::InferInput::QuantDir(quant_dir)
+        } else if let Some(tx_eqc_file) = t.get_one::<PathBuf>("tx-eqc") {
+            alevin_fry::infer::InferInput::TranscriptLevel(tx_eqc_file)
+        } else {
+            alevin_fry::infer::InferInput::GeneLevel {
+                count_mat_file: t.get_one("count-mat").unwrap(),
+                eq_label_file: t.get_one("eq-labels").unwrap(),
+            }
+        };
         let filter_list: Option<&PathBuf> = t.get_one("quant-subset");
+        let groups: Option<&PathBuf> = t.get_one("groups");
         let usa_mode = t.get_flag("usa");
+        let num_bootstraps: u32 = *t.get_one("num-bootstraps").unwrap();
+        let summary_stat = t.get_flag("summary-stat");
+        let em_max_iter: u32 = *t.get_one("em-max-iter").unwrap();
+        let em_rel_tol: f32 = *t.get_one("em-rel-tol").unwrap();
+        let rng_seed = if t.get_flag("deterministic") {
+            Some(*t.get_one::<u64>("seed").unwrap())
+        } else {
+            None
+        };
 
         alevin_fry::infer::infer(
-            count_mat,
-            eq_label_file,
+            input,
             usa_mode,
             use_mtx,
+            num_bootstraps,
+            summary_stat,
+            em_max_iter,
+            em_rel_tol,
+            rng_seed,
             num_threads,
             filter_list,
+            groups,
             output_dir,
             &log,
         )
@@ -0,0 +1,921 @@
+/*
+ * Copyright (c) 2020-2022 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+//! An owned, serializable configuration of a full alevin-fry run
+//! (generate-permit-list → collate → quant), and a [`Pipeline`] that
+//! executes it programmatically, without going through the command line.
+//!
+//! ```no_run
+//! use alevin_fry::cellfilter::CellFilterMethod;
+//! use alevin_fry::pipeline::{ExpectedOri, Pipeline, PermitListConfig, QuantConfig, RunConfig};
+//! use alevin_fry::quant::ResolutionStrategy;
+//!
+//! let config = RunConfig::builder()
+//!     .rad_dir("mapping".into())
+//!     .permit_dir("permit".into())
+//!     .quant_dir("quant".into())
+//!     .permit_list(
+//!         PermitListConfig::builder()
+//!             .filter_method(CellFilterMethod::KneeFinding)
+//!             .expected_ori(ExpectedOri::Forward)
+//!             .build(),
+//!     )
+//!     .quant(
+//!         QuantConfig::builder()
+//!             .tg_map("t2g.tsv".into())
+//!             .resolution(ResolutionStrategy::CellRangerLike)
+//!             .build(),
+//!     )
+//!     .build();
+//! let result = Pipeline::new(config).run().unwrap();
+//! println!("quantified {} cells", result.quant.num_cells);
+//! ```
+//!
+//! When all stages are run together with [`Pipeline::run`], the permit list
+//! frequencies and correction map are passed from generate-permit-list to
+//! collate in memory, the intermediate files can optionally be removed
+//! once quantification succeeds, and a `run_manifest.json` file recording
+//! the time taken and peak memory used by each stage is written to the
+//! quantification directory.
+
+use crate::cellfilter::{
+    generate_permit_list, generate_permit_list_with_maps, CellFilterMethod, PermitListStats,
+    PermitMaps,
+};
+use crate::collate::{
+    collate, collate_with_permit_maps, CollateCodec, CollateCompression, CollateOrder, CollateStats,
+};
+use crate::constants as afconst;
+use crate::em::{EmAlgorithm, EmOpts};
+use crate::errors::AlevinFryError;
+use crate::prog_opts::{GenPermitListOpts, QuantOpts};
+use crate::quant::{quantify, QuantStats, ResolutionStrategy, SplicedAmbiguityModel};
+use crate::utils as afutils;
+
+use anyhow::Context;
+use bio_types::strand::Strand;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use slog::{info, o, warn};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Instant;
+use typed_builder::TypedBuilder;
+
+/// The expected orientation of properly mapped reads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExpectedOri {
+    /// keep only forward alignments
+    #[serde(rename = "fw")]
+    Forward,
+    /// keep only reverse-complement alignments
+    #[serde(rename = "rc")]
+    Reverse,
+    /// keep alignments in either orientation
+    #[serde(rename = "both", alias = "either")]
+    Both,
+    /// infer the orientation from the data
+    #[serde(rename = "auto")]
+    Auto,
+}
+
+impl ExpectedOri {
+    /// The strand passed on to `generate_permit_list`, and whether
+    /// the strand should be inferred from the data.
+    fn to_strand(self) -> (Strand, bool) {
+        match self {
+            ExpectedOri::Forward => (Strand::Forward, false),
+            ExpectedOri::Reverse => (Strand::Reverse, false),
+            ExpectedOri::Both => (Strand::Unknown, false),
+            ExpectedOri::Auto => (Strand::Unknown, true),
+        }
+    }
+}
+
+fn default_num_threads() -> u32 {
+    num_cpus::get() as u32
+}
+
+fn default_auto_ori_chunks() -> usize {
+    100
+}
+
+fn default_max_records() -> u32 {
+    30_000_000
+}
+
+fn default_zstd_level() -> i32 {
+    CollateCompression::DEFAULT_ZSTD_LEVEL
+}
+
+fn default_small_thresh() -> usize {
+    10
+}
+
+fn default_thinning_factor() -> u32 {
+    16
+}
+
+fn default_gibbs_burn_in() -> u32 {
+    100
+}
+
+fn default_vb_prior() -> f32 {
+    EmOpts::DEFAULT_VB_PRIOR
+}
+
+fn default_em_max_iter() -> u32 {
+    100
+}
+
+fn default_em_rel_tol() -> f32 {
+    1e-2
+}
+
+fn default_prior_weight() -> f32 {
+    EmOpts::DEFAULT_PRIOR_WEIGHT
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Options for the generate-permit-list stage.
+#[derive(Clone, Debug, Serialize, Deserialize, TypedBuilder)]
+pub struct PermitListConfig {
+    /// how the permitted (true) barcodes are determined
+    pub filter_method: CellFilterMethod,
+    /// the expected orientation of properly mapped reads
+    pub expected_ori: ExpectedOri,
+    /// the number of chunks to sample when the orientation is `auto`
+    #[builder(default = default_auto_ori_chunks())]
+    #[serde(default = "default_auto_ori_chunks")]
+    pub auto_ori_chunks: usize,
+    /// whether to write an SVG knee plot
+    #[builder(default = false)]
+    #[serde(default)]
+    pub knee_plot: bool,
+    /// whether to spill a compressed copy of the input RAD file to the
+    /// permit list directory, from which it is read by collate (this is
+    /// always done if the input is a named pipe)
+    #[builder(default = false)]
+    #[serde(default)]
+    pub spill: bool,
+}
+
+/// Options for the collate stage.
+#[derive(Clone, Debug, Serialize, Deserialize, TypedBuilder)]
+pub struct CollateConfig {
+    /// the maximum number of read records to keep in memory at once
+    #[builder(default = default_max_records())]
+    #[serde(default = "default_max_records")]
+    pub max_records: u32,
+    /// the memory budget (in bytes) for the collate stage, which
+    /// determines the size of the temporary buckets and buffers
+    #[builder(default)]
+    #[serde(default)]
+    pub max_memory: Option<u64>,
+    /// whether to compress the collated RAD file with snappy; this
+    /// is a shorthand for `codec = snappy` and is ignored if a codec
+    /// is given
+    #[builder(default = false)]
+    #[serde(default)]
+    pub compress: bool,
+    /// the codec with which to compress the collated RAD file
+    #[builder(default)]
+    #[serde(default)]
+    pub codec: Option<CollateCodec>,
+    /// the zstd compression level
+    #[builder(default = default_zstd_level())]
+    #[serde(default = "default_zstd_level")]
+    pub zstd_level: i32,
+    /// the number of background zstd compression threads
+    #[builder(default = 0)]
+    #[serde(default)]
+    pub zstd_threads: u32,
+    /// the order in which the cells are written to the collated RAD file
+    #[builder(default)]
+    #[serde(default)]
+    pub order: CollateOrder,
+}
+
+impl Default for CollateConfig {
+    fn default() -> Self {
+        CollateConfig::builder().build()
+    }
+}
+
+impl CollateConfig {
+    /// The compression of the collated RAD file described by this
+    /// configuration.
+    pub fn compression(&self) -> CollateCompression {
+        match self.codec {
+            Some(CollateCodec::Zstd) => {
+                CollateCompression::zstd(self.zstd_level, self.zstd_threads)
+            }
+            Some(CollateCodec::Snappy) => CollateCompression::snappy(),
+            Some(CollateCodec::None) => CollateCompression::none(),
+            None => CollateCompression::from_flag(self.compress),
+        }
+    }
+}
+
+/// Options for the quant stage.  These mirror the options of the
+/// `quant` command.
+#[derive(Clone, Debug, Serialize, Deserialize, TypedBuilder)]
+pub struct QuantConfig {
+    /// the transcript to gene map
+    pub tg_map: PathBuf,
+    /// the resolution strategy by which molecules will be counted
+    pub resolution: ResolutionStrategy,
+    /// the preferred model of splicing ambiguity
+    #[builder(default)]
+    #[serde(default)]
+    pub sa_model: SplicedAmbiguityModel,
+    /// the number of bootstraps to perform
+    #[builder(default = 0)]
+    #[serde(default)]
+    pub num_bootstraps: u32,
+    /// the number of posterior samples to draw with the Gibbs sampler
+    #[builder(default = 0)]
+    #[serde(default)]
+    pub num_gibbs_samples: u32,
+    /// the number of rounds of the Gibbs sampler between recorded samples
+    #[builder(default = default_thinning_factor())]
+    #[serde(default = "default_thinning_factor")]
+    pub thinning_factor: u32,
+    /// the number of initial rounds of the Gibbs sampler to discard
+    #[builder(default = default_gibbs_burn_in())]
+    #[serde(default = "default_gibbs_burn_in")]
+    pub gibbs_burn_in: u32,
+    /// the algorithm used to resolve gene-level multimapping
+    #[builder(default)]
+    #[serde(default)]
+    pub em_algorithm: EmAlgorithm,
+    /// the per-gene Dirichlet prior used by the VBEM algorithm
+    #[builder(default = EmOpts::DEFAULT_VB_PRIOR)]
+    #[serde(default = "default_vb_prior")]
+    pub vb_prior: f32,
+    /// the maximum number of EM iterations per cell
+    #[builder(default = default_em_max_iter())]
+    #[serde(default = "default_em_max_iter")]
+    pub em_max_iter: u32,
+    /// the convergence tolerance of the EM
+    #[builder(default = default_em_rel_tol())]
+    #[serde(default = "default_em_rel_tol")]
+    pub em_rel_tol: f32,
+    /// make a first pass over all cells to compute a dataset-level
+    /// prior for the per-cell EM
+    #[builder(default = false)]
+    #[serde(default)]
+    pub cross_cell_prior: bool,
+    /// the number of pseudo-molecules of prior evidence added to each
+    /// multi-gene equivalence class
+    #[builder(default = default_prior_weight())]
+    #[serde(default = "default_prior_weight")]
+    pub prior_weight: f32,
+    /// compute the cross-cell prior separately for the clusters of
+    /// cells listed in this file
+    #[builder(default)]
+    #[serde(default)]
+    pub prior_clusters: Option<PathBuf>,
+    /// quantify transcripts, rather than genes
+    #[builder(default = false)]
+    #[serde(default)]
+    pub transcript_level: bool,
+    /// with `transcript_level`, also write the gene-level aggregate
+    /// of the transcript counts
+    #[builder(default = false)]
+    #[serde(default)]
+    pub gene_aggregate: bool,
+    /// the lengths of the reference sequences; if given, the EM is
+    /// length-aware
+    #[builder(default)]
+    #[serde(default)]
+    pub ref_lengths: Option<PathBuf>,
+    /// count reads rather than UMIs, for protocols without UMIs
+    #[builder(default = false)]
+    #[serde(default)]
+    pub count_reads: bool,
+    #[builder(default = false)]
+    #[serde(default)]
+    pub init_uniform: bool,
+    #[builder(default = false)]
+    #[serde(default)]
+    pub summary_stat: bool,
+    /// whether to write the gene-level equivalence class counts
+    #[builder(default = false)]
+    #[serde(default)]
+    pub dump_eq: bool,
+    /// whether to write the transcript-level equivalence class counts
+    #[builder(default = false)]
+    #[serde(default)]
+    pub dump_tx_eq: bool,
+    /// write the count matrix in matrix market (rather than EDS) format
+    #[builder(default = true)]
+    #[serde(default = "default_true")]
+    pub use_mtx: bool,
+    /// the Hamming distance within which potentially colliding UMIs
+    /// will be considered for correction; if not given, the default
+    /// for the resolution strategy is used.
+    #[builder(default)]
+    #[serde(default)]
+    pub umi_edit_dist: Option<u32>,
+    /// the order of a PUG above which the alternative resolution strategy
+    /// will be applied; if not given, the default for the resolution
+    /// strategy is used.
+    #[builder(default)]
+    #[serde(default)]
+    pub large_graph_thresh: Option<usize>,
+    /// cells with fewer than these many reads will be resolved using a custom approach
+    #[builder(default = default_small_thresh())]
+    #[serde(default = "default_small_thresh")]
+    pub small_thresh: usize,
+    /// only quantify the barcodes listed in this file
+    #[builder(default)]
+    #[serde(default)]
+    pub filter_list: Option<PathBuf>,
+    /// write cells in collated-file order and seed all random draws
+    /// from `seed` and the cell barcode
+    #[builder(default = false)]
+    #[serde(default)]
+    pub deterministic: bool,
+    /// the seed used for random draws in deterministic mode
+    #[builder(default = 0)]
+    #[serde(default)]
+    pub seed: u64,
+}
+
+impl QuantConfig {
+    /// Check that the options are consistent with each other, and resolve
+    /// the UMI edit distance and large graph threshold defaults.  Returns
+    /// the `pug_exact_umi` flag and the large graph threshold to use.
+    pub fn resolve(&self) -> Result<(bool, usize), AlevinFryError> {
+        let uses_pug = matches!(
+            self.resolution,
+            ResolutionStrategy::Parsimony
+                | ResolutionStrategy::ParsimonyEm
+                | ResolutionStrategy::ParsimonyGene
+                | ResolutionStrategy::ParsimonyGeneEm
+        );
+
+        let umi_edit_dist = self.umi_edit_dist.unwrap_or(u32::from(uses_pug));
+        let pug_exact_umi = match (umi_edit_dist, uses_pug) {
+            (0, pug) => pug,
+            (1, true) => false,
+            (d, _) => {
+                return Err(AlevinFryError::InvalidConfig(format!(
+                    "resolution strategy {:?} doesn't currently support {}-edit UMI resolution",
+                    self.resolution, d
+                )));
+            }
+        };
+
+        let large_graph_thresh = self
+            .large_graph_thresh
+            .unwrap_or(if uses_pug { 1000 } else { 0 });
+
+        if self.dump_eq && self.resolution == ResolutionStrategy::Trivial {
+            return Err(AlevinFryError::InvalidConfig(
+                "gene equivalence classes are not meaningful in case of Trivial resolution"
+                    .to_string(),
+            ));
+        }
+
+        if self.num_bootstraps > 0 && self.num_gibbs_samples > 0 {
+            return Err(AlevinFryError::InvalidConfig(
+                "num_bootstraps and num_gibbs_samples cannot both be set".to_string(),
+            ));
+        }
+
+        if self.summary_stat && self.num_bootstraps == 0 && self.num_gibbs_samples == 0 {
+            return Err(AlevinFryError::InvalidConfig(
+                "summary_stat requires num_bootstraps or num_gibbs_samples to be set".to_string(),
+            ));
+        }
+
+        if self.em_algorithm == EmAlgorithm::Vbem && self.vb_prior <= 0.0 {
+            return Err(AlevinFryError::InvalidConfig(format!(
+                "vb_prior must be positive, but was set to {}",
+                self.vb_prior
+            )));
+        }
+
+        if self.em_max_iter < 1 {
+            return Err(AlevinFryError::InvalidConfig(
+                "em_max_iter must be at least 1".to_string(),
+            ));
+        }
+
+        if self.em_rel_tol.is_nan() || self.em_rel_tol <= 0.0 {
+            return Err(AlevinFryError::InvalidConfig(format!(
+                "em_rel_tol must be positive, but was set to {}",
+                self.em_rel_tol
+            )));
+        }
+
+        if self.prior_weight < 0.0 {
+            return Err(AlevinFryError::InvalidConfig(format!(
+                "prior_weight must be non-negative, but was set to {}",
+                self.prior_weight
+            )));
+        }
+
+        if self.prior_clusters.is_some() && !self.cross_cell_prior {
+            return Err(AlevinFryError::InvalidConfig(
+                "prior_clusters requires cross_cell_prior to be set".to_string(),
+            ));
+        }
+
+        if self.cross_cell_prior
+            && !matches!(
+                self.resolution,
+                ResolutionStrategy::CellRangerLikeEm
+                    | ResolutionStrategy::ParsimonyEm
+                    | ResolutionStrategy::ParsimonyGeneEm
+            )
+        {
+            return Err(AlevinFryError::InvalidConfig(
+                "a cross-cell prior can only be used with the cr-like-em, parsimony-em, or parsimony-gene-em resolution strategies".to_string(),
+            ));
+        }
+
+        if self.ref_lengths.is_some()
+            && !matches!(
+                self.resolution,
+                ResolutionStrategy::CellRangerLikeEm
+                    | ResolutionStrategy::ParsimonyEm
+                    | ResolutionStrategy::ParsimonyGeneEm
+            )
+        {
+            return Err(AlevinFryError::InvalidConfig(
+                "ref_lengths can only be used with the cr-like-em, parsimony-em, or parsimony-gene-em resolution strategies".to_string(),
+            ));
+        }
+
+        if self.count_reads
+            && !matches!(
+                self.resolution,
+                ResolutionStrategy::CellRangerLike | ResolutionStrategy::CellRangerLikeEm
+            )
+        {
+            return Err(AlevinFryError::InvalidConfig(
+                "count_reads can only be used with the cr-like or cr-like-em resolution strategies"
+                    .to_string(),
+            ));
+        }
+
+        if self.gene_aggregate && !self.transcript_level {
+            return Err(AlevinFryError::InvalidConfig(
+                "gene_aggregate requires transcript_level to be set".to_string(),
+            ));
+        }
+
+        if self.transcript_level {
+            if !matches!(
+                self.resolution,
+                ResolutionStrategy::CellRangerLike | ResolutionStrategy::CellRangerLikeEm
+            ) {
+                return Err(AlevinFryError::InvalidConfig(
+                    "transcript-level quantification can only be used with the cr-like or cr-like-em resolution strategies".to_string(),
+                ));
+            }
+            if self.dump_eq
+                || self.num_bootstraps > 0
+                || self.num_gibbs_samples > 0
+                || self.cross_cell_prior
+            {
+                return Err(AlevinFryError::InvalidConfig(
+                    "transcript_level cannot be combined with dump_eq, num_bootstraps, num_gibbs_samples or cross_cell_prior".to_string(),
+                ));
+            }
+        }
+
+        if self.num_gibbs_samples > 0 && self.thinning_factor == 0 {
+            return Err(AlevinFryError::InvalidConfig(
+                "thinning_factor must be at least 1".to_string(),
+            ));
+        }
+
+        if (self.num_bootstraps > 0 || self.num_gibbs_samples > 0)
+            && !matches!(
+                self.resolution,
+                ResolutionStrategy::CellRangerLikeEm
+                    | ResolutionStrategy::ParsimonyEm
+                    | ResolutionStrategy::ParsimonyGeneEm
+            )
+        {
+            return Err(AlevinFryError::InvalidConfig(format!(
+                "num_bootstraps was set to {} and num_gibbs_samples to {}, but inferential replicates can only be drawn with the cr-like-em, parsimony-em, or parsimony-gene-em resolution strategies",
+                self.num_bootstraps, self.num_gibbs_samples
+            )));
+        }
+
+        Ok((pug_exact_umi, large_graph_thresh))
+    }
+}
+
+/// The configuration of a full alevin-fry run.
+#[derive(Clone, Debug, Serialize, Deserialize, TypedBuilder)]
+pub struct RunConfig {
+    /// the directory containing the input `map.rad` file
+    pub rad_dir: PathBuf,
+    /// the directory to which the permit list and collated
+    /// RAD file are written
+    pub permit_dir: PathBuf,
+    /// the directory to which the quantification results are written
+    pub quant_dir: PathBuf,
+    /// the number of threads to use
+    #[builder(default = default_num_threads())]
+    #[serde(default = "default_num_threads")]
+    pub num_threads: u32,
+    pub permit_list: PermitListConfig,
+    #[builder(default)]
+    #[serde(default)]
+    pub collate: CollateConfig,
+    pub quant: QuantConfig,
+    /// whether to remove the intermediate files (the collated RAD file
+    /// and the permit map) once the run has completed successfully
+    #[builder(default = false)]
+    #[serde(default)]
+    pub cleanup: bool,
+}
+
+/// The resources used by a single stage of a [`Pipeline`] run.
+#[derive(Clone, Debug, Serialize)]
+pub struct StageReport {
+    /// the name of the stage
+    pub stage: String,
+    /// the wall-clock time taken by the stage, in seconds
+    pub elapsed_secs: f64,
+    /// the peak resident set size of the process during the stage, in
+    /// bytes (if it could be determined).  If the peak could not be reset
+    /// at the start of the stage, this is the peak since the process started.
+    pub peak_rss_bytes: Option<u64>,
+}
+
+/// The results of each stage of a [`Pipeline`] run.
+#[derive(Clone, Debug, Serialize)]
+pub struct PipelineResult {
+    pub permit_list: PermitListStats,
+    pub collate: CollateStats,
+    pub quant: QuantStats,
+    /// the resources used by each stage, in the order they were run
+    pub stages: Vec<StageReport>,
+    /// the wall-clock time taken by the entire run, in seconds
+    pub total_elapsed_secs: f64,
+    /// the intermediate files removed after the run (if `cleanup` was set)
+    pub removed_files: Vec<PathBuf>,
+}
+
+/// The files written to the permit list directory that are only needed
+/// by subsequent stages of the pipeline, and that are therefore removed
+/// when `cleanup` is requested (the collated RAD file is also removed).
+const INTERMEDIATE_FILES: &[&str] = &[
+    "permit_map.bin",
+    "unmapped_bc_count_collated.bin",
+    afconst::SPILLED_RAD_FILE,
+];
+
+/// Run `f` as the stage `name` of the pipeline, recording the time it
+/// takes and the peak memory it uses.
+fn run_stage<T, F>(name: &str, f: F) -> Result<(T, StageReport), AlevinFryError>
+where
+    F: FnOnce() -> Result<T, AlevinFryError>,
+{
+    afutils::reset_peak_rss();
+    let start = Instant::now();
+    let res = f()?;
+    let report = StageReport {
+        stage: name.to_string(),
+        elapsed_secs: start.elapsed().as_secs_f64(),
+        peak_rss_bytes: afutils::peak_rss_bytes(),
+    };
+    Ok((res, report))
+}
+
+/// Executes the stages of an alevin-fry run described by a [`RunConfig`].
+pub struct Pipeline {
+    config: RunConfig,
+    cmdline: String,
+    log: slog::Logger,
+}
+
+impl Pipeline {
+    /// Create a pipeline for `config`.  By default, nothing is logged; use
+    /// [`Pipeline::with_logger`] to provide a logger.
+    pub fn new(config: RunConfig) -> Pipeline {
+        // record the configuration in place of a command line in the
+        // metadata written by each stage.
+        let cmdline = serde_json::to_string(&config).unwrap_or_default();
+        Pipeline {
+            config,
+            cmdline,
+            log: slog::Logger::root(slog::Discard, o!()),
+        }
+    }
+
+    /// Use `log` to log the progress of the pipeline.
+    pub fn with_logger(mut self, log: slog::Logger) -> Pipeline {
+        self.log = log;
+        self
+    }
+
+    /// Record `cmdline`, rather than the serialized configuration, as the
+    /// command line in the metadata written by each stage.
+    pub fn with_cmdline(mut self, cmdline: &str) -> Pipeline {
+        self.cmdline = cmdline.to_string();
+        self
+    }
+
+    /// The configuration of this pipeline.
+    pub fn config(&self) -> &RunConfig {
+        &self.config
+    }
+
+    fn permit_list_opts(&self) -> GenPermitListOpts<'_, '_, '_, '_, '_> {
+        let pl = &self.config.permit_list;
+        let (expected_ori, auto_ori) = pl.expected_ori.to_strand();
+        GenPermitListOpts::builder()
+            .input_dir(&self.config.rad_dir)
+            .output_dir(&self.config.permit_dir)
+            .fmeth(pl.filter_method.clone())
+            .expected_ori(expected_ori)
+            .auto_ori(auto_ori)
+            .auto_ori_chunks(pl.auto_ori_chunks)
+            .velo_mode(false)
+            .knee_plot(pl.knee_plot)
+            .spill(pl.spill)
+            .cmdline(&self.cmdline)
+            .version(afconst::VERSION)
+            .log(&self.log)
+            .build()
+    }
+
+    /// Run the generate-permit-list stage.
+    pub fn run_permit_list(&self) -> Result<PermitListStats, AlevinFryError> {
+        generate_permit_list(self.permit_list_opts())
+    }
+
+    /// Run the collate stage; the permit list must already exist.
+    pub fn run_collate(&self) -> Result<CollateStats, AlevinFryError> {
+        collate(
+            &self.config.permit_dir,
+            &self.config.rad_dir,
+            self.config.num_threads,
+            self.config.collate.max_records,
+            self.config.collate.max_memory,
+            self.config.collate.compression(),
+            self.config.collate.order,
+            &self.cmdline,
+            afconst::VERSION,
+            &self.log,
+        )
+    }
+
+    /// Run the quant stage; the collated RAD file must already exist.
+    pub fn run_quant(&self) -> Result<QuantStats, AlevinFryError> {
+        let qc = &self.config.quant;
+        let (pug_exact_umi, large_graph_thresh) = qc.resolve()?;
+        let quant_opts = QuantOpts::builder()
+            .input_dir(&self.config.permit_dir)
+            .tg_map(&qc.tg_map)
+            .output_dir(&self.config.quant_dir)
+            .num_threads(self.config.num_threads)
+            .num_bootstraps(qc.num_bootstraps)
+            .num_gibbs_samples(qc.num_gibbs_samples)
+            .thinning_factor(qc.thinning_factor)
+            .gibbs_burn_in(qc.gibbs_burn_in)
+            .em_algorithm(qc.em_algorithm)
+            .vb_prior(qc.vb_prior)
+            .em_max_iter(qc.em_max_iter)
+            .em_rel_tol(qc.em_rel_tol)
+            .cross_cell_prior(qc.cross_cell_prior)
+            .prior_weight(qc.prior_weight)
+            .prior_clusters(qc.prior_clusters.as_ref())
+            .transcript_level(qc.transcript_level)
+            .gene_aggregate(qc.gene_aggregate)
+            .ref_lengths(qc.ref_lengths.as_ref())
+            .count_reads(qc.count_reads)
+            .init_uniform(qc.init_uniform)
+            .summary_stat(qc.summary_stat)
+            .dump_eq(qc.dump_eq)
+            .dump_tx_eq(qc.dump_tx_eq)
+            .use_mtx(qc.use_mtx)
+            .resolution(qc.resolution)
+            .sa_model(qc.sa_model)
+            .small_thresh(qc.small_thresh)
+            .large_graph_thresh(large_graph_thresh)
+            .filter_list(qc.filter_list.as_ref())
+            .deterministic(qc.deterministic)
+            .seed(qc.seed)
+            .pug_exact_umi(pug_exact_umi)
+            .cmdline(&self.cmdline)
+            .version(afconst::VERSION)
+            .log(&self.log)
+            .build();
+        quantify(quant_opts)
+    }
+
+    /// Run the collate stage using the permit list state `maps` held
+    /// in memory.
+    fn run_collate_with_maps(&self, maps: PermitMaps) -> Result<CollateStats, AlevinFryError> {
+        collate_with_permit_maps(
+            &self.config.permit_dir,
+            &self.config.rad_dir,
+            self.config.num_threads,
+            self.config.collate.max_records,
+            self.config.collate.max_memory,
+            self.config.collate.compression(),
+            self.config.collate.order,
+            maps,
+            &self.cmdline,
+            afconst::VERSION,
+            &self.log,
+        )
+    }
+
+    /// Remove the intermediate files of the run, returning the
+    /// paths of the files that were removed.
+    fn cleanup(&self, collate: &CollateStats) -> Vec<PathBuf> {
+        let mut candidates = vec![collate.output_file.clone()];
+        candidates.extend(
+            INTERMEDIATE_FILES
+                .iter()
+                .map(|f| self.config.permit_dir.join(f)),
+        );
+
+        let mut removed = Vec::with_capacity(candidates.len());
+        for path in candidates.into_iter().filter(|p| p.exists()) {
+            match std::fs::remove_file(&path) {
+                Ok(()) => removed.push(path),
+                Err(e) => warn!(
+                    self.log,
+                    "could not remove intermediate file {} : {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        removed
+    }
+
+    /// Write the manifest of a completed run to `run_manifest.json`
+    /// in the quantification directory.
+    fn write_manifest(&self, result: &PipelineResult) -> anyhow::Result<()> {
+        let manifest = json!({
+            "cmd" : &self.cmdline,
+            "version_str" : afconst::VERSION,
+            "config" : &self.config,
+            "total_elapsed_secs" : result.total_elapsed_secs,
+            "stages" : &result.stages,
+            "removed_files" : &result.removed_files,
+            "permit_list" : &result.permit_list,
+            "collate" : &result.collate,
+            "quant" : &result.quant,
+        });
+
+        let m_path = self.config.quant_dir.join("run_manifest.json");
+        let mut m_file =
+            std::fs::File::create(&m_path).context("could not create run manifest file.")?;
+        let manifest_string =
+            serde_json::to_string_pretty(&manifest).context("could not format json.")?;
+        m_file
+            .write_all(manifest_string.as_bytes())
+            .context("cannot write to run_manifest.json file")?;
+        Ok(())
+    }
+
+    /// Run all stages of the pipeline, in order.  The permit list is
+    /// passed from generate-permit-list to collate in memory.
+    pub fn run(&self) -> Result<PipelineResult, AlevinFryError> {
+        // validate the quantification options before doing any work
+        self.config.quant.resolve()?;
+        let start = Instant::now();
+
+        let ((permit_list, maps), gpl_report) = run_stage("generate-permit-list", || {
+            generate_permit_list_with_maps(self.permit_list_opts())
+        })?;
+        info!(
+            self.log,
+            "permit list contains {} barcodes", permit_list.num_permitted
+        );
+
+        let (collate, collate_report) = run_stage("collate", || self.run_collate_with_maps(maps))?;
+        info!(self.log, "collated {} cells", collate.num_cells);
+
+        let (quant, quant_report) = run_stage("quant", || self.run_quant())?;
+        info!(self.log, "quantified {} cells", quant.num_cells);
+
+        let removed_files = if self.config.cleanup {
+            let removed = self.cleanup(&collate);
+            info!(self.log, "removed {} intermediate files", removed.len());
+            removed
+        } else {
+            Vec::new()
+        };
+
+        let result = PipelineResult {
+            permit_list,
+            collate,
+            quant,
+            stages: vec![gpl_report, collate_report, quant_report],
+            total_elapsed_secs: start.elapsed().as_secs_f64(),
+            removed_files,
+        };
+        self.write_manifest(&result)?;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quant_config() -> QuantConfig {
+        QuantConfig::builder()
+            .tg_map("t2g.tsv".into())
+            .resolution(ResolutionStrategy::CellRangerLikeEm)
+            .build()
+    }
+
+    #[test]
+    fn test_run_config_round_trip() {
+        let config = RunConfig::builder()
+            .rad_dir("mapping".into())
+            .permit_dir("permit".into())
+            .quant_dir("quant".into())
+            .permit_list(
+                PermitListConfig::builder()
+                    .filter_method(CellFilterMethod::KneeFinding)
+                    .expected_ori(ExpectedOri::Forward)
+                    .build(),
+            )
+            .quant(quant_config())
+            .build();
+        let json = serde_json::to_value(&config).unwrap();
+        let parsed: RunConfig = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+
+        // the omitted options take the same defaults as with the builder
+        let minimal = json!({
+            "rad_dir": "mapping",
+            "permit_dir": "permit",
+            "quant_dir": "quant",
+            "permit_list": { "filter_method": "KneeFinding", "expected_ori": "fw" },
+            "quant": { "tg_map": "t2g.tsv", "resolution": "cr-like-em" },
+        });
+        let parsed: RunConfig = serde_json::from_value(minimal).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+    }
+
+    #[test]
+    fn test_resolve_rejects_invalid_combinations() {
+        let invalid = [
+            QuantConfig {
+                transcript_level: true,
+                num_bootstraps: 10,
+                ..quant_config()
+            },
+            QuantConfig {
+                gene_aggregate: true,
+                ..quant_config()
+            },
+            QuantConfig {
+                num_bootstraps: 10,
+                num_gibbs_samples: 10,
+                ..quant_config()
+            },
+            QuantConfig {
+                prior_clusters: Some("clusters.tsv".into()),
+                ..quant_config()
+            },
+            QuantConfig {
+                resolution: ResolutionStrategy::CellRangerLike,
+                umi_edit_dist: Some(1),
+                ..quant_config()
+            },
+        ];
+        for config in invalid {
+            assert!(
+                matches!(config.resolve(), Err(AlevinFryError::InvalidConfig(_))),
+                "{:?} was accepted",
+                config
+            );
+        }
+
+        assert_eq!(quant_config().resolve().unwrap(), (false, 0));
+        let config = QuantConfig {
+            transcript_level: true,
+            gene_aggregate: true,
+            ..quant_config()
+        };
+        assert!(config.resolve().is_ok());
+    }
+}
@@ -5,6 +5,7 @@ use slog;
 use typed_builder::TypedBuilder;
 
 use crate::cellfilter::CellFilterMethod;
+use crate::em::{EmAlgorithm, EmOpts};
 use crate::quant::{ResolutionStrategy, SplicedAmbiguityModel};
 
 use std::path::PathBuf;
@@ -17,9 +18,39 @@ pub struct QuantOpts<'a, 'b, 'c, 'd, 'e, 'f, 'g> {
     pub output_dir: &'c PathBuf,
     pub num_threads: u32,
     pub num_bootstraps: u32,
+    #[builder(default = 0)]
+    pub num_gibbs_samples: u32,
+    #[builder(default = 16)]
+    pub thinning_factor: u32,
+    #[builder(default = 100)]
+    pub gibbs_burn_in: u32,
     pub init_uniform: bool,
+    #[builder(default)]
+    pub em_algorithm: EmAlgorithm,
+    #[builder(default = EmOpts::DEFAULT_VB_PRIOR)]
+    pub vb_prior: f32,
+    #[builder(default = 100)]
+    pub em_max_iter: u32,
+    #[builder(default = 1e-2)]
+    pub em_rel_tol: f32,
+    #[builder(default = false)]
+    pub cross_cell_prior: bool,
+    #[builder(default = EmOpts::DEFAULT_PRIOR_WEIGHT)]
+    pub prior_weight: f32,
+    #[builder(default)]
+    pub prior_clusters: Option<&'d PathBuf>,
+    #[builder(default = false)]
+    pub transcript_level: bool,
+    #[builder(default = false)]
+    pub gene_aggregate: bool,
+    #[builder(default)]
+    pub ref_lengths: Option<&'d PathBuf>,
+    #[builder(default = false)]
+    pub count_reads: bool,
     pub summary_stat: bool,
     pub dump_eq: bool,
+    #[builder(default = false)]
+    pub dump_tx_eq: bool,
     pub use_mtx: bool,
     pub resolution: ResolutionStrategy,
     pub pug_exact_umi: bool,
@@ -27,6 +58,10 @@ pub struct QuantOpts<'a, 'b, 'c, 'd, 'e, 'f, 'g> {
     pub small_thresh: usize,
     pub large_graph_thresh: usize,
     pub filter_list: Option<&'d PathBuf>,
+    #[builder(default = false)]
+    pub deterministic: bool,
+    #[builder(default = 0)]
+    pub seed: u64,
     pub cmdline: &'e str,
     pub version: &'f str,
     #[serde(skip_serializing)]
@@ -39,7 +74,15 @@ pub struct GenPermitListOpts<'a, 'b, 'c, 'd, 'e> {
     pub output_dir: &'b PathBuf,
     pub fmeth: CellFilterMethod,
     pub expected_ori: Strand,
+    #[builder(default = false)]
+    pub auto_ori: bool,
+    #[builder(default = 100)]
+    pub auto_ori_chunks: usize,
     pub velo_mode: bool,
+    #[builder(default = false)]
+    pub knee_plot: bool,
+    #[builder(default = false)]
+    pub spill: bool,
     pub cmdline: &'c str,
     pub version: &'d str,
     #[serde(skip_serializing)]
@@ -625,6 +625,37 @@ fn resolve_num_molecules_crlike_from_vec(
     }
 }
 
+/// For data without UMIs, count the reads (rather than the distinct UMIs)
+/// of a cell that are compatible with each set of labels.  The labels of a
+/// read are the genes of its alignments if `tid_to_gid` is given, and the
+/// transcripts of its alignments otherwise.
+pub fn get_num_reads(
+    cell_chunk: &rad_types::Chunk,
+    tid_to_gid: Option<&[u32]>,
+    eqclass_hash: &mut HashMap<Vec<u32>, u32, ahash::RandomState>,
+    _log: &slog::Logger,
+) {
+    let mut labels = Vec::<u32>::with_capacity(16);
+    for rec in &cell_chunk.reads {
+        labels.clear();
+        match tid_to_gid {
+            Some(t2g) => labels.extend(rec.refs.iter().map(|tid| t2g[*tid as usize])),
+            None => labels.extend(rec.refs.iter().copied()),
+        }
+        labels.sort_unstable();
+        labels.dedup();
+        if labels.is_empty() {
+            continue;
+        }
+        match eqclass_hash.get_mut(&labels) {
+            Some(c) => *c += 1,
+            None => {
+                eqclass_hash.insert(labels.clone(), 1);
+            }
+        }
+    }
+}
+
 pub fn get_num_molecules_cell_ranger_like_small(
     cell_chunk: &mut rad_types::Chunk,
     tid_to_gid: &[u32],
@@ -719,6 +750,31 @@ pub fn get_num_molecules_cell_ranger_like(
     }
 }
 
+/// Resolve the molecules of a cell as in `get_num_molecules_cell_ranger_like`,
+/// but without projecting the equivalence classes to the gene level, so that
+/// the resulting equivalence classes (and their UMI counts) in
+/// `tx_eqclass_hash` are over transcript ids.
+pub fn get_num_molecules_cell_ranger_like_transcript(
+    eq_map: &EqMap,
+    tx_eqclass_hash: &mut HashMap<Vec<u32>, u32, ahash::RandomState>,
+    _log: &slog::Logger,
+) {
+    // TODO: better capacity
+    let mut umi_tx_count_vec: Vec<(u64, u32, u32)> = vec![];
+
+    // for each equivalence class
+    for eqinfo in &eq_map.eqc_info {
+        // add every (umi, count), transcript pair as a triplet
+        // of (umi, transcript_id, count) to the output vector
+        for umi_ct in &eqinfo.umis {
+            for t in eq_map.refs_for_eqc(eqinfo.eq_num) {
+                umi_tx_count_vec.push((umi_ct.0, *t, umi_ct.1));
+            }
+        }
+    }
+    resolve_num_molecules_crlike_from_vec(&mut umi_tx_count_vec, tx_eqclass_hash);
+}
+
 pub fn get_num_molecules_trivial_discard_all_ambig(
     eq_map: &EqMap,
     tid_to_gid: &[u32],
@@ -17,17 +17,18 @@ use slog::{crit, info, warn};
 use needletail::bitkmer::*;
 use num_format::{Locale, ToFormattedString};
 use scroll::Pread;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufRead, BufReader, BufWriter};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::string::ToString;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
@@ -37,20 +38,27 @@ use std::fmt;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 
-use crate::em::{em_optimize, em_optimize_subset, run_bootstrap, EmInitType};
-use crate::eq_class::{EqMap, EqMapType, IndexedEqList};
+use crate::collate::CollateCodec;
+use crate::em::{
+    em_optimize, em_optimize_subset, run_bootstrap, run_bootstrap_subset, run_gibbs,
+    run_gibbs_subset, EmInitType, EmOpts, EmStats, GibbsOpts,
+};
+use crate::eq_class::{EqMap, EqMapType, IndexedEqList, TxEqcDump};
+use crate::errors::AlevinFryError;
 use crate::io_utils;
 use crate::prog_opts::QuantOpts;
 use crate::pugutils;
 use crate::utils as afutils;
 use libradicl::rad_types;
 
-type BufferedGzFile = BufWriter<GzEncoder<fs::File>>;
+pub(crate) type BufferedGzFile = BufWriter<GzEncoder<fs::File>>;
 
-#[derive(PartialEq, Eq, Debug, Default, Clone, Copy, Serialize)]
+#[derive(PartialEq, Eq, Debug, Default, Clone, Copy, Serialize, Deserialize)]
 pub enum SplicedAmbiguityModel {
+    #[serde(alias = "prefer-ambig")]
     PreferAmbiguity,
     #[default]
+    #[serde(alias = "winner-take-all")]
     WinnerTakeAll,
 }
 
@@ -66,15 +74,22 @@ impl FromStr for SplicedAmbiguityModel {
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Default, Clone, Copy, Serialize)]
+#[derive(PartialEq, Eq, Debug, Default, Clone, Copy, Serialize, Deserialize)]
 pub enum ResolutionStrategy {
+    #[serde(alias = "trivial")]
     Trivial,
     #[default]
+    #[serde(alias = "cr-like")]
     CellRangerLike,
+    #[serde(alias = "cr-like-em")]
     CellRangerLikeEm,
+    #[serde(alias = "parsimony-em")]
     ParsimonyEm,
+    #[serde(alias = "parsimony")]
     Parsimony,
+    #[serde(alias = "parsimony-gene-em")]
     ParsimonyGeneEm,
+    #[serde(alias = "parsimony-gene")]
     ParsimonyGene,
 }
 
@@ -101,13 +116,32 @@ impl FromStr for ResolutionStrategy {
     }
 }
 
-struct BootstrapHelper {
-    bsfile: Option<BufferedGzFile>,
-    mean_var_files: Option<(BufferedGzFile, BufferedGzFile)>,
+/// Summary of a run of [`quantify`].
+#[derive(Clone, Debug, Serialize)]
+pub struct QuantStats {
+    /// the directory to which the quantification results were written
+    pub output_dir: PathBuf,
+    /// the number of cells that were quantified
+    pub num_cells: u64,
+    /// the number of columns (genes, or gene-status pairs in USA mode) of the count matrix
+    pub num_genes: usize,
+    /// the number of read records processed
+    pub num_records: usize,
+    /// whether the quantification was performed in USA mode
+    pub usa_mode: bool,
+    /// the number of cells resolved using the alternative (large-graph) strategy
+    pub num_alt_resolved_cells: usize,
+    /// the number of cells for which resolution yielded no molecules
+    pub num_empty_resolved_cells: usize,
+}
+
+pub(crate) struct BootstrapHelper {
+    pub(crate) bsfile: Option<BufferedGzFile>,
+    pub(crate) mean_var_files: Option<(BufferedGzFile, BufferedGzFile)>,
 }
 
 impl BootstrapHelper {
-    fn new(
+    pub(crate) fn new(
         output_path: &std::path::Path,
         num_bootstraps: u32,
         summary_stat: bool,
@@ -151,13 +185,263 @@ impl BootstrapHelper {
     }
 }
 
+/// The counts of a single cell, in the form in which they will be written.
+pub(crate) enum CellCounts {
+    /// the EDS encoded count vector
+    Eds(Vec<u8>),
+    /// the (gene index, count) pairs of the expressed genes
+    Sparse(Vec<(usize, f32)>),
+}
+
+/// The output computed for a single cell, to be written by
+/// [`QuantOutputInfo::submit_cell`].
+struct CellOutput {
+    bc_str: String,
+    counts: CellCounts,
+    // the featureDump.txt fields following the barcode
+    features: String,
+    // the EDS encoded bootstraps (or their mean and variance)
+    bootstrap_eds: Vec<Vec<u8>>,
+    // the gene-level equivalence classes of this cell, if we are
+    // dumping them
+    gene_eqc: Option<Vec<(Vec<u32>, u32)>>,
+    // the transcript-level equivalence classes of this cell, if we are
+    // dumping them
+    tx_eqc: Option<Vec<(Vec<u32>, u32)>>,
+    em_stats: EmStats,
+    // the gene-level aggregate of transcript-level counts, if requested
+    gene_counts: Option<CellCounts>,
+}
+
+/// A summary of the per-cell EM statistics, recorded in `quant.json`.
+#[derive(Debug, Default)]
+struct EmSummary {
+    num_cells: usize,
+    // the cells in which the EM was run
+    num_em_cells: usize,
+    num_unconverged_cells: usize,
+    total_iterations: u64,
+    max_iterations: u32,
+    total_multi_gene_frac: f64,
+}
+
+impl EmSummary {
+    fn add(&mut self, stats: &EmStats) {
+        self.num_cells += 1;
+        self.total_multi_gene_frac += stats.multi_gene_frac as f64;
+        if stats.num_iterations > 0 {
+            self.num_em_cells += 1;
+            self.total_iterations += stats.num_iterations as u64;
+            self.max_iterations = self.max_iterations.max(stats.num_iterations);
+            if !stats.converged {
+                self.num_unconverged_cells += 1;
+            }
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mean = |total: f64, n: usize| if n > 0 { total / n as f64 } else { 0.0 };
+        json!({
+            "num_em_cells" : self.num_em_cells,
+            "num_unconverged_cells" : self.num_unconverged_cells,
+            "mean_iterations" : mean(self.total_iterations as f64, self.num_em_cells),
+            "max_iterations" : self.max_iterations,
+            "mean_multi_gene_frac" : mean(self.total_multi_gene_frac, self.num_cells),
+        })
+    }
+}
+
 struct QuantOutputInfo {
     barcode_file: BufWriter<fs::File>,
     eds_file: BufWriter<GzEncoder<fs::File>>,
     feature_file: BufWriter<fs::File>,
     trimat: sprs::TriMatI<f32, u32>,
+    // the gene-level aggregate of the transcript-level counts, if requested;
+    // the first is used with matrix market output, the second with EDS output
+    gene_trimat: Option<sprs::TriMatI<f32, u32>>,
+    gene_eds_file: Option<BufWriter<GzEncoder<fs::File>>>,
+    // the transcript-level equivalence classes, if we are dumping them
+    tx_eqc_map: Option<TxEqcMap>,
     row_index: usize,
     bootstrap_helper: BootstrapHelper, //sample_or_mean_and_var: (BufWriter<GzEncoder<fs::File>>)
+    // the per-cell EM statistics, if the resolution strategy uses the EM
+    em_stats_file: Option<BufWriter<fs::File>>,
+    em_summary: EmSummary,
+    // if true, cells are written in the order in which they
+    // appear in the collated file, rather than in the order
+    // in which they are finished.
+    ordered: bool,
+    // the next cell (in collated file order) to be written
+    next_cell: usize,
+    // finished cells waiting for the cells preceding them
+    pending: BTreeMap<usize, CellOutput>,
+}
+
+impl QuantOutputInfo {
+    /// Submit the output of the cell `cell_num`.  If the output is ordered,
+    /// the cell is held until all of the cells preceding it have been
+    /// written, so that row `i` of the output is always cell `i` of the
+    /// input.  Otherwise, it is written immediately.
+    fn submit_cell(&mut self, cell_num: usize, cell: CellOutput, eqid_map_lock: &Mutex<EqcMap>) {
+        if !self.ordered {
+            self.write_cell(cell, eqid_map_lock);
+            return;
+        }
+        self.pending.insert(cell_num, cell);
+        while let Some(cell) = self.pending.remove(&self.next_cell) {
+            self.write_cell(cell, eqid_map_lock);
+            self.next_cell += 1;
+        }
+    }
+
+    /// Write the output of `cell` as the next row of the output.
+    fn write_cell(&mut self, cell: CellOutput, eqid_map_lock: &Mutex<EqcMap>) {
+        // get the row index and then increment it
+        let row_index = self.row_index;
+        self.row_index += 1;
+
+        // write to barcode file
+        writeln!(&mut self.barcode_file, "{}", cell.bc_str).expect("can't write to barcode file.");
+
+        // write to matrix file
+        match cell.counts {
+            CellCounts::Eds(eds_bytes) => {
+                // write in eds format
+                self.eds_file
+                    .write_all(&eds_bytes)
+                    .expect("can't write to matrix file.");
+            }
+            CellCounts::Sparse(expressed) => {
+                // fill out the triplet matrix in memory
+                for (ind, val) in expressed {
+                    self.trimat.add_triplet(row_index, ind, val);
+                }
+            }
+        }
+
+        // write the gene-level aggregate in the same format
+        match cell.gene_counts {
+            Some(CellCounts::Eds(eds_bytes)) => {
+                if let Some(gene_eds_file) = &mut self.gene_eds_file {
+                    gene_eds_file
+                        .write_all(&eds_bytes)
+                        .expect("can't write to gene matrix file.");
+                }
+            }
+            Some(CellCounts::Sparse(expressed)) => {
+                if let Some(gene_trimat) = &mut self.gene_trimat {
+                    for (ind, val) in expressed {
+                        gene_trimat.add_triplet(row_index, ind, val);
+                    }
+                }
+            }
+            None => {}
+        }
+
+        writeln!(&mut self.feature_file, "{}\t{}", cell.bc_str, cell.features)
+            .expect("can't write to feature file");
+
+        if let Some(em_stats_file) = &mut self.em_stats_file {
+            let es = &cell.em_stats;
+            writeln!(
+                em_stats_file,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                cell.bc_str,
+                es.num_iterations,
+                es.converged,
+                es.final_rel_diff,
+                es.log_likelihood,
+                es.multi_gene_frac
+            )
+            .expect("can't write to EM stats file");
+            self.em_summary.add(es);
+        }
+
+        if let Some((meanf, varf)) = &mut self.bootstrap_helper.mean_var_files {
+            if let [mean_bytes, var_bytes] = &cell.bootstrap_eds[..] {
+                meanf
+                    .write_all(mean_bytes)
+                    .expect("can't write to bootstrap mean file.");
+                varf.write_all(var_bytes)
+                    .expect("can't write to bootstrap var file.");
+            }
+        } else if let Some(bsfile) = &mut self.bootstrap_helper.bsfile {
+            for bt_eds_bytes in &cell.bootstrap_eds {
+                bsfile
+                    .write_all(bt_eds_bytes)
+                    .expect("can't write to bootstrap file");
+            }
+        } // done bootstrap writing
+
+        // if we are dumping the equivalence class output, fill in
+        // the in-memory representation here.
+        if let Some(gene_eqc) = cell.gene_eqc {
+            let eqmap_deref = eqid_map_lock.lock();
+            let geqmap = &mut *eqmap_deref.unwrap();
+            // the next available global id for a gene-level
+            // equivalence class
+            let mut next_id = geqmap.global_eqc.len() as u64;
+            let num_cell_eqs = gene_eqc.len();
+            for (labels, count) in gene_eqc {
+                match geqmap.global_eqc.get(&labels) {
+                    Some(eqid) => {
+                        geqmap.cell_level_count.push((*eqid, count));
+                    }
+                    None => {
+                        geqmap.cell_level_count.push((next_id, count));
+                        geqmap.global_eqc.insert(labels, next_id);
+                        next_id += 1;
+                    }
+                }
+            }
+            geqmap.cell_offset.push((row_index, num_cell_eqs));
+        }
+
+        if let (Some(tx_eqc), Some(tx_eqc_map)) = (cell.tx_eqc, &mut self.tx_eqc_map) {
+            tx_eqc_map.add_cell(cell.bc_str, tx_eqc);
+        }
+    }
+}
+
+/// The transcript-level equivalence classes of the cells written so
+/// far, in row order.
+struct TxEqcMap {
+    // the id of each equivalence class label seen so far
+    label_ids: HashMap<Vec<u32>, u32, ahash::RandomState>,
+    dump: TxEqcDump,
+}
+
+impl TxEqcMap {
+    fn new(ref_names: Vec<String>) -> Self {
+        let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+        TxEqcMap {
+            label_ids: HashMap::with_hasher(s),
+            dump: TxEqcDump {
+                ref_names,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Record the equivalence classes (and their counts) of the cell
+    /// with barcode `bc_str` as the next row.
+    fn add_cell(&mut self, bc_str: String, cell_eqc: Vec<(Vec<u32>, u32)>) {
+        let mut counts = Vec::with_capacity(cell_eqc.len());
+        for (labels, count) in cell_eqc {
+            let eqid = match self.label_ids.get(&labels) {
+                Some(eqid) => *eqid,
+                None => {
+                    let eqid = self.dump.eq_labels.len() as u32;
+                    self.dump.eq_labels.push(labels.clone());
+                    self.label_ids.insert(labels, eqid);
+                    eqid
+                }
+            };
+            counts.push((eqid, count));
+        }
+        self.dump.barcodes.push(bc_str);
+        self.dump.cell_counts.push(counts);
+    }
 }
 
 struct EqcMap {
@@ -299,56 +583,363 @@ fn write_eqc_counts(
     Ok(true)
 }
 
+/// Gene-level abundances aggregated across all cells (and, optionally,
+/// across the cells of each cluster), used as an informative prior when
+/// resolving the gene-ambiguous molecules of each cell.
+struct CrossCellPrior {
+    // the normalized abundance profile of each cluster; the last
+    // profile is that of all cells
+    profiles: Vec<Vec<f32>>,
+    // the cluster of each barcode listed in the clusters file
+    cell_cluster: HashMap<u64, usize, ahash::RandomState>,
+}
+
+impl CrossCellPrior {
+    /// Compute the prior from the collated RAD file read by `br`.  The
+    /// abundance of each gene (or, in USA-mode, of each gene and splicing
+    /// status) is the number of distinct UMIs (or, if `count_reads` is
+    /// set, of reads), in each cell, that are unique to it.  If `clusters`
+    /// is given, it is a two-column
+    /// (headerless) tab-separated file of barcodes and cluster names, and
+    /// a separate profile is computed for each cluster; the remaining
+    /// cells use the profile of all cells.
+    #[allow(clippy::too_many_arguments)]
+    fn from_collated<R: Read>(
+        mut br: R,
+        tid_to_gid: &[u32],
+        usa_mode: bool,
+        num_rows: usize,
+        retained_bc: Option<&HashSet<u64, ahash::RandomState>>,
+        clusters: Option<&PathBuf>,
+        bclen: u16,
+        count_reads: bool,
+        log: &slog::Logger,
+    ) -> anyhow::Result<CrossCellPrior> {
+        let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+        let mut cell_cluster = HashMap::with_hasher(s);
+        let mut num_clusters = 0usize;
+        if let Some(cpath) = clusters {
+            let mut cluster_ids = HashMap::<String, usize>::new();
+            let cfile = File::open(cpath)
+                .with_context(|| format!("couldn't open the clusters file {}", cpath.display()))?;
+            for (lnum, line) in BufReader::new(cfile).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let (bc_str, cluster) = line.split_once('\t').with_context(|| {
+                    format!(
+                        "line {} of the clusters file doesn't have 2 tab-separated columns",
+                        lnum + 1
+                    )
+                })?;
+                let mut bnk = BitNuclKmer::new(bc_str.trim().as_bytes(), bclen as u8, false);
+                let (_, k, _) = bnk.next().with_context(|| {
+                    format!("invalid barcode on line {} of the clusters file", lnum + 1)
+                })?;
+                let next_id = cluster_ids.len();
+                let cid = *cluster_ids
+                    .entry(cluster.trim().to_string())
+                    .or_insert(next_id);
+                cell_cluster.insert(k.0, cid);
+            }
+            num_clusters = cluster_ids.len();
+        }
+
+        // skip over the header of the file
+        let hdr = rad_types::RadHeader::from_bytes(&mut br);
+        let _fl_tags = rad_types::TagSection::from_bytes(&mut br);
+        let rl_tags = rad_types::TagSection::from_bytes(&mut br);
+        let _al_tags = rad_types::TagSection::from_bytes(&mut br);
+        let _ft_vals = rad_types::FileTags::from_bytes(&mut br);
+        let types = io_utils::RecordTypes::from_tags(&rl_tags)?;
+
+        let mut sums = vec![vec![0f64; num_rows]; num_clusters + 1];
+        let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+        let mut cell_umis: HashSet<(Vec<u32>, u64), ahash::RandomState> = HashSet::with_hasher(s);
+        let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+        let mut gene_eqc: HashMap<Vec<u32>, u32, ahash::RandomState> = HashMap::with_hasher(s);
+        let mut labels = Vec::<u32>::new();
+        let mut num_prior_cells = 0usize;
+
+        for _ in 0..hdr.num_chunks {
+            let c = io_utils::read_chunk(&mut br, &types)?;
+            let bc = match c.reads.first() {
+                Some(r) => r.bc,
+                None => continue,
+            };
+            if let Some(rbc) = retained_bc {
+                if !rbc.contains(&bc) {
+                    continue;
+                }
+            }
+
+            // the distinct (gene set, UMI) pairs of this cell; without
+            // UMIs, every read is counted
+            for r in &c.reads {
+                labels.clear();
+                labels.extend(r.refs.iter().map(|t| tid_to_gid[*t as usize]));
+                labels.sort_unstable();
+                labels.dedup();
+                if count_reads {
+                    *gene_eqc.entry(labels.clone()).or_insert(0) += 1;
+                } else {
+                    cell_umis.insert((labels.clone(), r.umi));
+                }
+            }
+            for (l, _umi) in cell_umis.drain() {
+                *gene_eqc.entry(l).or_insert(0) += 1;
+            }
+
+            let cluster = cell_cluster.get(&bc).copied().unwrap_or(num_clusters);
+            let counts = if usa_mode {
+                afutils::extract_counts(&gene_eqc, num_rows)
+            } else {
+                let mut counts = vec![0f32; num_rows];
+                for (l, count) in gene_eqc.iter() {
+                    if l.len() == 1 {
+                        counts[l[0] as usize] += *count as f32;
+                    }
+                }
+                counts
+            };
+            for (i, v) in counts.iter().enumerate() {
+                sums[cluster][i] += *v as f64;
+                if cluster != num_clusters {
+                    sums[num_clusters][i] += *v as f64;
+                }
+            }
+            gene_eqc.clear();
+            num_prior_cells += 1;
+        }
+
+        // normalize each profile; clusters without any evidence
+        // fall back to the profile of all cells.
+        let global_total: f64 = sums[num_clusters].iter().sum();
+        let normalize =
+            |v: &[f64], total: f64| -> Vec<f32> { v.iter().map(|x| (x / total) as f32).collect() };
+        let global = if global_total > 0.0 {
+            normalize(&sums[num_clusters], global_total)
+        } else {
+            vec![0f32; num_rows]
+        };
+        let mut profiles = Vec::with_capacity(num_clusters + 1);
+        for cluster_sums in sums.iter().take(num_clusters) {
+            let total: f64 = cluster_sums.iter().sum();
+            if total > 0.0 {
+                profiles.push(normalize(cluster_sums, total));
+            } else {
+                profiles.push(global.clone());
+            }
+        }
+        profiles.push(global);
+
+        info!(
+            log,
+            "computed the cross-cell prior from {} cells in {} cluster(s).",
+            num_prior_cells.to_formatted_string(&Locale::en),
+            num_clusters.max(1)
+        );
+
+        Ok(CrossCellPrior {
+            profiles,
+            cell_cluster,
+        })
+    }
+
+    /// The prior abundance profile to use for the cell with barcode `bc`.
+    fn for_cell(&self, bc: u64) -> &[f32] {
+        let global = self.profiles.len() - 1;
+        &self.profiles[self.cell_cluster.get(&bc).copied().unwrap_or(global)]
+    }
+}
+
+/// Sum the transcript-level `counts` of a cell to the gene level.  In USA-mode,
+/// the counts of spliced and unspliced transcripts are assigned to the spliced
+/// and unspliced rows of their gene, respectively (and so the ambiguous rows
+/// are empty).  Returns the count of every gene row.
+fn aggregate_to_genes(
+    counts: &[f32],
+    tid_to_gid: &[u32],
+    num_gene_rows: usize,
+    usa_mode: bool,
+) -> Vec<f32> {
+    let mut gene_counts = vec![0f32; num_gene_rows];
+    let unspliced_offset = num_gene_rows / 3;
+    for (tid, c) in counts.iter().enumerate() {
+        if *c > 0.0 {
+            let gid = tid_to_gid[tid];
+            let row = if !usa_mode {
+                gid as usize
+            } else if afutils::is_spliced(gid) {
+                (gid >> 1) as usize
+            } else {
+                unspliced_offset + (gid >> 1) as usize
+            };
+            gene_counts[row] += *c;
+        }
+    }
+    gene_counts
+}
+
+/// Write the column names of the gene-level matrix to `path`.  In USA-mode,
+/// these are the spliced names, the unspliced names, and then the
+/// ambiguous names.
+fn write_gene_names(
+    path: &std::path::Path,
+    gene_names: &[String],
+    usa_mode: bool,
+) -> std::io::Result<()> {
+    let gn_file = File::create(path)?;
+    let mut gn_writer = BufWriter::new(gn_file);
+
+    // if we are not using unspliced then just write the gene names
+    if !usa_mode {
+        for g in gene_names {
+            gn_writer.write_all(format!("{}\n", g).as_bytes())?;
+        }
+    } else {
+        // otherwise, we write the spliced names, the unspliced names, and then
+        // the ambiguous names
+        for g in gene_names.iter() {
+            gn_writer.write_all(format!("{}\n", *g).as_bytes())?;
+        }
+        // unspliced
+        for g in gene_names.iter() {
+            gn_writer.write_all(format!("{}-U\n", *g).as_bytes())?;
+        }
+        // ambiguous
+        for g in gene_names.iter() {
+            gn_writer.write_all(format!("{}-A\n", *g).as_bytes())?;
+        }
+    }
+    gn_writer.flush()
+}
+
+/// Open the collated RAD file at `path`, compressed with `codec`, for reading.
+fn open_collated_reader(
+    path: &std::path::Path,
+    codec: CollateCodec,
+) -> anyhow::Result<Box<dyn Read>> {
+    let f = File::open(path).context("could not open the collated RAD file")?;
+    Ok(match codec {
+        CollateCodec::None => Box::new(BufReader::new(f)),
+        CollateCodec::Snappy => Box::new(snap::read::FrameDecoder::new(BufReader::new(f))),
+        CollateCodec::Zstd => Box::new(
+            zstd::stream::read::Decoder::new(f)
+                .context("could not create the zstd decoder for the collated RAD file")?,
+        ),
+    })
+}
+
 // TODO: see if we'd rather pass an structure
 // with these options
-pub fn quantify(quant_opts: QuantOpts) -> anyhow::Result<()> {
+pub fn quantify(quant_opts: QuantOpts) -> Result<QuantStats, AlevinFryError> {
     let parent = std::path::Path::new(quant_opts.input_dir);
     let log = quant_opts.log;
 
     // read the collate metadata
+    let collate_md_path = parent.join("collate.json");
+    if !collate_md_path.exists() {
+        return Err(AlevinFryError::MissingFile {
+            path: collate_md_path,
+            hint: Some("run collate before quant".to_string()),
+        });
+    }
     let collate_md_file =
-        File::open(parent.join("collate.json")).context("could not open the collate.json file.")?;
+        File::open(&collate_md_path).context("could not open the collate.json file.")?;
     let collate_md: serde_json::Value = serde_json::from_reader(&collate_md_file)?;
 
-    // is the collated RAD file compressed?
-    let compressed_input = collate_md["compressed_output"]
-        .as_bool()
-        .context("could not read compressed_output field from collate metadata.")?;
+    // the codec of the collated RAD file; collate.json files written
+    // before the codec was recorded only say whether the file is
+    // (snappy) compressed.
+    let codec = match collate_md.get("codec").and_then(|c| c.as_str()) {
+        Some(c) => CollateCodec::from_str(c).map_err(anyhow::Error::msg)?,
+        None => {
+            let compressed_input = collate_md["compressed_output"]
+                .as_bool()
+                .context("could not read compressed_output field from collate metadata.")?;
+            if compressed_input {
+                CollateCodec::Snappy
+            } else {
+                CollateCodec::None
+            }
+        }
+    };
 
-    if compressed_input {
-        let i_file =
-            File::open(parent.join("map.collated.rad.sz")).context("run collate before quant")?;
-        let br = snap::read::FrameDecoder::new(BufReader::new(&i_file));
+    // collate gives the records of inputs without UMIs a UMI of 0, which
+    // can only be quantified by counting reads.
+    let has_umis = collate_md
+        .get("has_umis")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    if !has_umis && !quant_opts.count_reads {
+        return Err(AlevinFryError::InvalidConfig(
+            "the input records have no UMIs; pass --count-reads to count reads instead."
+                .to_string(),
+        ));
+    }
 
-        info!(
-            log,
-            "quantifying from compressed, collated RAD file {:?}", i_file
-        );
+    let i_path = parent.join(codec.collated_file_name());
+    if !i_path.exists() {
+        return Err(AlevinFryError::MissingFile {
+            path: i_path,
+            hint: Some("run collate before quant".to_string()),
+        });
+    }
+    let i_file = File::open(&i_path).context("run collate before quant")?;
 
-        do_quantify(br, quant_opts)
+    // with a cross-cell prior, the collated file is read once more, up
+    // front, to aggregate the gene abundances across cells.
+    let prior_input = if quant_opts.cross_cell_prior {
+        Some(open_collated_reader(&i_path, codec)?)
     } else {
-        let i_file =
-            File::open(parent.join("map.collated.rad")).context("run collate before quant")?;
-        let br = BufReader::new(&i_file);
-
-        info!(
-            log,
-            "quantifying from uncompressed, collated RAD file {:?}", i_file
-        );
+        None
+    };
 
-        do_quantify(br, quant_opts)
+    match codec {
+        CollateCodec::None => {
+            let br = BufReader::new(&i_file);
+            info!(
+                log,
+                "quantifying from uncompressed, collated RAD file {:?}", i_file
+            );
+            do_quantify(br, prior_input, quant_opts)
+        }
+        CollateCodec::Snappy => {
+            let br = snap::read::FrameDecoder::new(BufReader::new(&i_file));
+            info!(
+                log,
+                "quantifying from snappy-compressed, collated RAD file {:?}", i_file
+            );
+            do_quantify(br, prior_input, quant_opts)
+        }
+        CollateCodec::Zstd => {
+            let br = zstd::stream::read::Decoder::new(&i_file)
+                .context("could not create the zstd decoder for the collated RAD file")?;
+            info!(
+                log,
+                "quantifying from zstd-compressed, collated RAD file {:?}", i_file
+            );
+            do_quantify(br, prior_input, quant_opts)
+        }
     }
 }
 
 // TODO: see if we'd rather pass an structure
 // with these options
-pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<()> {
+pub fn do_quantify<T: Read>(
+    mut br: T,
+    prior_input: Option<Box<dyn Read>>,
+    quant_opts: QuantOpts,
+) -> Result<QuantStats, AlevinFryError> {
     let parent = std::path::Path::new(quant_opts.input_dir);
     let hdr = rad_types::RadHeader::from_bytes(&mut br);
 
     let init_uniform = quant_opts.init_uniform;
     let summary_stat = quant_opts.summary_stat;
     let dump_eq = quant_opts.dump_eq;
+    let dump_tx_eq = quant_opts.dump_tx_eq;
     let use_mtx = quant_opts.use_mtx;
     let resolution = quant_opts.resolution;
     let pug_exact_umi = quant_opts.pug_exact_umi;
@@ -359,6 +950,31 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
     let log = quant_opts.log;
     let num_threads = quant_opts.num_threads;
     let num_bootstraps = quant_opts.num_bootstraps;
+    let em_opts = EmOpts {
+        algorithm: quant_opts.em_algorithm,
+        vb_prior: quant_opts.vb_prior,
+        max_iter: quant_opts.em_max_iter,
+        rel_diff_tolerance: quant_opts.em_rel_tol,
+        prior_weight: quant_opts.prior_weight,
+    };
+    let gibbs_opts = GibbsOpts {
+        num_samples: quant_opts.num_gibbs_samples,
+        thinning: quant_opts.thinning_factor,
+        burn_in: quant_opts.gibbs_burn_in,
+    };
+    // the number of inferential replicates (bootstraps or posterior
+    // samples) we will write for each cell; at most one of the two
+    // is non-zero.
+    let num_replicates = num_bootstraps.max(gibbs_opts.num_samples);
+    let deterministic = quant_opts.deterministic;
+    let transcript_level = quant_opts.transcript_level;
+    let gene_aggregate = quant_opts.gene_aggregate;
+    let count_reads = quant_opts.count_reads;
+    let rng_seed = if deterministic {
+        Some(quant_opts.seed)
+    } else {
+        None
+    };
 
     // in the collated rad file, we have 1 cell per chunk.
     // we make this value `mut` since, if we have a non-empty
@@ -410,11 +1026,6 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
             tid_to_gid = v;
             usa_mode = us;
             if usa_mode {
-                assert_eq!(
-		           num_bootstraps, 0,
-		           "currently USA-mode (all-in-one unspliced/spliced/ambiguous) analysis cannot be used with bootstrapping."
-		        );
-
                 match resolution {
                     ResolutionStrategy::Parsimony
                     | ResolutionStrategy::ParsimonyEm
@@ -443,7 +1054,7 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
             }
         }
         Err(e) => {
-            return Err(e);
+            return Err(e.into());
         }
     }
 
@@ -455,10 +1066,18 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
     );
 
     // read the map for the number of unmapped reads per corrected barcode
-    let bc_unmapped_file =
-        std::fs::File::open(parent.join("unmapped_bc_count_collated.bin")).unwrap();
-    let bc_unmapped_map: Arc<HashMap<u64, u32>> =
-        Arc::new(bincode::deserialize_from(&bc_unmapped_file).unwrap());
+    let bc_unmapped_path = parent.join("unmapped_bc_count_collated.bin");
+    if !bc_unmapped_path.exists() {
+        return Err(AlevinFryError::MissingFile {
+            path: bc_unmapped_path,
+            hint: Some("run collate before quant".to_string()),
+        });
+    }
+    let bc_unmapped_file = std::fs::File::open(&bc_unmapped_path)?;
+    let bc_unmapped_map: Arc<HashMap<u64, u32>> = Arc::new(
+        bincode::deserialize_from(&bc_unmapped_file)
+            .context("could not deserialize unmapped_bc_count_collated.bin")?,
+    );
 
     // file-level
     let fl_tags = rad_types::TagSection::from_bytes(&mut br);
@@ -473,8 +1092,16 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
     let ft_vals = rad_types::FileTags::from_bytes(&mut br);
     info!(log, "File-level tag values {:?}", ft_vals);
 
-    let bct = rl_tags.tags[0].typeid;
-    let umit = rl_tags.tags[1].typeid;
+    // the types for the barcodes and umis; the UMI tag is absent from
+    // files of protocols without UMIs, which can only be quantified by
+    // counting reads.
+    let types = io_utils::RecordTypes::from_tags(&rl_tags)?;
+    if !types.has_umi() && !count_reads {
+        return Err(AlevinFryError::InvalidConfig(
+            "the input records have no UMIs; pass --count-reads to count reads instead."
+                .to_string(),
+        ));
+    }
 
     // if we have a filter list, extract it here
     let mut retained_bc: Option<HashSet<u64, ahash::RandomState>> = None;
@@ -487,7 +1114,7 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
                 retained_bc = Some(fset);
             }
             Err(e) => {
-                return Err(e);
+                return Err(e.into());
             }
         }
     }
@@ -530,13 +1157,12 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
 
     // the number of cells left to process
     let cells_to_process = Arc::new(AtomicUsize::new(num_cells as usize));
+    // set if a worker thread fails, so that the others stop
+    let quant_failed = Arc::new(AtomicBool::new(false));
     // each thread needs a *read-only* copy of this transcript <-> gene map
     let tid_to_gid_shared = std::sync::Arc::new(tid_to_gid);
     // the number of reference sequences
     let ref_count = hdr.ref_count as u32;
-    // the types for the barcodes and umis
-    let bc_type = rad_types::decode_int_type_tag(bct).expect("unsupported barcode type id.");
-    let umi_type = rad_types::decode_int_type_tag(umit).expect("unsupported umi type id.");
     // the number of genes (different than the number of reference sequences, which are transcripts)
     let num_genes = gene_name_to_id.len();
 
@@ -553,7 +1179,7 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
     let bc_file = fs::File::create(bc_path)?;
 
     let mat_path = output_matrix_path.join("quants_mat.gz");
-    let boot_helper = BootstrapHelper::new(output_path, num_bootstraps, summary_stat);
+    let boot_helper = BootstrapHelper::new(output_path, num_replicates, summary_stat);
     let buffered = GzEncoder::new(fs::File::create(&mat_path)?, Compression::default());
 
     let ff_path = output_path.join("featureDump.txt");
@@ -562,6 +1188,21 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
 	 ff_file,
 	 "CB\tCorrectedReads\tMappedReads\tDeduplicatedReads\tMappingRate\tDedupRate\tMeanByMax\tNumGenesExpressed\tNumGenesOverMean"
      )?;
+    // if the resolution strategy uses the EM, record how it fared in each cell
+    let em_stats_file = match resolution {
+        ResolutionStrategy::CellRangerLikeEm
+        | ResolutionStrategy::ParsimonyEm
+        | ResolutionStrategy::ParsimonyGeneEm => {
+            let mut ef = BufWriter::new(fs::File::create(output_path.join("em_stats.txt"))?);
+            writeln!(
+                ef,
+                "CB\tEmIterations\tEmConverged\tEmFinalRelDiff\tEmLogLikelihood\tMultiGeneFrac"
+            )?;
+            Some(ef)
+        }
+        _ => None,
+    };
+
     let alt_res_cells = Arc::new(Mutex::new(Vec::<u64>::new()));
     let empty_resolved_cells = Arc::new(Mutex::new(Vec::<u64>::new()));
 
@@ -571,8 +1212,8 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
         0usize
     };
 
-    // the length of the vector of gene counts we'll use
-    let num_rows = if usa_mode {
+    // the number of gene-level rows
+    let num_gene_rows = if usa_mode {
         // the number of genes should be the max gene id + 1
         // over the gene ids in gene_name_to_id.  The +2 is
         // because the ids in gene_name_to_id are only for
@@ -592,26 +1233,100 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
         num_genes
     };
 
+    // the length of the vector of counts we'll use
+    let num_rows = if transcript_level {
+        ref_count as usize
+    } else {
+        num_gene_rows
+    };
+
     let usa_offsets = if usa_mode {
-        Some((num_rows / 3, (2 * num_rows / 3)))
+        Some((num_gene_rows / 3, (2 * num_gene_rows / 3)))
     } else {
         None
     };
 
+    // if the EM should be length-aware, get the length of each row
+    let label_lengths = match quant_opts.ref_lengths {
+        Some(lpath) => {
+            let ref_lengths = afutils::read_ref_lengths(lpath, &rname_to_id)?;
+            info!(
+                log,
+                "read the lengths of {} reference sequences; the EM will be length-aware.",
+                ref_lengths.len().to_formatted_string(&Locale::en)
+            );
+            Some(Arc::new(afutils::row_lengths(
+                &ref_lengths,
+                &tid_to_gid_shared,
+                num_rows,
+                usa_mode,
+                transcript_level,
+            )))
+        }
+        None => None,
+    };
+
+    // if requested, make a first pass over the cells to compute the
+    // cross-cell prior
+    let cross_cell_prior = match prior_input {
+        Some(pbr) => Some(Arc::new(CrossCellPrior::from_collated(
+            pbr,
+            &tid_to_gid_shared,
+            usa_mode,
+            num_rows,
+            retained_bc.as_ref(),
+            quant_opts.prior_clusters,
+            ft_vals.bclen,
+            count_reads,
+            log,
+        )?)),
+        None => None,
+    };
+
     let trimat = sprs::TriMatI::<f32, u32>::with_capacity((num_cells as usize, num_rows), tmcap);
+    let gene_trimat = if gene_aggregate && use_mtx {
+        Some(sprs::TriMatI::<f32, u32>::new((
+            num_cells as usize,
+            num_gene_rows,
+        )))
+    } else {
+        None
+    };
+    let gene_eds_file = if gene_aggregate && !use_mtx {
+        let gene_mat_path = output_matrix_path.join("quants_gene_mat.gz");
+        Some(BufWriter::new(GzEncoder::new(
+            fs::File::create(gene_mat_path)?,
+            Compression::default(),
+        )))
+    } else {
+        None
+    };
 
     let bc_writer = Arc::new(Mutex::new(QuantOutputInfo {
         barcode_file: BufWriter::new(bc_file),
         eds_file: BufWriter::new(buffered),
         feature_file: BufWriter::new(ff_file),
         trimat,
+        gene_trimat,
+        gene_eds_file,
+        tx_eqc_map: if dump_tx_eq {
+            Some(TxEqcMap::new(hdr.ref_names.clone()))
+        } else {
+            None
+        },
         row_index: 0usize,
         bootstrap_helper: boot_helper,
+        em_stats_file,
+        em_summary: EmSummary::default(),
+        ordered: deterministic,
+        next_cell: 0usize,
+        pending: BTreeMap::new(),
     }));
 
     let mmrate = Arc::new(Mutex::new(vec![0f64; num_cells as usize]));
 
-    let mut thread_handles: Vec<thread::JoinHandle<usize>> = Vec::with_capacity(n_workers);
+    let mut thread_handles: Vec<thread::JoinHandle<std::io::Result<usize>>> =
+        Vec::with_capacity(n_workers);
 
     // This is the hash table that will hold the global
     // (i.e. across all cells) gene-level equivalence
@@ -636,9 +1351,9 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
         let tid_to_gid = tid_to_gid_shared.clone();
         // and the atomic counter of remaining work
         let cells_remaining = cells_to_process.clone();
+        let quant_failed = quant_failed.clone();
         // they will need to know the bc and umi type
-        let bc_type = bc_type;
-        let umi_type = umi_type;
+        let types = types;
         // and the file writer
         let bcout = bc_writer.clone();
         // global gene-level eqc map
@@ -649,6 +1364,8 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
         let empty_resolved_cells = empty_resolved_cells.clone();
         let unmapped_count = bc_unmapped_map.clone();
         let mmrate = mmrate.clone();
+        let cross_cell_prior = cross_cell_prior.clone();
+        let label_lengths = label_lengths.clone();
 
         // if we are performing parsimony-gene or parsimony-gene-em
         // resolution, then the equivalence classes will be immediately
@@ -674,18 +1391,22 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
         };
 
         // now, make the worker thread
-        let handle = std::thread::spawn(move || {
+        let handle = std::thread::spawn(move || -> std::io::Result<usize> {
             // these can be created once and cleared after processing
             // each cell.
             let mut unique_evidence = vec![false; num_rows];
             let mut no_ambiguity = vec![false; num_rows];
             let mut eq_map = EqMap::new(num_eq_targets, eq_map_type);
+            // if we are dumping the transcript-level equivalence classes,
+            // they are collected here, independently of the resolution
+            // strategy.
+            let mut tx_eq_map = if dump_tx_eq {
+                Some(EqMap::new(ref_count, EqMapType::TranscriptLevel))
+            } else {
+                None
+            };
             let mut expressed_vec = Vec::<f32>::with_capacity(num_genes);
             let mut expressed_ind = Vec::<usize>::with_capacity(num_genes);
-            let mut eds_bytes = Vec::<u8>::new();
-            let mut bt_eds_bytes: Vec<u8> = Vec::new();
-            let mut eds_mean_bytes: Vec<u8> = Vec::new();
-            let mut eds_var_bytes: Vec<u8> = Vec::new();
 
             // the variable we will use to bind the *cell-specific* gene-level
             // equivalence class table.
@@ -698,8 +1419,9 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
             // classes of size greater than 1, and probabilistic results
             // will attempt to resolve gene multi-mapping reads by
             // running an EM algorithm.
-            let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
-            let mut gene_eqc: HashMap<Vec<u32>, u32, ahash::RandomState> = HashMap::with_hasher(s);
+            let eqc_hasher = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+            let mut gene_eqc: HashMap<Vec<u32>, u32, ahash::RandomState> =
+                HashMap::with_hasher(eqc_hasher.clone());
 
             let em_init_type = if init_uniform {
                 EmInitType::Uniform
@@ -715,8 +1437,9 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
 
             let mut local_nrec = 0usize;
             // pop MetaChunks from the work queue until everything is
-            // processed
-            while cells_remaining.load(Ordering::SeqCst) > 0 {
+            // processed (or another thread has failed)
+            while cells_remaining.load(Ordering::SeqCst) > 0 && !quant_failed.load(Ordering::SeqCst)
+            {
                 if let Some((
                     first_cell_in_chunk,
                     cells_in_chunk,
@@ -738,25 +1461,105 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
                             BufReader::new(&buf[byte_offset..(byte_offset + nbytes as usize)]);
                         byte_offset += nbytes as usize;
 
-                        let mut c = rad_types::Chunk::from_bytes(&mut nbr, &bc_type, &umi_type);
-                        if c.reads.is_empty() {
-                            warn!(log, "Discovered empty chunk; should not happen! cell_num = {}, nbytes = {}, nrec = {}", cell_num, nbytes, nrec);
-                        }
-
-                        // TODO: Clean up the expect() and merge with the check above
-                        // the expect shouldn't happen, but the message is redundant with
-                        // the above.  Plus, this would panic if it actually occurred.
-                        let bc = c.reads.first().expect("chunk with no reads").bc;
+                        let chunk = io_utils::read_chunk(&mut nbr, &types).and_then(|c| {
+                            if c.reads.is_empty() {
+                                Err(std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    format!(
+                                        "empty chunk in the collated file (cell_num = {}, nbytes = {}, nrec = {})",
+                                        cell_num, nbytes, nrec
+                                    ),
+                                ))
+                            } else {
+                                Ok(c)
+                            }
+                        });
+                        let mut c = match chunk {
+                            Ok(c) => c,
+                            Err(e) => {
+                                quant_failed.store(true, Ordering::SeqCst);
+                                return Err(e);
+                            }
+                        };
+                        let bc = c.reads[0].bc;
+                        // the source of randomness for this cell; in deterministic
+                        // mode this depends only on the seed and the barcode.
+                        let mut rng = afutils::cell_rng(rng_seed, bc);
+
+                        // if we are dumping the transcript-level equivalence
+                        // classes, record, for each class in this cell, its
+                        // number of distinct UMIs (or, without UMIs, of reads).
+                        let cell_tx_eqc = match &mut tx_eq_map {
+                            Some(tx_eq_map) => {
+                                tx_eq_map.init_from_chunk(&mut c);
+                                let mut ceqc = tx_eq_map
+                                    .eqc_info
+                                    .iter()
+                                    .map(|eqinfo| {
+                                        let count = if count_reads {
+                                            eqinfo.umis.iter().map(|(_, n)| *n).sum()
+                                        } else {
+                                            eqinfo.umis.len() as u32
+                                        };
+                                        (tx_eq_map.refs_for_eqc(eqinfo.eq_num).to_vec(), count)
+                                    })
+                                    .collect::<Vec<(Vec<u32>, u32)>>();
+                                tx_eq_map.clear();
+                                if deterministic {
+                                    ceqc.sort_unstable();
+                                }
+                                Some(ceqc)
+                            }
+                            None => None,
+                        };
+                        // the cross-cell abundances informing this cell's EM
+                        let cell_prior = cross_cell_prior.as_ref().map(|p| p.for_cell(bc));
+                        let cell_lengths = label_lengths.as_ref().map(|l| &l[..]);
 
                         // The structures we'll need to hold our output for this
                         // cell.
                         let mut counts: Vec<f32>;
                         let mut alt_resolution = false;
+                        // how the EM fared, if it was run for this cell
+                        let mut em_stats = EmStats::default();
 
                         let mut bootstraps: Vec<Vec<f32>> = Vec::new();
 
-                        let non_trivial = c.reads.len() >= small_thresh;
-                        if non_trivial {
+                        // without UMIs, the reads of even the smallest cells
+                        // are counted as for the other cells.
+                        let non_trivial = count_reads || c.reads.len() >= small_thresh;
+                        if transcript_level {
+                            // resolve the molecules (or reads) at the level of
+                            // transcripts; `gene_eqc` then holds transcript-level
+                            // equivalence classes.
+                            if count_reads {
+                                pugutils::get_num_reads(&c, None, &mut gene_eqc, &log);
+                            } else {
+                                eq_map.init_from_chunk(&mut c);
+                                pugutils::get_num_molecules_cell_ranger_like_transcript(
+                                    &eq_map,
+                                    &mut gene_eqc,
+                                    &log,
+                                );
+                                eq_map.clear();
+                            }
+                            let only_unique = resolution == ResolutionStrategy::CellRangerLike;
+                            (counts, em_stats) = em_optimize(
+                                &gene_eqc,
+                                &mut unique_evidence,
+                                &mut no_ambiguity,
+                                em_init_type,
+                                &mut rng,
+                                &em_opts,
+                                None,
+                                cell_lengths,
+                                num_rows,
+                                only_unique,
+                                &log,
+                            );
+                            unique_evidence.fill(false);
+                            no_ambiguity.fill(false);
+                        } else if non_trivial {
                             // TODO: some testing was done, but see if there
                             // is a better way to set this value.
                             let small_cell = c.reads.len() <= 250;
@@ -767,7 +1570,14 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
                             match resolution {
                                 ResolutionStrategy::CellRangerLike
                                 | ResolutionStrategy::CellRangerLikeEm => {
-                                    if small_cell {
+                                    if count_reads {
+                                        pugutils::get_num_reads(
+                                            &c,
+                                            Some(&tid_to_gid[..]),
+                                            &mut gene_eqc,
+                                            &log,
+                                        );
+                                    } else if small_cell {
                                         pugutils::get_num_molecules_cell_ranger_like_small(
                                             &mut c,
                                             &tid_to_gid,
@@ -806,12 +1616,16 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
                                                 &mut idx_eq_list,
                                                 &mut eq_id_count,
                                             );
-                                            counts = em_optimize_subset(
+                                            (counts, em_stats) = em_optimize_subset(
                                                 &idx_eq_list,
                                                 &eq_id_count,
                                                 &mut unique_evidence,
                                                 &mut no_ambiguity,
                                                 em_init_type,
+                                                &mut rng,
+                                                &em_opts,
+                                                cell_prior,
+                                                cell_lengths,
                                                 num_rows,
                                                 only_unique,
                                                 usa_offsets,
@@ -820,11 +1634,15 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
                                         }
                                         (false, _) => {
                                             // not USA-mode
-                                            counts = em_optimize(
+                                            (counts, em_stats) = em_optimize(
                                                 &gene_eqc,
                                                 &mut unique_evidence,
                                                 &mut no_ambiguity,
                                                 em_init_type,
+                                                &mut rng,
+                                                &em_opts,
+                                                cell_prior,
+                                                cell_lengths,
                                                 num_genes,
                                                 only_unique,
                                                 &log,
@@ -890,12 +1708,16 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
                                                 &mut idx_eq_list,
                                                 &mut eq_id_count,
                                             );
-                                            counts = em_optimize_subset(
+                                            (counts, em_stats) = em_optimize_subset(
                                                 &idx_eq_list,
                                                 &eq_id_count,
                                                 &mut unique_evidence,
                                                 &mut no_ambiguity,
                                                 em_init_type,
+                                                &mut rng,
+                                                &em_opts,
+                                                cell_prior,
+                                                cell_lengths,
                                                 num_rows,
                                                 only_unique,
                                                 usa_offsets,
@@ -904,11 +1726,15 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
                                         }
                                         (false, _) => {
                                             // not USA-mode
-                                            counts = em_optimize(
+                                            (counts, em_stats) = em_optimize(
                                                 &gene_eqc,
                                                 &mut unique_evidence,
                                                 &mut no_ambiguity,
                                                 em_init_type,
+                                                &mut rng,
+                                                &em_opts,
+                                                cell_prior,
+                                                cell_lengths,
                                                 num_genes,
                                                 only_unique,
                                                 &log,
@@ -919,14 +1745,63 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
                             }
 
                             if num_bootstraps > 0 {
-                                bootstraps = run_bootstrap(
-                                    &gene_eqc,
-                                    num_bootstraps,
-                                    &counts,
-                                    init_uniform,
-                                    summary_stat,
-                                    &log,
-                                );
+                                bootstraps = if usa_mode {
+                                    // bootstrapping is only allowed with the EM
+                                    // resolution strategies, so `idx_eq_list` and
+                                    // `eq_id_count` hold this cell's USA-mode
+                                    // equivalence classes.
+                                    run_bootstrap_subset(
+                                        &idx_eq_list,
+                                        &eq_id_count,
+                                        num_rows as u32,
+                                        num_bootstraps,
+                                        init_uniform,
+                                        summary_stat,
+                                        &mut rng,
+                                        &em_opts,
+                                        cell_lengths,
+                                        usa_offsets,
+                                        &log,
+                                    )
+                                } else {
+                                    run_bootstrap(
+                                        &gene_eqc,
+                                        num_bootstraps,
+                                        &counts,
+                                        init_uniform,
+                                        summary_stat,
+                                        &mut rng,
+                                        &em_opts,
+                                        cell_lengths,
+                                        &log,
+                                    )
+                                };
+                            } else if gibbs_opts.num_samples > 0 {
+                                // as with bootstrapping, in USA-mode the cell's
+                                // equivalence classes are in `idx_eq_list`.
+                                bootstraps = if usa_mode {
+                                    run_gibbs_subset(
+                                        &idx_eq_list,
+                                        &eq_id_count,
+                                        &counts,
+                                        gibbs_opts,
+                                        summary_stat,
+                                        &mut rng,
+                                        cell_lengths,
+                                        usa_offsets,
+                                        &log,
+                                    )
+                                } else {
+                                    run_gibbs(
+                                        &gene_eqc,
+                                        &counts,
+                                        gibbs_opts,
+                                        summary_stat,
+                                        &mut rng,
+                                        cell_lengths,
+                                        &log,
+                                    )
+                                };
                             }
 
                             // clear our local variables
@@ -992,12 +1867,12 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
                                     }
                                 }
                             }
-                            // if the user requested bootstraps
+                            // if the user requested bootstraps (or posterior samples)
                             // NOTE: we check that the specified resolution method
                             // is conceptually compatible with bootstrapping before
                             // invoking `quant`, so we don't bother checking that
                             // here.
-                            if num_bootstraps > 0 {
+                            if num_replicates > 0 {
                                 // TODO: should issue a warning here,
                                 // bootstrapping doesn't make sense for
                                 // unfiltered data.
@@ -1005,10 +1880,10 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
                                     // sample mean = quant
                                     bootstraps.push(counts.clone());
                                     // sample var = 0
-                                    bootstraps.push(vec![0f32; num_genes]);
+                                    bootstraps.push(vec![0f32; num_rows]);
                                 } else {
                                     // no variation
-                                    for _ in 0..num_bootstraps {
+                                    for _ in 0..num_replicates {
                                         bootstraps.push(counts.clone());
                                     }
                                 }
@@ -1065,128 +1940,117 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
                         // expressed mean / max expression
                         let mean_by_max = mean_expr / max_umi;
 
-                        let row_index: usize; // the index for this row (cell)
-                        {
-                            // writing the files
-                            let bc_mer: BitKmer = (bc, bclen as u8);
+                        // gather the output for this cell
+                        let bc_mer: BitKmer = (bc, bclen as u8);
+                        let bc_str = String::from_utf8(bitmer_to_bytes(bc_mer))
+                            .expect("barcode should be valid UTF-8");
 
-                            if !use_mtx {
-                                eds_bytes = sce::eds::as_bytes(&counts, num_rows)
-                                    .expect("can't convert vector to eds");
-                            }
+                        let cell_counts = if !use_mtx {
+                            CellCounts::Eds(
+                                sce::eds::as_bytes(&counts, num_rows)
+                                    .expect("can't convert vector to eds"),
+                            )
+                        } else {
+                            CellCounts::Sparse(
+                                expressed_ind
+                                    .iter()
+                                    .copied()
+                                    .zip(expressed_vec.iter().copied())
+                                    .collect(),
+                            )
+                        };
 
-                            // write bootstraps
-                            if num_bootstraps > 0 {
-                                // flatten the bootstraps
-                                if summary_stat {
-                                    eds_mean_bytes = sce::eds::as_bytes(&bootstraps[0], num_rows)
-                                        .expect("can't convert vector to eds");
-                                    eds_var_bytes = sce::eds::as_bytes(&bootstraps[1], num_rows)
-                                        .expect("can't convert vector to eds");
-                                } else {
-                                    for i in 0..num_bootstraps {
-                                        let bt_eds_bytes_slice =
-                                            sce::eds::as_bytes(&bootstraps[i as usize], num_rows)
-                                                .expect("can't convert vector to eds");
-                                        bt_eds_bytes.append(&mut bt_eds_bytes_slice.clone());
-                                    }
-                                }
+                        let gene_counts = if gene_aggregate {
+                            let gcounts =
+                                aggregate_to_genes(&counts, &tid_to_gid, num_gene_rows, usa_mode);
+                            if !use_mtx {
+                                Some(CellCounts::Eds(
+                                    sce::eds::as_bytes(&gcounts, num_gene_rows)
+                                        .expect("can't convert vector to eds"),
+                                ))
+                            } else {
+                                Some(CellCounts::Sparse(
+                                    gcounts
+                                        .into_iter()
+                                        .enumerate()
+                                        .filter(|(_, c)| *c > 0.0)
+                                        .collect(),
+                                ))
                             }
+                        } else {
+                            None
+                        };
 
-                            let writer_deref = bcout.lock();
-                            let writer = &mut *writer_deref.unwrap();
-
-                            // get the row index and then increment it
-                            row_index = writer.row_index;
-                            writer.row_index += 1;
-
-                            // write to barcode file
-                            let bc_bytes = &bitmer_to_bytes(bc_mer)[..];
-                            writeln!(&mut writer.barcode_file, "{}", unsafe {
-                                std::str::from_utf8_unchecked(bc_bytes)
+                        // flatten the bootstraps (if summary_stat is set, these
+                        // are the mean and the variance).
+                        let bootstrap_eds = bootstraps
+                            .iter()
+                            .map(|b| {
+                                sce::eds::as_bytes(b, num_rows)
+                                    .expect("can't convert vector to eds")
                             })
-                            .expect("can't write to barcode file.");
+                            .collect::<Vec<Vec<u8>>>();
+
+                        let features = format!(
+                            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                            (num_mapped + num_unmapped),
+                            num_mapped,
+                            sum_umi,
+                            mapping_rate,
+                            dedup_rate,
+                            mean_by_max,
+                            num_expr,
+                            num_genes_over_mean
+                        );
 
-                            // write to matrix file
-                            if !use_mtx {
-                                // write in eds format
-                                writer
-                                    .eds_file
-                                    .write_all(&eds_bytes)
-                                    .expect("can't write to matrix file.");
-                            } else {
-                                // fill out the triplet matrix in memory
-                                for (ind, val) in expressed_ind.iter().zip(expressed_vec.iter()) {
-                                    writer.trimat.add_triplet(row_index, *ind, *val);
-                                }
+                        // if we are dumping the equivalence class output, keep
+                        // this cell's equivalence classes.
+                        let cell_eqc = if dump_eq {
+                            let mut ceqc = gene_eqc
+                                .iter()
+                                .map(|(labels, count)| (labels.clone(), *count))
+                                .collect::<Vec<(Vec<u32>, u32)>>();
+                            if deterministic {
+                                ceqc.sort_unstable();
                             }
-                            writeln!(
-                                &mut writer.feature_file,
-                                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-                                unsafe { std::str::from_utf8_unchecked(bc_bytes) },
-                                (num_mapped + num_unmapped),
-                                num_mapped,
-                                sum_umi,
-                                mapping_rate,
-                                dedup_rate,
-                                mean_by_max,
-                                num_expr,
-                                num_genes_over_mean
-                            )
-                            .expect("can't write to feature file");
+                            Some(ceqc)
+                        } else {
+                            None
+                        };
 
-                            if num_bootstraps > 0 {
-                                if summary_stat {
-                                    if let Some((meanf, varf)) =
-                                        &mut writer.bootstrap_helper.mean_var_files
-                                    {
-                                        meanf
-                                            .write_all(&eds_mean_bytes)
-                                            .expect("can't write to bootstrap mean file.");
-                                        varf.write_all(&eds_var_bytes)
-                                            .expect("can't write to bootstrap var file.");
-                                    }
-                                } else if let Some(bsfile) = &mut writer.bootstrap_helper.bsfile {
-                                    bsfile
-                                        .write_all(&bt_eds_bytes)
-                                        .expect("can't write to bootstrap file");
-                                }
-                            } // done bootstrap writing
+                        {
+                            let writer_deref = bcout.lock();
+                            let writer = &mut *writer_deref.unwrap();
+                            writer.submit_cell(
+                                cell_num,
+                                CellOutput {
+                                    bc_str,
+                                    counts: cell_counts,
+                                    features,
+                                    bootstrap_eds,
+                                    gene_eqc: cell_eqc,
+                                    tx_eqc: cell_tx_eqc,
+                                    em_stats,
+                                    gene_counts,
+                                },
+                                &eqid_map_lockc,
+                            );
                         }
 
-                        // if we are dumping the equivalence class output, fill in
-                        // the in-memory representation here.
-                        if dump_eq {
-                            let eqmap_deref = eqid_map_lockc.lock();
-                            let geqmap = &mut *eqmap_deref.unwrap();
-                            // the next available global id for a gene-level
-                            // equivalence class
-                            let mut next_id = geqmap.global_eqc.len() as u64;
-                            for (labels, count) in gene_eqc.iter() {
-                                let mut found = true;
-                                match geqmap.global_eqc.get(labels) {
-                                    Some(eqid) => {
-                                        geqmap.cell_level_count.push((*eqid, *count));
-                                    }
-                                    None => {
-                                        found = false;
-                                        geqmap.cell_level_count.push((next_id, *count));
-                                    }
-                                }
-                                if !found {
-                                    geqmap.global_eqc.insert(labels.to_vec().clone(), next_id);
-                                    next_id += 1;
-                                }
-                            }
-                            //let bc_mer: BitKmer = (bc, bclen as u8);
-                            geqmap.cell_offset.push((row_index, gene_eqc.len()));
+                        // clear the gene eqc map; in deterministic mode, we
+                        // start from a fresh map so that its iteration order
+                        // (and hence the order of floating point operations
+                        // in the EM) doesn't depend on the cells this thread
+                        // processed before.
+                        if deterministic {
+                            gene_eqc = HashMap::with_hasher(eqc_hasher.clone());
+                        } else {
+                            gene_eqc.clear();
                         }
-                        // clear the gene eqc map
-                        gene_eqc.clear();
                     } // for all cells in this meta chunk
                 } // while we can get work
             } // while cells remain
-            local_nrec
+            Ok(local_nrec)
         });
 
         thread_handles.push(handle);
@@ -1198,53 +2062,57 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
         // we have a retained set
         io_utils::fill_work_queue_filtered(
             ret_bc,
-            &rl_tags,
+            &types,
             q,
             br,
             hdr.num_chunks as usize,
             &pbar,
+            &quant_failed,
         )?;
     } else {
         // we're quantifying everything
-        io_utils::fill_work_queue(q, br, hdr.num_chunks as usize, &pbar)?;
+        io_utils::fill_work_queue(q, br, hdr.num_chunks as usize, &pbar, &quant_failed)?;
     }
 
     let gn_path = output_matrix_path.join("quants_mat_cols.txt");
-    let gn_file = File::create(gn_path).expect("couldn't create gene name file.");
-    let mut gn_writer = BufWriter::new(gn_file);
-
-    // if we are not using unspliced then just write the gene names
-    if !usa_mode {
-        for g in gene_names {
-            gn_writer.write_all(format!("{}\n", g).as_bytes())?;
-        }
-    } else {
-        // otherwise, we write the spliced names, the unspliced names, and then
-        // the ambiguous names
-        for g in gene_names.iter() {
-            gn_writer.write_all(format!("{}\n", *g).as_bytes())?;
-        }
-        // unspliced
-        for g in gene_names.iter() {
-            gn_writer.write_all(format!("{}-U\n", *g).as_bytes())?;
+    if transcript_level {
+        // the columns are the reference transcripts
+        let mut tn_writer = BufWriter::new(File::create(gn_path)?);
+        for t in hdr.ref_names.iter() {
+            tn_writer.write_all(format!("{}\n", t).as_bytes())?;
         }
-        // ambiguous
-        for g in gene_names.iter() {
-            gn_writer.write_all(format!("{}-A\n", *g).as_bytes())?;
+        tn_writer.flush()?;
+        if gene_aggregate {
+            write_gene_names(
+                &output_matrix_path.join("quants_gene_mat_cols.txt"),
+                &gene_names,
+                usa_mode,
+            )?;
         }
+    } else {
+        write_gene_names(&gn_path, &gene_names, usa_mode)?;
     }
 
     let mut total_records = 0usize;
+    let mut quant_error = None;
     for h in thread_handles {
         match h.join() {
-            Ok(rc) => {
+            Ok(Ok(rc)) => {
                 total_records += rc;
             }
+            Ok(Err(e)) => {
+                quant_error.get_or_insert(anyhow::Error::new(e));
+            }
             Err(_e) => {
-                info!(log, "thread panicked");
+                crit!(log, "thread panicked");
+                quant_error.get_or_insert(anyhow::anyhow!("a quantification thread panicked"));
             }
         }
     }
+    // don't write a partial matrix
+    if let Some(e) = quant_error {
+        return Err(e.context("could not quantify the collated cells.").into());
+    }
 
     // write to matrix market if we are using it
     if use_mtx {
@@ -1257,6 +2125,19 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
         sprs::io::write_matrix_market(mtx_path, &writer.trimat)?;
     }
 
+    // write the gene-level aggregate of the transcript-level counts
+    {
+        let mut guard = bc_writer.lock().unwrap();
+        let writer = &mut *guard;
+        if let Some(gene_trimat) = &writer.gene_trimat {
+            let mtx_path = output_matrix_path.join("quants_gene_mat.mtx");
+            sprs::io::write_matrix_market(mtx_path, gene_trimat)?;
+        }
+        if let Some(gene_eds_file) = &mut writer.gene_eds_file {
+            gene_eds_file.flush()?;
+        }
+    }
+
     let pb_msg = format!(
         "finished quantifying {} cells.",
         num_cells.to_formatted_string(&Locale::en)
@@ -1273,25 +2154,71 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
         write_eqc_counts(&eqid_map_lock, num_rows, usa_mode, &output_matrix_path, log)?;
     }
 
+    if let Some(tx_eqc_map) = &bc_writer.lock().unwrap().tx_eqc_map {
+        info!(
+            log,
+            "Writing transcript-level equivalence classes with {:?} classes",
+            tx_eqc_map.dump.eq_labels.len()
+        );
+        tx_eqc_map
+            .dump
+            .write(output_matrix_path.join("tx_eqc.bin"))?;
+    }
+
+    // the cells are recorded in the order in which they were finished,
+    // so put them in collated-file order.
+    alt_res_cells.lock().unwrap().sort_unstable();
+    empty_resolved_cells.lock().unwrap().sort_unstable();
+
+    // summarize how the EM fared across cells
+    let em_summary = {
+        let mut guard = bc_writer.lock().unwrap();
+        let writer = &mut *guard;
+        match &mut writer.em_stats_file {
+            Some(ef) => {
+                ef.flush()?;
+                Some(writer.em_summary.to_json())
+            }
+            None => None,
+        }
+    };
+
+    // record which kind of inferential replicates (if any) were written
+    let replicate_type = if num_bootstraps > 0 {
+        Some("bootstrap")
+    } else if gibbs_opts.num_samples > 0 {
+        Some("gibbs")
+    } else {
+        None
+    };
+
     let meta_info = json!({
     "cmd" : quant_opts.cmdline,
     "version_str": quant_opts.version,
     "resolution_strategy" : resolution.to_string(),
     "num_quantified_cells" : num_cells,
-    "num_genes" : num_rows,
+    "num_genes" : num_gene_rows,
+    "num_transcripts" : if transcript_level { Some(num_rows) } else { None },
+    "matrix_dimensions" : [num_cells, num_rows],
+    "transcript_level" : transcript_level,
+    "length_aware" : quant_opts.ref_lengths.is_some(),
     "dump_eq" : dump_eq,
+    "dump_tx_eq" : dump_tx_eq,
     "usa_mode" : usa_mode,
     "alt_resolved_cell_numbers" : *alt_res_cells.lock().unwrap(),
     "empty_resolved_cell_numbers" : *empty_resolved_cells.lock().unwrap(),
+    "replicate_type" : replicate_type,
+    "num_replicates" : num_replicates,
+    "em_summary" : em_summary,
     "quant_options" : quant_opts
     });
 
     let mut meta_info_file =
-        File::create(output_path.join("quant.json")).expect("couldn't create quant.json file.");
-    let aux_info_str = serde_json::to_string_pretty(&meta_info).expect("could not format json.");
+        File::create(output_path.join("quant.json")).context("couldn't create quant.json file.")?;
+    let aux_info_str = serde_json::to_string_pretty(&meta_info)?;
     meta_info_file
         .write_all(aux_info_str.as_bytes())
-        .expect("cannot write to quant.json file");
+        .context("cannot write to quant.json file")?;
 
     // k3yavi: Todo delete after api stability
     // creating a dummy cmd_info.json for R compatibility
@@ -1308,12 +2235,174 @@ pub fn do_quantify<T: Read>(mut br: T, quant_opts: QuantOpts) -> anyhow::Result<
     .write_all(cmd_info_str.as_bytes())
     .expect("cannot write to quant_cmd_info.json file");
     */
-    Ok(())
+    let num_alt_resolved_cells = alt_res_cells.lock().unwrap().len();
+    let num_empty_resolved_cells = empty_resolved_cells.lock().unwrap().len();
+    Ok(QuantStats {
+        output_dir: output_path.to_path_buf(),
+        num_cells,
+        num_genes: num_rows,
+        num_records: total_records,
+        usa_mode,
+        num_alt_resolved_cells,
+        num_empty_resolved_cells,
+    })
 }
 
 // TODO: see if we'd rather pass an structure
 // with these options
-pub fn velo_quantify(_quant_opts: QuantOpts) -> anyhow::Result<()> {
+pub fn velo_quantify(_quant_opts: QuantOpts) -> Result<QuantStats, AlevinFryError> {
     unimplemented!("not implemented on this branch yet");
     //Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A collated RAD file without UMIs, with two references; each chunk
+    // is the (barcode, reference id) pairs of the reads of one cell.
+    fn umiless_rad_file(chunks: &[Vec<(u32, u32)>]) -> Vec<u8> {
+        let tag_section = |tags: &[(&str, u8)]| {
+            let mut b = (tags.len() as u16).to_le_bytes().to_vec();
+            for (name, typeid) in tags {
+                b.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                b.extend_from_slice(name.as_bytes());
+                b.push(*typeid);
+            }
+            b
+        };
+        let mut b = vec![0u8];
+        b.extend_from_slice(&2u64.to_le_bytes());
+        for name in ["t0", "t1"] {
+            b.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            b.extend_from_slice(name.as_bytes());
+        }
+        b.extend_from_slice(&(chunks.len() as u64).to_le_bytes());
+        b.extend_from_slice(&tag_section(&[("cblen", 2), ("ulen", 2)]));
+        b.extend_from_slice(&tag_section(&[("b", 3)]));
+        b.extend_from_slice(&tag_section(&[("compressed_ori_refid", 3)]));
+        b.extend_from_slice(&4u16.to_le_bytes());
+        b.extend_from_slice(&4u16.to_le_bytes());
+        for recs in chunks {
+            b.extend_from_slice(&((8 + 12 * recs.len()) as u32).to_le_bytes());
+            b.extend_from_slice(&(recs.len() as u32).to_le_bytes());
+            for (bc, tid) in recs {
+                b.extend_from_slice(&1u32.to_le_bytes());
+                b.extend_from_slice(&bc.to_le_bytes());
+                b.extend_from_slice(&(0x8000_0000 | tid).to_le_bytes());
+            }
+        }
+        b
+    }
+
+    #[test]
+    fn test_cross_cell_prior_counts_reads() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        // one cell with 3 reads of gene 0 and 1 read of gene 1; without
+        // UMIs, every read has a UMI of 0.
+        let rad = umiless_rad_file(&[vec![(5, 0), (5, 0), (5, 0), (5, 1)]]);
+        let prior = |count_reads: bool| {
+            CrossCellPrior::from_collated(
+                &rad[..],
+                &[0, 1],
+                false,
+                2,
+                None,
+                None,
+                4,
+                count_reads,
+                &log,
+            )
+            .unwrap()
+        };
+        assert_eq!(prior(true).for_cell(5), &[0.75, 0.25]);
+        // deduplicating on the UMI leaves one molecule of each gene
+        assert_eq!(prior(false).for_cell(5), &[0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_aggregate_to_genes() {
+        // transcripts 1 and 2 are of gene 1
+        assert_eq!(
+            aggregate_to_genes(&[1.0, 2.0, 3.5], &[0, 1, 1], 2, false),
+            vec![1.0, 5.5]
+        );
+
+        // in USA-mode, the even ids are the spliced, and the odd ids the
+        // unspliced, status of gene id >> 1; with 2 genes, the rows are
+        // the spliced, unspliced and ambiguous rows of each gene.
+        let tid_to_gid = [0, 1, 2, 3, 0];
+        let gene_counts = aggregate_to_genes(&[1.0, 2.0, 3.0, 4.0, 5.0], &tid_to_gid, 6, true);
+        assert_eq!(&gene_counts[0..2], &[6.0, 3.0]);
+        assert_eq!(&gene_counts[2..4], &[2.0, 4.0]);
+        // no transcript is ambiguous
+        assert_eq!(&gene_counts[4..6], &[0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_ordered_output_reorders_cells() {
+        let dir = std::env::temp_dir().join(format!("alevin_fry_reorder_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let create = |name: &str| fs::File::create(dir.join(name)).unwrap();
+        let mut out = QuantOutputInfo {
+            barcode_file: BufWriter::new(create("quants_mat_rows.txt")),
+            eds_file: BufWriter::new(GzEncoder::new(
+                create("quants_mat.gz"),
+                Compression::default(),
+            )),
+            feature_file: BufWriter::new(create("featureDump.txt")),
+            trimat: sprs::TriMatI::<f32, u32>::new((3, 2)),
+            gene_trimat: None,
+            gene_eds_file: None,
+            tx_eqc_map: None,
+            row_index: 0,
+            bootstrap_helper: BootstrapHelper {
+                bsfile: None,
+                mean_var_files: None,
+            },
+            em_stats_file: None,
+            em_summary: EmSummary::default(),
+            ordered: true,
+            next_cell: 0,
+            pending: BTreeMap::new(),
+        };
+        let eqid_map_lock = Mutex::new(EqcMap {
+            global_eqc: HashMap::with_hasher(ahash::RandomState::new()),
+            cell_level_count: Vec::new(),
+            cell_offset: Vec::new(),
+        });
+        let cell = |i: usize| CellOutput {
+            bc_str: format!("cell{}", i),
+            counts: CellCounts::Sparse(vec![(1, i as f32)]),
+            features: String::new(),
+            bootstrap_eds: Vec::new(),
+            gene_eqc: None,
+            tx_eqc: None,
+            em_stats: EmStats::default(),
+            gene_counts: None,
+        };
+
+        // the cells finish out of order; none can be written before cell 0
+        out.submit_cell(2, cell(2), &eqid_map_lock);
+        out.submit_cell(1, cell(1), &eqid_map_lock);
+        let pending_before_first = out.pending.len();
+        out.submit_cell(0, cell(0), &eqid_map_lock);
+        out.barcode_file.flush().unwrap();
+        let rows = std::fs::read_to_string(dir.join("quants_mat_rows.txt")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(pending_before_first, 2);
+        assert!(out.pending.is_empty());
+        assert_eq!(out.next_cell, 3);
+        assert_eq!(rows, "cell0\ncell1\ncell2\n");
+        // row i of the matrix holds the counts of cell i
+        let rows_and_counts: Vec<(u32, f32)> = out
+            .trimat
+            .row_inds()
+            .iter()
+            .copied()
+            .zip(out.trimat.data().iter().copied())
+            .collect();
+        assert_eq!(rows_and_counts, vec![(0, 0.0), (1, 1.0), (2, 2.0)]);
+    }
+}
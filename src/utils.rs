@@ -5,10 +5,11 @@ use bstr::io::BufReadExt;
 use core::fmt;
 use libradicl::utils::SPLICE_MASK_U32;
 use needletail::bitkmer::*;
+use rand::{rngs::StdRng, SeedableRng};
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::path::PathBuf;
 use std::str::FromStr;
 use thiserror::Error;
@@ -284,6 +285,153 @@ pub fn parse_tg_map(
     }
 }
 
+/// Parse the lengths of the reference sequences from `reader`, which is
+/// either a FASTA file or a tab-separated file whose first two columns are
+/// the name and the length of each sequence (the first line is skipped as a
+/// header if its second column is not a number, so e.g. a salmon `quant.sf`
+/// file can be used).  Returns the length of each reference, indexed by the
+/// ids in `rname_to_id`; every reference must be present.
+fn parse_ref_lengths<R: BufRead>(
+    reader: R,
+    rname_to_id: &HashMap<String, u32, ahash::RandomState>,
+) -> anyhow::Result<Vec<f32>> {
+    let mut lengths = vec![-1.0f32; rname_to_id.len()];
+    let mut set_length = |name: &str, len: f32| {
+        if let Some(id) = rname_to_id.get(name) {
+            lengths[*id as usize] = len;
+        }
+    };
+
+    let mut lines = reader.lines().peekable();
+    let is_fasta = match lines.peek() {
+        Some(Ok(l)) => l.starts_with('>'),
+        _ => false,
+    };
+
+    if is_fasta {
+        // the name of the current record and its length so far
+        let mut current: Option<(String, usize)> = None;
+        for line in lines {
+            let line = line?;
+            if let Some(header) = line.strip_prefix('>') {
+                if let Some((name, len)) = current.take() {
+                    set_length(&name, len as f32);
+                }
+                let name = header.split_whitespace().next().unwrap_or("");
+                current = Some((name.to_string(), 0));
+            } else if let Some((_, len)) = current.as_mut() {
+                *len += line.trim_end().len();
+            }
+        }
+        if let Some((name, len)) = current.take() {
+            set_length(&name, len as f32);
+        }
+    } else {
+        for (lnum, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut fields = line.split('\t');
+            let name = fields.next().unwrap_or("");
+            let len_field = fields.next().with_context(|| {
+                format!(
+                    "line {} of the reference lengths file doesn't have 2 tab-separated columns",
+                    lnum + 1
+                )
+            })?;
+            match len_field.trim().parse::<f32>() {
+                Ok(len) => set_length(name, len),
+                // a header line
+                Err(_) if lnum == 0 => {}
+                Err(e) => {
+                    return Err(anyhow!(
+                        "invalid length on line {} of the reference lengths file: {}",
+                        lnum + 1,
+                        e
+                    ));
+                }
+            }
+        }
+    }
+
+    let num_missing = lengths.iter().filter(|l| **l < 0.0).count();
+    if num_missing > 0 {
+        return Err(anyhow!(
+            "the reference lengths file is missing the length of {} of the {} reference sequences",
+            num_missing,
+            lengths.len()
+        ));
+    }
+    Ok(lengths)
+}
+
+/// Read the lengths of the reference sequences from the FASTA or
+/// tab-separated file `path`; see `parse_ref_lengths`.
+pub fn read_ref_lengths(
+    path: &PathBuf,
+    rname_to_id: &HashMap<String, u32, ahash::RandomState>,
+) -> anyhow::Result<Vec<f32>> {
+    let f = File::open(path).with_context(|| {
+        format!(
+            "couldn't open the reference lengths file {}",
+            path.display()
+        )
+    })?;
+    parse_ref_lengths(BufReader::new(f), rname_to_id)
+}
+
+/// The length of each row of the count vector, given the length of each
+/// reference sequence.  At the transcript level, these are just the
+/// reference lengths.  At the gene level, they are the mean length of the
+/// transcripts of each gene; in USA-mode, the spliced (unspliced) row of a
+/// gene takes the mean over the spliced (unspliced) sequences of the gene,
+/// and the ambiguous row takes the mean over all of them.
+pub fn row_lengths(
+    ref_lengths: &[f32],
+    tid_to_gid: &[u32],
+    num_rows: usize,
+    usa_mode: bool,
+    transcript_level: bool,
+) -> Vec<f32> {
+    if transcript_level {
+        return ref_lengths.iter().map(|l| l.max(1.0)).collect();
+    }
+
+    let mut sums = vec![0f64; num_rows];
+    let mut counts = vec![0u32; num_rows];
+    let mut add = |row: usize, len: f32| {
+        sums[row] += len as f64;
+        counts[row] += 1;
+    };
+    let unspliced_offset = num_rows / 3;
+    let ambig_offset = 2 * unspliced_offset;
+    for (len, gid) in ref_lengths.iter().zip(tid_to_gid.iter()) {
+        if usa_mode {
+            let base = (gid >> 1) as usize;
+            if is_spliced(*gid) {
+                add(base, *len);
+            } else {
+                add(unspliced_offset + base, *len);
+            }
+            add(ambig_offset + base, *len);
+        } else {
+            add(*gid as usize, *len);
+        }
+    }
+
+    sums.iter()
+        .zip(counts.iter())
+        .map(|(s, c)| {
+            if *c > 0 {
+                ((s / *c as f64) as f32).max(1.0)
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
 /// Extracts UMI counts from the `gene_eqc` HashMap.
 /// This function is to be used when we are counting UMIs in
 /// USA mode, and when we do not wish to consider gene-ambiguous
@@ -730,6 +878,41 @@ pub fn is_velo_mode(input_dir: &PathBuf) -> bool {
     }
 }
 
+/// Returns the peak resident set size (the "high water mark") of this
+/// process, in bytes, as reported by `/proc/self/status`.  Returns `None`
+/// if this information is not available (e.g. on a non-Linux system).
+pub fn peak_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmHWM:"))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+/// Attempts to reset the peak resident set size of this process to its
+/// current resident set size, so that [`peak_rss_bytes`] reports the peak
+/// usage from this point on.  Returns true if the reset succeeded.
+pub fn reset_peak_rss() -> bool {
+    // see the description of /proc/[pid]/clear_refs in proc(5)
+    std::fs::write("/proc/self/clear_refs", "5").is_ok()
+}
+
+/// Returns the random number generator to be used when processing the cell
+/// with barcode `bc`.  If a `seed` is provided, the generator is seeded from
+/// the seed and the barcode, so that the random draws made for a cell do not
+/// depend upon the thread that processes it or the order in which cells are
+/// processed.  Otherwise, the generator is seeded from the thread-local
+/// generator.
+pub fn cell_rng(seed: Option<u64>, bc: u64) -> StdRng {
+    match seed {
+        // mix the barcode (using the 64-bit golden ratio constant) so that
+        // nearby barcodes don't yield closely related seeds.
+        Some(s) => StdRng::seed_from_u64(s ^ bc.wrapping_mul(0x9E37_79B9_7F4A_7C15)),
+        None => StdRng::from_rng(rand::thread_rng()).expect("could not seed the cell RNG"),
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, PartialEq, Eq)]
 pub struct InternalVersionInfo {
@@ -782,12 +965,15 @@ impl FromStr for InternalVersionInfo {
 
 #[cfg(test)]
 mod tests {
+    use crate::utils::cell_rng;
     use crate::utils::generate_whitelist_set;
     use crate::utils::get_all_indels;
     use crate::utils::get_all_one_edit_neighbors;
     use crate::utils::get_all_snps;
     use crate::utils::get_bit_mask;
+    use crate::utils::parse_ref_lengths;
     use crate::utils::InternalVersionInfo;
+    use std::collections::HashMap;
     use std::collections::HashSet;
     use std::str::FromStr;
 
@@ -804,6 +990,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cell_rng() {
+        use rand::Rng;
+        let draw = |seed, bc| cell_rng(seed, bc).gen::<u64>();
+        assert_eq!(draw(Some(42), 7), draw(Some(42), 7));
+        assert_ne!(draw(Some(42), 7), draw(Some(42), 8));
+        assert_ne!(draw(Some(42), 7), draw(Some(43), 7));
+    }
+
+    #[test]
+    fn test_parse_ref_lengths() {
+        let mut rname_to_id = HashMap::with_hasher(ahash::RandomState::new());
+        rname_to_id.insert("tx1".to_string(), 0u32);
+        rname_to_id.insert("tx2".to_string(), 1u32);
+
+        let fasta = ">tx2 some description\nACGT\nAC\n>tx1\nACG\n>tx3\nA\n";
+        let lens = parse_ref_lengths(fasta.as_bytes(), &rname_to_id).unwrap();
+        assert_eq!(lens, vec![3.0, 6.0]);
+
+        let tsv = "Name\tLength\tEffectiveLength\ntx1\t100\t80.5\ntx2\t250\t230\n";
+        let lens = parse_ref_lengths(tsv.as_bytes(), &rname_to_id).unwrap();
+        assert_eq!(lens, vec![100.0, 250.0]);
+
+        let missing = "tx1\t100\n";
+        assert!(parse_ref_lengths(missing.as_bytes(), &rname_to_id).is_err());
+    }
+
     #[test]
     fn test_get_bit_mask() {
         let mut output = Vec::new();